@@ -1,21 +1,30 @@
-use crate::{GROUP_BOT_COMMANDS, MANAGER_COMMANDS, PRIVATE_BOT_COMMANDS};
+use crate::data::LogError;
+use crate::{GROUP_BOT_COMMANDS, MANAGER_COMMANDS, PLAY_BOT_COMMANDS, PRIVATE_BOT_COMMANDS};
 use async_recursion::async_recursion;
-use futures::StreamExt;
+use futures::future::{join_all, BoxFuture};
+use futures::FutureExt;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt::Debug;
-use std::ops::Add;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use telegram_bot::{
-    Api, ChatId, ChatMemberStatus, ChatRef, Document, EditMessageText, GetChatMember, GetFile,
-    HttpRequest, Integer, KeyboardButton, KickChatMember, Message, MessageId, MessageOrChannelPost,
-    ParseMode, ReplyKeyboardMarkup, ReplyKeyboardRemove, ReplyMarkup, Request, RequestType,
-    RequestUrl, ResponseType, SendMessage, ToChatRef, UpdateKind, User, UserId,
+    AnswerCallbackQuery, Api, CallbackQuery, ChatId, ChatMemberStatus, ChatRef, Document,
+    EditMessageText, GetChatMember, GetFile, HttpRequest, InlineKeyboardButton,
+    InlineKeyboardMarkup, InputFileUpload, Integer, KeyboardButton, KickChatMember, Message,
+    MessageId, MessageOrChannelPost, ParseMode, ReplyKeyboardMarkup, ReplyKeyboardRemove,
+    ReplyMarkup, Request, RequestType, RequestUrl, ResponseType, SendDocument, SendMessage,
+    ToChatRef, Update, UpdateKind, User, UserId,
 };
 use telegram_bot::{JsonIdResponse, True};
 use telegram_bot::{JsonRequestType, ToMessageId};
-use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::OwnedMutexGuard;
 use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
@@ -138,12 +147,12 @@ struct CreateChatInviteLink {
 }
 
 impl CreateChatInviteLink {
-    pub fn new(chat_id: ChatId) -> Self {
+    pub fn new(chat_id: ChatId, member_limit: Integer, expire_date: Integer) -> Self {
         CreateChatInviteLink {
             chat_id: chat_id.to_chat_ref(),
             name: None,
-            expire_date: None,
-            member_limit: None,
+            expire_date: Some(expire_date),
+            member_limit: Some(member_limit),
             creates_join_request: None,
         }
     }
@@ -158,97 +167,844 @@ impl Request for CreateChatInviteLink {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct PinChatMessage {
+    chat_id: ChatRef,
+    message_id: MessageId,
+    disable_notification: bool,
+}
+
+impl PinChatMessage {
+    pub fn new(chat_id: ChatId, message_id: MessageId) -> Self {
+        PinChatMessage {
+            chat_id: chat_id.to_chat_ref(),
+            message_id,
+            disable_notification: true,
+        }
+    }
+}
+
+impl Request for PinChatMessage {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<True>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("pinChatMessage"), self)
+    }
+}
+
+/// `SendMessage` with the forum topic, which the telegram_bot crate doesn't know about.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct SendThreadMessage<'s> {
+    #[serde(flatten)]
+    message: SendMessage<'s>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_thread_id: Option<Integer>,
+}
+
+impl<'s> Request for SendThreadMessage<'s> {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<MessageOrChannelPost>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("sendMessage"), self)
+    }
+}
+
+/// Updates are fetched raw, telegram_bot drops the fields it doesn't know, like the forum topic.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct GetRawUpdates {
+    offset: Integer,
+    timeout: Integer,
+}
+
+impl Request for GetRawUpdates {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<Vec<Value>>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("getUpdates"), self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct SendChatAction {
+    chat_id: ChatRef,
+    action: String,
+}
+
+impl SendChatAction {
+    pub fn new(chat_id: ChatId, action: &str) -> Self {
+        SendChatAction {
+            chat_id: chat_id.to_chat_ref(),
+            action: action.to_string(),
+        }
+    }
+}
+
+impl Request for SendChatAction {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<True>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("sendChatAction"), self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct UnpinChatMessage {
+    chat_id: ChatRef,
+    message_id: MessageId,
+}
+
+impl UnpinChatMessage {
+    pub fn new(chat_id: ChatId, message_id: MessageId) -> Self {
+        UnpinChatMessage {
+            chat_id: chat_id.to_chat_ref(),
+            message_id,
+        }
+    }
+}
+
+impl Request for UnpinChatMessage {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<True>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("unpinChatMessage"), self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct UnbanChatMember {
+    chat_id: ChatRef,
+    user_id: UserId,
+    only_if_banned: bool,
+}
+
+impl UnbanChatMember {
+    pub fn new(chat_id: ChatId, user_id: UserId) -> Self {
+        UnbanChatMember {
+            chat_id: chat_id.to_chat_ref(),
+            user_id,
+            only_if_banned: true,
+        }
+    }
+}
+
+impl Request for UnbanChatMember {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<True>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("unbanChatMember"), self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct DeleteMessage {
+    chat_id: ChatRef,
+    message_id: MessageId,
+}
+
+impl DeleteMessage {
+    pub fn new(chat_id: ChatId, message_id: MessageId) -> Self {
+        DeleteMessage {
+            chat_id: chat_id.to_chat_ref(),
+            message_id,
+        }
+    }
+}
+
+impl Request for DeleteMessage {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<True>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("deleteMessage"), self)
+    }
+}
+
+/// `sendPhoto` with the photo given by file id or URL, so it can be sent as JSON.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct SendPhoto {
+    chat_id: ChatRef,
+    photo: String,
+    caption: String,
+    parse_mode: ParseMode,
+    reply_markup: Option<ReplyMarkup>,
+}
+
+impl SendPhoto {
+    pub fn new(chat_id: ChatId, photo: String, caption: String) -> Self {
+        SendPhoto {
+            chat_id: chat_id.to_chat_ref(),
+            photo,
+            caption,
+            parse_mode: ParseMode::Html,
+            reply_markup: None,
+        }
+    }
+}
+
+impl Request for SendPhoto {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<MessageOrChannelPost>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("sendPhoto"), self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+struct EditMessageCaption {
+    chat_id: ChatRef,
+    message_id: MessageId,
+    caption: String,
+    parse_mode: ParseMode,
+    reply_markup: Option<ReplyMarkup>,
+}
+
+impl EditMessageCaption {
+    pub fn new(chat_id: ChatId, message_id: MessageId, caption: String) -> Self {
+        EditMessageCaption {
+            chat_id: chat_id.to_chat_ref(),
+            message_id,
+            caption,
+            parse_mode: ParseMode::Html,
+            reply_markup: None,
+        }
+    }
+}
+
+impl Request for EditMessageCaption {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<MessageOrChannelPost>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot::types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("editMessageCaption"), self)
+    }
+}
+
+#[derive(Debug)]
+pub enum BotError {
+    /// Telegram asked to wait longer than we are willing to.
+    RateLimited(Duration),
+    /// Telegram refused the request, retrying won't help.
+    BadRequest(String),
+    /// A single failed attempt, these are retried.
+    Network(String),
+    RetriesExhausted,
+}
+
+pub type BotResult<T> = Result<T, BotError>;
+
+impl BotError {
+    fn classify(error_message: String) -> Self {
+        if let Some(retry_after) = TelegramBot::retry_after(&error_message) {
+            BotError::RateLimited(retry_after)
+        } else if error_message.contains("Bad Request") || error_message.contains("Forbidden") {
+            BotError::BadRequest(error_message)
+        } else {
+            BotError::Network(error_message)
+        }
+    }
+
+    /// The user has blocked the bot or deleted their account.
+    pub fn is_blocked(&self) -> bool {
+        matches!(self, BotError::BadRequest(error_message) if error_message.contains("Forbidden"))
+    }
+
+    /// Telegram can't be reached, as opposed to refusing this particular request.
+    pub fn is_outage(&self) -> bool {
+        matches!(self, BotError::Network(_) | BotError::RetriesExhausted)
+    }
+}
+
+impl Display for BotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotError::RateLimited(retry_after) => {
+                write!(f, "Rate limited for {} seconds", retry_after.as_secs())
+            }
+            BotError::BadRequest(error_message) => write!(f, "{}", error_message),
+            BotError::Network(error_message) => write!(f, "Network error: {}", error_message),
+            BotError::RetriesExhausted => write!(f, "Retries limit reached"),
+        }
+    }
+}
+
+impl<T> LogError<T> for BotResult<T> {
+    fn log_error(self) -> Option<T> {
+        match self {
+            Ok(value) => Some(value),
+            Err(err) => {
+                log::error!("{}", err);
+                None
+            }
+        }
+    }
+}
+
+pub enum BotUpdate {
+    /// With the forum topic the message was sent to.
+    Message(Message, Option<Integer>),
+    CallbackQuery(CallbackQuery),
+}
+
+/// Spaces out requests by Telegram's limits: one message per second in a chat and about thirty
+/// overall. Senders to the same chat are served in the order they asked.
+struct RateLimiter {
+    chats: Mutex<HashMap<ChatId, Arc<tokio::sync::Mutex<Instant>>>>,
+    global: tokio::sync::Mutex<Instant>,
+    chat_interval: Duration,
+    global_interval: Duration,
+}
+
+/// Holds the chat's slot, the next one opens a second after the guard is dropped, whether the
+/// request succeeded, failed or the task was cancelled.
+struct SlotGuard(OwnedMutexGuard<Instant>, Duration);
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        *self.0 = Instant::now() + self.1;
+    }
+}
+
+impl RateLimiter {
+    const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+    const GLOBAL_INTERVAL: Duration = Duration::from_millis(35);
+
+    fn new() -> Self {
+        Self::with_intervals(Self::CHAT_INTERVAL, Self::GLOBAL_INTERVAL)
+    }
+
+    fn with_intervals(chat_interval: Duration, global_interval: Duration) -> Self {
+        RateLimiter {
+            chats: Mutex::new(HashMap::new()),
+            global: tokio::sync::Mutex::new(Instant::now()),
+            chat_interval,
+            global_interval,
+        }
+    }
+
+    async fn acquire(&self, chat_id: ChatId) -> SlotGuard {
+        let chat = self
+            .chats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(chat_id)
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(Instant::now())))
+            .clone();
+        let slot = chat.lock_owned().await;
+        tokio::time::sleep_until(*slot).await;
+        let mut global = self.global.lock().await;
+        tokio::time::sleep_until(*global).await;
+        *global = Instant::now() + self.global_interval;
+        SlotGuard(slot, self.chat_interval)
+    }
+}
+
+/// Requests made to Telegram, cheap enough to be bumped from every sending task.
+#[derive(Default)]
+pub struct BotStats {
+    requests: AtomicU64,
+    retries: AtomicU64,
+    failures: AtomicU64,
+    /// Milliseconds the last successful request took, zero before the first one.
+    round_trip: AtomicU64,
+}
+
+impl BotStats {
+    fn sent<T, E>(&self, started: Instant, result: &Result<T, E>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if result.is_ok() {
+            self.round_trip
+                .store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn retried(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request was given up on.
+    fn failed(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Display for BotStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "запросов {}, повторов {}, ошибок {}, последний ответ за {} мс",
+            self.requests.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed),
+            self.round_trip.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Optional message waiting in the queue of its chat.
+struct Queued {
+    text: String,
+    silent: bool,
+    thread_id: Option<Integer>,
+}
+
+/// Sends one chunk of an optional message, the error is the text of the Telegram error.
+type SendChunk =
+    Arc<dyn Fn(ChatId, String, &Queued) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// Optional messages waiting to be sent, a worker per chat sends them in the order they were
+/// queued, splitting and retrying them.
+struct Outbound {
+    queues: Mutex<HashMap<ChatId, UnboundedSender<Queued>>>,
+    limiter: Arc<RateLimiter>,
+    send_chunk: SendChunk,
+    stats: Arc<BotStats>,
+}
+
+impl Outbound {
+    /// A worker with nothing to send for this long stops, the next message starts a new one.
+    const IDLE: Duration = Duration::from_secs(600);
+
+    fn new(limiter: Arc<RateLimiter>, send_chunk: SendChunk, stats: Arc<BotStats>) -> Arc<Self> {
+        Arc::new(Outbound {
+            queues: Mutex::new(HashMap::new()),
+            limiter,
+            send_chunk,
+            stats,
+        })
+    }
+
+    fn queues(&self) -> MutexGuard<HashMap<ChatId, UnboundedSender<Queued>>> {
+        self.queues
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn push(self: &Arc<Self>, chat_id: ChatId, message: Queued) {
+        let mut queues = self.queues();
+        let sender = queues.entry(chat_id).or_insert_with(|| {
+            let (sender, receiver) = unbounded_channel();
+            tokio::spawn(self.clone().drain(chat_id, receiver));
+            sender
+        });
+        if let Err(err) = sender.send(message) {
+            log::error!("Outbound queue of chat {} is closed: {}", chat_id, err);
+            queues.remove(&chat_id);
+        }
+    }
+
+    async fn drain(self: Arc<Self>, chat_id: ChatId, mut receiver: UnboundedReceiver<Queued>) {
+        loop {
+            let message = match tokio::time::timeout(Self::IDLE, receiver.recv()).await {
+                Ok(Some(next)) => next,
+                Ok(None) => return,
+                Err(_) => {
+                    // A message pushed before the queue is dropped is still sent
+                    let mut queues = self.queues();
+                    match receiver.try_recv() {
+                        Ok(next) => next,
+                        Err(_) => {
+                            queues.remove(&chat_id);
+                            return;
+                        }
+                    }
+                }
+            };
+            self.send(chat_id, &message).await;
+        }
+    }
+
+    async fn send(&self, chat_id: ChatId, message: &Queued) {
+        for chunk in split_message(&message.text, TelegramBot::MAX_LEN) {
+            // Flood waits don't use up the tries
+            let mut tries = 0u8;
+            loop {
+                if tries == TelegramBot::TRIES {
+                    self.stats.failed();
+                    break;
+                }
+                let _slot = self.limiter.acquire(chat_id).await;
+                let started = Instant::now();
+                let result = (self.send_chunk)(chat_id, chunk.clone(), message).await;
+                self.stats.sent(started, &result);
+                let error_message = match result {
+                    Ok(()) => break,
+                    Err(error_message) => error_message,
+                };
+                match BotError::classify(error_message) {
+                    BotError::RateLimited(retry_after)
+                        if retry_after <= TelegramBot::MAX_RETRY_AFTER =>
+                    {
+                        self.stats.retried();
+                        tokio::time::sleep(retry_after + TelegramBot::jitter()).await;
+                    }
+                    BotError::Network(error_message) => {
+                        tries += 1;
+                        log::error!(
+                            "Error sending optional message: {}, message: {}",
+                            error_message,
+                            message.text
+                        );
+                        self.stats.retried();
+                    }
+                    err => {
+                        log::error!(
+                            "Error sending optional message, won't retry: {}, message: {}",
+                            err,
+                            message.text
+                        );
+                        self.stats.failed();
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// New text or caption of a message along with its inline keyboard.
+#[derive(Debug, Clone)]
+struct Edit {
+    chat_id: ChatId,
+    message_id: MessageId,
+    text: String,
+    /// The message is a photo, the text goes to its caption.
+    caption: bool,
+    reply_markup: Option<ReplyMarkup>,
+}
+
+impl Edit {
+    fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.text.hash(&mut hasher);
+        self.caption.hash(&mut hasher);
+        format!("{:?}", self.reply_markup).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Makes a single attempt to edit a message, the error is the text of the Telegram error.
+type SendEdit = Arc<dyn Fn(Edit) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// Content last sent to the most recently edited messages.
+struct EditCache {
+    shown: HashMap<(ChatId, MessageId), u64>,
+    order: VecDeque<(ChatId, MessageId)>,
+}
+
+impl EditCache {
+    const CAPACITY: usize = 1000;
+
+    fn new() -> Self {
+        EditCache {
+            shown: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns false if the message already shows this content.
+    fn update(&mut self, key: (ChatId, MessageId), hash: u64) -> bool {
+        if self.shown.insert(key, hash) == Some(hash) {
+            return false;
+        }
+        self.order.retain(|other| *other != key);
+        self.order.push_back(key);
+        if self.order.len() > Self::CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.shown.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// The edit didn't go through, so the message shows something else.
+    fn forget(&mut self, key: (ChatId, MessageId)) {
+        self.shown.remove(&key);
+        self.order.retain(|other| *other != key);
+    }
+}
+
+/// Sends edits, skipping the ones that wouldn't change the message, Telegram refuses those with
+/// "message is not modified".
+struct Edits {
+    cache: Mutex<EditCache>,
+    send_edit: SendEdit,
+    stats: Arc<BotStats>,
+}
+
+impl Edits {
+    const NOT_MODIFIED: &'static str = "message is not modified";
+
+    fn new(send_edit: SendEdit, stats: Arc<BotStats>) -> Arc<Self> {
+        Arc::new(Edits {
+            cache: Mutex::new(EditCache::new()),
+            send_edit,
+            stats,
+        })
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, EditCache> {
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    async fn send(&self, edit: Edit, tries: u8) -> BotResult<()> {
+        let key = (edit.chat_id, edit.message_id);
+        if !self.cache().update(key, edit.content_hash()) {
+            return Ok(());
+        }
+        let mut failed = 0u8;
+        let result = loop {
+            if failed == tries {
+                break Err(BotError::RetriesExhausted);
+            }
+            let started = Instant::now();
+            let result = (self.send_edit)(edit.clone()).await;
+            self.stats.sent(started, &result);
+            let error_message = match result {
+                Ok(()) => break Ok(()),
+                Err(error_message) => error_message,
+            };
+            if error_message.contains(Self::NOT_MODIFIED) {
+                break Ok(());
+            }
+            match BotError::classify(error_message) {
+                BotError::RateLimited(retry_after)
+                    if retry_after <= TelegramBot::MAX_RETRY_AFTER =>
+                {
+                    self.stats.retried();
+                    tokio::time::sleep(retry_after + TelegramBot::jitter()).await;
+                }
+                BotError::Network(error_message) => {
+                    self.stats.retried();
+                    failed += 1;
+                    log::error!(
+                        "Error editing message: {}, edit: {:#?}",
+                        error_message,
+                        edit
+                    );
+                    if failed < tries {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+                err => break Err(err),
+            }
+        };
+        if result.is_err() {
+            self.stats.failed();
+            self.cache().forget(key);
+        }
+        result
+    }
+}
+
 #[derive(Clone)]
 pub struct TelegramBot {
     token: String,
     api: Api,
-    next_time_slot: Arc<RwLock<HashMap<ChatId, Instant>>>,
+    limiter: Arc<RateLimiter>,
+    outbound: Arc<Outbound>,
+    edits: Arc<Edits>,
+    stats: Arc<BotStats>,
+    /// Forum topic new messages go to, the general one if `None`.
+    thread_id: Option<Integer>,
 }
 
 impl TelegramBot {
     const MAX_LEN: usize = 4096;
+    pub const MAX_CAPTION_LEN: usize = 1024;
     const TRIES: u8 = 20;
+    /// Longer flood waits are given up on instead of stalling the caller.
+    const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+    const INVITE_LINK_LIFETIME: Duration = Duration::from_secs(15 * 60);
+    /// Seconds Telegram holds a request for updates open when there are none.
+    const POLL_TIMEOUT: Integer = 30;
 
-    pub fn new(token: String) -> (TelegramBot, UnboundedReceiverStream<Message>) {
+    pub fn new(token: String) -> (TelegramBot, UnboundedReceiverStream<BotUpdate>) {
         let api = Api::new(token.clone());
-        let mut stream = api.stream();
         let (sender, receiver) = unbounded_channel();
+        tokio::spawn(Self::poll_updates(api.clone(), sender));
 
-        tokio::spawn(async move {
-            while let Some(update) = stream.next().await {
-                match update {
-                    Ok(update) => match update.kind {
-                        UpdateKind::Message(message) => match sender.send(message) {
-                            Ok(_) => {}
-                            Err(err) => {
-                                panic!("Error with sending update: {}", err);
-                            }
-                        },
-                        _ => {}
-                    },
-                    Err(err) => {
-                        log::error!("Error with update: {:#?}", err);
-                    }
-                };
-            }
-        });
-
+        let limiter = Arc::new(RateLimiter::new());
+        let stats = Arc::new(BotStats::default());
+        let send_api = api.clone();
+        let outbound = Outbound::new(
+            limiter.clone(),
+            Arc::new(move |chat_id, text, message: &Queued| {
+                let api = send_api.clone();
+                let mut request = Self::new_message(chat_id, text);
+                if message.silent {
+                    request.disable_notification();
+                }
+                let request = Self::in_topic(request, message.thread_id);
+                async move {
+                    api.send(request)
+                        .await
+                        .map(|_| ())
+                        .map_err(|err| format!("{}", err))
+                }
+                .boxed()
+            }),
+            stats.clone(),
+        );
+        let edit_api = api.clone();
+        let edits = Edits::new(
+            Arc::new(move |edit: Edit| {
+                let api = edit_api.clone();
+                async move {
+                    let result = if edit.caption {
+                        let mut request =
+                            EditMessageCaption::new(edit.chat_id, edit.message_id, edit.text);
+                        request.reply_markup = edit.reply_markup;
+                        api.send(request).await.map(|_| ())
+                    } else {
+                        let mut request =
+                            Self::new_edit_message(edit.chat_id, edit.message_id, edit.text);
+                        if let Some(reply_markup) = edit.reply_markup {
+                            request.reply_markup(reply_markup);
+                        }
+                        api.send(request).await.map(|_| ())
+                    };
+                    result.map_err(|err| format!("{}", err))
+                }
+                .boxed()
+            }),
+            stats.clone(),
+        );
         (
             TelegramBot {
                 token,
                 api,
-                next_time_slot: Arc::new(RwLock::new(HashMap::new())),
+                limiter,
+                outbound,
+                edits,
+                stats,
+                thread_id: None,
             },
             UnboundedReceiverStream::new(receiver),
         )
     }
 
+    async fn poll_updates(api: Api, sender: UnboundedSender<BotUpdate>) {
+        let mut offset = 0;
+        loop {
+            let request = GetRawUpdates {
+                offset,
+                timeout: Self::POLL_TIMEOUT,
+            };
+            let timeout = Duration::from_secs(Self::POLL_TIMEOUT as u64 + 10);
+            let updates = match tokio::time::timeout(timeout, api.send(request)).await {
+                Ok(Ok(updates)) => updates,
+                Ok(Err(err)) => {
+                    log::error!("Error with update: {:#?}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            for raw in updates {
+                if let Some(update_id) = raw.get("update_id").and_then(Value::as_i64) {
+                    offset = offset.max(update_id + 1);
+                }
+                // Replies in ordinary groups have a thread too, only forum topics are followed
+                let thread_id = raw
+                    .get("message")
+                    .filter(|message| message.get("is_topic_message") == Some(&Value::Bool(true)))
+                    .and_then(|message| message.get("message_thread_id"))
+                    .and_then(Value::as_i64);
+                let update = match serde_json::from_value::<Update>(raw) {
+                    Ok(update) => update,
+                    Err(err) => {
+                        log::error!("Error with update: {}", err);
+                        continue;
+                    }
+                };
+                let update = match update.kind {
+                    UpdateKind::Message(message) => BotUpdate::Message(message, thread_id),
+                    UpdateKind::CallbackQuery(query) => BotUpdate::CallbackQuery(query),
+                    _ => continue,
+                };
+                match sender.send(update) {
+                    Ok(_) => {}
+                    Err(err) => {
+                        panic!("Error with sending update: {}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The same bot sending new messages to the forum topic, or to the general one if it is
+    /// `None`.
+    pub fn in_thread(&self, thread_id: Option<Integer>) -> TelegramBot {
+        TelegramBot {
+            thread_id,
+            ..self.clone()
+        }
+    }
+
+    fn in_topic(message: SendMessage, thread_id: Option<Integer>) -> SendThreadMessage {
+        SendThreadMessage {
+            message,
+            message_thread_id: thread_id,
+        }
+    }
+
     #[async_recursion]
     pub async fn send_message(
         &self,
         chat_id: ChatId,
         text: String,
         keyboard_options: KeyboardOptions,
-    ) -> Option<MessageId> {
-        let text = text.as_str().chars().collect::<Vec<_>>();
-        if text.len() > Self::MAX_LEN {
-            let mut at = Self::MAX_LEN;
-            while text[at - 1] != '\n' {
-                at -= 1;
-                if at == 1usize {
-                    at = Self::MAX_LEN;
-                    break;
-                }
+    ) -> BotResult<MessageId> {
+        let chunks = split_message(&text, Self::MAX_LEN);
+        if chunks.len() > 1 {
+            let mut last = None;
+            for chunk in chunks {
+                last = Some(
+                    self.send_message(chat_id, chunk, keyboard_options.clone())
+                        .await?,
+                );
             }
-            self.send_message(
-                chat_id,
-                text[..at].iter().cloned().collect::<String>(),
-                keyboard_options,
-            )
-            .await;
-            if at + 1 != text.len() {
-                self.send_message(
-                    chat_id,
-                    text[at..].iter().cloned().collect::<String>(),
-                    keyboard_options,
-                )
-                .await;
-            }
-            return None;
+            return Ok(last.unwrap());
         }
-        let text = text.iter().cloned().collect::<String>();
-        self.wait_for_slot(chat_id).await;
-        self.block_slot(chat_id);
+        let _slot = self.limiter.acquire(chat_id).await;
         let mut request = SendMessage::new(chat_id, text.clone());
         request.parse_mode(ParseMode::Html);
         let reply_markup = keyboard_options.reply_markup();
         if let Some(reply_markup) = reply_markup {
             request.reply_markup(reply_markup);
         }
-        let message = self.send_request(request).await.unwrap();
-        self.save_slot(chat_id);
+        let message = self
+            .send_request(Self::in_topic(request, self.thread_id))
+            .await?;
         match message {
-            MessageOrChannelPost::Message(message) => Some(message.id),
+            MessageOrChannelPost::Message(message) => Ok(message.id),
+            MessageOrChannelPost::ChannelPost(_) => unreachable!(),
+        }
+    }
+
+    /// Photo with an html caption, fails with `BadRequest` if Telegram refused it (e.g. a broken
+    /// link).
+    pub async fn send_photo(
+        &self,
+        chat_id: ChatId,
+        photo: String,
+        caption: String,
+        keyboard_options: KeyboardOptions,
+    ) -> BotResult<MessageId> {
+        let _slot = self.limiter.acquire(chat_id).await;
+        let mut request = SendPhoto::new(chat_id, photo, caption);
+        request.reply_markup = keyboard_options.reply_markup();
+        match self.send_request(request).await? {
+            MessageOrChannelPost::Message(message) => Ok(message.id),
             MessageOrChannelPost::ChannelPost(_) => unreachable!(),
         }
     }
@@ -259,22 +1015,24 @@ impl TelegramBot {
         message
     }
 
+    pub fn stats(&self) -> &BotStats {
+        &self.stats
+    }
+
     pub async fn try_send_once(&self, chat_id: ChatId, message: String) -> Option<MessageId> {
         let message = message.as_str().chars().collect::<Vec<_>>();
         if message.len() > Self::MAX_LEN {
             None
         } else {
-            match self
-                .api
-                .send(Self::new_message(
-                    chat_id,
-                    message.iter().cloned().collect(),
-                ))
-                .await
-            {
+            let started = Instant::now();
+            let request = Self::new_message(chat_id, message.iter().cloned().collect());
+            let result = self.api.send(Self::in_topic(request, self.thread_id)).await;
+            self.stats.sent(started, &result);
+            match result {
                 Ok(res) => Some(res.to_message_id()),
                 Err(err) => {
                     log::error!("Try send once failed with error: {}", err);
+                    self.stats.failed();
                     None
                 }
             }
@@ -282,13 +1040,16 @@ impl TelegramBot {
     }
 
     pub fn try_edit_message(&self, chat_id: ChatId, message_id: MessageId, message: String) {
-        let bot = self.clone();
+        let edits = self.edits.clone();
         tokio::spawn(async move {
-            match bot
-                .api
-                .send(Self::new_edit_message(chat_id, message_id, message))
-                .await
-            {
+            let edit = Edit {
+                chat_id,
+                message_id,
+                text: message,
+                caption: false,
+                reply_markup: None,
+            };
+            match edits.send(edit, 1).await {
                 Ok(_) => {}
                 Err(err) => {
                     log::error!("Try edit message failed with error: {}", err);
@@ -297,59 +1058,51 @@ impl TelegramBot {
         });
     }
 
-    pub fn try_send_message(&self, chat_id: ChatId, message: String) {
+    /// Failures, e.g. when the bot may not delete messages in the chat, are only logged.
+    pub fn try_delete_message(&self, chat_id: ChatId, message_id: MessageId) {
         let bot = self.clone();
         tokio::spawn(async move {
-            let message = message.as_str().chars().collect::<Vec<_>>();
-            let mut from = 0usize;
-            while from < message.len() {
-                let mut to = message.len().min(from + Self::MAX_LEN);
-                while to != message.len() && message[to - 1] != '\n' {
-                    to -= 1;
-                    if to == from + 1 {
-                        to = from + Self::MAX_LEN;
-                        break;
-                    }
-                }
-                for _ in 0..Self::TRIES {
-                    match bot
-                        .api
-                        .send(Self::new_message(
-                            chat_id,
-                            message[from..to].iter().cloned().collect(),
-                        ))
-                        .await
-                    {
-                        Ok(_) => {
-                            break;
-                        }
-                        Err(err) => {
-                            let error_message = format!("{}", err);
-                            if error_message.contains("Bad Request")
-                                || error_message.contains("Forbidden")
-                            {
-                                log::error!(
-                                    "Error sending optional message, won't retry: {}, message: {}",
-                                    error_message,
-                                    message.iter().collect::<String>()
-                                );
-                                return;
-                            }
-                            log::error!(
-                                "Error sending optional message: {}, message: {}",
-                                error_message,
-                                message.iter().collect::<String>()
-                            );
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                        }
-                    }
+            match bot.api.send(DeleteMessage::new(chat_id, message_id)).await {
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("Delete message failed with error: {}", err);
                 }
-                from = to;
-                tokio::time::sleep(Duration::from_secs(1)).await;
             }
         });
     }
 
+    /// Shows the action, e.g. "typing", in the chat until the next message. A single attempt
+    /// outside of the rate limit, nothing depends on it arriving.
+    pub fn send_chat_action(&self, chat_id: ChatId, action: &str) {
+        let api = self.api.clone();
+        let request = SendChatAction::new(chat_id, action);
+        tokio::spawn(async move {
+            if let Err(err) = api.send(request).await {
+                log::debug!("Chat action failed with error: {}", err);
+            }
+        });
+    }
+
+    pub fn try_send_message(&self, chat_id: ChatId, message: String) {
+        self.try_send(chat_id, message, false);
+    }
+
+    /// Same as `try_send_message`, but the message arrives without a notification.
+    pub fn try_send_silent_message(&self, chat_id: ChatId, message: String) {
+        self.try_send(chat_id, message, true);
+    }
+
+    fn try_send(&self, chat_id: ChatId, message: String, silent: bool) {
+        self.outbound.push(
+            chat_id,
+            Queued {
+                text: message,
+                silent,
+                thread_id: self.thread_id,
+            },
+        );
+    }
+
     fn new_edit_message(
         chat_id: ChatId,
         message_id: MessageId,
@@ -360,60 +1113,165 @@ impl TelegramBot {
         edit
     }
 
-    pub async fn edit_message(&self, chat_id: ChatId, message_id: MessageId, text: String) {
-        self.send_request(Self::new_edit_message(chat_id, message_id, text))
-            .await;
+    pub async fn edit_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+    ) -> BotResult<()> {
+        self.edit_message_with_markup(chat_id, message_id, text, KeyboardOptions::None)
+            .await
     }
 
-    pub async fn get_file(&self, document: Document) -> Option<(String, String)> {
-        let doc = document.clone();
-        if document.file_name.is_none()
-            || document
-                .mime_type
-                .unwrap_or_else(|| "".to_string())
-                .starts_with("text")
-        {
-            return None;
-        }
-        let file = self.send_request(GetFile::new(doc.clone())).await.unwrap();
-        let url = file.get_url(self.token.as_str());
-        if url.is_none() {
-            return None;
-        }
-        let url = url.unwrap();
-        download_content(url)
+    /// Edits the message and replaces its inline keyboard; `KeyboardOptions::None` removes it.
+    pub async fn edit_message_with_markup(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        keyboard_options: KeyboardOptions,
+    ) -> BotResult<()> {
+        let edit = Edit {
+            chat_id,
+            message_id,
+            text,
+            caption: false,
+            reply_markup: keyboard_options.reply_markup(),
+        };
+        self.edits.send(edit, Self::TRIES).await
+    }
+
+    /// Same as `edit_message_with_markup`, for messages sent with `send_photo`.
+    pub async fn edit_caption_with_markup(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        caption: String,
+        keyboard_options: KeyboardOptions,
+    ) -> BotResult<()> {
+        let edit = Edit {
+            chat_id,
+            message_id,
+            text: caption,
+            caption: true,
+            reply_markup: keyboard_options.reply_markup(),
+        };
+        self.edits.send(edit, Self::TRIES).await
+    }
+
+    /// Stops the loading indicator on the pressed button, doesn't wait for the reply.
+    pub fn try_answer_callback_query(&self, query: CallbackQuery) {
+        let bot = self.clone();
+        tokio::spawn(async move {
+            match bot.api.send(AnswerCallbackQuery::new(query, "")).await {
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("Answer callback query failed with error: {}", err);
+                }
+            }
+        });
+    }
+
+    /// Name and text content of the document, only text files are supported.
+    pub async fn get_file(&self, document: Document) -> Result<(String, String), FileError> {
+        let (file_name, content) = self.get_file_bytes(document).await?;
+        let content = String::from_utf8(content).map_err(|_| FileError::Unsupported)?;
+        Ok((
+            file_name,
+            content.trim_start_matches('\u{feff}').to_string(),
+        ))
+    }
+
+    /// Name and raw content of the document.
+    pub async fn get_file_bytes(&self, document: Document) -> Result<(String, Vec<u8>), FileError> {
+        let file_name = document.file_name.clone().ok_or(FileError::Unsupported)?;
+        let file = self
+            .send_request(GetFile::new(document))
+            .await
+            .map_err(|_| FileError::DownloadFailed)?;
+        let url = file
+            .get_url(self.token.as_str())
+            .ok_or(FileError::DownloadFailed)?;
+        let content = download_content(url)
             .await
-            .map(|content| (document.file_name.unwrap(), content))
+            .ok_or(FileError::DownloadFailed)?;
+        Ok((file_name, content))
+    }
+
+    pub async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: String,
+        content: Vec<u8>,
+    ) -> BotResult<()> {
+        let _slot = self.limiter.acquire(chat_id).await;
+        self.send_request(SendDocument::new(
+            chat_id,
+            InputFileUpload::with_data(content, file_name),
+        ))
+        .await
+        .map(|_| ())
     }
 
-    pub async fn kick(&self, chat_id: ChatId, user_id: UserId) {
-        self.wait_for_slot(chat_id).await;
-        self.block_slot(chat_id);
+    /// Removes the user from the chat, unless `ban` is set they can join again with a new link.
+    pub async fn kick(&self, chat_id: ChatId, user_id: UserId, ban: bool) -> BotResult<()> {
+        let _slot = self.limiter.acquire(chat_id).await;
         if self.is_chat_member(chat_id, user_id).await {
             self.send_request(KickChatMember::new(chat_id, user_id))
-                .await;
+                .await?;
+            if !ban {
+                self.send_request(UnbanChatMember::new(chat_id, user_id))
+                    .await?;
+            }
         }
-        self.save_slot(chat_id);
+        Ok(())
     }
 
-    pub async fn invalidate_invite_link(&self, chat_id: ChatId, invite_link: String) {
-        self.wait_for_slot(chat_id).await;
-        self.block_slot(chat_id);
-        self.send_request(RevokeChatInviteLink::new(chat_id, invite_link))
-            .await;
-        self.save_slot(chat_id);
+    /// Pins without notifying the chat members.
+    pub async fn pin_message(&self, chat_id: ChatId, message_id: MessageId) -> BotResult<()> {
+        self.send_request(PinChatMessage::new(chat_id, message_id))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn unpin_message(&self, chat_id: ChatId, message_id: MessageId) -> BotResult<()> {
+        self.send_request(UnpinChatMessage::new(chat_id, message_id))
+            .await
+            .map(|_| ())
     }
 
-    pub async fn create_invite_link(&self, chat_id: ChatId) -> String {
-        self.wait_for_slot(chat_id).await;
-        self.block_slot(chat_id);
-        let res = self
-            .send_request(CreateChatInviteLink::new(chat_id))
+    pub async fn invalidate_invite_link(
+        &self,
+        chat_id: ChatId,
+        invite_link: String,
+    ) -> BotResult<()> {
+        let _slot = self.limiter.acquire(chat_id).await;
+        self.send_request(RevokeChatInviteLink::new(chat_id, invite_link))
             .await
+            .map(|_| ())
+    }
+
+    /// Link for the given number of joiners, it expires in `INVITE_LINK_LIFETIME`.
+    pub async fn create_invite_link(
+        &self,
+        chat_id: ChatId,
+        member_limit: usize,
+    ) -> BotResult<String> {
+        let _slot = self.limiter.acquire(chat_id).await;
+        // Telegram accepts limits from 1 to 99999
+        let member_limit = member_limit.clamp(1, 99999) as Integer;
+        let expire_date = (SystemTime::now() + Self::INVITE_LINK_LIFETIME)
+            .duration_since(UNIX_EPOCH)
             .unwrap()
-            .invite_link;
-        self.save_slot(chat_id);
-        res
+            .as_secs() as Integer;
+        Ok(self
+            .send_request(CreateChatInviteLink::new(
+                chat_id,
+                member_limit,
+                expire_date,
+            ))
+            .await?
+            .invite_link)
     }
 
     async fn is_chat_member(&self, chat_id: ChatId, user_id: UserId) -> bool {
@@ -421,18 +1279,19 @@ impl TelegramBot {
             .send_request(GetChatMember::new(chat_id, user_id))
             .await
         {
-            None => false,
-            Some(member) => member.status == ChatMemberStatus::Member,
+            Err(_) => false,
+            Ok(member) => member.status == ChatMemberStatus::Member,
         }
     }
 
-    pub async fn all_players_in_chat(&self, chat_id: ChatId, users: Vec<UserId>) -> bool {
-        for user_id in users {
-            if self.is_chat_member(chat_id, user_id).await {
-                return false;
-            }
-        }
-        true
+    /// Membership of each of the users, queried concurrently.
+    pub async fn chat_members(&self, chat_id: ChatId, users: &[UserId]) -> Vec<bool> {
+        join_all(
+            users
+                .iter()
+                .map(|user_id| self.is_chat_member(chat_id, *user_id)),
+        )
+        .await
     }
 
     fn build_commands(commands: &[(&str, &str)]) -> Vec<BotCommand> {
@@ -446,99 +1305,133 @@ impl TelegramBot {
     }
 
     //noinspection RsSelfConvention
-    pub async fn set_commands(&self, main_chat: ChatId, manager: ChatId) {
+    pub async fn set_commands(&self, main_chat: ChatId, managers: &[ChatId]) -> BotResult<()> {
         self.send_request(SetMyCommands::new(
             Self::build_commands(&PRIVATE_BOT_COMMANDS),
             "all_private_chats",
             None,
         ))
-        .await;
-        let mut manager_commands = Self::build_commands(&MANAGER_COMMANDS);
-        manager_commands.append(&mut Self::build_commands(&PRIVATE_BOT_COMMANDS));
-        self.send_request(SetMyCommands::new(manager_commands, "chat", Some(manager)))
-            .await;
+        .await?;
+        for manager in managers {
+            self.set_manager_commands(*manager).await?;
+        }
         self.send_request(SetMyCommands::new(
             Self::build_commands(&GROUP_BOT_COMMANDS),
             "all_group_chats",
             None,
         ))
-        .await;
+        .await?;
         self.send_request(DeleteMyCommands::new("chat", Some(main_chat)))
-            .await;
+            .await?;
         self.send_request(SetMyCommands::new(Vec::new(), "chat", Some(main_chat)))
-            .await;
+            .await?;
+        Ok(())
     }
 
-    async fn wait_for_slot(&self, chat_id: ChatId) {
-        let now = Instant::now();
-        // let guard = ;
-        let next_time_slot = self
-            .next_time_slot
-            .read()
-            .unwrap()
-            .get(&chat_id)
-            .unwrap_or(&now)
-            .clone();
-        if next_time_slot > now {
-            tokio::time::sleep_until(next_time_slot).await;
-        }
+    //noinspection RsSelfConvention
+    pub async fn set_manager_commands(&self, manager: ChatId) -> BotResult<()> {
+        let mut manager_commands = Self::build_commands(&MANAGER_COMMANDS);
+        manager_commands.append(&mut Self::build_commands(&PRIVATE_BOT_COMMANDS));
+        self.send_request(SetMyCommands::new(manager_commands, "chat", Some(manager)))
+            .await
+            .map(|_| ())
     }
 
-    fn save_slot(&self, chat_id: ChatId) {
-        let mut guard = self.next_time_slot.write().unwrap();
-        guard.insert(chat_id, Instant::now().add(Duration::from_secs(1)));
+    //noinspection RsSelfConvention
+    pub async fn set_play_commands(&self) -> BotResult<()> {
+        self.send_request(SetMyCommands::new(
+            Self::build_commands(&PLAY_BOT_COMMANDS),
+            "all_group_chats",
+            None,
+        ))
+        .await
+        .map(|_| ())
     }
 
-    fn block_slot(&self, chat_id: ChatId) {
-        let mut guard = self.next_time_slot.write().unwrap();
-        guard.insert(chat_id, Instant::now().add(Duration::from_secs(100)));
+    /// The chat falls back to the commands of all private chats.
+    pub async fn remove_manager_commands(&self, manager: ChatId) -> BotResult<()> {
+        self.send_request(DeleteMyCommands::new("chat", Some(manager)))
+            .await
+            .map(|_| ())
     }
 
     async fn send_request<Req: Request + Clone + Debug>(
         &self,
         request: Req,
-    ) -> Option<<<Req as Request>::Response as ResponseType>::Type> {
-        for _ in 0..Self::TRIES {
+    ) -> BotResult<<<Req as Request>::Response as ResponseType>::Type> {
+        let mut tries = 0u8;
+        while tries < Self::TRIES {
+            let started = Instant::now();
             let result = self.api.send(request.clone()).await;
+            self.stats.sent(started, &result);
             match result {
                 Ok(result) => {
-                    return Some(result);
+                    return Ok(result);
                 }
-                Err(err) => {
-                    let error_message = format!("{}", err);
-                    if error_message.contains("Bad Request") || error_message.contains("Forbidden")
-                    {
+                Err(err) => match BotError::classify(format!("{}", err)) {
+                    BotError::RateLimited(retry_after) if retry_after <= Self::MAX_RETRY_AFTER => {
+                        self.stats.retried();
+                        log::warn!("Too many requests, retrying in {:?}", retry_after);
+                        tokio::time::sleep(retry_after + Self::jitter()).await;
+                    }
+                    BotError::Network(error_message) => {
+                        tries += 1;
+                        self.stats.retried();
                         log::error!(
-                            "Error sending message, won't retry: {}, message: {:#?}",
+                            "Error sending message: {}, message: {:#?}",
                             error_message,
                             request
                         );
-                        return None;
+                        tokio::time::sleep(Duration::from_secs(1)).await;
                     }
-                    log::error!(
-                        "Error sending message: {}, message: {:#?}",
-                        error_message,
-                        request
-                    );
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-                }
+                    err => {
+                        log::error!(
+                            "Error sending message, won't retry: {}, message: {:#?}",
+                            err,
+                            request
+                        );
+                        self.stats.failed();
+                        return Err(err);
+                    }
+                },
             }
         }
-        panic!("Retries limit reached");
+        log::error!("Retries limit reached, giving up on: {:#?}", request);
+        self.stats.failed();
+        Err(BotError::RetriesExhausted)
+    }
+
+    /// Telegram answers flood with "Too Many Requests: retry after N", N in seconds.
+    fn retry_after(error_message: &str) -> Option<Duration> {
+        const PREFIX: &str = "retry after ";
+        let at = error_message.find(PREFIX)? + PREFIX.len();
+        let seconds = error_message[at..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>();
+        seconds.parse().ok().map(Duration::from_secs)
+    }
+
+    /// Keeps requests that were told to wait the same time from all coming back at once.
+    fn jitter() -> Duration {
+        Duration::from_millis(rand::thread_rng().gen_range(0..500))
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub enum KeyboardOptions {
     None,
     Remove,
-    Plus,
+    InlinePlus,
+    YesNo,
     YesNoPause,
     YesNoContinue,
+    Numbers(Vec<usize>),
 }
 
 impl KeyboardOptions {
-    const PLUS: [&'static str; 1] = ["+"];
+    const PLUS: &'static str = "+";
+    const YES_NO: [&'static str; 2] = ["да", "нет"];
     const YES_NO_PAUSE: [&'static str; 3] = ["да", "нет", "пауза"];
     const YES_NO_CONTINUE: [&'static str; 3] = ["да", "нет", "продолжить"];
 
@@ -548,13 +1441,25 @@ impl KeyboardOptions {
             KeyboardOptions::Remove => {
                 Some(ReplyMarkup::ReplyKeyboardRemove(ReplyKeyboardRemove::new()))
             }
-            KeyboardOptions::Plus => Some(Self::build_keyboard(&Self::PLUS)),
+            KeyboardOptions::InlinePlus => Some(ReplyMarkup::InlineKeyboardMarkup(
+                InlineKeyboardMarkup::from(vec![vec![InlineKeyboardButton::callback(
+                    Self::PLUS,
+                    Self::PLUS,
+                )]]),
+            )),
+            KeyboardOptions::YesNo => Some(Self::build_keyboard(&Self::YES_NO)),
             KeyboardOptions::YesNoPause => Some(Self::build_keyboard(&Self::YES_NO_PAUSE)),
             KeyboardOptions::YesNoContinue => Some(Self::build_keyboard(&Self::YES_NO_CONTINUE)),
+            KeyboardOptions::Numbers(numbers) => Some(Self::build_keyboard(
+                &numbers
+                    .iter()
+                    .map(|number| number.to_string())
+                    .collect::<Vec<_>>(),
+            )),
         }
     }
 
-    fn build_keyboard(keys: &[&'static str]) -> ReplyMarkup {
+    fn build_keyboard<S: AsRef<str>>(keys: &[S]) -> ReplyMarkup {
         ReplyMarkup::ReplyKeyboardMarkup(ReplyKeyboardMarkup::from(vec![keys
             .iter()
             .map(|key| KeyboardButton::new(key))
@@ -562,11 +1467,127 @@ impl KeyboardOptions {
     }
 }
 
-pub async fn download_content(url: String) -> Option<String> {
+enum HtmlToken {
+    Text(String),
+    Open(String, String),
+    Close(String, String),
+}
+
+impl HtmlToken {
+    fn text(&self) -> &str {
+        match self {
+            HtmlToken::Text(text) | HtmlToken::Open(_, text) | HtmlToken::Close(_, text) => text,
+        }
+    }
+}
+
+/// Single characters, entities like `&amp;` and whole tags, so a split never cuts through them.
+fn html_tokens(text: &str) -> Vec<HtmlToken> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut at = 0;
+    while at < chars.len() {
+        let end = match chars[at] {
+            '<' => chars[at..].iter().position(|c| *c == '>'),
+            '&' => chars[at..]
+                .iter()
+                .take(10)
+                .position(|c| *c == ';' || c.is_whitespace())
+                .filter(|end| chars[at + end] == ';'),
+            _ => None,
+        }
+        .map_or(at + 1, |end| at + end + 1);
+        let token = chars[at..end].iter().collect::<String>();
+        tokens.push(if end - at > 1 && chars[at] == '<' {
+            let name = token
+                .trim_start_matches(&['<', '/'][..])
+                .trim_end_matches('>')
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_string();
+            if chars[at + 1] == '/' {
+                HtmlToken::Close(name, token)
+            } else {
+                HtmlToken::Open(name, token)
+            }
+        } else {
+            HtmlToken::Text(token)
+        });
+        at = end;
+    }
+    tokens
+}
+
+fn closing_tags(open: &[(String, String)]) -> String {
+    open.iter()
+        .rev()
+        .map(|(name, _)| format!("</{}>", name))
+        .collect()
+}
+
+/// Splits html into chunks of at most `max_len` characters, preferably after a newline. Tags open
+/// at a split are closed at the end of the chunk and reopened at the start of the next one.
+pub fn split_message(text: &str, max_len: usize) -> Vec<String> {
+    let tokens = html_tokens(text);
+    let mut chunks = Vec::new();
+    let mut open: Vec<(String, String)> = Vec::new();
+    let mut start = 0;
+    while start < tokens.len() {
+        let prefix = open.iter().map(|(_, tag)| tag.as_str()).collect::<String>();
+        let mut len = prefix.chars().count();
+        let mut current = open.clone();
+        let mut end = start;
+        let mut split = None;
+        while end < tokens.len() {
+            let mut next = current.clone();
+            match &tokens[end] {
+                HtmlToken::Text(_) => {}
+                HtmlToken::Open(name, tag) => next.push((name.clone(), tag.clone())),
+                HtmlToken::Close(name, _) => {
+                    if let Some(pos) = next.iter().rposition(|(open, _)| open == name) {
+                        next.remove(pos);
+                    }
+                }
+            }
+            let token_len = tokens[end].text().chars().count();
+            if end > start && len + token_len + closing_tags(&next).chars().count() > max_len {
+                break;
+            }
+            len += token_len;
+            current = next;
+            end += 1;
+            if tokens[end - 1].text() == "\n" {
+                split = Some((end, current.clone()));
+            }
+        }
+        let (end, next_open) = match split {
+            Some(split) if end < tokens.len() => split,
+            _ => (end, current),
+        };
+        let mut chunk = prefix;
+        for token in tokens[start..end].iter() {
+            chunk += token.text();
+        }
+        chunk += closing_tags(&next_open).as_str();
+        chunks.push(chunk);
+        open = next_open;
+        start = end;
+    }
+    chunks
+}
+
+pub enum FileError {
+    /// No file name or content that isn't utf-8 text.
+    Unsupported,
+    DownloadFailed,
+}
+
+pub async fn download_content(url: String) -> Option<Vec<u8>> {
     let response = reqwest::get(url.as_str()).await;
     match response {
-        Ok(response) => match response.text().await {
-            Ok(text) => Some(text),
+        Ok(response) => match response.bytes().await {
+            Ok(bytes) => Some(bytes.to_vec()),
             Err(err) => {
                 log::error!("Error processing downloaded file: {}", err);
                 None
@@ -578,3 +1599,227 @@ pub async fn download_content(url: String) -> Option<String> {
         }
     }
 }
+
+#[test]
+fn test_retry_after() {
+    assert_eq!(
+        TelegramBot::retry_after("Too Many Requests: retry after 35"),
+        Some(Duration::from_secs(35))
+    );
+    assert_eq!(
+        TelegramBot::retry_after("Too Many Requests: retry after 7 (chat)"),
+        Some(Duration::from_secs(7))
+    );
+    assert_eq!(
+        TelegramBot::retry_after("Bad Request: chat not found"),
+        None
+    );
+    assert_eq!(TelegramBot::retry_after("retry after soon"), None);
+}
+
+#[test]
+fn test_classify_error() {
+    assert!(matches!(
+        BotError::classify("Too Many Requests: retry after 35".to_string()),
+        BotError::RateLimited(retry_after) if retry_after == Duration::from_secs(35)
+    ));
+    assert!(matches!(
+        BotError::classify("Forbidden: bot was kicked from the group chat".to_string()),
+        BotError::BadRequest(_)
+    ));
+    let err = BotError::classify("error trying to connect: Connection refused".to_string());
+    assert!(matches!(err, BotError::Network(_)));
+    assert!(err.is_outage());
+    assert!(!BotError::classify("Bad Request: chat not found".to_string()).is_outage());
+}
+
+#[test]
+fn test_split_rating_list() {
+    let mut text = "<b>Рейтинг игроков:</b>\n".to_string();
+    for place in 1..=300 {
+        text += format!(
+            "<b>{}.</b> <a href=\"tg://user?id={}\">Игрок &amp; {}</a> 1500\n",
+            place, place, place
+        )
+        .as_str();
+    }
+    let chunks = split_message(&text, TelegramBot::MAX_LEN);
+    assert!(chunks.len() > 1);
+    for chunk in chunks.iter() {
+        assert!(chunk.chars().count() <= TelegramBot::MAX_LEN);
+        assert!(chunk.ends_with('\n'));
+        assert_eq!(chunk.matches("<b>").count(), chunk.matches("</b>").count());
+        assert_eq!(chunk.matches("<a ").count(), chunk.matches("</a>").count());
+    }
+    assert_eq!(chunks.concat(), text);
+}
+
+#[test]
+fn test_split_long_line() {
+    let text = format!("<b>{}</b>", "x".repeat(5000));
+    let chunks = split_message(&text, TelegramBot::MAX_LEN);
+    assert_eq!(chunks.len(), 2);
+    for chunk in chunks.iter() {
+        assert!(chunk.chars().count() <= TelegramBot::MAX_LEN);
+        assert!(chunk.starts_with("<b>") && chunk.ends_with("</b>"));
+    }
+    assert_eq!(chunks.concat().matches('x').count(), 5000);
+    let text = "&amp;".repeat(1000);
+    let chunks = split_message(&text, TelegramBot::MAX_LEN);
+    assert_eq!(chunks.len(), 2);
+    assert!(chunks.iter().all(|chunk| chunk.len() % 5 == 0));
+    assert_eq!(chunks.concat(), text);
+    assert!(split_message("", TelegramBot::MAX_LEN).is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_outbound_order() {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let recorded = sent.clone();
+    let (delivered_sender, mut delivered) = unbounded_channel();
+    let stats = Arc::new(BotStats::default());
+    let outbound = Outbound::new(
+        Arc::new(RateLimiter::with_intervals(Duration::ZERO, Duration::ZERO)),
+        Arc::new(move |chat_id: ChatId, text: String, _: &Queued| {
+            let recorded = recorded.clone();
+            let delivered_sender = delivered_sender.clone();
+            async move {
+                // Earlier messages take longer, so they would overtake each other if sent at once
+                let delay = 20 - text.parse::<u64>().unwrap_or(0);
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                let failure = match text.as_str() {
+                    "3" => "failed",
+                    "flood" => "flood wait",
+                    _ => "",
+                };
+                let failures = recorded
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(id, text)| *id == chat_id && text == failure)
+                    .count();
+                let error = match failure {
+                    "failed" if failures == 0 => "Internal Server Error",
+                    // Waits don't count as tries, so even more of them than tries are waited out
+                    "flood wait" if failures <= TelegramBot::TRIES as usize => {
+                        "Too Many Requests: retry after 1"
+                    }
+                    _ => {
+                        recorded.lock().unwrap().push((chat_id, text));
+                        delivered_sender.send(chat_id).unwrap();
+                        return Ok(());
+                    }
+                };
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((chat_id, failure.to_string()));
+                Err(error.to_string())
+            }
+            .boxed()
+        }),
+        stats.clone(),
+    );
+    let queued = |text: String, silent: bool| Queued {
+        text,
+        silent,
+        thread_id: None,
+    };
+    for i in 0..10 {
+        outbound.push(ChatId::new(1), queued(i.to_string(), false));
+        outbound.push(ChatId::new(2), queued(i.to_string(), true));
+    }
+    outbound.push(ChatId::new(3), queued("flood".to_string(), false));
+    for _ in 0..21 {
+        delivered.recv().await.unwrap();
+    }
+    {
+        let sent = sent.lock().unwrap();
+        for chat_id in [ChatId::new(1), ChatId::new(2)] {
+            let texts = sent
+                .iter()
+                .filter(|(id, text)| *id == chat_id && text != "failed")
+                .map(|(_, text)| text.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                texts,
+                (0..10).map(|i: u32| i.to_string()).collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(sent.last().unwrap(), &(ChatId::new(3), "flood".to_string()));
+    }
+    let flood_waits = TelegramBot::TRIES as u64 + 1;
+    assert_eq!(stats.requests.load(Ordering::Relaxed), 23 + flood_waits);
+    assert_eq!(stats.retries.load(Ordering::Relaxed), 2 + flood_waits);
+    assert_eq!(stats.failures.load(Ordering::Relaxed), 0);
+
+    // Idle workers are dropped, and a new one starts with the next message
+    tokio::time::sleep(Outbound::IDLE * 2).await;
+    assert!(outbound.queues().is_empty());
+    outbound.push(ChatId::new(1), queued("10".to_string(), false));
+    assert_eq!(delivered.recv().await, Some(ChatId::new(1)));
+    assert_eq!(outbound.queues().len(), 1);
+}
+
+#[tokio::test]
+async fn test_edit_deduplication() {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let recorded = sent.clone();
+    let stats = Arc::new(BotStats::default());
+    let edits = Edits::new(
+        Arc::new(move |edit: Edit| {
+            let recorded = recorded.clone();
+            async move {
+                recorded.lock().unwrap().push(edit.text.clone());
+                match edit.text.as_str() {
+                    "broken" => Err("Bad Request: can't parse entities".to_string()),
+                    "same" => Err("Bad Request: message is not modified".to_string()),
+                    _ => Ok(()),
+                }
+            }
+            .boxed()
+        }),
+        stats.clone(),
+    );
+    let edit = |message_id: i64, text: &str| Edit {
+        chat_id: ChatId::new(1),
+        message_id: MessageId::new(message_id),
+        text: text.to_string(),
+        caption: false,
+        reply_markup: None,
+    };
+    assert!(edits.send(edit(1, "1"), 1).await.is_ok());
+    assert!(edits.send(edit(1, "1"), 1).await.is_ok());
+    assert!(edits.send(edit(2, "1"), 1).await.is_ok());
+    assert!(edits.send(edit(1, "2"), 1).await.is_ok());
+    assert!(edits.send(edit(1, "1"), 1).await.is_ok());
+    let mut with_keyboard = edit(1, "1");
+    with_keyboard.reply_markup = KeyboardOptions::InlinePlus.reply_markup();
+    assert!(edits.send(with_keyboard, 1).await.is_ok());
+    assert_eq!(sent.lock().unwrap().len(), 5);
+
+    // A failed edit leaves the message as it was, so it is sent again
+    assert!(edits.send(edit(3, "broken"), 1).await.is_err());
+    assert!(edits.send(edit(3, "broken"), 1).await.is_err());
+    assert_eq!(sent.lock().unwrap().len(), 7);
+
+    assert!(edits.send(edit(4, "same"), 1).await.is_ok());
+    assert!(edits.send(edit(4, "same"), 1).await.is_ok());
+    assert_eq!(sent.lock().unwrap().len(), 8);
+    assert_eq!(stats.requests.load(Ordering::Relaxed), 8);
+    assert_eq!(stats.failures.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_thread_message() {
+    let message = |thread_id: Option<Integer>| {
+        serde_json::to_value(TelegramBot::in_topic(
+            SendMessage::new(ChatId::new(1), "text"),
+            thread_id,
+        ))
+        .unwrap()
+    };
+    assert!(message(None).get("message_thread_id").is_none());
+    assert_eq!(message(Some(5))["message_thread_id"], 5);
+    assert_eq!(message(Some(5))["text"], "text");
+}
@@ -1,13 +1,18 @@
+#[cfg(test)]
+use crate::game::create_test_game;
 use crate::game::Game;
-use crate::topic::TopicSet;
+use crate::topic::{Question, Topic, TopicSet};
 use borsh::{BorshDeserialize, BorshSerialize};
 use sled::transaction::{
-    ConflictableTransactionResult, TransactionalTree, UnabortableTransactionError,
+    ConflictableTransactionResult, TransactionError, TransactionalTree, UnabortableTransactionError,
 };
 use sled::Db;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 #[cfg(test)]
 use std::fs::File;
+use std::io::Write;
 #[cfg(test)]
 use std::io::{BufRead, BufReader};
 use std::ops::{Add, BitAnd, Shl, Shr};
@@ -15,6 +20,75 @@ use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use telegram_bot::{ChatId, User, UserId};
 
+#[derive(Debug)]
+pub enum DataError {
+    Db(sled::Error),
+    Conflict,
+    /// A stored key doesn't have the expected format.
+    MalformedKey(String),
+    /// The dump was taken at a different schema version, 0 if it predates versioning.
+    SchemaMismatch(u32),
+}
+
+pub type DataResult<T> = Result<T, DataError>;
+
+impl Display for DataError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataError::Db(err) => write!(f, "Error while working with db {}", err),
+            DataError::Conflict => write!(f, "Unresolved transaction conflict"),
+            DataError::MalformedKey(key) => write!(f, "Malformed key {}", key),
+            DataError::SchemaMismatch(version) => write!(
+                f,
+                "Dump schema version {} differs from {}",
+                version,
+                Data::MIGRATIONS.len()
+            ),
+        }
+    }
+}
+
+impl From<sled::Error> for DataError {
+    fn from(err: sled::Error) -> Self {
+        DataError::Db(err)
+    }
+}
+
+impl From<UnabortableTransactionError> for DataError {
+    fn from(err: UnabortableTransactionError) -> Self {
+        match err {
+            UnabortableTransactionError::Storage(err) => DataError::Db(err),
+            UnabortableTransactionError::Conflict => DataError::Conflict,
+        }
+    }
+}
+
+impl From<TransactionError<UnabortableTransactionError>> for DataError {
+    fn from(err: TransactionError<UnabortableTransactionError>) -> Self {
+        match err {
+            TransactionError::Abort(err) => err.into(),
+            TransactionError::Storage(err) => DataError::Db(err),
+        }
+    }
+}
+
+/// Logs database errors so callers can carry on without the value.
+pub trait LogError<T> {
+    fn log_error(self) -> Option<T>;
+}
+
+impl<T> LogError<T> for DataResult<T> {
+    fn log_error(self) -> Option<T> {
+        match self {
+            Ok(value) => Some(value),
+            Err(err) => {
+                log::error!("{}", err);
+                None
+            }
+        }
+    }
+}
+
 pub enum UserBanResult {
     Banned,
     AlreadyInList,
@@ -30,6 +104,9 @@ pub struct Data {
 impl Data {
     const NEXT_RESET_KEY: &'static str = "next-reset";
     const USER_DATA_KEY: &'static str = "user-data";
+    const USER_STATS_KEY: &'static str = "user-stats";
+    const RATING_HISTORY_KEY: &'static str = "rating-history";
+    const MONTHLY_KEY: &'static str = "monthly";
     const SETS_KEY: &'static str = "sets";
     const ACTIVE_SETS_KEY: &'static str = "active-sets";
     const WAS_ACTIVE_SETS_KEY: &'static str = "was-active-sets";
@@ -40,349 +117,1409 @@ impl Data {
     const BLOCKED_SET_KEY: &'static str = "blocked_set";
     const BAN_LIST_KEY: &'static str = "ban-list";
     const LAST_PLAYED_KEY: &'static str = "last-played";
+    const PRIVATE_CHAT_KEY: &'static str = "private-chat";
+    const USERNAME_KEY: &'static str = "username";
+    const GLOBAL_BAN_KEY: &'static str = "global-ban";
+    const DAILY_LIMIT_KEY: &'static str = "daily-limit";
+    const DAILY_GAMES_KEY: &'static str = "daily-games";
+    const ANNOUNCE_RESULTS_KEY: &'static str = "announce-results";
+    const GAME_ARCHIVE_KEY: &'static str = "game-archive";
+    const SCHEMA_VERSION_KEY: &'static str = "schema-version";
+    const ADJUSTMENTS_KEY: &'static str = "adjustments";
+    const USER_SETTINGS_KEY: &'static str = "user-settings";
+    const RATING_RANGE_KEY: &'static str = "rating-range";
+    const REMATCH_DELAY_KEY: &'static str = "rematch-delay";
+    const QUEUE_KEY: &'static str = "queue";
+    const PARTIES_KEY: &'static str = "parties";
+    const MANAGERS_KEY: &'static str = "managers";
+    const TOPIC_INDEX_KEY: &'static str = "topic-index";
+    const COPIES_PLAYED_KEY: &'static str = "copies-played";
+    const DISABLED_TOPICS_KEY: &'static str = "disabled-topics";
+    const ADDED_AT_KEY: &'static str = "added-at";
+    const SET_VERSION_KEY: &'static str = "set-version";
+    const SET_VERSIONS_KEY: &'static str = "set-versions";
 
     const SIZE_SUFFIX: &'static str = "size";
 
+    /// Migration `i` upgrades the database from schema version `i` to `i + 1`.
+    const MIGRATIONS: [fn(&Data) -> DataResult<()>; 8] = [
+        Self::migrate_versioned_records,
+        Self::migrate_peak_rating,
+        Self::migrate_question_images,
+        Self::migrate_topic_index,
+        Self::migrate_escaping,
+        Self::migrate_all_required,
+        Self::migrate_authors,
+        Self::migrate_queue_expiry,
+    ];
+    /// Prefix byte of stored games, games stored with a different layout are dropped on load.
+    const GAME_STATE_VERSION: u8 = 11;
+
     const START_RATING: u32 = 15000;
     const MAX_BAN_LIST: usize = 50;
     const STORE_PLAYED: usize = 10;
+    const MAX_RATING_HISTORY: usize = 50;
+    const MAX_ARCHIVED_GAMES: usize = 1000;
+    const MAX_ADJUSTMENTS: usize = 50;
+    const DEFAULT_REMATCH_DELAY: u32 = 3;
 
     pub fn new(db: &str) -> Self {
+        Self::with_db(sled::open(db).unwrap())
+    }
+
+    /// Database that is removed when dropped.
+    #[cfg(test)]
+    pub fn new_temporary() -> Self {
+        Self::with_db(sled::Config::new().temporary(true).open().unwrap())
+    }
+
+    fn with_db(db: Db) -> Self {
         let res = Data {
-            db: sled::open(db).unwrap(),
+            db,
             sets: Arc::new(RwLock::new(HashMap::new())),
         };
+        // An old database that can't be upgraded is not something the bot can run with
+        res.migrate().unwrap();
         res.load_sets();
         res
     }
 
     pub fn wipe(&self) {
         self.db.clear().unwrap();
+        self.set_schema_version(Self::MIGRATIONS.len() as u32)
+            .unwrap();
+    }
+
+    //noinspection RsSelfConvention
+    fn set_schema_version(&self, version: u32) -> DataResult<()> {
+        self.insert(&Self::SCHEMA_VERSION_KEY.to_string(), &version)
+    }
+
+    /// Runs pending migrations, a database without a version is either new or predates versioning.
+    fn migrate(&self) -> DataResult<()> {
+        let mut version = match self.get::<u32>(&Self::SCHEMA_VERSION_KEY.to_string())? {
+            Some(version) => version as usize,
+            None if self.db.is_empty() => {
+                self.set_schema_version(Self::MIGRATIONS.len() as u32)?;
+                Self::MIGRATIONS.len()
+            }
+            None => 0,
+        };
+        if version > Self::MIGRATIONS.len() {
+            panic!(
+                "Database schema version {} is newer than the supported {}",
+                version,
+                Self::MIGRATIONS.len()
+            );
+        }
+        while version < Self::MIGRATIONS.len() {
+            log::info!("Migrating database to schema version {}", version + 1);
+            Self::MIGRATIONS[version](self)?;
+            version += 1;
+            self.set_schema_version(version as u32)?;
+        }
+        Ok(())
+    }
+
+    /// User data gets a versioned layout with the number of games played, taken from the stats.
+    /// Stored games had no version prefix and embed the old user data layout, they are dropped,
+    /// the bot is only stopped once all games are over anyway.
+    fn migrate_versioned_records(&self) -> DataResult<()> {
+        let users = self
+            .db
+            .scan_prefix(format!("{}#", Self::USER_DATA_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        let games = self
+            .db
+            .scan_prefix(format!("{}#", Self::GAME_STATE_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        let prefix_len = Self::USER_DATA_KEY.len() + 1;
+        self.transaction(|db| {
+            for key in users.iter() {
+                let legacy: LegacyUserData = Self::get_tree_raw(db, key)?.unwrap();
+                let user_id = String::from_utf8_lossy(&key[prefix_len..]).to_string();
+                let stats: LegacyUserStats =
+                    Self::get_tree(db, &format!("{}#{}", Self::USER_STATS_KEY, user_id))?
+                        .unwrap_or_default();
+                let user_data = UserData {
+                    display_name: legacy.display_name,
+                    rating: legacy.rating,
+                    games_played: stats.games,
+                };
+                Self::insert_tree_raw(db, key, &user_data)?;
+            }
+            for key in games.iter() {
+                Self::remove_tree_raw(db, key)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// User stats get the peak rating, unknown for the games played before, so it starts at zero.
+    fn migrate_peak_rating(&self) -> DataResult<()> {
+        let keys = self
+            .db
+            .scan_prefix(format!("{}#", Self::USER_STATS_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transaction(|db| {
+            for key in keys.iter() {
+                let legacy: LegacyUserStats = Self::get_tree_raw(db, key)?.unwrap();
+                let stats = UserStats {
+                    games: legacy.games,
+                    wins: legacy.wins,
+                    total_score: legacy.total_score,
+                    peak_rating: 0,
+                };
+                Self::insert_tree_raw(db, key, &stats)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Questions get an optional image, none of the stored ones has it.
+    fn migrate_question_images(&self) -> DataResult<()> {
+        let keys = self
+            .db
+            .scan_prefix(format!("{}#", Self::SETS_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transaction(|db| {
+            for key in keys.iter() {
+                let legacy: LegacyTopicSet = Self::get_tree_raw(db, key)?.unwrap();
+                let set = LegacyImageTopicSet {
+                    id: legacy.id,
+                    title: legacy.title,
+                    description: legacy.description,
+                    topics: legacy
+                        .topics
+                        .into_iter()
+                        .map(|topic| LegacyImageTopic {
+                            name: topic.name,
+                            questions: topic
+                                .questions
+                                .into_iter()
+                                .map(|question| LegacyImageQuestion {
+                                    cost: question.cost,
+                                    question: question.question,
+                                    answers: question.answers,
+                                    comment: question.comment,
+                                    image: None,
+                                })
+                                .collect(),
+                        })
+                        .collect(),
+                };
+                Self::insert_tree_raw(db, key, &set)?;
+            }
+            Ok(())
+        })
     }
 
-    pub fn get_last_played(&self, user: UserId) -> Vec<UserId> {
-        self.get_list::<i64>(&format!("{}#{}", Self::LAST_PLAYED_KEY, user))
+    /// Topics of the stored sets are indexed by their fingerprints.
+    fn migrate_topic_index(&self) -> DataResult<()> {
+        let keys = self
+            .db
+            .scan_prefix(format!("{}#", Self::SETS_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transaction(|db| {
+            for key in keys.iter() {
+                let set: LegacyImageTopicSet = Self::get_tree_raw(db, key)?.unwrap();
+                let set = TopicSet::from(set);
+                Self::index_topics(db, &set.id, &[], &set.topics)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets uploaded as JSON used to be stored without html-escaping, decoding first keeps the
+    /// escaped ones as they are.
+    fn migrate_escaping(&self) -> DataResult<()> {
+        let keys = self
+            .db
+            .scan_prefix(format!("{}#", Self::SETS_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transaction(|db| {
+            for key in keys.iter() {
+                let old: LegacyImageTopicSet = Self::get_tree_raw(db, key)?.unwrap();
+                let old = TopicSet::from(old);
+                let set = old.decoded().encoded();
+                Self::index_topics(db, &set.id, &old.topics, &set.topics)?;
+                Self::insert_tree_raw(db, key, &LegacyImageTopicSet::from(set))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Questions may require all of their answers, none of the stored ones does.
+    fn migrate_all_required(&self) -> DataResult<()> {
+        let keys = self
+            .db
+            .scan_prefix(format!("{}#", Self::SETS_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transaction(|db| {
+            for key in keys.iter() {
+                let legacy: LegacyImageTopicSet = Self::get_tree_raw(db, key)?.unwrap();
+                let set = LegacyAuthorTopicSet::from(TopicSet::from(legacy));
+                Self::insert_tree_raw(db, key, &set)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets and topics get an optional author, none of the stored ones has it.
+    fn migrate_authors(&self) -> DataResult<()> {
+        let keys = self
+            .db
+            .scan_prefix(format!("{}#", Self::SETS_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transaction(|db| {
+            for key in keys.iter() {
+                let legacy: LegacyAuthorTopicSet = Self::get_tree_raw(db, key)?.unwrap();
+                Self::insert_tree_raw(db, key, &TopicSet::from(legacy))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The queue keeps the expiry of each search, the saved one was started with the default.
+    fn migrate_queue_expiry(&self) -> DataResult<()> {
+        let legacy = match self.get::<Vec<LegacyQueueRecord>>(&Self::QUEUE_KEY.to_string())? {
+            Some(legacy) => legacy,
+            None => return Ok(()),
+        };
+        self.set_queue(
+            legacy
+                .into_iter()
+                .map(|record| QueueRecord {
+                    user_id: record.user_id,
+                    entered: record.entered,
+                    last_update: record.last_update,
+                    set_id: record.set_id,
+                    max_players: record.max_players,
+                    expiry: LegacyQueueRecord::EXPIRY,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn get_last_played(&self, user: UserId) -> DataResult<Vec<UserId>> {
+        Ok(self
+            .get_list::<i64>(&format!("{}#{}", Self::LAST_PLAYED_KEY, user))?
             .iter()
             .map(|id| UserId::new(*id))
-            .collect()
+            .collect())
     }
 
-    pub fn add_game(&self, users: &[UserId]) {
+    pub fn add_game(&self, users: &[UserId]) -> DataResult<()> {
+        let now = SystemTime::now();
         for user in users {
+            self.add_daily_game(*user, now)?;
             for other in users {
                 if *user != *other {
-                    self.add_played(user, other);
+                    self.add_played(user, other)?;
                 }
             }
         }
+        Ok(())
     }
 
-    fn add_played(&self, user: &UserId, other: &UserId) {
-        let other = *other;
-        let key = format!("{}#{}", Self::LAST_PLAYED_KEY, user);
-        if self.remove_element::<i64>(&key, &other.into()) {
-            self.add_element::<i64>(&key, &other.into());
-        } else {
-            if self.list_size(&key) == Self::STORE_PLAYED {
-                self.remove_at::<i64>(&key, 0usize);
+    fn daily_games_key(user: UserId, now: SystemTime) -> String {
+        format!("{}#{}#{}", Self::DAILY_GAMES_KEY, date(now), user)
+    }
+
+    fn add_daily_game(&self, user: UserId, now: SystemTime) -> DataResult<()> {
+        let key = Self::daily_games_key(user, now);
+        self.insert(&key, &(self.get::<u32>(&key)?.unwrap_or(0) + 1))
+    }
+
+    pub fn get_daily_limit(&self) -> DataResult<Option<u32>> {
+        self.get(&Self::DAILY_LIMIT_KEY.to_string())
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_daily_limit(&self, limit: Option<u32>) -> DataResult<()> {
+        match limit {
+            None => self.remove(&Self::DAILY_LIMIT_KEY.to_string()),
+            Some(limit) => self.insert(&Self::DAILY_LIMIT_KEY.to_string(), &limit),
+        }
+    }
+
+    pub fn get_queue(&self) -> DataResult<Vec<QueueRecord>> {
+        Ok(self.get(&Self::QUEUE_KEY.to_string())?.unwrap_or_default())
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_queue(&self, queue: Vec<QueueRecord>) -> DataResult<()> {
+        self.insert(&Self::QUEUE_KEY.to_string(), &queue)
+    }
+
+    /// Partners requested by the players in the queue, as pairs of user ids.
+    pub fn get_parties(&self) -> DataResult<Vec<(i64, i64)>> {
+        Ok(self
+            .get(&Self::PARTIES_KEY.to_string())?
+            .unwrap_or_default())
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_parties(&self, parties: Vec<(i64, i64)>) -> DataResult<()> {
+        self.insert(&Self::PARTIES_KEY.to_string(), &parties)
+    }
+
+    /// Minutes of waiting in the queue during which recent opponents are not matched again.
+    pub fn get_rematch_delay(&self) -> DataResult<u32> {
+        Ok(self
+            .get(&Self::REMATCH_DELAY_KEY.to_string())?
+            .unwrap_or(Self::DEFAULT_REMATCH_DELAY))
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_rematch_delay(&self, minutes: u32) -> DataResult<()> {
+        self.insert(&Self::REMATCH_DELAY_KEY.to_string(), &minutes)
+    }
+
+    /// Maximum rating difference with opponents the user accepts in the queue.
+    pub fn get_rating_range(&self, user: UserId) -> DataResult<Option<u32>> {
+        self.get(&format!("{}#{}", Self::RATING_RANGE_KEY, user))
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_rating_range(&self, user: UserId, range: Option<u32>) -> DataResult<()> {
+        let key = format!("{}#{}", Self::RATING_RANGE_KEY, user);
+        match range {
+            None => self.remove(&key),
+            Some(range) => self.insert(&key, &range),
+        }
+    }
+
+    /// Whether the user has played the maximum number of rated games today, days start at
+    /// midnight UTC.
+    pub fn daily_limit_reached(&self, user: UserId, now: SystemTime) -> DataResult<bool> {
+        Ok(match self.get_daily_limit()? {
+            None => false,
+            Some(limit) => {
+                self.get::<u32>(&Self::daily_games_key(user, now))?
+                    .unwrap_or(0)
+                    >= limit
             }
-            self.add_element::<i64>(&key, &other.into());
+        })
+    }
+
+    fn add_played(&self, user: &UserId, other: &UserId) -> DataResult<()> {
+        let other: i64 = (*other).into();
+        let key = format!("{}#{}", Self::LAST_PLAYED_KEY, user);
+        let mut list = self.get_list::<i64>(&key)?;
+        list.retain(|id| *id != other);
+        if list.len() == Self::STORE_PLAYED {
+            list.remove(0);
         }
+        list.push(other);
+        self.insert(&key, &list)
     }
 
-    pub fn in_ban_list(&self, user: UserId, other: UserId) -> bool {
-        self.get_list::<i64>(&format!("{}#{}", Self::BAN_LIST_KEY, user))
-            .contains(&other.into())
+    pub fn in_ban_list(&self, user: UserId, other: UserId) -> DataResult<bool> {
+        Ok(self
+            .get_list::<i64>(&format!("{}#{}", Self::BAN_LIST_KEY, user))?
+            .contains(&other.into()))
     }
 
-    pub fn add_to_ban_list(&self, user: UserId, other: UserId) -> UserBanResult {
-        if self.in_ban_list(user, other) {
+    pub fn add_to_ban_list(&self, user: UserId, other: UserId) -> DataResult<UserBanResult> {
+        Ok(if self.in_ban_list(user, other)? {
             UserBanResult::AlreadyInList
         } else {
             let key = format!("{}#{}", Self::BAN_LIST_KEY, user);
-            if self.list_size(&key) == Self::MAX_BAN_LIST {
+            if self.get_list::<i64>(&key)?.len() == Self::MAX_BAN_LIST {
                 UserBanResult::SizeLimitReached
             } else {
-                self.add_element::<i64>(&key, &other.into());
+                self.add_element::<i64>(&key, &other.into())?;
                 UserBanResult::Banned
             }
-        }
+        })
+    }
+
+    pub fn remove_from_ban_list(&self, user: UserId, other: UserId) -> DataResult<bool> {
+        self.remove_element::<i64>(&format!("{}#{}", Self::BAN_LIST_KEY, user), &other.into())
+    }
+
+    pub fn get_ban_list(&self, user: UserId) -> DataResult<Vec<UserId>> {
+        Ok(self
+            .get_list::<i64>(&format!("{}#{}", Self::BAN_LIST_KEY, user))?
+            .iter()
+            .map(|id| UserId::new(*id))
+            .collect())
+    }
+
+    pub fn is_globally_banned(&self, id: UserId) -> DataResult<bool> {
+        Ok(self.get_global_ban_list()?.contains(&id))
     }
 
-    pub fn remove_from_ban_list(&self, user: UserId, other: UserId) -> bool {
-        if self.in_ban_list(user, other) {
-            self.remove_element::<i64>(&format!("{}#{}", Self::BAN_LIST_KEY, user), &other.into());
-            true
+    /// Returns false if the user was already in the requested state.
+    //noinspection RsSelfConvention
+    pub fn set_globally_banned(&self, id: UserId, banned: bool) -> DataResult<bool> {
+        let key = Self::GLOBAL_BAN_KEY.to_string();
+        if banned {
+            if self.is_globally_banned(id)? {
+                Ok(false)
+            } else {
+                self.add_element::<i64>(&key, &id.into())?;
+                Ok(true)
+            }
         } else {
-            false
+            self.remove_element::<i64>(&key, &id.into())
         }
     }
 
-    pub fn get_ban_list(&self, user: UserId) -> Vec<UserId> {
-        self.get_list::<i64>(&format!("{}#{}", Self::BAN_LIST_KEY, user))
+    pub fn get_global_ban_list(&self) -> DataResult<Vec<UserId>> {
+        Ok(self
+            .get_list::<i64>(&Self::GLOBAL_BAN_KEY.to_string())?
             .iter()
             .map(|id| UserId::new(*id))
-            .collect()
+            .collect())
+    }
+
+    /// Managers promoted at runtime, the one configured at startup is not stored.
+    pub fn get_managers(&self) -> DataResult<Vec<UserId>> {
+        Ok(self
+            .get_list::<i64>(&Self::MANAGERS_KEY.to_string())?
+            .iter()
+            .map(|id| UserId::new(*id))
+            .collect())
+    }
+
+    pub fn is_manager(&self, id: UserId) -> DataResult<bool> {
+        Ok(self.get_managers()?.contains(&id))
     }
 
-    pub fn is_set_blocked(&self, id: UserId, set: &String) -> bool {
-        let res: Option<bool> = self.get(&format!("{}#{}#{}", Self::BLOCKED_SET_KEY, id, set));
-        if let Some(_) = res {
-            true
+    /// Returns false if the user was already in the requested state.
+    //noinspection RsSelfConvention
+    pub fn set_manager(&self, id: UserId, manager: bool) -> DataResult<bool> {
+        let key = Self::MANAGERS_KEY.to_string();
+        if manager {
+            if self.is_manager(id)? {
+                Ok(false)
+            } else {
+                self.add_element::<i64>(&key, &id.into())?;
+                Ok(true)
+            }
         } else {
-            false
+            self.remove_element::<i64>(&key, &id.into())
+        }
+    }
+
+    pub fn has_private_chat(&self, id: UserId) -> DataResult<bool> {
+        Ok(self
+            .get::<bool>(&format!("{}#{}", Self::PRIVATE_CHAT_KEY, id))?
+            .is_some())
+    }
+
+    pub fn add_private_chat(&self, id: UserId) -> DataResult<()> {
+        if !self.has_private_chat(id)? {
+            self.insert(&format!("{}#{}", Self::PRIVATE_CHAT_KEY, id), &true)?;
+        }
+        Ok(())
+    }
+
+    pub fn remember_username(&self, user: &User) -> DataResult<()> {
+        if let Some(username) = &user.username {
+            let key = format!("{}#{}", Self::USERNAME_KEY, username.to_lowercase());
+            let id: i64 = user.id.into();
+            if self.get::<i64>(&key)? != Some(id) {
+                self.insert(&key, &id)?;
+            }
         }
+        Ok(())
+    }
+
+    /// Finds a user who has interacted with the bot by the Telegram username, without the `@`.
+    pub fn find_username(&self, username: &str) -> DataResult<Option<UserId>> {
+        Ok(self
+            .get::<i64>(&format!(
+                "{}#{}",
+                Self::USERNAME_KEY,
+                username.to_lowercase()
+            ))?
+            .map(UserId::new))
+    }
+
+    pub fn announce_results(&self) -> DataResult<bool> {
+        Ok(self
+            .get::<bool>(&Self::ANNOUNCE_RESULTS_KEY.to_string())?
+            .unwrap_or(false))
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_announce_results(&self, announce: bool) -> DataResult<()> {
+        self.insert(&Self::ANNOUNCE_RESULTS_KEY.to_string(), &announce)
+    }
+
+    /// Whether topics count as played for users who played their copies in other sets.
+    pub fn copies_played(&self) -> DataResult<bool> {
+        Ok(self
+            .get::<bool>(&Self::COPIES_PLAYED_KEY.to_string())?
+            .unwrap_or(false))
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_copies_played(&self, copies_played: bool) -> DataResult<()> {
+        self.insert(&Self::COPIES_PLAYED_KEY.to_string(), &copies_played)
+    }
+
+    pub fn is_set_blocked(&self, id: UserId, set: &String) -> DataResult<bool> {
+        let res: Option<bool> = self.get(&format!("{}#{}#{}", Self::BLOCKED_SET_KEY, id, set))?;
+        Ok(res.is_some())
     }
 
     //noinspection RsSelfConvention
-    pub fn set_set_blocked(&self, id: UserId, set: &String, to_block: bool) -> bool {
-        if self.is_set_blocked(id, &set) {
+    pub fn set_set_blocked(&self, id: UserId, set: &String, to_block: bool) -> DataResult<bool> {
+        if self.is_set_blocked(id, &set)? {
             if to_block {
-                false
+                Ok(false)
             } else {
-                self.remove(&format!("{}#{}#{}", Self::BLOCKED_SET_KEY, id, set));
-                true
+                self.remove(&format!("{}#{}#{}", Self::BLOCKED_SET_KEY, id, set))?;
+                Ok(true)
             }
         } else {
             if to_block {
-                self.insert(&format!("{}#{}#{}", Self::BLOCKED_SET_KEY, id, set), &true);
-                true
+                self.insert(&format!("{}#{}#{}", Self::BLOCKED_SET_KEY, id, set), &true)?;
+                Ok(true)
             } else {
-                false
+                Ok(false)
             }
         }
     }
 
-    pub fn update_player(&self, id: UserId, mut user_data: UserData) -> UserData {
-        if let Some(old_data) = self.get_user_data(&id) {
+    pub fn update_player(&self, id: UserId, mut user_data: UserData) -> DataResult<UserData> {
+        if let Some(old_data) = self.get_user_data(&id)? {
             user_data.rating = old_data.rating;
+            user_data.games_played = old_data.games_played;
         }
-        self.set_user_data(id, &user_data);
-        user_data
+        self.set_user_data(id, &user_data)?;
+        Ok(user_data)
+    }
+
+    pub fn add_game_chat(&self, chat_id: &i64) -> DataResult<()> {
+        self.add_element(&Self::GAME_CHATS_KEY.to_string(), chat_id)
     }
 
-    pub fn add_game_chat(&self, chat_id: &i64) {
-        self.add_element(&Self::GAME_CHATS_KEY.to_string(), chat_id);
+    pub fn remove_game_chat(&self, chat_id: &i64) -> DataResult<()> {
+        self.remove_element(&Self::GAME_CHATS_KEY.to_string(), chat_id)?;
+        Ok(())
     }
 
-    pub fn remove_game_chat(&self, chat_id: &i64) {
-        self.remove_element(&Self::GAME_CHATS_KEY.to_string(), chat_id);
+    /// Keeps the room when Telegram upgrades it to a supergroup with a new id.
+    pub fn migrate_game_chat(&self, chat_id: &i64, new_chat_id: &i64) -> DataResult<()> {
+        self.remove_game_chat(chat_id)?;
+        self.add_game_chat(new_chat_id)
     }
 
-    pub fn get_game_chats(&self) -> Vec<ChatId> {
-        self.get_list::<i64>(&Self::GAME_CHATS_KEY.to_string())
+    pub fn get_game_chats(&self) -> DataResult<Vec<ChatId>> {
+        Ok(self
+            .get_list::<i64>(&Self::GAME_CHATS_KEY.to_string())?
             .iter()
             .map(|id| ChatId::new(*id))
-            .collect()
+            .collect())
     }
 
-    pub fn save_game_state(&self, game: &Game) {
-        self.insert(&format!("{}#{}", Self::GAME_STATE_KEY, game.chat_id), game);
+    pub fn save_game_state(&self, game: &Game) -> DataResult<()> {
+        let mut value = vec![Self::GAME_STATE_VERSION];
+        game.serialize(&mut value).unwrap();
+        self.db.insert(
+            format!("{}#{}", Self::GAME_STATE_KEY, game.chat_id).as_bytes(),
+            value,
+        )?;
+        Ok(())
     }
 
-    pub fn get_game_states(&self) -> Vec<Game> {
-        self.db
-            .scan_prefix(Self::GAME_STATE_KEY)
-            .map(|r| match r {
-                Ok((_, value)) => Game::deserialize(&mut value.as_ref()).unwrap(),
-                Err(err) => panic!("Error while working with db {}", err),
-            })
-            .collect()
+    pub fn get_game_states(&self) -> DataResult<Vec<Game>> {
+        let mut games = Vec::new();
+        for item in self.db.scan_prefix(Self::GAME_STATE_KEY) {
+            let (key, value) = item?;
+            match value.split_first() {
+                Some((&Self::GAME_STATE_VERSION, mut game)) => match Game::deserialize(&mut game) {
+                    Ok(game) => games.push(game),
+                    Err(err) => {
+                        log::error!(
+                            "Dropping corrupt game {}: {}",
+                            String::from_utf8_lossy(&key),
+                            err
+                        );
+                        self.remove_raw(&key)?;
+                    }
+                },
+                _ => {
+                    log::warn!(
+                        "Dropping game {} stored with an unsupported layout",
+                        String::from_utf8_lossy(&key)
+                    );
+                    self.remove_raw(&key)?;
+                }
+            }
+        }
+        Ok(games)
     }
 
-    pub fn remove_game(&self, id: i64) {
-        self.remove(&format!("{}#{}", Self::GAME_STATE_KEY, id));
+    pub fn remove_game(&self, id: i64) -> DataResult<()> {
+        self.remove(&format!("{}#{}", Self::GAME_STATE_KEY, id))
     }
 
-    pub fn save_game_results(&self, results: &HashMap<i64, (UserData, i32, bool)>) {
+    pub fn save_game_results(
+        &self,
+        results: &HashMap<i64, (UserData, i32, bool)>,
+        teams: &HashMap<i64, u8>,
+        places: &HashMap<i64, usize>,
+        set_id: &str,
+    ) -> DataResult<()> {
+        let now = SystemTime::now();
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let month = year_month(now);
         self.transaction(|db| {
             let results = results.iter().collect::<Vec<_>>();
+            let mut rated = HashSet::new();
+            for (user_id, _) in results.iter() {
+                let settings: UserSettings =
+                    Self::get_tree(db, &format!("{}#{}", Self::USER_SETTINGS_KEY, user_id))?
+                        .unwrap_or_default();
+                if !settings.unrated {
+                    rated.insert(**user_id);
+                }
+            }
             let mut datas = Vec::new();
             for user in results.iter() {
+                if !rated.contains(user.0) {
+                    continue;
+                }
                 let mut data: UserData =
-                    Self::get_tree(db, &format!("{}#{}", Self::USER_DATA_KEY, user.0)).unwrap();
+                    Self::get_tree(db, &format!("{}#{}", Self::USER_DATA_KEY, user.0))?.unwrap();
                 let ra = data.rating;
-                let mut delta = 0i32;
-                for other in results.iter() {
-                    if user.0 == other.0 {
-                        continue;
+                let mut matchups = Vec::new();
+                if teams.is_empty() {
+                    for other in results.iter() {
+                        if user.0 == other.0 || !rated.contains(other.0) {
+                            continue;
+                        }
+                        let other_data: UserData =
+                            Self::get_tree(db, &format!("{}#{}", Self::USER_DATA_KEY, other.0))?
+                                .unwrap();
+                        matchups.push((other_data.rating as f64, user.1 .1, other.1 .1));
+                    }
+                } else {
+                    let mut own_score = 0i32;
+                    let mut other_score = 0i32;
+                    let mut other_rating = 0f64;
+                    let mut opponents = 0usize;
+                    for other in results.iter() {
+                        if teams[other.0] == teams[user.0] {
+                            own_score += other.1 .1;
+                        } else {
+                            other_score += other.1 .1;
+                            if rated.contains(other.0) {
+                                let other_data: UserData = Self::get_tree(
+                                    db,
+                                    &format!("{}#{}", Self::USER_DATA_KEY, other.0),
+                                )?
+                                .unwrap();
+                                other_rating += other_data.rating as f64;
+                                opponents += 1;
+                            }
+                        }
+                    }
+                    if opponents > 0 {
+                        matchups.push((other_rating / opponents as f64, own_score, other_score));
                     }
-                    let other_data: UserData =
-                        Self::get_tree(db, &format!("{}#{}", Self::USER_DATA_KEY, other.0))
-                            .unwrap();
-                    let rb = other_data.rating;
-                    let ea = 1f64 / (1f64 + 10f64.powf(((rb as f64) - (ra as f64)) / 4000f64));
-                    let sa = if user.1 .1 < other.1 .1 {
-                        0f64
-                    } else if user.1 .1 > other.1 .1 {
-                        1f64
-                    } else {
-                        0.5f64
-                    };
-                    delta += (100f64 * (sa - ea)).round() as i32;
+                }
+                let mut delta = 0i32;
+                for (rb, own_score, other_score) in matchups {
+                    delta += Self::matchup_delta(ra as f64, rb, own_score, other_score);
                 }
                 delta = delta.max(-(ra as i32) + 10);
                 data.rating = (data.rating as i32 + delta) as u32;
-                datas.push((*user.0, data));
+                data.games_played += 1;
+                datas.push((*user.0, data, user.1 .1, ra));
             }
-            for (user_id, data) in datas {
+            for (user_id, data, score, old_rating) in datas {
                 Self::insert_tree(db, &format!("{}#{}", Self::USER_DATA_KEY, &user_id), &data)?;
+                let key = format!("{}#{}", Self::RATING_HISTORY_KEY, &user_id);
+                let mut history: Vec<RatingChange> = Self::get_tree(db, &key)?.unwrap_or_default();
+                history.push(RatingChange {
+                    timestamp,
+                    set_id: set_id.to_string(),
+                    score,
+                    old_rating,
+                    new_rating: data.rating,
+                });
+                if history.len() > Self::MAX_RATING_HISTORY {
+                    history.drain(..history.len() - Self::MAX_RATING_HISTORY);
+                }
+                Self::insert_tree(db, &key, &history)?;
+                let key = format!("{}#{}#{}", Self::MONTHLY_KEY, month, &user_id);
+                let points: i32 = Self::get_tree(db, &key)?.unwrap_or(0);
+                Self::insert_tree(db, &key, &(points + data.rating as i32 - old_rating as i32))?;
+                let key = format!("{}#{}", Self::USER_STATS_KEY, &user_id);
+                let mut stats: UserStats = Self::get_tree(db, &key)?.unwrap_or_default();
+                stats.games += 1;
+                if places.get(&user_id) == Some(&1) {
+                    stats.wins += 1;
+                }
+                stats.total_score += score as i64;
+                stats.peak_rating = stats.peak_rating.max(data.rating);
+                Self::insert_tree(db, &key, &stats)?;
             }
             Ok(())
         })
     }
 
-    pub fn get_next_reset(&self) -> SystemTime {
-        self.get_raw(Self::NEXT_RESET_KEY.as_bytes())
-            .map(|time| UNIX_EPOCH.add(Duration::from_millis(time)))
-            .unwrap_or_else(|| SystemTime::now())
+    /// Elo rating change for a single matchup. Ratings are stored multiplied by 10 (see
+    /// `display_rating`), so the divisor of 4000 is the classic 400 and the K-factor of 100 is 10.
+    /// This is the curve all stored ratings were computed with, no migration is needed.
+    fn matchup_delta(ra: f64, rb: f64, own_score: i32, other_score: i32) -> i32 {
+        let ea = 1f64 / (1f64 + 10f64.powf((rb - ra) / 4000f64));
+        let sa = if own_score < other_score {
+            0f64
+        } else if own_score > other_score {
+            1f64
+        } else {
+            0.5f64
+        };
+        (100f64 * (sa - ea)).round() as i32
+    }
+
+    pub fn get_next_reset(&self) -> DataResult<Option<SystemTime>> {
+        Ok(self
+            .get_raw(Self::NEXT_RESET_KEY.as_bytes())?
+            .map(|time| UNIX_EPOCH.add(Duration::from_millis(time))))
     }
 
     //noinspection RsSelfConvention
-    pub fn set_next_reset(&self, time: SystemTime) {
+    pub fn set_next_reset(&self, time: SystemTime) -> DataResult<()> {
         self.insert_raw(
             Self::NEXT_RESET_KEY.as_bytes(),
             &(time.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64),
-        );
+        )
     }
 
-    pub fn get_user_data(&self, id: &UserId) -> Option<UserData> {
+    pub fn get_user_data(&self, id: &UserId) -> DataResult<Option<UserData>> {
         self.get(&format!("{}#{}", Self::USER_DATA_KEY, id))
     }
 
-    pub fn get_or_create_user(&self, user: User) -> UserData {
-        let mut user_data = match self.get_user_data(&user.id) {
-            None => UserData {
-                display_name: "".to_string(),
-                rating: Self::START_RATING,
-            },
+    pub fn get_or_create_user(&self, user: User) -> DataResult<UserData> {
+        self.remember_username(&user)?;
+        let mut user_data = match self.get_user_data(&user.id)? {
+            None => UserData::new("".to_string(), Self::START_RATING),
             Some(user_data) => user_data,
         };
         user_data.display_name = display_name(&user);
-        user_data
+        Ok(user_data)
+    }
+
+    //noinspection RsSelfConvention
+    pub fn set_user_data(&self, id: UserId, user_data: &UserData) -> DataResult<()> {
+        self.insert(&format!("{}#{}", Self::USER_DATA_KEY, id), user_data)
+    }
+
+    /// Users that never changed their settings get the defaults.
+    pub fn get_user_settings(&self, id: UserId) -> DataResult<UserSettings> {
+        Ok(self
+            .get(&format!("{}#{}", Self::USER_SETTINGS_KEY, id))?
+            .unwrap_or_default())
     }
 
     //noinspection RsSelfConvention
-    pub fn set_user_data(&self, id: UserId, user_data: &UserData) {
-        self.insert(&format!("{}#{}", Self::USER_DATA_KEY, id), user_data);
+    pub fn set_user_settings(&self, id: UserId, settings: &UserSettings) -> DataResult<()> {
+        self.insert(&format!("{}#{}", Self::USER_SETTINGS_KEY, id), settings)
     }
 
-    pub fn rating_discount(&self) {
-        let users = self.db.scan_prefix(Self::USER_DATA_KEY).collect::<Vec<_>>();
+    fn discounted_rating(rating: u32) -> u32 {
+        let start = Self::START_RATING as i64;
+        (start + (rating as i64 - start) * 99 / 100) as u32
+    }
+
+    pub fn rating_discount(&self) -> DataResult<()> {
+        let keys = self
+            .db
+            .scan_prefix(Self::USER_DATA_KEY)
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
         self.transaction(|db| {
-            for item in users.iter() {
-                match item {
-                    Ok((key, value)) => {
-                        let mut user_data = UserData::deserialize(&mut value.as_ref()).unwrap();
-                        user_data.rating =
-                            Self::START_RATING + (user_data.rating - Self::START_RATING) * 99 / 100;
-                        Self::insert_tree_raw(db, key.as_ref(), &user_data)?;
-                    }
-                    Err(err) => panic!("Error while working with db {}", err),
+            for key in keys.iter() {
+                let user_data: Option<UserData> = Self::get_tree_raw(db, key.as_ref())?;
+                if let Some(mut user_data) = user_data {
+                    user_data.rating = Self::discounted_rating(user_data.rating);
+                    Self::insert_tree_raw(db, key.as_ref(), &user_data)?;
                 }
             }
             Ok(())
         })
     }
 
-    pub fn add_new_set(&self, id: &String, set: TopicSet) -> bool {
-        if self.was_active(&id) && set.topics.len() != self.get_set(id).unwrap().topics.len() {
-            return false;
+    /// Applies the rating discount if the scheduled reset is due and moves the schedule forward.
+    /// Returns whether the discount was applied.
+    pub fn rating_reset(&self, now: SystemTime, interval: Duration) -> DataResult<bool> {
+        match self.get_next_reset()? {
+            None => {
+                self.set_next_reset(now + interval)?;
+                Ok(false)
+            }
+            Some(next_reset) if next_reset > now => Ok(false),
+            Some(mut next_reset) => {
+                self.rating_discount()?;
+                while next_reset <= now {
+                    next_reset += interval;
+                }
+                self.set_next_reset(next_reset)?;
+                Ok(true)
+            }
         }
-        self.insert(&format!("{}#{}", Self::SETS_KEY, id), &set);
-        self.sets.write().unwrap().insert(id.clone(), Arc::new(set));
-        true
     }
 
-    pub fn get_active_set_ids(&self) -> Vec<String> {
-        self.get_list(&Self::ACTIVE_SETS_KEY.to_string())
+    fn is_exported(key: &str, with_sets: bool) -> bool {
+        !key.starts_with(&format!("{}#", Self::GAME_STATE_KEY))
+            && key != Self::QUEUE_KEY
+            && key != Self::PARTIES_KEY
+            && (with_sets
+                || !key.starts_with(&format!("{}#", Self::SETS_KEY))
+                    && !key.starts_with(&format!("{}#", Self::TOPIC_INDEX_KEY))
+                    && !key.starts_with(&format!("{}#", Self::SET_VERSION_KEY))
+                    && !key.starts_with(&format!("{}#", Self::SET_VERSIONS_KEY)))
+    }
+
+    /// Dumps the whole database except running games and the queue, topic sets are only included
+    /// on request since they are large.
+    pub fn export(&self, with_sets: bool) -> DataResult<DataDump> {
+        let mut entries = Vec::new();
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            let key = String::from_utf8_lossy(&key).to_string();
+            if Self::is_exported(&key, with_sets) {
+                entries.push((key, value.to_vec()));
+            }
+        }
+        Ok(DataDump { with_sets, entries })
     }
 
-    pub fn get_was_active_set_ids(&self) -> Vec<String> {
-        self.get_list(&Self::WAS_ACTIVE_SETS_KEY.to_string())
+    /// Replaces everything the dump covers with its content, keys the dump doesn't cover (running
+    /// games, the queue and, for dumps without sets, topic sets) are kept. Migrations would take
+    /// the kept records for old ones, so only dumps of the current schema are accepted.
+    pub fn import(&self, dump: &DataDump) -> DataResult<()> {
+        let version = dump
+            .entries
+            .iter()
+            .find(|(key, _)| key == Self::SCHEMA_VERSION_KEY)
+            .and_then(|(_, value)| u32::try_from_slice(value).ok())
+            .unwrap_or(0);
+        if version != Self::MIGRATIONS.len() as u32 {
+            return Err(DataError::SchemaMismatch(version));
+        }
+        let keys = self.db.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        self.transaction(|db| {
+            for key in keys.iter() {
+                if Self::is_exported(&String::from_utf8_lossy(key), dump.with_sets) {
+                    Self::remove_tree_raw(db, key)?;
+                }
+            }
+            for (key, value) in dump.entries.iter() {
+                if Self::is_exported(key, dump.with_sets) {
+                    db.insert(key.as_bytes(), value.as_slice())?;
+                }
+            }
+            Ok(())
+        })?;
+        if dump.with_sets {
+            self.sets.write().unwrap().clear();
+            self.load_sets();
+        }
+        Ok(())
     }
 
-    pub fn is_active(&self, set_id: &String) -> bool {
-        self.get_active_set_ids().contains(set_id)
+    /// Sets that were ever active can only grow, since played topics are stored by index.
+    /// Uploading a set again makes a new version, the old one is kept for the games playing it.
+    pub fn add_new_set(&self, id: &String, set: TopicSet) -> DataResult<bool> {
+        let old = self.get_set(id);
+        if self.was_active(&id)? && set.topics.len() < old.as_ref().unwrap().topics.len() {
+            return Ok(false);
+        }
+        let old_topics = old.as_ref().map_or(&[][..], |old| old.topics.as_slice());
+        self.transaction(|db| {
+            if let Some(old) = &old {
+                let version_key = format!("{}#{}", Self::SET_VERSION_KEY, id);
+                let version: u32 = Self::get_tree(db, &version_key)?.unwrap_or(0);
+                Self::insert_tree(
+                    db,
+                    &format!("{}#{}@{}", Self::SET_VERSIONS_KEY, id, version),
+                    &**old,
+                )?;
+                Self::insert_tree(db, &version_key, &(version + 1))?;
+            }
+            Self::insert_tree(db, &format!("{}#{}", Self::SETS_KEY, id), &set)?;
+            Self::index_topics(db, id, old_topics, &set.topics)
+        })?;
+        self.sets.write().unwrap().insert(id.clone(), Arc::new(set));
+        Ok(true)
     }
 
-    pub fn was_active(&self, set_id: &String) -> bool {
-        self.get_was_active_set_ids().contains(set_id)
+    /// Replaces the index entries of the set's old topics with the new ones.
+    fn index_topics(
+        db: &TransactionalTree,
+        set_id: &String,
+        old_topics: &[Topic],
+        topics: &[Topic],
+    ) -> ConflictableTransactionResult<(), UnabortableTransactionError> {
+        for topic in old_topics.iter() {
+            let key = format!("{}#{:016x}", Self::TOPIC_INDEX_KEY, topic.fingerprint());
+            let mut copies: Vec<(String, usize)> = Self::get_tree(db, &key)?.unwrap_or_default();
+            copies.retain(|(id, _)| id != set_id);
+            if copies.is_empty() {
+                Self::remove_tree(db, &key)?;
+            } else {
+                Self::insert_tree(db, &key, &copies)?;
+            }
+        }
+        for (index, topic) in topics.iter().enumerate() {
+            let key = format!("{}#{:016x}", Self::TOPIC_INDEX_KEY, topic.fingerprint());
+            let mut copies: Vec<(String, usize)> = Self::get_tree(db, &key)?.unwrap_or_default();
+            copies.push((set_id.clone(), index));
+            Self::insert_tree(db, &key, &copies)?;
+        }
+        Ok(())
     }
 
-    pub fn add_active(&self, set_id: &String) {
-        self.add_element(&Self::WAS_ACTIVE_SETS_KEY.to_string(), set_id);
-        self.add_element(&Self::ACTIVE_SETS_KEY.to_string(), set_id);
+    /// Sets and topic indices the topic was uploaded with.
+    fn get_topic_copies(&self, topic: &Topic) -> DataResult<Vec<(String, usize)>> {
+        Ok(self
+            .get(&format!(
+                "{}#{:016x}",
+                Self::TOPIC_INDEX_KEY,
+                topic.fingerprint()
+            ))?
+            .unwrap_or_default())
     }
 
-    pub fn remove_active(&self, set_id: &String) {
-        self.remove_element(&Self::ACTIVE_SETS_KEY.to_string(), set_id);
+    /// Topics of the set that are already in other sets.
+    pub fn find_duplicate_topics(
+        &self,
+        set_id: &String,
+        set: &TopicSet,
+    ) -> DataResult<Vec<DuplicateTopic>> {
+        let mut duplicates = Vec::new();
+        for (index, topic) in set.topics.iter().enumerate() {
+            let original = self
+                .get_topic_copies(topic)?
+                .into_iter()
+                .find(|(id, _)| id != set_id);
+            if let Some((original_set, original_index)) = original {
+                duplicates.push(DuplicateTopic {
+                    index,
+                    original_set,
+                    original_index,
+                });
+            }
+        }
+        Ok(duplicates)
+    }
+
+    pub fn get_active_set_ids(&self) -> DataResult<Vec<String>> {
+        self.get_list(&Self::ACTIVE_SETS_KEY.to_string())
+    }
+
+    pub fn get_was_active_set_ids(&self) -> DataResult<Vec<String>> {
+        self.get_list(&Self::WAS_ACTIVE_SETS_KEY.to_string())
+    }
+
+    pub fn is_active(&self, set_id: &String) -> DataResult<bool> {
+        Ok(self.get_active_set_ids()?.contains(set_id))
+    }
+
+    pub fn was_active(&self, set_id: &String) -> DataResult<bool> {
+        Ok(self.get_was_active_set_ids()?.contains(set_id))
+    }
+
+    pub fn add_active(&self, set_id: &String) -> DataResult<()> {
+        self.add_element(&Self::WAS_ACTIVE_SETS_KEY.to_string(), set_id)?;
+        self.add_element(&Self::ACTIVE_SETS_KEY.to_string(), set_id)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.insert(&format!("{}#{}", Self::ADDED_AT_KEY, set_id), &now)
+    }
+
+    /// Sets turned on last go first, the ones turned on before the time was recorded go last.
+    pub fn get_active_set_ids_newest_first(&self) -> DataResult<Vec<String>> {
+        let mut sets = self
+            .get_active_set_ids()?
+            .into_iter()
+            .map(|set_id| {
+                let added_at: Option<u64> =
+                    self.get(&format!("{}#{}", Self::ADDED_AT_KEY, set_id))?;
+                Ok((added_at.unwrap_or(0), set_id))
+            })
+            .collect::<DataResult<Vec<_>>>()?;
+        sets.sort_by(|(left_added, left_id), (right_added, right_id)| {
+            right_added
+                .cmp(left_added)
+                .then_with(|| left_id.cmp(right_id))
+        });
+        Ok(sets.into_iter().map(|(_, set_id)| set_id).collect())
+    }
+
+    pub fn remove_active(&self, set_id: &String) -> DataResult<()> {
+        self.remove_element(&Self::ACTIVE_SETS_KEY.to_string(), set_id)?;
+        Ok(())
+    }
+
+    /// Everyone who has ever registered.
+    pub fn get_user_ids(&self) -> DataResult<Vec<UserId>> {
+        let prefix = format!("{}#", Self::USER_DATA_KEY);
+        self.db
+            .scan_prefix(prefix.as_bytes())
+            .keys()
+            .map(|key| {
+                let key = key?;
+                let key = String::from_utf8_lossy(&key);
+                key[prefix.len()..]
+                    .parse::<i64>()
+                    .map(UserId::new)
+                    .map_err(|_| DataError::MalformedKey(key.to_string()))
+            })
+            .collect::<DataResult<Vec<_>>>()
     }
 
-    pub fn get_rating_list(&self, top: usize) -> String {
-        let mut users: Vec<UserData> = self
+    pub fn get_rating_list(&self) -> DataResult<Vec<RankedEntry>> {
+        let prefix = format!("{}#", Self::USER_DATA_KEY);
+        let users = self
             .db
-            .scan_prefix(Self::USER_DATA_KEY)
-            .map(|result| match result {
-                Ok((_, value)) => UserData::deserialize(&mut value.as_ref()).unwrap(),
-                Err(err) => panic!("Error while working with db {}", err),
+            .scan_prefix(prefix.as_bytes())
+            .map(|result| {
+                let (key, value) = result?;
+                let user_id = String::from_utf8_lossy(&key[prefix.len()..])
+                    .parse::<i64>()
+                    .unwrap();
+                let user_data = UserData::deserialize(&mut value.as_ref()).unwrap();
+                Ok((user_id, user_data.display_name(), user_data.rating as i64))
             })
-            .collect::<Vec<_>>();
-        users.sort_by(|u1, u2| u2.rating.cmp(&u1.rating));
-        let mut res = String::new();
-        let mut place = 1usize;
-        let mut last_rating = 0u32;
-        let mut add = 0usize;
-        for user_data in users.iter() {
-            if user_data.rating != last_rating {
-                last_rating = user_data.rating;
-                place += add;
-                if place > top {
-                    break;
-                }
-                add = 0;
-            }
-            add += 1;
-            res += format!(
-                "<b>{}.</b> {} {}\n",
+            .collect::<DataResult<Vec<_>>>()?;
+        Ok(Self::ranked_list(users))
+    }
+
+    /// Rating points gained or lost during the month in the `YYYY-MM` format.
+    pub fn get_monthly_list(&self, month: &str) -> DataResult<Vec<RankedEntry>> {
+        let prefix = format!("{}#{}#", Self::MONTHLY_KEY, month);
+        let users = self
+            .db
+            .scan_prefix(prefix.as_bytes())
+            .map(|result| {
+                let (key, value) = result?;
+                let user_id = String::from_utf8_lossy(&key[prefix.len()..])
+                    .parse::<i64>()
+                    .unwrap();
+                let points = i32::deserialize(&mut value.as_ref()).unwrap();
+                let name = self
+                    .get_user_data(&UserId::new(user_id))?
+                    .map(|user_data| user_data.display_name())
+                    .unwrap_or_default();
+                Ok((user_id, name, points as i64))
+            })
+            .collect::<DataResult<Vec<_>>>()?;
+        Ok(Self::ranked_list(users))
+    }
+
+    /// Sorts entries by value descending, equal values share the place.
+    fn ranked_list(mut entries: Vec<(i64, String, i64)>) -> Vec<RankedEntry> {
+        entries.sort_by(|(_, _, v1), (_, _, v2)| v2.cmp(v1));
+        let mut res: Vec<RankedEntry> = Vec::with_capacity(entries.len());
+        for (i, (user_id, name, value)) in entries.into_iter().enumerate() {
+            let place = match res.last() {
+                Some(last) if last.value == value => last.place,
+                _ => i + 1,
+            };
+            res.push(RankedEntry {
                 place,
-                user_data.display_name(),
-                display_rating(user_data.rating)
-            )
-            .as_str();
+                user_id,
+                name,
+                value,
+            });
         }
         res
     }
 
+    pub fn get_user_stats(&self, id: &UserId) -> DataResult<UserStats> {
+        Ok(self
+            .get(&format!("{}#{}", Self::USER_STATS_KEY, id))?
+            .unwrap_or_default())
+    }
+
+    /// Rating changes of the user, oldest first. Stored as a single value since it is rewritten
+    /// after every game.
+    pub fn get_rating_history(&self, id: &UserId) -> DataResult<Vec<RatingChange>> {
+        Ok(self
+            .get(&format!("{}#{}", Self::RATING_HISTORY_KEY, id))?
+            .unwrap_or_default())
+    }
+
+    /// Ids are increasing, including across restarts.
+    pub fn next_game_id(&self) -> DataResult<u64> {
+        Ok(self.db.generate_id()?)
+    }
+
+    /// Keys are zero padded so they sort by game id. Only the most recent games are kept.
+    pub fn archive_game(&self, record: &GameRecord) -> DataResult<()> {
+        self.insert(
+            &format!("{}#{:020}", Self::GAME_ARCHIVE_KEY, record.id),
+            record,
+        )?;
+        let keys = self
+            .db
+            .scan_prefix(format!("{}#", Self::GAME_ARCHIVE_KEY))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.len() > Self::MAX_ARCHIVED_GAMES {
+            for key in keys[..keys.len() - Self::MAX_ARCHIVED_GAMES].iter() {
+                self.remove_raw(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Most recent archived games, newest first.
+    pub fn get_recent_games(&self, limit: usize) -> DataResult<Vec<GameRecord>> {
+        self.db
+            .scan_prefix(format!("{}#", Self::GAME_ARCHIVE_KEY))
+            .values()
+            .rev()
+            .take(limit)
+            .map(|value| Ok(GameRecord::deserialize(&mut value?.as_ref()).unwrap()))
+            .collect()
+    }
+
+    /// Returns false without storing anything if the game already has the maximum number of
+    /// adjustments.
+    pub fn add_adjustment(&self, game_id: u64, adjustment: &ScoreAdjustment) -> DataResult<bool> {
+        let key = format!("{}#{}", Self::ADJUSTMENTS_KEY, game_id);
+        let mut adjustments = self.get_adjustments(game_id)?;
+        if adjustments.len() >= Self::MAX_ADJUSTMENTS {
+            return Ok(false);
+        }
+        adjustments.push(adjustment.clone());
+        self.insert(&key, &adjustments)?;
+        Ok(true)
+    }
+
+    pub fn get_adjustments(&self, game_id: u64) -> DataResult<Vec<ScoreAdjustment>> {
+        Ok(self
+            .get(&format!("{}#{}", Self::ADJUSTMENTS_KEY, game_id))?
+            .unwrap_or_default())
+    }
+
+    pub fn get_profile(&self, id: UserId) -> DataResult<Option<String>> {
+        let user_data = match self.get_user_data(&id)? {
+            None => return Ok(None),
+            Some(user_data) => user_data,
+        };
+        let stats = self.get_user_stats(&id)?;
+        let mut exhausted = 0usize;
+        for set_id in self.get_active_set_ids()? {
+            if !self.is_set_blocked(id, &set_id)? && self.topics_in_set_remain(id, &set_id)? == 0 {
+                exhausted += 1;
+            }
+        }
+        let win_rate = (stats.wins * 100).checked_div(stats.games).unwrap_or(0);
+        let average_score = stats
+            .total_score
+            .checked_div(stats.games as i64)
+            .unwrap_or(0);
+        Ok(Some(format!(
+            "<b>{}</b>\nРейтинг: {}\nМаксимальный рейтинг: {}\nСыграно игр: {}\nПобед: {} ({}%)\nСредний счет: {}\nПолностью сыграно пакетов: {}",
+            user_data.display_name(),
+            display_rating(user_data.rating),
+            display_rating(stats.peak_rating.max(user_data.rating)),
+            stats.games,
+            stats.wins,
+            win_rate,
+            average_score,
+            exhausted
+        )))
+    }
+
     pub fn get_set(&self, id: &String) -> Option<Arc<TopicSet>> {
         self.sets.read().unwrap().get(id).map(|set| set.clone())
     }
 
-    pub fn get_played(&self, user_id: UserId, set_id: &String) -> Option<BitSet> {
-        self.get(&format!("{}#{}#{}", Self::PLAYED_KEY, user_id, set_id))
+    /// Version of the set `get_set` returns, the first upload is version 0.
+    pub fn get_set_version(&self, id: &String) -> DataResult<u32> {
+        Ok(self
+            .get(&format!("{}#{}", Self::SET_VERSION_KEY, id))?
+            .unwrap_or(0))
+    }
+
+    /// The set as it was at the given version, for games that started before it was uploaded again.
+    pub fn get_set_at_version(
+        &self,
+        id: &String,
+        version: u32,
+    ) -> DataResult<Option<Arc<TopicSet>>> {
+        if version == self.get_set_version(id)? {
+            return Ok(self.get_set(id));
+        }
+        let set: Option<TopicSet> =
+            self.get(&format!("{}#{}@{}", Self::SET_VERSIONS_KEY, id, version))?;
+        Ok(set.map(Arc::new))
     }
 
-    fn get_count_played(&self, user_id: UserId, set_id: &String) -> usize {
+    /// Removes the old versions of the sets no stored game plays.
+    pub fn prune_set_versions(&self) -> DataResult<()> {
+        let used = self
+            .get_game_states()?
+            .iter()
+            .map(|game| {
+                format!(
+                    "{}#{}@{}",
+                    Self::SET_VERSIONS_KEY,
+                    game.set_id,
+                    game.set_version
+                )
+            })
+            .collect::<HashSet<_>>();
+        for item in self.db.scan_prefix(format!("{}#", Self::SET_VERSIONS_KEY)) {
+            let (key, _) = item?;
+            if !used.contains(String::from_utf8_lossy(&key).as_ref()) {
+                self.remove_raw(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_played(&self, user_id: UserId, set_id: &String) -> DataResult<Option<BitSet>> {
         self.get(&format!("{}#{}#{}", Self::PLAYED_KEY, user_id, set_id))
-            .unwrap_or(0usize)
     }
 
-    pub fn topics_in_set_remain(&self, user_id: UserId, set_id: &String) -> usize {
-        if self.is_set_blocked(user_id, set_id) {
-            0
+    /// Played topics of the set together with the ones whose copies in other sets were played.
+    pub fn get_played_with_copies(
+        &self,
+        user_id: UserId,
+        set_id: &String,
+    ) -> DataResult<Option<BitSet>> {
+        let mut played = self.get_played(user_id, set_id)?;
+        let set = match self.get_set(set_id) {
+            Some(set) => set,
+            None => return Ok(played),
+        };
+        let mut other_played = HashMap::new();
+        for (index, topic) in set.topics.iter().enumerate() {
+            for (other_set, other_index) in self.get_topic_copies(topic)? {
+                if &other_set == set_id {
+                    continue;
+                }
+                let other_bit_set = match other_played.entry(other_set) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let bit_set = self.get_played(user_id, entry.key())?;
+                        entry.insert(bit_set)
+                    }
+                };
+                let copy_played = other_bit_set
+                    .as_ref()
+                    .is_some_and(|bit_set: &BitSet| bit_set.is_set(other_index));
+                if copy_played {
+                    played
+                        .get_or_insert_with(|| BitSet::new(set.topics.len()))
+                        .set_bit(index);
+                }
+            }
+        }
+        Ok(played)
+    }
+
+    fn get_count_played(&self, user_id: UserId, set_id: &String) -> DataResult<usize> {
+        Ok(self
+            .get(&format!("{}#{}#{}", Self::PLAYED_KEY, user_id, set_id))?
+            .unwrap_or(0usize))
+    }
+
+    pub fn topics_in_set_remain(&self, user_id: UserId, set_id: &String) -> DataResult<usize> {
+        self.topics_remain(user_id, set_id, &self.get_set_topics(set_id)?)
+    }
+
+    /// Size and disabled topics of the set, read once to check many players with `topics_remain`.
+    pub fn get_set_topics(&self, set_id: &String) -> DataResult<SetTopics> {
+        Ok(SetTopics {
+            total: self.get_set(set_id).unwrap().topics.len(),
+            disabled: self.get_disabled_topics(set_id)?,
+        })
+    }
+
+    pub fn topics_remain(
+        &self,
+        user_id: UserId,
+        set_id: &String,
+        set_topics: &SetTopics,
+    ) -> DataResult<usize> {
+        if self.is_set_blocked(user_id, set_id)? {
+            return Ok(0);
+        }
+        let total = set_topics.total;
+        Ok(match &set_topics.disabled {
+            None => total - self.get_count_played(user_id, set_id)?,
+            Some(disabled) => {
+                let mut unavailable = self
+                    .get_played(user_id, set_id)?
+                    .unwrap_or_else(|| BitSet::new(total));
+                unavailable.unite(disabled);
+                total - unavailable.size
+            }
+        })
+    }
+
+    pub fn get_disabled_topics(&self, set_id: &String) -> DataResult<Option<BitSet>> {
+        self.get(&format!("{}#{}", Self::DISABLED_TOPICS_KEY, set_id))
+    }
+
+    /// Disables or re-enables a topic of the set for new games, returns false if nothing changed.
+    //noinspection RsSelfConvention
+    pub fn set_topic_disabled(
+        &self,
+        set_id: &String,
+        index: usize,
+        to_disable: bool,
+    ) -> DataResult<bool> {
+        let key = format!("{}#{}", Self::DISABLED_TOPICS_KEY, set_id);
+        let mut disabled = self
+            .get_disabled_topics(set_id)?
+            .unwrap_or_else(|| BitSet::new(index + 1));
+        if disabled.is_set(index) == to_disable {
+            return Ok(false);
+        }
+        if to_disable {
+            disabled.set_bit(index);
         } else {
-            self.get_set(set_id).unwrap().topics.len() - self.get_count_played(user_id, set_id)
+            disabled.unset_bit(index);
         }
+        if disabled.size == 0 {
+            self.remove(&key)?;
+        } else {
+            self.insert(&key, &disabled)?;
+        }
+        Ok(true)
     }
 
     //noinspection RsSelfConvention
-    pub fn set_played(&self, users: &[&UserId], set_id: &String, topics: &[usize]) {
+    pub fn set_played(
+        &self,
+        users: &[&UserId],
+        set_id: &String,
+        topics: &[usize],
+    ) -> DataResult<()> {
         self.transaction(|db| {
             let topic_count = self.get_set(set_id).unwrap().topics.len();
             for user_id in users.iter() {
                 let mut played =
-                    Self::get_tree(db, &format!("{}#{}#{}", Self::PLAYED_KEY, user_id, set_id))
+                    Self::get_tree(db, &format!("{}#{}#{}", Self::PLAYED_KEY, user_id, set_id))?
                         .unwrap_or_else(|| BitSet::new(topic_count));
+                played.grow(topic_count);
                 for id in topics.iter() {
                     played.set_bit(*id);
                 }
@@ -398,7 +1535,7 @@ impl Data {
                 )?;
             }
             Ok(())
-        });
+        })
     }
 
     fn load_sets(&self) {
@@ -414,7 +1551,7 @@ impl Data {
         }
     }
 
-    fn get<T: BorshSerialize + BorshDeserialize>(&self, key: &String) -> Option<T> {
+    fn get<T: BorshSerialize + BorshDeserialize>(&self, key: &String) -> DataResult<Option<T>> {
         self.get_raw(key.as_bytes())
     }
 
@@ -422,43 +1559,43 @@ impl Data {
     fn get_tree<T: BorshSerialize + BorshDeserialize>(
         db: &TransactionalTree,
         key: &String,
-    ) -> Option<T> {
+    ) -> Result<Option<T>, UnabortableTransactionError> {
         Self::get_tree_raw(db, key.as_bytes())
     }
 
-    fn get_raw<T: BorshSerialize + BorshDeserialize>(&self, key: &[u8]) -> Option<T> {
-        match self.db.get(key) {
-            Ok(result) => match result {
-                None => None,
-                Some(result) => Some(T::deserialize(&mut result.as_ref()).unwrap()),
-            },
-            Err(err) => panic!("Error while working with db {}", err),
-        }
+    /// Values that fail to deserialize mean the database is corrupted, so that still panics.
+    fn get_raw<T: BorshSerialize + BorshDeserialize>(&self, key: &[u8]) -> DataResult<Option<T>> {
+        Ok(self
+            .db
+            .get(key)?
+            .map(|result| T::deserialize(&mut result.as_ref()).unwrap()))
     }
 
     //noinspection RsSelfConvention
     fn get_tree_raw<T: BorshSerialize + BorshDeserialize>(
         db: &TransactionalTree,
         key: &[u8],
-    ) -> Option<T> {
-        match db.get(key) {
-            Ok(result) => match result {
-                None => None,
-                Some(result) => Some(T::deserialize(&mut result.as_ref()).unwrap()),
-            },
-            Err(err) => panic!("Error while working with db {}", err),
-        }
+    ) -> Result<Option<T>, UnabortableTransactionError> {
+        Ok(db
+            .get(key)?
+            .map(|result| T::deserialize(&mut result.as_ref()).unwrap()))
     }
 
-    fn insert<T: BorshSerialize + BorshDeserialize>(&self, key: &String, element: &T) {
+    fn insert<T: BorshSerialize + BorshDeserialize>(
+        &self,
+        key: &String,
+        element: &T,
+    ) -> DataResult<()> {
         self.insert_raw(key.as_bytes(), element)
     }
 
-    fn insert_raw<T: BorshSerialize + BorshDeserialize>(&self, key: &[u8], element: &T) {
-        match self.db.insert(key, element.try_to_vec().unwrap()) {
-            Ok(_) => {}
-            Err(err) => panic!("Error while working with db {}", err),
-        }
+    fn insert_raw<T: BorshSerialize + BorshDeserialize>(
+        &self,
+        key: &[u8],
+        element: &T,
+    ) -> DataResult<()> {
+        self.db.insert(key, element.try_to_vec().unwrap())?;
+        Ok(())
     }
 
     fn insert_tree<T: BorshSerialize + BorshDeserialize>(
@@ -478,15 +1615,13 @@ impl Data {
         Ok(())
     }
 
-    fn remove(&self, key: &String) {
+    fn remove(&self, key: &String) -> DataResult<()> {
         self.remove_raw(key.as_bytes())
     }
 
-    fn remove_raw(&self, key: &[u8]) {
-        match self.db.remove(key) {
-            Ok(_) => {}
-            Err(err) => panic!("Error while working with db {}", err),
-        }
+    fn remove_raw(&self, key: &[u8]) -> DataResult<()> {
+        self.db.remove(key)?;
+        Ok(())
     }
 
     fn remove_tree(
@@ -504,87 +1639,425 @@ impl Data {
         Ok(())
     }
 
-    fn transaction<F>(&self, f: F)
+    fn transaction<F>(&self, f: F) -> DataResult<()>
     where
         F: Fn(&TransactionalTree) -> ConflictableTransactionResult<(), UnabortableTransactionError>,
     {
-        match self.db.transaction(f) {
-            Ok(_) => {}
-            Err(err) => panic!("Error while working with db {}", err),
+        self.db.transaction(f)?;
+        Ok(())
+    }
+
+    /// Lists are stored as a single value under the key. Lists written in the old format, one key
+    /// per element plus a size key, are converted on first access.
+    fn get_list<T: BorshSerialize + BorshDeserialize>(&self, key: &String) -> DataResult<Vec<T>> {
+        match self.get::<Vec<T>>(key)? {
+            Some(list) => Ok(list),
+            None => self.migrate_list(key),
         }
     }
 
-    fn get_list<T: BorshSerialize + BorshDeserialize>(&self, key: &String) -> Vec<T> {
-        let len = self
-            .get(&&format!("{}#{}", key, Self::SIZE_SUFFIX))
-            .unwrap_or(0usize);
+    fn migrate_list<T: BorshSerialize + BorshDeserialize>(
+        &self,
+        key: &String,
+    ) -> DataResult<Vec<T>> {
+        let len = match self.get::<usize>(&format!("{}#{}", key, Self::SIZE_SUFFIX))? {
+            None => return Ok(Vec::new()),
+            Some(len) => len,
+        };
         let mut res = Vec::new();
         for i in 0usize..len {
-            res.push(self.get(&format!("{}#{}", key, i)).unwrap());
+            res.push(self.get(&format!("{}#{}", key, i))?.unwrap());
         }
-        res
-    }
-
-    fn add_element<T: BorshSerialize + BorshDeserialize>(&self, key: &String, element: &T) {
-        let len = self
-            .get::<usize>(&format!("{}#{}", key, Self::SIZE_SUFFIX))
-            .unwrap_or(0usize);
         self.transaction(|db| {
-            Self::insert_tree(db, &format!("{}#{}", key, len), element)?;
-            Self::insert_tree(db, &format!("{}#{}", key, Self::SIZE_SUFFIX), &(len + 1))?;
+            Self::insert_tree(db, key, &res)?;
+            for i in 0usize..len {
+                Self::remove_tree(db, &format!("{}#{}", key, i))?;
+            }
+            Self::remove_tree(db, &format!("{}#{}", key, Self::SIZE_SUFFIX))?;
             Ok(())
-        });
+        })?;
+        Ok(res)
     }
 
-    fn remove_element<T: BorshSerialize + BorshDeserialize + PartialEq>(
+    fn add_element<T: BorshSerialize + BorshDeserialize + Clone>(
         &self,
         key: &String,
         element: &T,
-    ) -> bool {
-        let len = self.list_size(key);
-        for i in 0usize..len {
-            if self.get::<T>(&format!("{}#{}", key, i)).unwrap() == *element {
-                self.remove_at::<T>(key, i);
-                return true;
-            }
-        }
-        false
+    ) -> DataResult<()> {
+        let mut list = self.get_list::<T>(key)?;
+        list.push(element.clone());
+        self.insert(key, &list)
     }
 
-    fn remove_at<T: BorshSerialize + BorshDeserialize>(&self, key: &String, i: usize) {
-        let len = self.list_size(key) - 1;
-        self.transaction(|db| {
-            for j in i..len {
-                Self::insert_tree(
-                    db,
-                    &format!("{}#{}", key, j),
-                    &Self::get_tree::<T>(db, &format!("{}#{}", key, j + 1)).unwrap(),
-                )?;
+    fn remove_element<T: BorshSerialize + BorshDeserialize + PartialEq>(
+        &self,
+        key: &String,
+        element: &T,
+    ) -> DataResult<bool> {
+        let mut list = self.get_list::<T>(key)?;
+        match list.iter().position(|e| e == element) {
+            None => Ok(false),
+            Some(i) => {
+                list.remove(i);
+                self.insert(key, &list)?;
+                Ok(true)
             }
-            Self::remove_tree(db, &format!("{}#{}", key, len))?;
-            Self::insert_tree(db, &format!("{}#{}", key, Self::SIZE_SUFFIX), &len)?;
-            Ok(())
-        });
-    }
-
-    fn list_size(&self, key: &String) -> usize {
-        self.get(&format!("{}#{}", key, Self::SIZE_SUFFIX))
-            .unwrap_or(0usize)
+        }
     }
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct UserData {
     display_name: String,
     pub rating: u32,
+    pub games_played: u32,
+}
+
+/// Stored layouts of `UserData`. A new field goes into a new variant, so records written by older
+/// versions still deserialize.
+#[derive(BorshSerialize, BorshDeserialize)]
+enum UserDataVersion {
+    V1 {
+        display_name: String,
+        rating: u32,
+        games_played: u32,
+    },
+}
+
+/// `UserData` as stored before the schema was versioned.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyUserData {
+    display_name: String,
+    rating: u32,
+}
+
+impl BorshSerialize for UserData {
+    fn serialize<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        UserDataVersion::V1 {
+            display_name: self.display_name.clone(),
+            rating: self.rating,
+            games_played: self.games_played,
+        }
+        .serialize(writer)
+    }
+}
+
+impl BorshDeserialize for UserData {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        Ok(match UserDataVersion::deserialize(buf)? {
+            UserDataVersion::V1 {
+                display_name,
+                rating,
+                games_played,
+            } => UserData {
+                display_name,
+                rating,
+                games_played,
+            },
+        })
+    }
 }
 
 impl UserData {
+    pub fn new(display_name: String, rating: u32) -> Self {
+        Self {
+            display_name,
+            rating,
+            games_played: 0,
+        }
+    }
+
     pub fn display_name(&self) -> String {
         html_escape::encode_text(&self.display_name).to_string()
     }
 }
 
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    Russian,
+    English,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct UserSettings {
+    /// Whether the message about a found game is sent with a notification.
+    pub notifications: bool,
+    pub language: Language,
+    /// Games of unrated players change neither their rating nor the ratings of their opponents.
+    pub unrated: bool,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self {
+            notifications: true,
+            language: Language::Russian,
+            unrated: false,
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct UserStats {
+    pub games: u32,
+    pub wins: u32,
+    pub total_score: i64,
+    /// Highest rating reached after a game, zero for users whose games predate it.
+    pub peak_rating: u32,
+}
+
+/// `UserStats` as stored before the peak rating was tracked.
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+struct LegacyUserStats {
+    games: u32,
+    wins: u32,
+    total_score: i64,
+}
+
+/// `TopicSet` as stored before questions could have an image.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyTopicSet {
+    id: String,
+    title: String,
+    description: String,
+    topics: Vec<LegacyTopic>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyTopic {
+    name: String,
+    questions: Vec<LegacyQuestion>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyQuestion {
+    cost: u16,
+    question: String,
+    answers: Vec<String>,
+    comment: Option<String>,
+}
+
+/// `TopicSet` as stored before questions could require all of their answers.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyImageTopicSet {
+    id: String,
+    title: String,
+    description: String,
+    topics: Vec<LegacyImageTopic>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyImageTopic {
+    name: String,
+    questions: Vec<LegacyImageQuestion>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyImageQuestion {
+    cost: u16,
+    question: String,
+    answers: Vec<String>,
+    comment: Option<String>,
+    image: Option<String>,
+}
+
+impl From<LegacyImageTopicSet> for TopicSet {
+    fn from(legacy: LegacyImageTopicSet) -> Self {
+        TopicSet {
+            id: legacy.id,
+            title: legacy.title,
+            description: legacy.description,
+            topics: legacy
+                .topics
+                .into_iter()
+                .map(|topic| Topic {
+                    name: topic.name,
+                    questions: topic
+                        .questions
+                        .into_iter()
+                        .map(|question| Question {
+                            cost: question.cost,
+                            question: question.question,
+                            answers: question.answers,
+                            comment: question.comment,
+                            image: question.image,
+                            all_required: false,
+                        })
+                        .collect(),
+                    author: None,
+                })
+                .collect(),
+            author: None,
+        }
+    }
+}
+
+/// Only for migrations that run before `Data::migrate_all_required`, nothing requires all answers.
+impl From<TopicSet> for LegacyImageTopicSet {
+    fn from(set: TopicSet) -> Self {
+        LegacyImageTopicSet {
+            id: set.id,
+            title: set.title,
+            description: set.description,
+            topics: set
+                .topics
+                .into_iter()
+                .map(|topic| LegacyImageTopic {
+                    name: topic.name,
+                    questions: topic
+                        .questions
+                        .into_iter()
+                        .map(|question| LegacyImageQuestion {
+                            cost: question.cost,
+                            question: question.question,
+                            answers: question.answers,
+                            comment: question.comment,
+                            image: question.image,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `TopicSet` as stored before sets and topics could have an author.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyAuthorTopicSet {
+    id: String,
+    title: String,
+    description: String,
+    topics: Vec<LegacyAuthorTopic>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyAuthorTopic {
+    name: String,
+    questions: Vec<Question>,
+}
+
+impl From<LegacyAuthorTopicSet> for TopicSet {
+    fn from(legacy: LegacyAuthorTopicSet) -> Self {
+        TopicSet {
+            id: legacy.id,
+            title: legacy.title,
+            description: legacy.description,
+            topics: legacy
+                .topics
+                .into_iter()
+                .map(|topic| Topic {
+                    name: topic.name,
+                    questions: topic.questions,
+                    author: None,
+                })
+                .collect(),
+            author: None,
+        }
+    }
+}
+
+/// Only for migrations that run before `Data::migrate_authors`, nothing has an author.
+impl From<TopicSet> for LegacyAuthorTopicSet {
+    fn from(set: TopicSet) -> Self {
+        LegacyAuthorTopicSet {
+            id: set.id,
+            title: set.title,
+            description: set.description,
+            topics: set
+                .topics
+                .into_iter()
+                .map(|topic| LegacyAuthorTopic {
+                    name: topic.name,
+                    questions: topic.questions,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Raw key-value pairs of the database, see `Data::export`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct DataDump {
+    pub with_sets: bool,
+    pub entries: Vec<(String, Vec<u8>)>,
+}
+
+/// Topic of an uploaded set that some other set already has.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateTopic {
+    pub index: usize,
+    pub original_set: String,
+    pub original_index: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RankedEntry {
+    pub place: usize,
+    pub user_id: i64,
+    pub name: String,
+    pub value: i64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct RatingChange {
+    pub timestamp: u64,
+    pub set_id: String,
+    pub score: i32,
+    pub old_rating: u32,
+    pub new_rating: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct GameRecord {
+    pub id: u64,
+    pub timestamp: u64,
+    pub set_id: String,
+    pub topics: Vec<usize>,
+    pub scores: Vec<(i64, i32)>,
+    pub aborted: bool,
+}
+
+pub struct SetTopics {
+    total: usize,
+    disabled: Option<BitSet>,
+}
+
+/// Player waiting in the queue, times are in milliseconds since the epoch.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct QueueRecord {
+    pub user_id: i64,
+    pub entered: u64,
+    pub last_update: u64,
+    pub set_id: Option<String>,
+    pub max_players: u8,
+    /// Milliseconds without registering again after which the search is given up.
+    pub expiry: u64,
+}
+
+/// `QueueRecord` as stored before the expiry could be chosen.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct LegacyQueueRecord {
+    user_id: i64,
+    entered: u64,
+    last_update: u64,
+    set_id: Option<String>,
+    max_players: u8,
+}
+
+impl LegacyQueueRecord {
+    /// The only expiry there was, in milliseconds.
+    const EXPIRY: u64 = 600_000;
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct ScoreAdjustment {
+    pub chat_id: i64,
+    pub timestamp: u64,
+    pub adjusted_by: i64,
+    pub target: i64,
+    pub delta: i32,
+}
+
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct BitSet {
     pub size: usize,
@@ -599,28 +2072,104 @@ impl BitSet {
         }
     }
 
+    /// Makes room for at least `len` bits, new bits are unset. Bit sets stored before a set got
+    /// more topics are shorter than the set.
+    pub fn grow(&mut self, len: usize) {
+        let bytes = len.div_ceil(8);
+        if self.set.len() < bytes {
+            self.set.resize(bytes, 0u8);
+        }
+    }
+
     pub fn set_bit(&mut self, index: usize) {
         if self.is_set(index) {
             return;
         }
+        self.grow(index + 1);
         self.size += 1;
         self.set[index / 8] += 1u8.shl(index % 8);
     }
 
+    pub fn unset_bit(&mut self, index: usize) {
+        if !self.is_set(index) {
+            return;
+        }
+        self.size -= 1;
+        self.set[index / 8] -= 1u8.shl(index % 8);
+    }
+
     pub fn is_set(&self, index: usize) -> bool {
-        self.set[index / 8].shr(index % 8).bitand(1) == 1
+        match self.set.get(index / 8) {
+            None => false,
+            Some(byte) => byte.shr(index % 8).bitand(1) == 1,
+        }
     }
 
     pub fn unite(&mut self, right: &BitSet) {
-        assert_eq!(self.set.len(), right.set.len());
+        self.grow(right.set.len() * 8);
         self.size = 0;
         for i in 0..self.set.len() {
-            self.set[i] |= right.set[i];
+            if let Some(byte) = right.set.get(i) {
+                self.set[i] |= byte;
+            }
             self.size += self.set[i].count_ones() as usize;
         }
     }
 }
 
+fn civil_date(time: SystemTime) -> (i64, i64, i64) {
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (time.duration_since(UNIX_EPOCH).unwrap().as_secs() / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn year_month(time: SystemTime) -> String {
+    let (year, month, _) = civil_date(time);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Whether the text is a month in the `YYYY-MM` format of `year_month`.
+pub fn is_year_month(text: &str) -> bool {
+    match text.split_once('-') {
+        Some((year, month)) => {
+            year.len() == 4
+                && month.len() == 2
+                && year
+                    .chars()
+                    .chain(month.chars())
+                    .all(|c| c.is_ascii_digit())
+                && (1..=12).contains(&month.parse::<u32>().unwrap())
+        }
+        None => false,
+    }
+}
+
+pub fn date(time: SystemTime) -> String {
+    let (year, month, day) = civil_date(time);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Date and time in UTC, `YYYY-MM-DD HH:MM:SS`.
+pub fn date_time(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap().as_secs() % 86400;
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        date(time),
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
 pub fn display_rating(rating: u32) -> u32 {
     (rating + 5) / 10
 }
@@ -634,48 +2183,957 @@ pub fn display_name(user: &User) -> String {
 
 #[test]
 fn test_rating() {
-    let mut results = Vec::new();
-    for i in 0..3 {
-        results.push((
-            i as i64,
-            (
-                UserData {
-                    display_name: i.to_string(),
-                    rating: 15000,
-                },
-                i * 10 as i32,
-                true,
-            ),
+    assert_eq!(Data::matchup_delta(15000f64, 15000f64, 10, 0), 50);
+    assert_eq!(Data::matchup_delta(15000f64, 15000f64, 0, 10), -50);
+    assert_eq!(Data::matchup_delta(15000f64, 15000f64, 10, 10), 0);
+    assert_eq!(Data::matchup_delta(16000f64, 15000f64, 10, 0), 36);
+    assert_eq!(Data::matchup_delta(16000f64, 15000f64, 0, 10), -64);
+    assert_eq!(Data::matchup_delta(14000f64, 15000f64, 10, 0), 64);
+    assert_eq!(Data::matchup_delta(14000f64, 15000f64, 0, 10), -36);
+}
+
+#[test]
+fn test_save_game_results() {
+    let data = Data::new_temporary();
+    let mut results = HashMap::new();
+    for (id, rating, score) in [(1i64, 15000u32, 30i32), (2, 15000, 20), (3, 16000, 10)] {
+        let user_data = UserData::new(id.to_string(), rating);
+        data.set_user_data(UserId::new(id), &user_data).unwrap();
+        results.insert(id, (user_data, score, true));
+    }
+    let places = HashMap::from([(1, 1), (2, 2), (3, 3)]);
+    data.save_game_results(&results, &HashMap::new(), &places, "set")
+        .unwrap();
+    let rating = |id: i64| {
+        data.get_user_data(&UserId::new(id))
+            .unwrap()
+            .unwrap()
+            .rating
+    };
+    assert_eq!(rating(1), 15000 + 50 + 64);
+    assert_eq!(rating(2), 15000 - 50 + 64);
+    assert_eq!(rating(3), 16000 - 64 - 64);
+    let stats = data.get_user_stats(&UserId::new(1)).unwrap();
+    assert_eq!((stats.games, stats.wins, stats.total_score), (1, 1, 30));
+    assert_eq!(stats.peak_rating, rating(1));
+    let stats = data.get_user_stats(&UserId::new(3)).unwrap();
+    assert_eq!((stats.games, stats.wins, stats.total_score), (1, 0, 10));
+    let monthly = data
+        .get_monthly_list(&year_month(SystemTime::now()))
+        .unwrap();
+    assert_eq!(
+        monthly
+            .iter()
+            .map(|entry| (entry.place, entry.user_id, entry.value))
+            .collect::<Vec<_>>(),
+        vec![(1, 1, 114), (2, 2, 14), (3, 3, -128)]
+    );
+    for _ in 0..Data::MAX_RATING_HISTORY {
+        data.save_game_results(&results, &HashMap::new(), &places, "other")
+            .unwrap();
+    }
+    let history = data.get_rating_history(&UserId::new(3)).unwrap();
+    assert_eq!(history.len(), Data::MAX_RATING_HISTORY);
+    assert!(history.iter().all(|change| change.set_id == "other"));
+    assert_eq!(history[0].old_rating, 16000 - 64 - 64);
+    for window in history.windows(2) {
+        assert_eq!(window[0].new_rating, window[1].old_rating);
+    }
+    assert_eq!(history.last().unwrap().new_rating, rating(3));
+    assert_eq!(
+        data.get_user_stats(&UserId::new(3)).unwrap().peak_rating,
+        16000 - 64 - 64
+    );
+}
+
+#[test]
+fn test_list_migration() {
+    let data = Data::new_temporary();
+    let old_key = format!("{}#1", Data::BAN_LIST_KEY);
+    data.insert(&format!("{}#{}", old_key, Data::SIZE_SUFFIX), &2usize)
+        .unwrap();
+    data.insert(&format!("{}#0", old_key), &5i64).unwrap();
+    data.insert(&format!("{}#1", old_key), &6i64).unwrap();
+    data.insert(&format!("{}#2", Data::BAN_LIST_KEY), &vec![7i64])
+        .unwrap();
+    data.insert(
+        &format!("{}#{}", Data::GAME_CHATS_KEY, Data::SIZE_SUFFIX),
+        &1usize,
+    )
+    .unwrap();
+    data.insert(&format!("{}#0", Data::GAME_CHATS_KEY), &-100i64)
+        .unwrap();
+
+    assert_eq!(
+        data.get_ban_list(UserId::new(1)).unwrap(),
+        vec![UserId::new(5), UserId::new(6)]
+    );
+    assert_eq!(
+        data.get::<usize>(&format!("{}#{}", old_key, Data::SIZE_SUFFIX))
+            .unwrap(),
+        None
+    );
+    assert_eq!(data.get::<i64>(&format!("{}#0", old_key)).unwrap(), None);
+    assert_eq!(
+        data.get_ban_list(UserId::new(2)).unwrap(),
+        vec![UserId::new(7)]
+    );
+    assert!(data
+        .remove_from_ban_list(UserId::new(1), UserId::new(5))
+        .unwrap());
+    assert!(matches!(
+        data.add_to_ban_list(UserId::new(1), UserId::new(8))
+            .unwrap(),
+        UserBanResult::Banned
+    ));
+    assert_eq!(
+        data.get_ban_list(UserId::new(1)).unwrap(),
+        vec![UserId::new(6), UserId::new(8)]
+    );
+
+    data.add_game_chat(&-200).unwrap();
+    assert_eq!(
+        data.get_game_chats().unwrap(),
+        vec![ChatId::new(-100), ChatId::new(-200)]
+    );
+    data.migrate_game_chat(&-100, &-1000000000100).unwrap();
+    assert_eq!(
+        data.get_game_chats().unwrap(),
+        vec![ChatId::new(-200), ChatId::new(-1000000000100)]
+    );
+
+    for other in 2..=(Data::STORE_PLAYED as i64 + 2) {
+        data.add_game(&[UserId::new(1), UserId::new(other)])
+            .unwrap();
+    }
+    data.add_game(&[UserId::new(1), UserId::new(5)]).unwrap();
+    let last_played = data.get_last_played(UserId::new(1)).unwrap();
+    assert_eq!(last_played.len(), Data::STORE_PLAYED);
+    assert_eq!(last_played.first(), Some(&UserId::new(3)));
+    assert_eq!(last_played.last(), Some(&UserId::new(5)));
+}
+
+#[test]
+fn test_global_ban() {
+    let path = std::env::temp_dir().join(format!("svoyak-global-ban-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    {
+        let data = Data::new(path);
+        data.wipe();
+        assert!(data.set_globally_banned(UserId::new(1), true).unwrap());
+        assert!(!data.set_globally_banned(UserId::new(1), true).unwrap());
+        assert!(data.set_globally_banned(UserId::new(2), true).unwrap());
+        assert!(data.set_globally_banned(UserId::new(2), false).unwrap());
+        assert!(!data.set_globally_banned(UserId::new(3), false).unwrap());
+    }
+    {
+        let data = Data::new(path);
+        assert!(data.is_globally_banned(UserId::new(1)).unwrap());
+        assert!(!data.is_globally_banned(UserId::new(2)).unwrap());
+        assert_eq!(data.get_global_ban_list().unwrap(), vec![UserId::new(1)]);
+        data.wipe();
+    }
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_user_ids() {
+    let data = Data::new_temporary();
+    for id in [3, 1, 2] {
+        data.set_user_data(
+            UserId::new(id),
+            &UserData::new(id.to_string(), Data::START_RATING),
+        )
+        .unwrap();
+    }
+    let mut user_ids = data.get_user_ids().unwrap();
+    user_ids.sort_by_key(|id| i64::from(*id));
+    assert_eq!(
+        user_ids,
+        vec![UserId::new(1), UserId::new(2), UserId::new(3)]
+    );
+    data.db.insert("user-data#broken", vec![0u8]).unwrap();
+    assert!(matches!(
+        data.get_user_ids(),
+        Err(DataError::MalformedKey(key)) if key == "user-data#broken"
+    ));
+}
+
+#[test]
+fn test_managers() {
+    let data = Data::new_temporary();
+    assert!(data.set_manager(UserId::new(1), true).unwrap());
+    assert!(!data.set_manager(UserId::new(1), true).unwrap());
+    assert!(data.set_manager(UserId::new(2), true).unwrap());
+    assert!(data.set_manager(UserId::new(2), false).unwrap());
+    assert!(!data.set_manager(UserId::new(3), false).unwrap());
+    assert!(data.is_manager(UserId::new(1)).unwrap());
+    assert!(!data.is_manager(UserId::new(2)).unwrap());
+    assert_eq!(data.get_managers().unwrap(), vec![UserId::new(1)]);
+}
+
+#[test]
+fn test_daily_limit() {
+    let data = Data::new_temporary();
+    let user = UserId::new(1);
+    let before_midnight = UNIX_EPOCH + Duration::from_secs(1_717_199_999);
+    let after_midnight = UNIX_EPOCH + Duration::from_secs(1_717_200_000);
+    data.add_daily_game(user, before_midnight).unwrap();
+    data.add_daily_game(user, before_midnight).unwrap();
+    assert!(!data.daily_limit_reached(user, before_midnight).unwrap());
+    data.set_daily_limit(Some(2)).unwrap();
+    assert!(data.daily_limit_reached(user, before_midnight).unwrap());
+    assert!(!data.daily_limit_reached(user, after_midnight).unwrap());
+    assert!(!data
+        .daily_limit_reached(UserId::new(2), before_midnight)
+        .unwrap());
+    data.add_daily_game(user, after_midnight).unwrap();
+    assert!(!data.daily_limit_reached(user, after_midnight).unwrap());
+    data.set_daily_limit(None).unwrap();
+    assert!(!data.daily_limit_reached(user, before_midnight).unwrap());
+}
+
+#[test]
+fn test_bit_set_grow() {
+    let mut old = BitSet::new(36);
+    for i in (0..36).step_by(2) {
+        old.set_bit(i);
+    }
+    let mut unused = BitSet::new(48);
+    unused.unite(&old);
+    assert_eq!(unused.size, 18);
+    assert!(unused.is_set(34));
+    assert!(!unused.is_set(35));
+    assert!(!unused.is_set(47));
+    assert!(!old.is_set(47));
+    old.grow(48);
+    old.set_bit(47);
+    assert_eq!(old.size, 19);
+    assert!(old.is_set(47));
+    let mut short = BitSet::new(36);
+    short.unite(&old);
+    assert_eq!(short.size, 19);
+}
+
+#[test]
+fn test_grown_set_upload() {
+    let data = Data::new_temporary();
+    let set_id = "set".to_string();
+    let topic_set = |count: usize| TopicSet::with_empty_topics(&set_id, count);
+    assert!(data.add_new_set(&set_id, topic_set(36)).unwrap());
+    data.add_active(&set_id).unwrap();
+    let user = UserId::new(1);
+    data.set_played(&[&user], &set_id, &(0..30).collect::<Vec<_>>())
+        .unwrap();
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 6);
+    assert!(!data.add_new_set(&set_id, topic_set(30)).unwrap());
+    assert!(data.add_new_set(&set_id, topic_set(48)).unwrap());
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 18);
+    let played = data.get_played(user, &set_id).unwrap().unwrap();
+    assert!(played.is_set(29));
+    assert!(!played.is_set(40));
+    data.set_played(&[&user], &set_id, &[40, 47]).unwrap();
+    let played = data.get_played(user, &set_id).unwrap().unwrap();
+    assert!(played.is_set(47));
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 16);
+}
+
+#[test]
+fn test_duplicate_topics() {
+    let data = Data::new_temporary();
+    let topic = |name: &str, questions: &[&str]| {
+        Topic::new(
+            name.to_string(),
+            questions
+                .iter()
+                .enumerate()
+                .map(|(i, question)| {
+                    Question::new(
+                        (i as u16 + 1) * 10,
+                        question.to_string(),
+                        &["answer".to_string()],
+                        None,
+                    )
+                })
+                .collect(),
+        )
+    };
+    let topic_set = |id: &str, topics: Vec<Topic>| {
+        TopicSet::new(id.to_string(), "title".to_string(), String::new(), topics)
+    };
+    let first = "first".to_string();
+    let second = "second".to_string();
+    data.add_new_set(
+        &first,
+        topic_set(
+            "first",
+            vec![
+                topic("Столицы", &["Франция", "Италия"]),
+                topic("Реки", &["Париж", "Лондон"]),
+            ],
+        ),
+    )
+    .unwrap();
+    let copy = topic_set(
+        "second",
+        vec![
+            topic("Горы", &["Азия", "Европа"]),
+            topic("СТОЛИЦЫ", &["Франция", "  Италия "]),
+            topic("Столицы", &["Франция", "Испания"]),
+        ],
+    );
+    assert_eq!(
+        data.find_duplicate_topics(&second, &copy).unwrap(),
+        vec![DuplicateTopic {
+            index: 1,
+            original_set: first.clone(),
+            original_index: 0,
+        }]
+    );
+    data.add_new_set(&second, copy).unwrap();
+    // A set is not a copy of itself
+    let copy = data.get_set(&second).unwrap();
+    assert_eq!(data.find_duplicate_topics(&second, &copy).unwrap().len(), 1);
+
+    let user = UserId::new(1);
+    assert!(data
+        .get_played_with_copies(user, &second)
+        .unwrap()
+        .is_none());
+    data.set_played(&[&user], &first, &[0]).unwrap();
+    let played = data.get_played_with_copies(user, &second).unwrap().unwrap();
+    assert_eq!(played.size, 1);
+    assert!(played.is_set(1));
+    assert!(data.get_played(user, &second).unwrap().is_none());
+
+    // Re-uploading a set replaces its topics in the index
+    data.add_new_set(
+        &first,
+        topic_set("first", vec![topic("Реки", &["Париж", "Лондон"])]),
+    )
+    .unwrap();
+    assert!(data
+        .find_duplicate_topics(&second, &copy)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_export_import() {
+    let source = Data::new_temporary();
+    let target = Data::new_temporary();
+    let set_id = "set".to_string();
+    source
+        .add_new_set(&set_id, TopicSet::with_empty_topics("set", 10))
+        .unwrap();
+    source.add_active(&set_id).unwrap();
+    source
+        .set_user_data(UserId::new(1), &UserData::new("1".to_string(), 16000))
+        .unwrap();
+    source
+        .add_to_ban_list(UserId::new(1), UserId::new(2))
+        .unwrap();
+    source
+        .set_played(&[&UserId::new(1)], &set_id, &[3])
+        .unwrap();
+    source.add_game_chat(&-100).unwrap();
+    target
+        .set_user_data(UserId::new(3), &UserData::new("3".to_string(), 15000))
+        .unwrap();
+    let other_set = "other".to_string();
+    target
+        .add_new_set(&other_set, TopicSet::with_empty_topics("other", 10))
+        .unwrap();
+
+    let dump = source.export(false).unwrap();
+    let dump: DataDump = serde_json::from_slice(&serde_json::to_vec(&dump).unwrap()).unwrap();
+    assert!(dump
+        .entries
+        .iter()
+        .all(|(key, _)| !key.starts_with("sets#")));
+    target.import(&dump).unwrap();
+    assert_eq!(
+        target
+            .get_user_data(&UserId::new(1))
+            .unwrap()
+            .unwrap()
+            .rating,
+        16000
+    );
+    assert!(target.get_user_data(&UserId::new(3)).unwrap().is_none());
+    assert!(target.in_ban_list(UserId::new(1), UserId::new(2)).unwrap());
+    assert!(target
+        .get_played(UserId::new(1), &set_id)
+        .unwrap()
+        .unwrap()
+        .is_set(3));
+    assert_eq!(target.get_active_set_ids().unwrap(), vec![set_id.clone()]);
+    assert_eq!(target.get_game_chats().unwrap(), vec![ChatId::new(-100)]);
+    assert!(target.get_set(&other_set).is_some());
+    assert!(target.get_set(&set_id).is_none());
+
+    target.import(&source.export(true).unwrap()).unwrap();
+    assert!(target.get_set(&other_set).is_none());
+    assert_eq!(target.get_set(&set_id).unwrap().topics.len(), 10);
+    assert_eq!(target.export(true).unwrap(), source.export(true).unwrap());
+
+    // Dumps of another schema, or from before it was versioned, are refused and change nothing
+    for version in [None, Some(4u32)] {
+        let mut dump = source.export(false).unwrap();
+        dump.entries
+            .retain(|(key, _)| key != Data::SCHEMA_VERSION_KEY && !key.starts_with("user-data#"));
+        if let Some(version) = version {
+            dump.entries.push((
+                Data::SCHEMA_VERSION_KEY.to_string(),
+                version.try_to_vec().unwrap(),
+            ));
+        }
+        assert!(matches!(
+            target.import(&dump),
+            Err(DataError::SchemaMismatch(found)) if found == version.unwrap_or(0)
         ));
+        assert!(target.get_user_data(&UserId::new(1)).unwrap().is_some());
+        assert_eq!(target.get_set(&set_id).unwrap().topics.len(), 10);
     }
-    for user in results.iter() {
-        let ra = user.1 .0.rating;
-        let mut delta = 0i32;
-        for other in results.iter() {
-            if user.0 == other.0 {
-                continue;
-            }
-            let rb = other.1 .0.rating;
-            let ea = 1f64 / (1f64 + 10f64.powf(((rb as f64) - (ra as f64)) / 400f64));
-            let sa = if user.1 .1 < other.1 .1 {
-                0f64
-            } else if user.1 .1 > other.1 .1 {
-                1f64
-            } else {
-                0.5f64
-            };
-            delta += (100f64 * (sa - ea)).round() as i32;
-        }
-        delta = delta.max(-(ra as i32) + 10);
-        println!(
-            "{} {} {}",
-            user.0,
-            delta,
-            display_rating(((user.1 .0.rating as i32) + delta) as u32)
+}
+
+#[test]
+fn test_game_archive() {
+    let data = Data::new_temporary();
+    let record = |timestamp: u64| GameRecord {
+        id: timestamp,
+        timestamp,
+        set_id: "set".to_string(),
+        topics: vec![1, 2, 3],
+        scores: vec![(1, 30), (2, -10)],
+        aborted: false,
+    };
+    for timestamp in 0..Data::MAX_ARCHIVED_GAMES as u64 + 5 {
+        data.archive_game(&record(timestamp)).unwrap();
+    }
+    let last = Data::MAX_ARCHIVED_GAMES as u64 + 4;
+    let games = data.get_recent_games(3).unwrap();
+    assert_eq!(
+        games.iter().map(|game| game.timestamp).collect::<Vec<_>>(),
+        vec![last, last - 1, last - 2]
+    );
+    let games = data.get_recent_games(usize::MAX).unwrap();
+    assert_eq!(games.len(), Data::MAX_ARCHIVED_GAMES);
+    assert_eq!(games.last().unwrap().timestamp, 5);
+}
+
+#[test]
+fn test_schema_migration() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let legacy = LegacyUserData {
+        display_name: "old".to_string(),
+        rating: 16000,
+    };
+    db.insert("user-data#1", legacy.try_to_vec().unwrap())
+        .unwrap();
+    let stats = LegacyUserStats {
+        games: 3,
+        wins: 1,
+        total_score: 100,
+    };
+    db.insert("user-stats#1", stats.try_to_vec().unwrap())
+        .unwrap();
+    let settings = UserSettings {
+        notifications: false,
+        language: Language::English,
+        unrated: true,
+    };
+    db.insert("user-settings#1", settings.try_to_vec().unwrap())
+        .unwrap();
+    let queue = vec![LegacyQueueRecord {
+        user_id: 1,
+        entered: 1_000,
+        last_update: 2_000,
+        set_id: None,
+        max_players: 4,
+    }];
+    db.insert("queue", queue.try_to_vec().unwrap()).unwrap();
+    db.insert("game-state#-100", vec![1u8, 2, 3]).unwrap();
+    let set = LegacyTopicSet {
+        id: "set".to_string(),
+        title: "title".to_string(),
+        description: "description".to_string(),
+        topics: vec![LegacyTopic {
+            name: "topic".to_string(),
+            questions: vec![LegacyQuestion {
+                cost: 10,
+                question: "question".to_string(),
+                answers: vec!["answer".to_string()],
+                comment: None,
+            }],
+        }],
+    };
+    db.insert("sets#set", set.try_to_vec().unwrap()).unwrap();
+    let data = Data::with_db(db);
+    for _ in 0..2 {
+        assert_eq!(
+            data.get::<u32>(&Data::SCHEMA_VERSION_KEY.to_string())
+                .unwrap(),
+            Some(Data::MIGRATIONS.len() as u32)
         );
+        let user_data = data.get_user_data(&UserId::new(1)).unwrap().unwrap();
+        assert_eq!(user_data.display_name(), "old");
+        assert_eq!(user_data.rating, 16000);
+        assert_eq!(user_data.games_played, 3);
+        let stats = data.get_user_stats(&UserId::new(1)).unwrap();
+        assert_eq!((stats.games, stats.wins, stats.total_score), (3, 1, 100));
+        assert_eq!(stats.peak_rating, 0);
+        let settings = data.get_user_settings(UserId::new(1)).unwrap();
+        assert!(!settings.notifications && settings.unrated);
+        assert_eq!(settings.language, Language::English);
+        let queue = data.get_queue().unwrap();
+        assert_eq!((queue[0].max_players, queue[0].expiry), (4, 600_000));
+        assert!(data.get_game_states().unwrap().is_empty());
+        let set = data.get_set(&"set".to_string()).unwrap();
+        let question = &set.topics[0].questions[0];
+        assert_eq!(question.question, "question");
+        assert_eq!(question.answers, vec!["answer".to_string()]);
+        assert!(question.image.is_none());
+        assert!(!question.all_required);
+        assert!(set.author.is_none() && set.topics[0].author.is_none());
+        data.migrate().unwrap();
+    }
+    data.db.insert("game-state#-200", vec![99u8, 1]).unwrap();
+    assert!(data.get_game_states().unwrap().is_empty());
+    assert!(data.db.get("game-state#-200").unwrap().is_none());
+    data.db
+        .insert("game-state#-300", vec![Data::GAME_STATE_VERSION, 1])
+        .unwrap();
+    assert!(data.get_game_states().unwrap().is_empty());
+    assert!(data.db.get("game-state#-300").unwrap().is_none());
+}
+
+#[test]
+fn test_escaping_migration() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    db.insert(Data::SCHEMA_VERSION_KEY, 4u32.try_to_vec().unwrap())
+        .unwrap();
+    let question = |text: &str, answer: &str| Question {
+        cost: 10,
+        question: text.to_string(),
+        answers: vec![answer.to_string()],
+        comment: None,
+        image: None,
+        all_required: false,
+    };
+    let raw = TopicSet {
+        id: "raw".to_string(),
+        title: "Tom & Jerry".to_string(),
+        description: String::new(),
+        topics: vec![Topic {
+            name: "<Книги>".to_string(),
+            questions: vec![question("Роман <Война и мир>", "<Война и мир>")],
+            author: None,
+        }],
+        author: None,
+    };
+    db.insert(
+        "sets#raw",
+        LegacyImageTopicSet::from(raw).try_to_vec().unwrap(),
+    )
+    .unwrap();
+    let escaped = TopicSet::new(
+        "escaped".to_string(),
+        "Tom & Jerry".to_string(),
+        String::new(),
+        vec![Topic::new(
+            "<Книги>".to_string(),
+            vec![Question::new(
+                10,
+                "Роман <Война и мир>".to_string(),
+                &["<Война и мир>".to_string()],
+                None,
+            )],
+        )],
+    );
+    db.insert(
+        "sets#escaped",
+        LegacyImageTopicSet::from(escaped).try_to_vec().unwrap(),
+    )
+    .unwrap();
+    let data = Data::with_db(db);
+    for set_id in ["raw", "escaped"] {
+        let set = data.get_set(&set_id.to_string()).unwrap();
+        assert_eq!(set.title, "Tom &amp; Jerry");
+        assert_eq!(set.topics[0].name, "&lt;Книги&gt;");
+        let question = &set.topics[0].questions[0];
+        assert_eq!(question.question, "Роман &lt;Война и мир&gt;");
+        assert!(question.check_answer("Война и мир", true));
+        assert_eq!(data.get_topic_copies(&set.topics[0]).unwrap().len(), 2);
     }
 }
 
+#[test]
+fn test_adjustments() {
+    let data = Data::new_temporary();
+    let first = data.next_game_id().unwrap();
+    let second = data.next_game_id().unwrap();
+    assert!(second > first);
+    let adjustment = |delta: i32| ScoreAdjustment {
+        chat_id: -100,
+        timestamp: 0,
+        adjusted_by: 1,
+        target: 2,
+        delta,
+    };
+    for i in 0..Data::MAX_ADJUSTMENTS {
+        assert!(data.add_adjustment(first, &adjustment(i as i32)).unwrap());
+    }
+    assert!(!data.add_adjustment(first, &adjustment(-1)).unwrap());
+    assert!(data.add_adjustment(second, &adjustment(10)).unwrap());
+    let adjustments = data.get_adjustments(first).unwrap();
+    assert_eq!(adjustments.len(), Data::MAX_ADJUSTMENTS);
+    assert_eq!(adjustments[3], adjustment(3));
+    assert_eq!(data.get_adjustments(second).unwrap(), vec![adjustment(10)]);
+    assert!(data.get_adjustments(second + 1).unwrap().is_empty());
+}
+
+#[test]
+fn test_unrated_players() {
+    let data = Data::new_temporary();
+    assert_eq!(
+        data.get_user_settings(UserId::new(3)).unwrap(),
+        UserSettings::default()
+    );
+    let mut results = HashMap::new();
+    for (id, rating, score) in [(1i64, 15000u32, 30i32), (2, 15000, 20), (3, 16000, 10)] {
+        let user_data = UserData::new(id.to_string(), rating);
+        data.set_user_data(UserId::new(id), &user_data).unwrap();
+        results.insert(id, (user_data, score, true));
+    }
+    let settings = UserSettings {
+        unrated: true,
+        ..UserSettings::default()
+    };
+    data.set_user_settings(UserId::new(3), &settings).unwrap();
+    assert_eq!(data.get_user_settings(UserId::new(3)).unwrap(), settings);
+    let places = HashMap::from([(1, 1), (2, 2), (3, 3)]);
+    data.save_game_results(&results, &HashMap::new(), &places, "set")
+        .unwrap();
+    let user_data = |id: i64| data.get_user_data(&UserId::new(id)).unwrap().unwrap();
+    assert_eq!(user_data(1).rating, 15000 + 50);
+    assert_eq!(user_data(2).rating, 15000 - 50);
+    assert_eq!(user_data(3).rating, 16000);
+    assert_eq!(user_data(3).games_played, 0);
+    assert_eq!(data.get_user_stats(&UserId::new(3)).unwrap().games, 0);
+    assert!(data.get_rating_history(&UserId::new(3)).unwrap().is_empty());
+}
+
+#[test]
+fn test_rating_range() {
+    let data = Data::new_temporary();
+    let user = UserId::new(1);
+    assert_eq!(data.get_rating_range(user).unwrap(), None);
+    data.set_rating_range(user, Some(500)).unwrap();
+    assert_eq!(data.get_rating_range(user).unwrap(), Some(500));
+    assert_eq!(data.get_rating_range(UserId::new(2)).unwrap(), None);
+    data.set_rating_range(user, None).unwrap();
+    assert_eq!(data.get_rating_range(user).unwrap(), None);
+}
+
+#[test]
+fn test_queue() {
+    let data = Data::new_temporary();
+    assert!(data.get_queue().unwrap().is_empty());
+    let queue = vec![QueueRecord {
+        user_id: 1,
+        entered: 1_000,
+        last_update: 2_000,
+        set_id: Some("set".to_string()),
+        max_players: 5,
+        expiry: 300_000,
+    }];
+    data.set_queue(queue.clone()).unwrap();
+    assert_eq!(data.get_queue().unwrap(), queue);
+    assert!(data.get_parties().unwrap().is_empty());
+    data.set_parties(vec![(1, 2)]).unwrap();
+    assert_eq!(data.get_parties().unwrap(), vec![(1, 2)]);
+    assert!(data
+        .export(false)
+        .unwrap()
+        .entries
+        .iter()
+        .all(|(key, _)| key != "queue" && key != "parties"));
+    data.set_queue(Vec::new()).unwrap();
+    assert!(data.get_queue().unwrap().is_empty());
+}
+
+#[test]
+fn test_ban_list_limit() {
+    let data = Data::new_temporary();
+    let user = UserId::new(1);
+    for i in 0..Data::MAX_BAN_LIST {
+        assert!(matches!(
+            data.add_to_ban_list(user, UserId::new(100 + i as i64))
+                .unwrap(),
+            UserBanResult::Banned
+        ));
+    }
+    assert!(matches!(
+        data.add_to_ban_list(user, UserId::new(100)).unwrap(),
+        UserBanResult::AlreadyInList
+    ));
+    assert!(matches!(
+        data.add_to_ban_list(user, UserId::new(2)).unwrap(),
+        UserBanResult::SizeLimitReached
+    ));
+    assert!(!data.in_ban_list(user, UserId::new(2)).unwrap());
+    assert!(data.remove_from_ban_list(user, UserId::new(100)).unwrap());
+    assert!(matches!(
+        data.add_to_ban_list(user, UserId::new(2)).unwrap(),
+        UserBanResult::Banned
+    ));
+    assert_eq!(data.get_ban_list(user).unwrap().len(), Data::MAX_BAN_LIST);
+    assert!(data.get_ban_list(UserId::new(2)).unwrap().is_empty());
+}
+
+#[test]
+fn test_set_versions() {
+    let data = Data::new_temporary();
+    let set_id = "set".to_string();
+    let topic_set = |name: &str| {
+        let topics = (0..5)
+            .map(|i| Topic::new(format!("{} {}", name, i), Vec::new()))
+            .collect();
+        TopicSet::new(set_id.clone(), "title".to_string(), String::new(), topics)
+    };
+    assert!(data.add_new_set(&set_id, topic_set("Тема")).unwrap());
+    data.add_active(&set_id).unwrap();
+    assert_eq!(data.get_set_version(&set_id).unwrap(), 0);
+    let user = UserId::new(1);
+    data.set_played(&[&user], &set_id, &[0, 1]).unwrap();
+    assert!(data
+        .add_new_set(&set_id, topic_set("Исправленная"))
+        .unwrap());
+    assert_eq!(data.get_set_version(&set_id).unwrap(), 1);
+    let old = data.get_set_at_version(&set_id, 0).unwrap().unwrap();
+    assert_eq!(old.topics[0].name, "Тема 0");
+    let new = data.get_set_at_version(&set_id, 1).unwrap().unwrap();
+    assert_eq!(new.topics[0].name, "Исправленная 0");
+    assert_eq!(
+        data.get_set(&set_id).unwrap().topics[0].name,
+        "Исправленная 0"
+    );
+    assert!(data.get_set_at_version(&set_id, 2).unwrap().is_none());
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 3);
+    assert!(data
+        .export(false)
+        .unwrap()
+        .entries
+        .iter()
+        .all(|(key, _)| !key.starts_with("set-version")));
+
+    // The old version is kept while a stored game plays it
+    let mut game = create_test_game(2, HashMap::new());
+    game.set_id = set_id.clone();
+    data.save_game_state(&game).unwrap();
+    data.prune_set_versions().unwrap();
+    assert!(data.get_set_at_version(&set_id, 0).unwrap().is_some());
+    data.remove_game(game.chat_id).unwrap();
+    data.prune_set_versions().unwrap();
+    assert!(data.get_set_at_version(&set_id, 0).unwrap().is_none());
+    assert!(data.get_set_at_version(&set_id, 1).unwrap().is_some());
+}
+
+#[test]
+fn test_active_sets_newest_first() {
+    let data = Data::new_temporary();
+    for set_id in ["old", "first", "second", "third"] {
+        data.add_active(&set_id.to_string()).unwrap();
+    }
+    data.remove(&format!("{}#old", Data::ADDED_AT_KEY)).unwrap();
+    data.insert(&format!("{}#first", Data::ADDED_AT_KEY), &100u64)
+        .unwrap();
+    data.insert(&format!("{}#third", Data::ADDED_AT_KEY), &100u64)
+        .unwrap();
+    data.insert(&format!("{}#second", Data::ADDED_AT_KEY), &200u64)
+        .unwrap();
+    assert_eq!(
+        data.get_active_set_ids_newest_first().unwrap(),
+        vec!["second", "first", "third", "old"]
+    );
+    data.remove_active(&"second".to_string()).unwrap();
+    assert_eq!(
+        data.get_active_set_ids_newest_first().unwrap(),
+        vec!["first", "third", "old"]
+    );
+}
+
+#[test]
+fn test_disabled_topics() {
+    let data = Data::new_temporary();
+    let set_id = "set".to_string();
+    let set = TopicSet::with_empty_topics(&set_id, 10);
+    assert!(data.add_new_set(&set_id, set).unwrap());
+    let user = UserId::new(1);
+    assert!(data.get_disabled_topics(&set_id).unwrap().is_none());
+    assert!(data.set_topic_disabled(&set_id, 3, true).unwrap());
+    assert!(data.set_topic_disabled(&set_id, 8, true).unwrap());
+    assert!(!data.set_topic_disabled(&set_id, 8, true).unwrap());
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 8);
+    data.set_played(&[&user], &set_id, &[0, 3]).unwrap();
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 7);
+    assert!(data.set_topic_disabled(&set_id, 3, false).unwrap());
+    assert!(!data.set_topic_disabled(&set_id, 3, false).unwrap());
+    let disabled = data.get_disabled_topics(&set_id).unwrap().unwrap();
+    assert_eq!(disabled.size, 1);
+    assert!(disabled.is_set(8) && !disabled.is_set(3));
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 7);
+    assert!(data.set_topic_disabled(&set_id, 8, false).unwrap());
+    assert!(data.get_disabled_topics(&set_id).unwrap().is_none());
+    assert_eq!(data.topics_in_set_remain(user, &set_id).unwrap(), 8);
+}
+
+#[test]
+fn test_topics_in_set_remain() {
+    let data = Data::new_temporary();
+    let set_id = "set".to_string();
+    let set = TopicSet::with_empty_topics(&set_id, 12);
+    assert!(data.add_new_set(&set_id, set).unwrap());
+    let first = UserId::new(1);
+    let second = UserId::new(2);
+    assert_eq!(data.topics_in_set_remain(first, &set_id).unwrap(), 12);
+    data.set_played(&[&first, &second], &set_id, &[0, 1, 2])
+        .unwrap();
+    data.set_played(&[&first], &set_id, &[2, 3]).unwrap();
+    assert_eq!(data.topics_in_set_remain(first, &set_id).unwrap(), 8);
+    assert_eq!(data.topics_in_set_remain(second, &set_id).unwrap(), 9);
+    assert!(data.set_set_blocked(second, &set_id, true).unwrap());
+    assert_eq!(data.topics_in_set_remain(second, &set_id).unwrap(), 0);
+    assert_eq!(
+        data.topics_in_set_remain(UserId::new(3), &set_id).unwrap(),
+        12
+    );
+}
+
+#[test]
+fn test_rating_list_ties() {
+    let data = Data::new_temporary();
+    for (id, rating) in [(1, 1500), (2, 1700), (3, 1500), (4, 1700), (5, 1400)] {
+        data.update_player(UserId::new(id), UserData::new(id.to_string(), rating))
+            .unwrap();
+    }
+    let places = data
+        .get_rating_list()
+        .unwrap()
+        .iter()
+        .map(|entry| (entry.place, entry.value))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        places,
+        vec![(1, 1700), (1, 1700), (3, 1500), (3, 1500), (5, 1400)]
+    );
+}
+
+#[test]
+fn test_ranked_list() {
+    let entries = Data::ranked_list(vec![
+        (1, "a".to_string(), 10),
+        (2, "b".to_string(), 30),
+        (3, "c".to_string(), 10),
+        (4, "d".to_string(), 30),
+        (5, "e".to_string(), 5),
+    ]);
+    assert_eq!(
+        entries
+            .iter()
+            .map(|entry| (entry.place, entry.value))
+            .collect::<Vec<_>>(),
+        vec![(1, 30), (1, 30), (3, 10), (3, 10), (5, 5)]
+    );
+}
+
+#[test]
+fn test_year_month() {
+    assert_eq!(year_month(UNIX_EPOCH), "1970-01");
+    assert!(is_year_month("2024-05") && is_year_month(&year_month(UNIX_EPOCH)));
+    for text in [
+        "2024-13",
+        "2024-00",
+        "2024-5",
+        "24-05",
+        "2024-05-01",
+        "<b>-05",
+    ] {
+        assert!(!is_year_month(text));
+    }
+    assert_eq!(
+        year_month(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+        "2000-02"
+    );
+    assert_eq!(
+        year_month(UNIX_EPOCH + Duration::from_secs(1_717_199_999)),
+        "2024-05"
+    );
+    assert_eq!(
+        year_month(UNIX_EPOCH + Duration::from_secs(1_717_200_000)),
+        "2024-06"
+    );
+    assert_eq!(
+        date(UNIX_EPOCH + Duration::from_secs(1_717_199_999)),
+        "2024-05-31"
+    );
+    assert_eq!(
+        date_time(UNIX_EPOCH + Duration::from_secs(1_717_199_999)),
+        "2024-05-31 23:59:59"
+    );
+    assert_eq!(
+        date(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+        "2000-02-29"
+    );
+}
+
+#[test]
+fn test_discounted_rating() {
+    assert_eq!(
+        Data::discounted_rating(Data::START_RATING),
+        Data::START_RATING
+    );
+    assert_eq!(Data::discounted_rating(25000), 24900);
+    assert_eq!(Data::discounted_rating(5000), 5100);
+    assert_eq!(Data::discounted_rating(15050), 15049);
+}
+
+#[test]
+fn test_rating_reset() {
+    let path = std::env::temp_dir().join(format!("svoyak-rating-reset-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let interval = Duration::from_secs(7 * 24 * 60 * 60);
+    let start = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    {
+        let data = Data::new(path);
+        data.wipe();
+        data.set_user_data(UserId::new(1), &UserData::new("1".to_string(), 25000))
+            .unwrap();
+        assert!(!data.rating_reset(start, interval).unwrap());
+        assert_eq!(data.get_next_reset().unwrap(), Some(start + interval));
+        assert!(!data.rating_reset(start + interval / 2, interval).unwrap());
+        assert_eq!(
+            data.get_user_data(&UserId::new(1)).unwrap().unwrap().rating,
+            25000
+        );
+    }
+    {
+        let data = Data::new(path);
+        assert_eq!(data.get_next_reset().unwrap(), Some(start + interval));
+        assert!(data
+            .rating_reset(start + interval * 3 + interval / 2, interval)
+            .unwrap());
+        assert_eq!(data.get_next_reset().unwrap(), Some(start + interval * 4));
+        assert_eq!(
+            data.get_user_data(&UserId::new(1)).unwrap().unwrap().rating,
+            24900
+        );
+        data.wipe();
+    }
+    std::fs::remove_dir_all(path).unwrap();
+}
+
 #[test]
 fn main() {
     env_logger::Builder::new()
@@ -699,9 +3157,10 @@ fn main() {
             id.clone(),
             std::fs::read_to_string(format!("../olddb/{}.json", &id)).unwrap(),
         )
-        .unwrap();
-        data.add_new_set(&id, set);
-        data.add_active(&id);
+        .unwrap()
+        .0;
+        data.add_new_set(&id, set).unwrap();
+        data.add_active(&id).unwrap();
         log::info!("set {} loaded", id);
     }
     // let active = read_file("active.list");
@@ -715,13 +3174,7 @@ fn main() {
         let id = players[i].parse::<i64>().unwrap();
         let display_name = players[i + 1].clone();
         let rating = 10 * players[i + 2].parse::<u32>().unwrap();
-        users.insert(
-            id,
-            UserData {
-                display_name,
-                rating,
-            },
-        );
+        users.insert(id, UserData::new(display_name, rating));
     }
     let player_count = players.len() / 3;
     let played = read_file("played.list");
@@ -743,11 +3196,12 @@ fn main() {
         }
         if !played.is_empty() {
             if let Some(user) = users.get(&id) {
-                data.set_user_data(UserId::new(id), user);
+                data.set_user_data(UserId::new(id), user).unwrap();
             }
         }
         for (set_id, topics) in played {
-            data.set_played(&vec![&UserId::new(id)], &set_id, &topics);
+            data.set_played(&vec![&UserId::new(id)], &set_id, &topics)
+                .unwrap();
         }
         at += 1;
         log::info!("User processed {}/{}", at, player_count);
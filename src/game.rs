@@ -1,13 +1,15 @@
-use crate::bot::{KeyboardOptions, TelegramBot};
-use crate::data::{display_rating, Data, UserData};
-use crate::topic::{encode, Question, Topic, TopicSet};
-use crate::{player_list, StatusUpdate, UpdateType};
+use crate::bot::{BotResult, BotUpdate, KeyboardOptions, TelegramBot};
+use crate::data::{display_rating, Data, GameRecord, LogError, ScoreAdjustment, UserData};
+use crate::topic::{author_line, encode, Question, Topic, TopicSet};
+use crate::{player_list, Config, StatusUpdate, UpdateType};
 use borsh::{BorshDeserialize, BorshSerialize};
 use futures::{stream::select_all, StreamExt};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::time::Duration;
-use telegram_bot::{ChatId, Message, MessageId, MessageKind, UserId};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use telegram_bot::{
+    CallbackQuery, ChatId, Message, MessageId, MessageKind, MessageOrChannelPost, User, UserId,
+};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::UnboundedReceiverStream;
@@ -18,10 +20,14 @@ pub enum GameState {
     BeforeTopic(bool),
     BeforeFirstQuestion(bool),
     BeforeQuestion(bool),
-    Question(i64, Vec<i64>),
+    Question(i64, Vec<i64>, u64, Vec<i64>),
     Answer(i64, Vec<i64>, i64),
     AfterQuestion(bool, Vec<i64>, Option<i64>),
+    AppealVote(bool, Vec<i64>, Option<i64>, i64, Vec<(i64, bool)>),
     SpecialScore(bool),
+    FinalBetting(Vec<(i64, i32)>),
+    FinalAnswer(i64, Vec<(i64, i32)>, Vec<(i64, String)>),
+    TopicSelection(i64, Vec<usize>),
     AfterGame,
 }
 
@@ -38,12 +44,16 @@ impl GameState {
             GameState::BeforeGame(_, _) => KeyboardOptions::Remove,
             GameState::BeforeTopic(_) => KeyboardOptions::Remove,
             GameState::BeforeFirstQuestion(_) => KeyboardOptions::Remove,
-            GameState::BeforeQuestion(_) => KeyboardOptions::Plus,
-            GameState::Question(_, _) => KeyboardOptions::None,
+            GameState::BeforeQuestion(_) => KeyboardOptions::Remove,
+            GameState::Question(..) => KeyboardOptions::None,
             GameState::Answer(_, _, _) => KeyboardOptions::Remove,
             GameState::AfterQuestion(false, _, _) => KeyboardOptions::YesNoPause,
             GameState::AfterQuestion(true, _, _) => KeyboardOptions::YesNoContinue,
+            GameState::AppealVote(..) => KeyboardOptions::YesNo,
             GameState::SpecialScore(_) => KeyboardOptions::Remove,
+            GameState::FinalBetting(_) => KeyboardOptions::Remove,
+            GameState::FinalAnswer(_, _, _) => KeyboardOptions::Remove,
+            GameState::TopicSelection(_, choices) => KeyboardOptions::Numbers(choices.clone()),
             GameState::AfterGame => KeyboardOptions::Remove,
         }
     }
@@ -60,7 +70,13 @@ impl GameState {
                 *paused = to_pause;
                 was
             }
-            GameState::Question(_, _) | GameState::Answer(_, _, _) | GameState::AfterGame => false,
+            GameState::Question(..)
+            | GameState::Answer(_, _, _)
+            | GameState::AppealVote(..)
+            | GameState::FinalBetting(_)
+            | GameState::FinalAnswer(_, _, _)
+            | GameState::TopicSelection(..)
+            | GameState::AfterGame => false,
         }
     }
 
@@ -72,7 +88,13 @@ impl GameState {
             | GameState::BeforeQuestion(paused)
             | GameState::AfterQuestion(paused, _, _)
             | GameState::SpecialScore(paused) => *paused,
-            GameState::Question(_, _) | GameState::Answer(_, _, _) | GameState::AfterGame => {
+            GameState::Question(..)
+            | GameState::Answer(_, _, _)
+            | GameState::AppealVote(..)
+            | GameState::FinalBetting(_)
+            | GameState::FinalAnswer(_, _, _)
+            | GameState::TopicSelection(..)
+            | GameState::AfterGame => {
                 unreachable!()
             }
         }
@@ -86,40 +108,184 @@ impl GameState {
             | GameState::SpecialScore(..)
             | GameState::BeforeFirstQuestion(..)
             | GameState::BeforeQuestion(..) => true,
-            GameState::Question(..) | GameState::Answer(..) | GameState::AfterGame => false,
+            GameState::Question(..)
+            | GameState::Answer(..)
+            | GameState::AppealVote(..)
+            | GameState::FinalBetting(..)
+            | GameState::FinalAnswer(..)
+            | GameState::TopicSelection(..)
+            | GameState::AfterGame => false,
         }
     }
 }
 
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug)]
+pub struct Timings {
+    pub first_thinking: u8,
+    pub successive_thinking: u8,
+    pub answer: u8,
+    /// Pauses a player may take per game.
+    pub pauses: u8,
+    /// Total minutes a player may keep the game paused.
+    pub pause_minutes: u8,
+}
+
+impl Timings {
+    pub const MIN: u8 = 5;
+    pub const MAX: u8 = 120;
+    pub const MAX_PAUSES: u8 = 10;
+    pub const MAX_PAUSE_MINUTES: u8 = 30;
+
+    pub fn new(first_thinking: u8, successive_thinking: u8, answer: u8) -> Option<Self> {
+        if [first_thinking, successive_thinking, answer]
+            .iter()
+            .all(|value| *value >= Self::MIN && *value <= Self::MAX)
+        {
+            Some(Self {
+                first_thinking,
+                successive_thinking,
+                answer,
+                ..Self::default()
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn first_thinking(&self) -> Duration {
+        Duration::from_secs(self.first_thinking as u64)
+    }
+
+    pub fn successive_thinking(&self) -> Duration {
+        Duration::from_secs(self.successive_thinking as u64)
+    }
+
+    pub fn answer(&self) -> Duration {
+        Duration::from_secs(self.answer as u64)
+    }
+
+    /// Same timings with other pause limits, `None` if they are out of range.
+    pub fn with_pauses(self, pauses: u8, pause_minutes: u8) -> Option<Self> {
+        if pauses <= Self::MAX_PAUSES && pause_minutes <= Self::MAX_PAUSE_MINUTES {
+            Some(Self {
+                pauses,
+                pause_minutes,
+                ..self
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn max_pause_time(&self) -> u64 {
+        self.pause_minutes as u64 * 60
+    }
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            first_thinking: 15,
+            successive_thinking: 10,
+            answer: 30,
+            pauses: 3,
+            pause_minutes: 10,
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TopicPicker {
+    None,
+    Last,
+    Winner,
+}
+
+impl TopicPicker {
+    pub fn description(&self) -> &'static str {
+        match self {
+            TopicPicker::None => "нет",
+            TopicPicker::Last => "последний по очкам",
+            TopicPicker::Winner => "победитель предыдущей темы",
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
+struct GameHistory {
+    topic_scores: Vec<HashMap<i64, i32>>,
+    transcript: Vec<(usize, usize, Option<i64>)>,
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct Game {
+    pub id: u64,
     pub chat_id: i64,
     source_chats: Vec<i64>,
     game_state: GameState,
     pub set_id: String,
+    /// Version of the set the game started with, re-uploading the set doesn't affect it.
+    pub set_version: u32,
     topics: Vec<usize>,
     current_topic: usize,
     current_question: usize,
     players: HashMap<i64, (UserData, i32, bool)>,
     spectators: HashSet<i64>,
     invite_link: String,
+    timings: Timings,
+    final_round: bool,
+    teams: HashMap<i64, u8>,
+    blind: bool,
+    topic_picker: TopicPicker,
+    /// Answers have to match exactly, typos are not forgiven.
+    strict: bool,
+    skip_votes: HashSet<i64>,
+    pauses: HashMap<i64, (u8, u64)>,
+    paused_by: Option<(i64, u64)>,
+    usernames: HashMap<String, i64>,
+    pending_adjustment: Option<(i64, i64, i32)>,
+    departure_vote: Option<(i64, Vec<(i64, bool)>)>,
+    /// Players the others voted to continue without, they don't count in votes, answers and
+    /// final bets and aren't rated.
+    absent: HashSet<i64>,
+    substitutions: Vec<(i64, i64)>,
+    last_wrong: Vec<i64>,
+    announced: bool,
+    /// Pinned message with the current topic and scores, kept up to date during the game.
+    status_message: Option<i64>,
+    /// The current question was sent as a photo, so its text is edited through the caption.
+    question_photo: bool,
+    /// Forum topics of the source chats the game was proposed in.
+    source_threads: HashMap<i64, i64>,
+    history: GameHistory,
 }
 
 impl Game {
     pub fn new(
+        id: u64,
         chat_id: i64,
         source_chats: Vec<i64>,
+        source_threads: HashMap<i64, i64>,
         set_id: String,
+        set_version: u32,
         topics: Vec<usize>,
         players: HashMap<i64, UserData>,
         spectators: HashSet<i64>,
         invite_link: String,
+        timings: Timings,
+        final_round: bool,
+        teams: HashMap<i64, u8>,
+        blind: bool,
+        topic_picker: TopicPicker,
+        strict: bool,
     ) -> Self {
         Game {
+            id,
             chat_id,
             source_chats,
             game_state: GameState::BeforeGame(false, 5u8),
             set_id,
+            set_version,
             topics,
             current_topic: 0usize,
             current_question: 0usize,
@@ -129,13 +295,212 @@ impl Game {
                 .collect(),
             spectators,
             invite_link,
+            timings,
+            final_round,
+            teams,
+            blind,
+            topic_picker,
+            strict,
+            skip_votes: HashSet::new(),
+            pauses: HashMap::new(),
+            paused_by: None,
+            usernames: HashMap::new(),
+            pending_adjustment: None,
+            departure_vote: None,
+            absent: HashSet::new(),
+            substitutions: Vec::new(),
+            last_wrong: Vec::new(),
+            announced: false,
+            status_message: None,
+            question_photo: false,
+            source_threads,
+            history: GameHistory::default(),
+        }
+    }
+
+    fn remember_username(&mut self, user: &User) {
+        let id = user.id.into();
+        if let Some(username) = &user.username {
+            if self.players.contains_key(&id) {
+                self.usernames.insert(username.to_lowercase(), id);
+            }
+        }
+    }
+
+    fn ranked_players(&self) -> Vec<i64> {
+        let mut ranked = self.player_ids();
+        ranked.sort_by_key(|id| (-self.players[id].1, self.players[id].0.display_name(), *id));
+        ranked
+    }
+
+    fn record(&self, aborted: bool) -> GameRecord {
+        GameRecord {
+            id: self.id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            set_id: self.set_id.clone(),
+            topics: self.topics.clone(),
+            scores: self
+                .players
+                .iter()
+                .map(|(id, (_, score, _))| (*id, *score))
+                .collect(),
+            aborted,
+        }
+    }
+
+    fn places(&self) -> HashMap<i64, usize> {
+        let mut places = HashMap::new();
+        if self.teams.is_empty() {
+            for (id, (_, score, _)) in self.players.iter() {
+                let better = self
+                    .players
+                    .values()
+                    .filter(|(_, other, _)| other > score)
+                    .count();
+                places.insert(*id, better + 1);
+            }
+        } else {
+            let team_scores = self.team_scores();
+            for (id, team) in self.teams.iter() {
+                let score = team_scores.iter().find(|(t, _)| t == team).unwrap().1;
+                let better = team_scores
+                    .iter()
+                    .filter(|(_, other)| *other > score)
+                    .count();
+                places.insert(*id, better + 1);
+            }
+        }
+        places
+    }
+
+    fn find_player(&self, reference: &str) -> Option<i64> {
+        match reference.strip_prefix('@') {
+            Some(username) => self.usernames.get(&username.to_lowercase()).cloned(),
+            None => match reference.parse::<usize>() {
+                Ok(index) if index >= 1 => self.ranked_players().get(index - 1).cloned(),
+                _ => None,
+            },
+        }
+    }
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    fn remaining_pause_time(&self, id: &i64) -> Option<Duration> {
+        let (count, total) = self.pauses.get(id).cloned().unwrap_or((0, 0));
+        if count >= self.timings.pauses || total >= self.timings.max_pause_time() {
+            None
+        } else {
+            Some(Duration::from_secs(self.timings.max_pause_time() - total))
+        }
+    }
+
+    fn start_pause(&mut self, id: i64) {
+        self.pauses.entry(id).or_insert((0, 0)).0 += 1;
+        self.paused_by = Some((id, Self::now().as_secs()));
+    }
+
+    fn end_pause(&mut self) {
+        if let Some((id, since)) = self.paused_by.take() {
+            self.pauses.entry(id).or_insert((0, 0)).1 +=
+                Self::now().as_secs().saturating_sub(since);
+        }
+    }
+
+    fn score_question(&mut self, answered: &[i64], correct: Option<i64>, cost: i32) {
+        for id in answered.iter() {
+            self.add_points(*id, if correct == Some(*id) { cost } else { -cost });
+        }
+    }
+
+    fn add_points(&mut self, id: i64, points: i32) {
+        self.players.get_mut(&id).unwrap().1 += points;
+        let topic_scores = &mut self.history.topic_scores;
+        if topic_scores.len() <= self.current_topic {
+            topic_scores.resize_with(self.current_topic + 1, HashMap::new);
+        }
+        *topic_scores[self.current_topic].entry(id).or_insert(0) += points;
+    }
+
+    pub fn player_ids(&self) -> Vec<i64> {
+        self.players.keys().cloned().collect()
+    }
+
+    fn is_active(&self, id: &i64) -> bool {
+        self.players.contains_key(id) && !self.absent.contains(id)
+    }
+
+    fn active_count(&self) -> usize {
+        self.players.len() - self.absent.len()
+    }
+
+    /// Results of the players still in the game.
+    fn rated_players(&self) -> HashMap<i64, (UserData, i32, bool)> {
+        self.players
+            .iter()
+            .filter(|(id, _)| !self.absent.contains(id))
+            .map(|(id, player)| (*id, player.clone()))
+            .collect()
+    }
+
+    fn can_answer(&self, id: &i64, answers: &[i64]) -> bool {
+        self.is_active(id)
+            && !answers.contains(id)
+            && match self.teams.get(id) {
+                None => true,
+                Some(team) => !answers.iter().any(|other| self.teams[other] == *team),
+            }
+    }
+
+    fn team_scores(&self) -> Vec<(u8, i32)> {
+        let mut scores = HashMap::new();
+        for (id, team) in self.teams.iter() {
+            *scores.entry(*team).or_insert(0i32) += self.players[id].1;
         }
+        let mut scores = scores.into_iter().collect::<Vec<_>>();
+        scores.sort_by_key(|(team, score)| (-*score, *team));
+        scores
     }
 }
 
 pub enum Event {
     Message(Message),
+    CallbackQuery(CallbackQuery),
     Timeout(u64),
+    GracePeriod(i64, u64),
+    Countdown(u64, u64),
+    StatusEdit,
+    /// The play bot couldn't reach Telegram, carries the message to deliver later, if any.
+    Outage(Option<(String, KeyboardOptions)>),
+    Reconnect,
+}
+
+/// The play bot can't reach Telegram, the game is paused at the first state that allows it.
+#[derive(Default)]
+struct Outage {
+    reconnect_scheduled: bool,
+    paused_game: bool,
+}
+
+impl Outage {
+    /// Returns whether a reconnect has to be scheduled and whether the game was paused just now.
+    fn begin(&mut self, game_state: &mut GameState) -> (bool, bool) {
+        let paused = !self.paused_game && game_state.pausable() && !game_state.set_pause(true);
+        self.paused_game |= paused;
+        (
+            !std::mem::replace(&mut self.reconnect_scheduled, true),
+            paused,
+        )
+    }
+
+    /// Returns whether the game was paused because of the outage and is resumed now.
+    fn end(&mut self, game_state: &mut GameState) -> bool {
+        std::mem::take(&mut self.paused_game) && game_state.set_pause(false)
+    }
 }
 
 pub struct GameHandle {
@@ -147,8 +512,16 @@ pub struct GameHandle {
     play_bot: TelegramBot,
     scheduler_bot: TelegramBot,
     data: Data,
+    config: Config,
     topic_set: Arc<TopicSet>,
     state_id: u64,
+    last_score_request: Option<Instant>,
+    grace_periods: HashMap<i64, u64>,
+    countdown_handles: Vec<JoinHandle<()>>,
+    status_edit_scheduled: bool,
+    shown_status: String,
+    undelivered: Vec<(String, KeyboardOptions)>,
+    outage: Outage,
 }
 
 impl GameHandle {
@@ -156,10 +529,17 @@ impl GameHandle {
     const AFTER_GAME: Duration = Duration::from_secs(60);
     const INTERMISSION: Duration = Duration::from_secs(8);
     const PRE_GAME_STEP: Duration = Duration::from_secs(60);
-    const FIRST_THINKING: Duration = Duration::from_secs(15);
-    const SUCCESSIVE_THINKING: Duration = Duration::from_secs(10);
     const PRE_GAME: Duration = Duration::from_secs(15);
-    const ANSWER: Duration = Duration::from_secs(30);
+    const FINAL_BETTING: Duration = Duration::from_secs(60);
+    const FINAL_ANSWER: Duration = Duration::from_secs(60);
+    const APPEAL: Duration = Duration::from_secs(30);
+    const SCORE_COOLDOWN: Duration = Duration::from_secs(15);
+    const BUZZ_LOCKOUT: Duration = Duration::from_secs(2);
+    const COUNTDOWN: [u64; 2] = [10, 5];
+    const TOPIC_SELECTION: Duration = Duration::from_secs(30);
+    /// Scoring changes within this time are shown in the pinned status with a single edit.
+    const STATUS_DEBOUNCE: Duration = Duration::from_secs(3);
+    const RECONNECT: Duration = Duration::from_secs(30);
 
     pub fn create_game(
         play_bot: TelegramBot,
@@ -168,6 +548,7 @@ impl GameHandle {
         game: Game,
         topic_set: Arc<TopicSet>,
         data: Data,
+        config: Config,
     ) -> Self {
         let (timeout_sender, timeout_receiver) = unbounded_channel();
         let timeout_stream = UnboundedReceiverStream::new(timeout_receiver);
@@ -180,8 +561,16 @@ impl GameHandle {
             play_bot,
             scheduler_bot,
             data,
+            config,
             topic_set,
             state_id: 0u64,
+            last_score_request: None,
+            grace_periods: HashMap::new(),
+            countdown_handles: Vec::new(),
+            status_edit_scheduled: false,
+            shown_status: String::new(),
+            undelivered: Vec::new(),
+            outage: Outage::default(),
         }
     }
 
@@ -195,12 +584,89 @@ impl GameHandle {
         text: String,
         keyboard_options: KeyboardOptions,
     ) -> Option<i64> {
+        if self.outage.reconnect_scheduled {
+            self.report_outage(Some((text, keyboard_options)));
+            return None;
+        }
+        match self.deliver(text.clone(), keyboard_options.clone()).await {
+            Ok(id) => Some(id),
+            Err(err) => {
+                log::error!("Error sending game message: {}", err);
+                if err.is_outage() {
+                    self.report_outage(Some((text, keyboard_options)));
+                }
+                None
+            }
+        }
+    }
+
+    async fn deliver(&self, text: String, keyboard_options: KeyboardOptions) -> BotResult<i64> {
         self.play_bot
             .send_message(ChatId::new(self.game.chat_id), text, keyboard_options)
             .await
             .map(|id| id.into())
     }
 
+    fn report_outage(&self, undelivered: Option<(String, KeyboardOptions)>) {
+        match self.timeout_sender.send(Event::Outage(undelivered)) {
+            Ok(_) => {}
+            Err(err) => log::error!("Error with sending update: {}", err),
+        }
+    }
+
+    /// Pauses the game until the play bot can deliver messages again, a state that can't be paused
+    /// goes on until the next one that can.
+    fn process_outage(&mut self, undelivered: Option<(String, KeyboardOptions)>) {
+        self.undelivered.extend(undelivered);
+        let (schedule_reconnect, paused) = self.outage.begin(&mut self.game.game_state);
+        if paused {
+            self.cancel_timer();
+            self.data.save_game_state(&self.game).log_error();
+        }
+        if !schedule_reconnect {
+            return;
+        }
+        let sender = self.timeout_sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Self::RECONNECT).await;
+            match sender.send(Event::Reconnect) {
+                Ok(_) => {}
+                Err(err) => log::error!("Error with sending update: {}", err),
+            }
+        });
+    }
+
+    /// Resends what was lost during the outage and resumes the game paused because of it.
+    async fn reconnect(&mut self) {
+        self.outage.reconnect_scheduled = false;
+        while let Some((text, keyboard_options)) = self.undelivered.first().cloned() {
+            match self.deliver(text, keyboard_options).await {
+                Ok(_) => {}
+                Err(err) if err.is_outage() => {
+                    log::error!("Play bot is still unavailable: {}", err);
+                    self.process_outage(None);
+                    return;
+                }
+                Err(err) => log::error!("Error resending game message: {}", err),
+            }
+            self.undelivered.remove(0);
+        }
+        if self.outage.end(&mut self.game.game_state) {
+            self.send_message("Связь восстановлена, игра возобновлена".to_string())
+                .await;
+            self.schedule_timeout(Self::INTERMISSION);
+            self.data.save_game_state(&self.game).log_error();
+        } else if !self.game.game_state.paused()
+            && self
+                .timeout_handle
+                .as_ref()
+                .map_or(true, |handle| handle.is_finished())
+        {
+            // A step that failed during the outage left nothing to move the game on
+            self.schedule_timeout(Self::INTERMISSION);
+        }
+    }
+
     fn schedule_timeout(&mut self, duration: Duration) {
         self.cancel_timer();
         self.state_id += 1;
@@ -215,46 +681,115 @@ impl GameHandle {
         }))
     }
 
-    async fn end_question(&mut self, pause_game: bool) {
-        if let GameState::Question(_, answers) = &self.game.game_state {
-            self.game.game_state = GameState::AfterQuestion(pause_game, answers.clone(), None);
-            self.send_message(self.current_question().display_answers(false))
-                .await;
+    fn schedule_countdown(&mut self, duration: Duration) {
+        for remaining in Self::COUNTDOWN {
+            let remaining = Duration::from_secs(remaining);
+            if remaining >= duration {
+                continue;
+            }
+            let sender = self.timeout_sender.clone();
+            let id = self.state_id;
+            self.countdown_handles.push(tokio::spawn(async move {
+                tokio::time::sleep(duration - remaining).await;
+                match sender.send(Event::Countdown(id, remaining.as_secs())) {
+                    Ok(_) => {}
+                    Err(err) => log::error!("Error with sending update: {}", err),
+                }
+            }));
+        }
+    }
+
+    async fn show_countdown(&mut self, remaining: u64) {
+        if let GameState::Question(message_id, ..) = self.game.game_state {
+            self.edit_question(
+                message_id,
+                self.countdown_text(remaining),
+                KeyboardOptions::InlinePlus,
+            )
+            .await;
+        }
+    }
+
+    fn countdown_text(&self, remaining: u64) -> String {
+        format!("{}\n⏳ осталось {} секунд", self.question_text(), remaining)
+    }
+
+    fn schedule_grace_period(&mut self, user: i64) {
+        let id = self.grace_periods.get(&user).cloned().unwrap_or(0) + 1;
+        self.grace_periods.insert(user, id);
+        let sender = self.timeout_sender.clone();
+        let grace = self.config.leave_grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            match sender.send(Event::GracePeriod(user, id)) {
+                Ok(_) => {}
+                Err(err) => log::error!("Error with sending update: {}", err),
+            }
+        });
+    }
+
+    async fn end_question(&mut self, pause_game: bool, skipped: bool) {
+        if let GameState::Question(message_id, answers, ..) = self.game.game_state.clone() {
+            let answers = if skipped { Vec::new() } else { answers };
+            self.game.game_state = GameState::AfterQuestion(pause_game, answers, None);
+            self.edit_message(&message_id).await;
+            let answer = self.current_question().display_answers(false);
+            self.send_message(if skipped {
+                format!("Вопрос пропущен\n{}", answer)
+            } else {
+                answer
+            })
+            .await;
             self.schedule_timeout(Self::INTERMISSION);
         } else {
             unreachable!()
         }
     }
 
-    async fn incorrect_answer(&mut self, timeout: bool, force_stop_timer: bool) {
+    async fn incorrect_answer(&mut self, timeout: bool, pause_game: bool) {
         if let GameState::Answer(message_id, answers, current) = self.game.game_state.clone() {
-            let restart_timer = !force_stop_timer && answers.len() + 1 != self.game.players.len();
             let mut answers = answers.clone();
             answers.push(current);
-            self.game.game_state = GameState::Question(message_id, answers);
-            self.edit_message(&message_id).await;
-            self.play_bot
-                .send_message(
-                    ChatId::new(self.game.chat_id),
-                    format!(
-                        "{}, {}",
-                        if timeout {
-                            "Время вышло"
-                        } else {
-                            "Это неправильный ответ"
-                        },
-                        self.user_name(&current),
-                    ),
-                    KeyboardOptions::Plus,
-                )
+            let verdict = format!(
+                "{}, {}",
+                if timeout {
+                    "Время вышло"
+                } else {
+                    "Это неправильный ответ"
+                },
+                self.user_name(&current),
+            );
+            if pause_game
+                || !self
+                    .game
+                    .players
+                    .keys()
+                    .any(|id| self.game.can_answer(id, &answers))
+            {
+                self.game.game_state = GameState::AfterQuestion(pause_game, answers, None);
+                self.send_message(format!(
+                    "{}\n{}",
+                    verdict,
+                    self.current_question().display_answers(false)
+                ))
                 .await;
-            if restart_timer {
-                self.schedule_timeout(Self::SUCCESSIVE_THINKING);
+                self.schedule_timeout(if pause_game {
+                    Self::PAUSE
+                } else {
+                    Self::INTERMISSION
+                });
             } else {
-                match self.timeout_sender.send(Event::Timeout(self.state_id)) {
-                    Ok(_) => {}
-                    Err(err) => panic!("Error with sending update: {}", err),
-                }
+                // The lockout only matters right after the question is shown
+                self.game.game_state = GameState::Question(message_id, answers, 0, Vec::new());
+                self.edit_question(
+                    message_id,
+                    self.question_text(),
+                    KeyboardOptions::InlinePlus,
+                )
+                .await;
+                self.send_message(verdict).await;
+                self.schedule_timeout(self.game.timings.successive_thinking());
+                self.schedule_countdown(self.game.timings.successive_thinking());
             }
         } else {
             unreachable!()
@@ -262,41 +797,75 @@ impl GameHandle {
     }
 
     async fn edit_message(&mut self, message_id: &i64) {
-        self.play_bot
-            .edit_message(
-                ChatId::new(self.game.chat_id),
-                MessageId::new(*message_id),
-                self.question_text(),
-            )
+        self.edit_question(*message_id, self.question_text(), KeyboardOptions::None)
             .await;
     }
 
+    async fn edit_question(
+        &self,
+        message_id: i64,
+        text: String,
+        keyboard_options: KeyboardOptions,
+    ) {
+        let chat_id = ChatId::new(self.game.chat_id);
+        let message_id = MessageId::new(message_id);
+        if self.game.question_photo {
+            self.play_bot
+                .edit_caption_with_markup(chat_id, message_id, text, keyboard_options)
+                .await
+                .log_error();
+        } else {
+            self.play_bot
+                .edit_message_with_markup(chat_id, message_id, text, keyboard_options)
+                .await
+                .log_error();
+        }
+    }
+
     async fn end_game(&mut self, aborted: bool) {
         self.game.game_state = GameState::AfterGame;
         self.send_message("Игра окончена!".to_string()).await;
-        let outcome = if !aborted {
+        self.data
+            .archive_game(&self.game.record(aborted))
+            .log_error();
+        let (outcome, announcement) = if !aborted {
             let current_ratings = self
                 .game
                 .players
                 .iter()
-                .map(|(id, _)| {
+                .map(|(id, (user, ..))| {
                     (
                         *id,
-                        self.data.get_user_data(&UserId::new(*id)).unwrap().rating,
+                        self.data
+                            .get_user_data(&UserId::new(*id))
+                            .log_error()
+                            .flatten()
+                            .map_or(user.rating, |user_data| user_data.rating),
                     )
                 })
                 .collect::<HashMap<_, _>>();
             if !aborted {
-                self.data.save_game_results(&self.game.players);
+                self.data
+                    .save_game_results(
+                        &self.game.rated_players(),
+                        &self.game.teams,
+                        &self.game.places(),
+                        &self.game.set_id,
+                    )
+                    .log_error();
             }
             let updated_ratings = self
                 .game
                 .players
                 .iter()
-                .map(|(id, _)| {
+                .map(|(id, (user, ..))| {
                     (
                         *id,
-                        self.data.get_user_data(&UserId::new(*id)).unwrap().rating,
+                        self.data
+                            .get_user_data(&UserId::new(*id))
+                            .log_error()
+                            .flatten()
+                            .map_or(user.rating, |user_data| user_data.rating),
                     )
                 })
                 .collect::<HashMap<_, _>>();
@@ -307,6 +876,7 @@ impl GameHandle {
                 .map(|(id, (user, score, _))| {
                     (
                         *score,
+                        *id,
                         user.display_name(),
                         current_ratings[id],
                         updated_ratings[id],
@@ -314,40 +884,203 @@ impl GameHandle {
                 })
                 .collect::<Vec<_>>();
             entries.sort_by(|(sc1, ..), (sc2, ..)| sc2.cmp(sc1));
-            let mut result = String::new();
-            for (score, name, old_rating, new_rating) in entries {
-                result += format!(
-                    "{} {} {} ({})\n",
-                    name,
+            let mut result = self.team_list();
+            if !result.is_empty() {
+                result += "\n";
+            }
+            let mut announcement = result.clone();
+            for (score, id, name, old_rating, new_rating) in entries {
+                let line = format!(
+                    "{} {} ({})\n",
                     score,
                     display_rating(new_rating),
                     (display_rating(new_rating) as i64) - (display_rating(old_rating) as i64)
-                )
-                .as_str();
+                );
+                result += format!("{} {}", name, line).as_str();
+                announcement +=
+                    format!("<a href=\"tg://user?id={}\">{}</a> {}", id, name, line).as_str();
             }
-            result
+            result += self.adjustment_summary().as_str();
+            (result, Some(announcement))
         } else {
-            "Игра отменена".to_string()
+            ("Игра отменена".to_string(), None)
         };
         self.schedule_timeout(Self::AFTER_GAME);
         for source_id in self.game.source_chats.iter() {
-            self.scheduler_bot.try_send_message(
+            let source_bot = self.source_bot(*source_id);
+            source_bot.try_send_message(
                 ChatId::new(*source_id),
                 format!(
                     "<b>Игра завершена.</b>\nПакет: {}\n{}",
                     self.topic_set.title, outcome
                 ),
             );
+            if !aborted {
+                source_bot.try_send_message(ChatId::new(*source_id), self.topic_breakdown());
+            }
+        }
+        if let Some(announcement) = announcement {
+            if self.data.announce_results().log_error().unwrap_or(false) && !self.game.announced {
+                self.game.announced = true;
+                self.scheduler_bot.try_send_message(
+                    self.config.main_chat,
+                    format!(
+                        "<b>Игра завершена.</b>\nПакет: {}\n{}",
+                        self.topic_set.title, announcement
+                    ),
+                );
+            }
+        }
+        if !aborted {
+            self.send_transcript();
         }
     }
 
-    fn update_status(&self) {
+    fn send_transcript(&self) {
+        let transcript = self.transcript();
+        let mut fallback = false;
+        for id in self.game.players.keys() {
+            if self
+                .data
+                .has_private_chat(UserId::new(*id))
+                .log_error()
+                .unwrap_or(false)
+            {
+                self.scheduler_bot
+                    .try_send_message(ChatId::new(*id), transcript.clone());
+            } else {
+                fallback = true;
+            }
+        }
+        if fallback {
+            for source_id in self.game.source_chats.iter() {
+                if !self.game.players.contains_key(source_id) {
+                    self.source_bot(*source_id)
+                        .try_send_message(ChatId::new(*source_id), transcript.clone());
+                }
+            }
+        }
+    }
+
+    fn transcript(&self) -> String {
+        let mut result = format!("<b>Вопросы игры по пакету {}</b>\n", self.topic_set.title);
+        let mut last_topic = None;
+        for (topic, question, correct) in self.game.history.transcript.iter() {
+            let topic_data = &self.topic_set.topics[self.game.topics[*topic]];
+            if last_topic != Some(*topic) {
+                last_topic = Some(*topic);
+                result += format!("\n<b>Тема:</b> {}\n", topic_data.name).as_str();
+            }
+            let question = &topic_data.questions[*question];
+            result += format!(
+                "<b>{}.</b> {}\n<b>Ответ:</b> {}{}\n",
+                question.cost,
+                question.question,
+                question
+                    .answers
+                    .join(if question.all_required { " + " } else { " / " }),
+                match correct {
+                    Some(id) if self.game.players.contains_key(id) => {
+                        format!(" ({})", self.user_name(id))
+                    }
+                    _ => String::new(),
+                }
+            )
+            .as_str();
+        }
+        result
+    }
+
+    fn topic_breakdown(&self) -> String {
+        let mut result = "<b>Результаты по темам:</b>\n".to_string();
+        for (i, topic_scores) in self.game.history.topic_scores.iter().enumerate() {
+            let mut entries = topic_scores.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|(id, points)| (-**points, **id));
+            result += format!(
+                "<b>{}</b>: {}\n",
+                self.topic_set.topics[self.game.topics[i]].name,
+                if entries.is_empty() {
+                    "-".to_string()
+                } else {
+                    entries
+                        .iter()
+                        .map(|(id, points)| format!("{} {:+}", self.user_name(id), points))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            )
+            .as_str();
+        }
+        result
+    }
+
+    fn update_status(&mut self) {
         self.status_sender
             .send(StatusUpdate {
                 chat_id: self.game.chat_id,
                 update_type: UpdateType::StatusUpdate(self.status()),
             })
             .unwrap();
+        self.schedule_status_edit();
+    }
+
+    fn status_message_text(&self) -> String {
+        format!(
+            "{}\n\n<b>Счёт:</b>\n{}",
+            self.status().trim(),
+            self.score_list()
+        )
+    }
+
+    async fn create_status_message(&mut self) {
+        let chat_id = ChatId::new(self.game.chat_id);
+        let text = self.status_message_text();
+        if let Some(message_id) = self
+            .play_bot
+            .send_message(chat_id, text.clone(), KeyboardOptions::None)
+            .await
+            .log_error()
+        {
+            self.play_bot
+                .pin_message(chat_id, message_id)
+                .await
+                .log_error();
+            self.game.status_message = Some(message_id.into());
+            self.shown_status = text;
+        }
+    }
+
+    fn schedule_status_edit(&mut self) {
+        if self.game.status_message.is_none() || self.status_edit_scheduled {
+            return;
+        }
+        self.status_edit_scheduled = true;
+        let sender = self.timeout_sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Self::STATUS_DEBOUNCE).await;
+            match sender.send(Event::StatusEdit) {
+                Ok(_) => {}
+                Err(err) => log::error!("Error with sending update: {}", err),
+            }
+        });
+    }
+
+    async fn edit_status_message(&mut self) {
+        self.status_edit_scheduled = false;
+        let text = self.status_message_text();
+        if let Some(message_id) = self.game.status_message {
+            if text != self.shown_status {
+                self.play_bot
+                    .edit_message(
+                        ChatId::new(self.game.chat_id),
+                        MessageId::new(message_id),
+                        text.clone(),
+                    )
+                    .await
+                    .log_error();
+                self.shown_status = text;
+            }
+        }
     }
 
     pub fn status(&self) -> String {
@@ -378,13 +1111,80 @@ impl GameHandle {
         self.game.players[id].0.display_name()
     }
 
+    async fn buzz(
+        &mut self,
+        from: i64,
+        message_id: i64,
+        answers: Vec<i64>,
+        shown_at: u64,
+        locked: Vec<i64>,
+    ) {
+        if locked.contains(&from)
+            && Game::now() < Duration::from_millis(shown_at) + Self::BUZZ_LOCKOUT
+        {
+            self.send_message(format!(
+                "{}, после неправильного ответа на прошлый вопрос можно нажимать \"+\" только через {} секунды",
+                self.user_name(&from),
+                Self::BUZZ_LOCKOUT.as_secs()
+            ))
+            .await;
+        } else if self.game.can_answer(&from, &answers) {
+            self.game.game_state = GameState::Answer(message_id, answers, from);
+            self.edit_question(
+                message_id,
+                format!(
+                    "<b>Тема</b> {}\n<b>{}.</b> Вопрос скрыт",
+                    self.topic_title(),
+                    self.current_question().cost
+                ),
+                KeyboardOptions::None,
+            )
+            .await;
+            self.send_message(format!("Ваш ответ, {}?", self.user_name(&from)))
+                .await;
+            self.schedule_timeout(self.game.timings.answer());
+        }
+    }
+
+    /// Presses of the inline "+" button under the question; typed "+" goes through `process_message`.
+    async fn process_callback_query(&mut self, query: CallbackQuery) {
+        let from = query.from.id.into();
+        self.game.remember_username(&query.from);
+        let pressed_plus = query.data.as_deref() == Some("+");
+        let pressed_on = match &query.message {
+            Some(MessageOrChannelPost::Message(message)) => Some(message.id),
+            _ => None,
+        };
+        self.play_bot.try_answer_callback_query(query);
+        if !pressed_plus {
+            return;
+        }
+        if let GameState::Question(message_id, answers, shown_at, locked) =
+            self.game.game_state.clone()
+        {
+            // A button left under an earlier question doesn't buzz in on this one
+            if pressed_on != Some(MessageId::new(message_id)) {
+                return;
+            }
+            self.buzz(from, message_id, answers, shown_at, locked).await;
+            self.data.save_game_state(&self.game).log_error();
+        }
+    }
+
     async fn process_message(&mut self, message: Message) {
+        if let MessageKind::MigrateToChatId { data } = message.kind {
+            self.migrate_chat(message.chat.id().into(), data);
+            return;
+        }
         if message.from.is_none() {
             return;
         }
         match message.kind {
             MessageKind::Text { data, .. } => {
                 let from = &message.from.as_ref().unwrap().id.into();
+                if message.chat.id() == ChatId::new(self.game.chat_id) {
+                    self.game.remember_username(message.from.as_ref().unwrap());
+                }
                 let data = data.trim().to_string();
                 let tokens = data.split(" ").collect::<Vec<_>>();
                 if tokens.is_empty() {
@@ -392,65 +1192,136 @@ impl GameHandle {
                 }
                 let command_str = tokens[0].to_lowercase();
                 let mut command = command_str.as_str();
+                // Commands picked from the menu in a group are addressed to the bot
+                if let Some(pos) = command.find("@") {
+                    command = &command[0..pos];
+                }
                 if command.starts_with("/") {
                     command = &command[1..];
                 }
                 let tokens = &tokens[1..];
+                if message.chat.id() != ChatId::new(self.game.chat_id) {
+                    match self.game.game_state.clone() {
+                        GameState::FinalBetting(bets) => {
+                            self.accept_bet(message.chat.id(), *from, bets, &data).await;
+                        }
+                        GameState::FinalAnswer(message_id, bets, answers) => {
+                            self.accept_final_answer(
+                                message.chat.id(),
+                                *from,
+                                message_id,
+                                bets,
+                                answers,
+                                &data,
+                            )
+                            .await;
+                        }
+                        _ => {}
+                    }
+                    self.data.save_game_state(&self.game).log_error();
+                    return;
+                }
                 if command == "abort" {
                     self.end_game(true).await;
-                    self.data.save_game_state(&self.game);
+                    self.data.save_game_state(&self.game).log_error();
+                    return;
+                } else if (command == "да" || command == "нет")
+                    && self.game.departure_vote.is_some()
+                {
+                    self.vote_on_departure(*from, command == "да").await;
+                    self.data.save_game_state(&self.game).log_error();
+                    return;
+                } else if (command == "substitute" || command == "замена")
+                    && self.game.game_state.pausable()
+                {
+                    let substitute = match message.reply_to_message.as_deref() {
+                        Some(MessageOrChannelPost::Message(reply)) => reply.from.clone(),
+                        _ => None,
+                    };
+                    self.substitute_player(*from, tokens, substitute).await;
+                    self.data.save_game_state(&self.game).log_error();
                     return;
                 } else if self.game.game_state.pausable() {
                     if (command == "pause" || command == "пауза")
-                        && !self.game.game_state.set_pause(true)
+                        && self.game.players.contains_key(from)
+                        && !self.game.game_state.paused()
                     {
-                        self.send_message("Игра приостановлена".to_string()).await;
-                        self.schedule_timeout(Self::PAUSE);
-                        self.data.save_game_state(&self.game);
+                        match self.game.remaining_pause_time(from) {
+                            Some(remaining) => {
+                                self.game.game_state.set_pause(true);
+                                self.game.start_pause(*from);
+                                self.send_message("Игра приостановлена".to_string()).await;
+                                self.schedule_timeout(Self::PAUSE.min(remaining));
+                                self.data.save_game_state(&self.game).log_error();
+                            }
+                            None => {
+                                self.send_message(format!(
+                                    "{}, вы исчерпали лимит пауз ({} пауз или {} минут за игру)",
+                                    self.user_name(from),
+                                    self.game.timings.pauses,
+                                    self.game.timings.pause_minutes
+                                ))
+                                .await;
+                            }
+                        }
                         return;
                     } else if (command == "continue" || command == "продолжить")
                         && self.game.game_state.set_pause(false)
                     {
+                        self.game.end_pause();
+                        self.game.pending_adjustment = None;
                         self.send_message("Игра возобновлена".to_string()).await;
                         self.schedule_timeout(Self::INTERMISSION);
-                        self.data.save_game_state(&self.game);
+                        self.data.save_game_state(&self.game).log_error();
+                        return;
+                    } else if command == "score" || command == "счёт" || command == "счет" {
+                        let allowed = match self.last_score_request {
+                            None => true,
+                            Some(last) => last.elapsed() >= Self::SCORE_COOLDOWN,
+                        };
+                        if allowed {
+                            self.last_score_request = Some(Instant::now());
+                            let position = if self.game.current_topic < self.game.topics.len() {
+                                format!(
+                                    " (тема {}/{}, вопрос {}/{})",
+                                    self.game.current_topic + 1,
+                                    self.game.topics.len(),
+                                    self.game.current_question + 1,
+                                    self.current_topic().questions.len()
+                                )
+                            } else {
+                                String::new()
+                            };
+                            self.send_message(format!(
+                                "<b>Текущий счёт</b>{}:\n{}",
+                                position,
+                                self.score_list()
+                            ))
+                            .await;
+                        }
                         return;
                     } else if self.game.game_state.paused()
                         && (command == "adjust" || command == "исправить")
                     {
-                        if tokens.len() != 1 {
-                            self.send_message("Неверное число аргументов".to_string())
-                                .await;
-                        } else {
-                            match tokens[0].parse::<i32>() {
-                                Ok(by) => {
-                                    if by.abs() > 10000 || by % 10 != 0 {
-                                        self.send_message("Некорректное число очков".to_string())
-                                            .await;
-                                    } else {
-                                        match self.game.players.get_mut(from) {
-                                            None => {}
-                                            Some(player_data) => {
-                                                player_data.1 += by;
-                                                self.send_message(format!(
-                                                    "Новое количество очков у {} - {}",
-                                                    self.user_name(from),
-                                                    self.game.players[from].1,
-                                                ))
-                                                .await;
-                                            }
-                                        }
-                                        self.update_status();
-                                    }
-                                }
-                                Err(_) => {
-                                    self.send_message("Некорректное число очков".to_string())
-                                        .await;
-                                }
+                        self.request_adjustment(*from, tokens).await;
+                        self.data.save_game_state(&self.game).log_error();
+                        return;
+                    } else if self.game.game_state.paused()
+                        && (command == "да" || command == "нет")
+                        && self.game.players.contains_key(from)
+                    {
+                        if let Some((requester, target, by)) = self.game.pending_adjustment {
+                            if command == "нет" {
+                                self.game.pending_adjustment = None;
+                                self.send_message("Исправление счёта отменено".to_string())
+                                    .await;
+                            } else if requester != *from {
+                                self.game.pending_adjustment = None;
+                                self.apply_adjustment(requester, target, by).await;
                             }
+                            self.data.save_game_state(&self.game).log_error();
+                            return;
                         }
-                        self.data.save_game_state(&self.game);
-                        return;
                     }
                 }
                 match self.game.game_state.clone() {
@@ -458,26 +1329,25 @@ impl GameHandle {
                     GameState::BeforeTopic(_) => {}
                     GameState::BeforeFirstQuestion(_) => {}
                     GameState::BeforeQuestion(_) => {}
-                    GameState::Question(message_id, answers) => {
-                        if command == "+"
-                            && !answers.contains(from)
-                            && self.game.players.contains_key(from)
+                    GameState::Question(message_id, answers, shown_at, locked) => {
+                        if (command == "skip" || command == "пропустить")
+                            && self.game.is_active(from)
                         {
-                            self.game.game_state = GameState::Answer(message_id, answers, *from);
-                            self.play_bot
-                                .edit_message(
-                                    message.chat.id(),
-                                    MessageId::new(message_id),
-                                    format!(
-                                        "<b>Тема</b> {}\n<b>{}.</b> Вопрос скрыт",
-                                        self.topic_title(),
-                                        self.current_question().cost
-                                    ),
-                                )
-                                .await;
-                            self.send_message(format!("Ваш ответ, {}?", self.user_name(from)))
+                            if self.game.skip_votes.insert(*from) {
+                                if self.game.skip_votes.len() * 2 > self.game.active_count() {
+                                    self.end_question(false, true).await;
+                                } else {
+                                    self.send_message(format!(
+                                        "Голосов за пропуск вопроса: {}/{}",
+                                        self.game.skip_votes.len(),
+                                        self.game.active_count() / 2 + 1
+                                    ))
+                                    .await;
+                                }
+                            }
+                        } else if command == "+" {
+                            self.buzz(*from, message_id, answers, shown_at, locked)
                                 .await;
-                            self.schedule_timeout(Self::ANSWER);
                         }
                     }
                     GameState::Answer(message_id, mut answers, current) => {
@@ -485,7 +1355,10 @@ impl GameHandle {
                             if data == "+" {
                                 return;
                             }
-                            if self.current_question().check_answer(data.as_str()) {
+                            if self
+                                .current_question()
+                                .check_answer(data.as_str(), self.game.strict)
+                            {
                                 answers.push(current);
                                 self.game.game_state =
                                     GameState::AfterQuestion(false, answers, Some(current));
@@ -504,7 +1377,28 @@ impl GameHandle {
                     }
                     GameState::AfterQuestion(paused, answers, correct) => {
                         if tokens.is_empty() {
-                            if (command == "yes" || command == "да")
+                            if (command == "yes"
+                                || command == "да"
+                                || command == "appeal"
+                                || command == "апелляция")
+                                && answers.contains(from)
+                                && correct != Some(*from)
+                                && self.game.active_count() > 1
+                            {
+                                self.game.game_state = GameState::AppealVote(
+                                    paused,
+                                    answers,
+                                    correct,
+                                    *from,
+                                    Vec::new(),
+                                );
+                                self.send_message(format!(
+                                    "{} просит засчитать свой ответ. Остальные игроки, засчитать?",
+                                    self.user_name(from)
+                                ))
+                                .await;
+                                self.schedule_timeout(Self::APPEAL);
+                            } else if (command == "yes" || command == "да")
                                 && answers.contains(from)
                                 && correct != Some(*from)
                             {
@@ -532,13 +1426,75 @@ impl GameHandle {
                             }
                         }
                     }
+                    GameState::AppealVote(paused, answers, correct, appellant, mut votes) => {
+                        let vote = if command == "yes" || command == "да" {
+                            Some(true)
+                        } else if command == "no" || command == "нет" {
+                            Some(false)
+                        } else {
+                            None
+                        };
+                        if let Some(vote) = vote {
+                            if *from != appellant
+                                && self.game.is_active(from)
+                                && !votes.iter().any(|(id, _)| id == from)
+                            {
+                                votes.push((*from, vote));
+                                let all_voted = votes.len() + 1 == self.game.active_count();
+                                self.game.game_state = GameState::AppealVote(
+                                    paused, answers, correct, appellant, votes,
+                                );
+                                self.send_message(format!(
+                                    "Голос принят, {}",
+                                    self.user_name(from)
+                                ))
+                                .await;
+                                if all_voted {
+                                    self.schedule_timeout(Duration::from_secs(0));
+                                }
+                            }
+                        }
+                    }
                     GameState::SpecialScore(_) => {}
+                    GameState::FinalBetting(_) => {
+                        // Bets are secret, other players would see them here
+                        if self.game.players.contains_key(from) && data.parse::<i32>().is_ok() {
+                            self.send_message(format!(
+                                "{}, ставку нужно отправить боту в личные сообщения",
+                                self.user_name(from)
+                            ))
+                            .await;
+                        }
+                    }
+                    GameState::TopicSelection(picker, choices) => {
+                        if *from == picker {
+                            if let Ok(label) = command.parse::<usize>() {
+                                if choices.contains(&label) {
+                                    self.select_topic(label);
+                                    self.start_topic().await;
+                                }
+                            }
+                        }
+                    }
+                    GameState::FinalAnswer(_, _, answers) => {
+                        // Other players would see the answer here
+                        if self.game.is_active(from) && !answers.iter().any(|(id, _)| id == from) {
+                            self.send_message(format!(
+                                "{}, ответ нужно отправить боту в личные сообщения",
+                                self.user_name(from)
+                            ))
+                            .await;
+                        }
+                    }
                     GameState::AfterGame => {
                         self.schedule_timeout(Self::AFTER_GAME);
                     }
                 }
             }
             MessageKind::NewChatMembers { data } => {
+                for user in data.iter() {
+                    self.game.remember_username(user);
+                }
                 if let GameState::BeforeGame(paused, _) = self.game.game_state {
                     for user in data {
                         let id = user.id.into();
@@ -546,8 +1502,9 @@ impl GameHandle {
                             None => {
                                 if !self.game.spectators.contains(&id) {
                                     self.play_bot
-                                        .kick(ChatId::from(self.game.chat_id), user.id)
-                                        .await;
+                                        .kick(ChatId::from(self.game.chat_id), user.id, false)
+                                        .await
+                                        .log_error();
                                 }
                             }
                             Some(player_data) => player_data.2 = true,
@@ -570,33 +1527,658 @@ impl GameHandle {
                 } else {
                     for user in data {
                         let id = user.id.into();
-                        if !self.game.players.contains_key(&id)
-                            && !self.game.spectators.contains(&id)
-                        {
-                            self.play_bot
-                                .kick(ChatId::from(self.game.chat_id), user.id)
+                        match self.game.players.get_mut(&id) {
+                            None => {
+                                if !self.game.spectators.contains(&id) {
+                                    self.play_bot
+                                        .kick(ChatId::from(self.game.chat_id), user.id, false)
+                                        .await
+                                        .log_error();
+                                }
+                            }
+                            Some(player_data) => {
+                                if !player_data.2 {
+                                    player_data.2 = true;
+                                    self.game.absent.remove(&id);
+                                    self.grace_periods.remove(&id);
+                                    if matches!(self.game.departure_vote, Some((departed, _)) if departed == id)
+                                    {
+                                        self.game.departure_vote = None;
+                                    }
+                                    self.send_message(format!(
+                                        "{} вернулся в игру",
+                                        self.user_name(&id)
+                                    ))
+                                    .await;
+                                }
+                            }
+                        }
+                    }
+                }
+                self.play_bot
+                    .try_delete_message(message.chat.id(), message.id);
+            }
+            MessageKind::LeftChatMember { data } => {
+                let id = data.id.into();
+                if self.game.game_state != GameState::AfterGame {
+                    if let Some(player_data) = self.game.players.get_mut(&id) {
+                        player_data.2 = false;
+                        if let GameState::BeforeGame(..) = self.game.game_state {
+                            self.send_message(format!("{} покинул чат", self.user_name(&id)))
                                 .await;
+                        } else {
+                            let pause = self.game.game_state.pausable()
+                                && !self.game.game_state.set_pause(true);
+                            self.send_message(format!(
+                                "{} покинул чат{}. Если игрок не вернется в течение {} минут, будет проведено голосование о продолжении игры без него",
+                                self.user_name(&id),
+                                if pause { ", игра приостановлена" } else { "" },
+                                self.config.leave_grace.as_secs() / 60
+                            ))
+                            .await;
+                            if pause {
+                                self.schedule_timeout(Self::PAUSE);
+                            }
+                            self.schedule_grace_period(id);
                         }
+                        // The link the player came with may have expired or run out of uses
+                        self.reissue_invite_link().await;
+                        self.send_return_link(id);
                     }
                 }
+                self.play_bot
+                    .try_delete_message(message.chat.id(), message.id);
             }
             _ => {}
         };
-        self.data.save_game_state(&self.game);
+        self.data.save_game_state(&self.game).log_error();
     }
 
-    async fn show_score(&mut self) {
-        let mut score_list = self
+    async fn resolve_appeal(
+        &mut self,
+        paused: bool,
+        answers: Vec<i64>,
+        correct: Option<i64>,
+        appellant: i64,
+        votes: Vec<(i64, bool)>,
+    ) {
+        let yes = votes.iter().filter(|(_, vote)| *vote).count();
+        let no = votes.len() - yes;
+        let (correct, verdict) = if yes > no {
+            (Some(appellant), "Апелляция принята")
+        } else if no > yes {
+            (
+                if correct == Some(appellant) {
+                    None
+                } else {
+                    correct
+                },
+                "Апелляция отклонена",
+            )
+        } else {
+            (correct, "Голоса разделились, решение не изменено")
+        };
+        self.game.game_state = GameState::AfterQuestion(paused, answers, correct);
+        self.send_message(format!(
+            "{} ({}: за - {}, против - {})",
+            verdict,
+            self.user_name(&appellant),
+            yes,
+            no
+        ))
+        .await;
+        self.schedule_timeout(if paused {
+            Self::PAUSE
+        } else {
+            Self::INTERMISSION
+        });
+    }
+
+    async fn accept_bet(
+        &mut self,
+        chat_id: ChatId,
+        from: i64,
+        mut bets: Vec<(i64, i32)>,
+        text: &str,
+    ) {
+        if !self.game.is_active(&from) || bets.iter().any(|(id, _)| *id == from) {
+            return;
+        }
+        let bet = match text.parse::<i32>() {
+            Ok(bet) => bet,
+            Err(_) => {
+                return;
+            }
+        };
+        let max_bet = self.game.players[&from].1.max(0);
+        if bet < 0 || bet > max_bet {
+            self.play_bot.try_send_message(
+                chat_id,
+                format!(
+                    "{}, ставка должна быть от 0 до {}",
+                    self.user_name(&from),
+                    max_bet
+                ),
+            );
+            return;
+        }
+        bets.push((from, bet));
+        let all_bets = bets.len() == self.game.players.len();
+        self.game.game_state = GameState::FinalBetting(bets);
+        self.play_bot
+            .try_send_message(chat_id, format!("Ставка {} принята", bet));
+        self.send_message(format!("Ставка принята, {}", self.user_name(&from)))
+            .await;
+        if all_bets {
+            self.schedule_timeout(Duration::from_secs(0));
+        }
+    }
+
+    async fn accept_final_answer(
+        &mut self,
+        chat_id: ChatId,
+        from: i64,
+        message_id: i64,
+        bets: Vec<(i64, i32)>,
+        mut answers: Vec<(i64, String)>,
+        text: &str,
+    ) {
+        if !self.game.is_active(&from) || answers.iter().any(|(id, _)| *id == from) {
+            return;
+        }
+        answers.push((from, text.to_string()));
+        let all_answers = answers.len() == self.game.active_count();
+        self.game.game_state = GameState::FinalAnswer(message_id, bets, answers);
+        self.play_bot
+            .try_send_message(chat_id, "Ответ принят".to_string());
+        self.send_message(format!("Ответ принят, {}", self.user_name(&from)))
+            .await;
+        if all_answers {
+            self.schedule_timeout(Duration::from_secs(0));
+        }
+    }
+
+    async fn start_final_betting(&mut self) {
+        let bets = self
             .game
             .players
             .iter()
-            .map(|(_, (data, score, _))| (data.display_name(), *score))
+            .filter(|(id, (_, score, _))| *score <= 0 || self.game.absent.contains(id))
+            .map(|(id, _)| (*id, 0i32))
             .collect::<Vec<_>>();
-        score_list.sort_by(|(_, s1), (_, s2)| s2.cmp(s1));
+        let all_bets = bets.len() == self.game.players.len();
+        self.game.game_state = GameState::FinalBetting(bets);
+        self.send_message(format!(
+            "<b>Финал</b>\n<b>Тема:</b> {}\nСделайте ставку от 0 до своего счёта, отправив число боту в личные сообщения. Игроки с неположительным счётом ставят 0",
+            self.topic_title()
+        ))
+        .await;
+        if all_bets {
+            self.schedule_timeout(Duration::from_secs(0));
+        } else {
+            self.schedule_timeout(Self::FINAL_BETTING);
+        }
+    }
+
+    async fn show_final_question(&mut self, mut bets: Vec<(i64, i32)>) {
+        for id in self.game.players.keys() {
+            if !bets.iter().any(|(bet_id, _)| bet_id == id) {
+                bets.push((*id, 0i32));
+            }
+        }
+        self.game.current_question = self.current_topic().questions.len() - 1;
+        let id = match self
+            .deliver(self.question_text(), KeyboardOptions::Remove)
+            .await
+        {
+            Ok(id) => id,
+            Err(err) if err.is_outage() => {
+                // Betting goes on, the question is shown again once the bot is back
+                log::error!("Error sending final question: {}", err);
+                self.process_outage(None);
+                return;
+            }
+            Err(err) => {
+                log::error!("Error sending final question: {}", err);
+                self.send_message("Не удалось показать вопрос финала, он пропущен".to_string())
+                    .await;
+                self.game.current_question = 0usize;
+                self.game.current_topic += 1;
+                self.update_status();
+                self.game.game_state = GameState::BeforeTopic(false);
+                self.show_score().await;
+                return;
+            }
+        };
+        self.game.game_state = GameState::FinalAnswer(id, bets, Vec::new());
+        self.send_message(
+            "Отправьте ответ боту в личные сообщения. Засчитывается только первый ответ"
+                .to_string(),
+        )
+        .await;
+        self.schedule_timeout(Self::FINAL_ANSWER);
+    }
+
+    async fn finish_final(&mut self, bets: Vec<(i64, i32)>, answers: Vec<(i64, String)>) {
+        let question = self.current_question();
         let mut result = String::new();
-        for (name, score) in score_list {
-            result += format!("{} {}\n", name, score).as_str();
+        for (id, bet) in bets.iter() {
+            let answer = answers
+                .iter()
+                .find(|(answer_id, _)| answer_id == id)
+                .map(|(_, answer)| answer.clone());
+            let delta = match &answer {
+                Some(answer) if question.check_answer(answer, self.game.strict) => *bet,
+                _ => -*bet,
+            };
+            self.game.add_points(*id, delta);
+            result += format!(
+                "{}: {} ({:+})\n",
+                self.user_name(id),
+                answer
+                    .map(|answer| encode(&answer))
+                    .unwrap_or_else(|| "нет ответа".to_string()),
+                delta
+            )
+            .as_str();
+        }
+        self.send_message(format!("{}\n{}", result, question.display_answers(false)))
+            .await;
+        self.game.history.transcript.push((
+            self.game.current_topic,
+            self.game.current_question,
+            None,
+        ));
+        self.game.current_question = 0usize;
+        self.game.current_topic += 1;
+        self.update_status();
+        self.game.game_state = GameState::BeforeTopic(false);
+        self.show_score().await;
+    }
+
+    async fn grace_period_expired(&mut self, user: i64) {
+        if self.game.game_state == GameState::AfterGame
+            || self.game.players[&user].2
+            || self.game.departure_vote.is_some()
+        {
+            return;
+        }
+        if self
+            .game
+            .players
+            .iter()
+            .all(|(_, (_, _, present))| !*present)
+        {
+            self.send_message("Все игроки покинули чат".to_string())
+                .await;
+            self.end_game(true).await;
+            return;
         }
+        self.game.departure_vote = Some((user, Vec::new()));
+        self.send_message(format!(
+            "{} не вернулся. Продолжить игру без него? Ответьте \"Да\" чтобы продолжить или \"Нет\" чтобы прервать игру",
+            self.user_name(&user)
+        ))
+        .await;
+    }
+
+    async fn vote_on_departure(&mut self, from: i64, vote: bool) {
+        let (departed, mut votes) = self.game.departure_vote.clone().unwrap();
+        if from == departed
+            || !self.game.is_active(&from)
+            || votes.iter().any(|(id, _)| *id == from)
+        {
+            return;
+        }
+        votes.push((from, vote));
+        let voters = self
+            .game
+            .players
+            .iter()
+            .filter(|(id, (_, _, present))| {
+                **id != departed && *present && !self.game.absent.contains(id)
+            })
+            .count();
+        let yes = votes.iter().filter(|(_, vote)| *vote).count();
+        let no = votes.len() - yes;
+        if no * 2 > voters {
+            self.game.departure_vote = None;
+            self.send_message("Игра прервана по решению игроков".to_string())
+                .await;
+            self.end_game(true).await;
+        } else if yes * 2 > voters || votes.len() >= voters {
+            self.game.departure_vote = None;
+            self.game.absent.insert(departed);
+            self.game.skip_votes.remove(&departed);
+            self.send_message(format!(
+                "Игра продолжается без {}",
+                self.user_name(&departed)
+            ))
+            .await;
+            if self.game.game_state.pausable() && self.game.game_state.set_pause(false) {
+                self.game.end_pause();
+                self.game.pending_adjustment = None;
+                self.send_message("Игра возобновлена".to_string()).await;
+                self.schedule_timeout(Self::INTERMISSION);
+            }
+        } else {
+            self.game.departure_vote = Some((departed, votes));
+        }
+    }
+
+    async fn substitute_player(&mut self, from: i64, tokens: &[&str], substitute: Option<User>) {
+        let original = if self.config.is_manager(UserId::new(from), &self.data) && tokens.len() == 1
+        {
+            self.game.find_player(tokens[0])
+        } else if tokens.is_empty() && self.game.players.contains_key(&from) {
+            Some(from)
+        } else {
+            None
+        };
+        let original = match original {
+            None => {
+                self.send_message("Замену может запросить только выбывающий игрок".to_string())
+                    .await;
+                return;
+            }
+            Some(original) => original,
+        };
+        let substitute = match substitute {
+            Some(substitute) if !substitute.is_bot => substitute,
+            _ => {
+                self.send_message(
+                    "Отправьте команду ответом на сообщение пользователя, который вас заменит"
+                        .to_string(),
+                )
+                .await;
+                return;
+            }
+        };
+        let id = substitute.id.into();
+        if self.game.players.contains_key(&id) {
+            self.send_message(format!("{} уже участвует в игре", self.user_name(&id)))
+                .await;
+            return;
+        }
+        let original_name = self.user_name(&original);
+        let user_data = match self
+            .data
+            .get_or_create_user(substitute.clone())
+            .and_then(|user_data| self.data.update_player(substitute.id, user_data))
+        {
+            Ok(user_data) => user_data,
+            Err(err) => {
+                log::error!("{}", err);
+                self.send_message("Не удалось выполнить замену".to_string())
+                    .await;
+                return;
+            }
+        };
+        let (_, score, _) = self.game.players.remove(&original).unwrap();
+        self.game.players.insert(id, (user_data, score, true));
+        self.game.spectators.remove(&id);
+        self.game.substitutions.push((original, id));
+        for topic_scores in self.game.history.topic_scores.iter_mut() {
+            if let Some(points) = topic_scores.remove(&original) {
+                topic_scores.insert(id, points);
+            }
+        }
+        if let Some(team) = self.game.teams.remove(&original) {
+            self.game.teams.insert(id, team);
+        }
+        self.game.usernames.retain(|_, user| *user != original);
+        self.game.remember_username(&substitute);
+        self.game.skip_votes.remove(&original);
+        self.game.absent.remove(&original);
+        self.grace_periods.remove(&original);
+        if matches!(self.game.departure_vote, Some((departed, _)) if departed == original) {
+            self.game.departure_vote = None;
+        }
+        self.data
+            .set_played(
+                &[&substitute.id],
+                &self.game.set_id,
+                &self.game.topics[self.game.current_topic..],
+            )
+            .log_error();
+        self.play_bot
+            .kick(ChatId::new(self.game.chat_id), UserId::new(original), false)
+            .await
+            .log_error();
+        self.reissue_invite_link().await;
+        self.send_message(format!(
+            "{} заменен на {} со счётом {}\nНовая ссылка для входа в игру: {}",
+            original_name,
+            self.user_name(&id),
+            score,
+            self.game.invite_link
+        ))
+        .await;
+        self.status_sender
+            .send(StatusUpdate {
+                chat_id: self.game.chat_id,
+                update_type: UpdateType::PlayerReplaced(original, id),
+            })
+            .unwrap();
+        self.update_status();
+    }
+
+    /// The play chat or a chat the game was proposed in was upgraded to a supergroup, the state
+    /// of the game is stored under the new id of the play chat.
+    fn migrate_chat(&mut self, chat_id: i64, new_chat_id: i64) {
+        if chat_id == self.game.chat_id {
+            self.data.remove_game(self.game.chat_id).log_error();
+            self.game.chat_id = new_chat_id;
+        }
+        for source_id in self.game.source_chats.iter_mut() {
+            if *source_id == chat_id {
+                *source_id = new_chat_id;
+            }
+        }
+        if let Some(thread_id) = self.game.source_threads.remove(&chat_id) {
+            self.game.source_threads.insert(new_chat_id, thread_id);
+        }
+        self.data.save_game_state(&self.game).log_error();
+    }
+
+    /// The scheduler bot posting to the forum topic the game was proposed in.
+    fn source_bot(&self, source_id: i64) -> TelegramBot {
+        self.scheduler_bot
+            .in_thread(self.game.source_threads.get(&source_id).copied())
+    }
+
+    /// The new link only admits as many people as there are players and spectators yet to join.
+    async fn reissue_invite_link(&mut self) {
+        let chat_id = ChatId::new(self.game.chat_id);
+        self.play_bot
+            .invalidate_invite_link(chat_id, self.game.invite_link.clone())
+            .await
+            .log_error();
+        let absent = self
+            .game
+            .players
+            .values()
+            .filter(|(_, _, present)| !*present)
+            .count();
+        let spectators = self
+            .game
+            .spectators
+            .iter()
+            .map(|id| UserId::new(*id))
+            .collect::<Vec<_>>();
+        let absent_spectators = self
+            .play_bot
+            .chat_members(chat_id, &spectators)
+            .await
+            .into_iter()
+            .filter(|member| !*member)
+            .count();
+        if let Some(invite_link) = self
+            .play_bot
+            .create_invite_link(chat_id, absent + absent_spectators)
+            .await
+            .log_error()
+        {
+            self.game.invite_link = invite_link;
+        }
+        self.data.save_game_state(&self.game).log_error();
+    }
+
+    /// Privately if the player has a chat with the bot, otherwise in the chats the game was
+    /// proposed in.
+    fn send_return_link(&self, id: i64) {
+        let text = format!(
+            "{}, ссылка для возвращения в игру: {}",
+            self.user_name(&id),
+            self.game.invite_link
+        );
+        if self
+            .data
+            .has_private_chat(UserId::new(id))
+            .log_error()
+            .unwrap_or(false)
+        {
+            self.scheduler_bot.try_send_message(ChatId::new(id), text);
+        } else {
+            for source_id in self.game.source_chats.iter() {
+                self.source_bot(*source_id)
+                    .try_send_message(ChatId::new(*source_id), text.clone());
+            }
+        }
+    }
+
+    async fn request_adjustment(&mut self, from: i64, tokens: &[&str]) {
+        if !self.game.players.contains_key(&from) {
+            return;
+        }
+        let (target, amount) = match tokens.len() {
+            1 => (Some(from), tokens[0]),
+            2 => (self.game.find_player(tokens[0]), tokens[1]),
+            _ => {
+                self.send_message("Неверное число аргументов".to_string())
+                    .await;
+                return;
+            }
+        };
+        let by = match amount.parse::<i32>() {
+            Ok(by) if by.abs() <= 10000 && by % 10 == 0 => by,
+            _ => {
+                self.send_message("Некорректное число очков".to_string())
+                    .await;
+                return;
+            }
+        };
+        match target {
+            None => {
+                self.send_message(format!("Игрок {} не участвует в игре", tokens[0]))
+                    .await;
+            }
+            Some(target) if target == from => {
+                self.apply_adjustment(from, target, by).await;
+            }
+            Some(target) => {
+                self.game.pending_adjustment = Some((from, target, by));
+                self.send_message(format!(
+                    "{} предлагает изменить счёт игрока {} на {}. Для подтверждения другой игрок должен написать \"Да\", для отмены - \"Нет\"",
+                    self.user_name(&from),
+                    self.user_name(&target),
+                    by
+                ))
+                .await;
+            }
+        }
+    }
+
+    async fn apply_adjustment(&mut self, adjusted_by: i64, target: i64, by: i32) {
+        let adjustment = ScoreAdjustment {
+            chat_id: self.game.chat_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            adjusted_by,
+            target,
+            delta: by,
+        };
+        if self
+            .data
+            .add_adjustment(self.game.id, &adjustment)
+            .log_error()
+            == Some(false)
+        {
+            self.send_message("Достигнут лимит исправлений счёта за игру".to_string())
+                .await;
+            return;
+        }
+        let old_score = self.game.players[&target].1;
+        self.game.players.get_mut(&target).unwrap().1 += by;
+        self.send_message(format!(
+            "Количество очков у {} изменено: {} -> {}",
+            self.user_name(&target),
+            old_score,
+            self.game.players[&target].1,
+        ))
+        .await;
+        self.update_status();
+    }
+
+    /// Net manual adjustments per player, empty if there were none.
+    fn adjustment_summary(&self) -> String {
+        let mut net = HashMap::new();
+        for adjustment in self
+            .data
+            .get_adjustments(self.game.id)
+            .log_error()
+            .unwrap_or_default()
+        {
+            *net.entry(adjustment.target).or_insert(0) += adjustment.delta;
+        }
+        let mut lines = net
+            .into_iter()
+            .filter(|(_, delta)| *delta != 0)
+            .map(|(id, delta)| format!("{} {:+}", self.user_name(&id), delta))
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            return String::new();
+        }
+        lines.sort();
+        format!("\nИсправления счёта: {}\n", lines.join(", "))
+    }
+
+    fn team_list(&self) -> String {
+        let mut result = String::new();
+        for (team, score) in self.game.team_scores() {
+            let mut members = self
+                .game
+                .teams
+                .iter()
+                .filter(|(_, other)| **other == team)
+                .map(|(id, _)| self.user_name(id))
+                .collect::<Vec<_>>();
+            members.sort();
+            result += format!("Команда {}: {} {}\n", team + 1, members.join(", "), score).as_str();
+        }
+        result
+    }
+
+    fn score_list(&self) -> String {
+        let mut result = self.team_list();
+        if !result.is_empty() {
+            result += "\n";
+        }
+        for (i, id) in self.game.ranked_players().iter().enumerate() {
+            result += format!(
+                "{}. {} {}\n",
+                i + 1,
+                self.user_name(id),
+                self.game.players[id].1
+            )
+            .as_str();
+        }
+        result
+    }
+
+    async fn show_score(&mut self) {
         self.send_message(format!(
             "<b>{} счёт:</b>\n{}",
             if self.game.current_topic == self.game.topics.len() {
@@ -604,24 +2186,132 @@ impl GameHandle {
             } else {
                 "Teкущий"
             },
-            result
+            self.score_list()
         ))
         .await;
         self.schedule_timeout(Self::INTERMISSION);
     }
 
+    async fn start_topic(&mut self) {
+        self.game.game_state = GameState::BeforeFirstQuestion(false);
+        let remaining = self.game.topics.len() - self.game.current_topic;
+        self.send_message(format!(
+            "{}\n<b>Тема {}:</b> {}{}",
+            if remaining == 1 {
+                "Последняя тема".to_string()
+            } else {
+                format!("Осталось {}", Topic::topic_word(remaining))
+            },
+            self.topic_label(self.game.current_topic),
+            self.topic_title(),
+            author_line(&self.current_topic().author)
+        ))
+        .await;
+        self.schedule_timeout(Self::INTERMISSION);
+    }
+
+    fn topic_label(&self, position: usize) -> usize {
+        if self.game.blind {
+            position + 1
+        } else {
+            self.game.topics[position] + 1
+        }
+    }
+
+    fn selectable_positions(&self) -> std::ops::Range<usize> {
+        let end = if self.game.final_round {
+            self.game.topics.len() - 1
+        } else {
+            self.game.topics.len()
+        };
+        self.game.current_topic..end
+    }
+
+    fn topic_picker(&self) -> Option<i64> {
+        match self.game.topic_picker {
+            TopicPicker::None => None,
+            TopicPicker::Last => self.game.ranked_players().last().cloned(),
+            TopicPicker::Winner => self
+                .game
+                .history
+                .topic_scores
+                .get(self.game.current_topic - 1)
+                .and_then(|topic_scores| {
+                    topic_scores
+                        .iter()
+                        .filter(|(id, points)| **points > 0 && self.game.players.contains_key(id))
+                        .max_by_key(|(id, points)| (**points, -**id))
+                        .map(|(id, _)| *id)
+                })
+                .or_else(|| self.game.ranked_players().first().cloned()),
+        }
+    }
+
+    async fn start_topic_selection(&mut self) -> bool {
+        if self.game.current_topic == 0 || self.selectable_positions().len() < 2 {
+            return false;
+        }
+        let picker = match self.topic_picker() {
+            None => return false,
+            Some(picker) => picker,
+        };
+        let choices = self
+            .selectable_positions()
+            .map(|position| self.topic_label(position))
+            .collect::<Vec<_>>();
+        self.game.game_state = GameState::TopicSelection(picker, choices);
+        self.send_topic_selection().await;
+        self.schedule_timeout(Self::TOPIC_SELECTION);
+        true
+    }
+
+    async fn send_topic_selection(&mut self) {
+        if let GameState::TopicSelection(picker, _) = self.game.game_state {
+            let mut text = format!(
+                "{}, выберите следующую тему. Если тема не будет выбрана за {} секунд, будет сыграна первая из списка:\n",
+                self.user_name(&picker),
+                Self::TOPIC_SELECTION.as_secs()
+            );
+            for position in self.selectable_positions() {
+                text += if self.game.blind {
+                    format!("Тема {}\n", self.topic_label(position))
+                } else {
+                    format!(
+                        "{}. {}\n",
+                        self.topic_label(position),
+                        self.topic_set.topics[self.game.topics[position]].name
+                    )
+                }
+                .as_str();
+            }
+            self.send_message(text).await;
+        }
+    }
+
+    fn select_topic(&mut self, label: usize) {
+        if let Some(position) = self
+            .selectable_positions()
+            .find(|position| self.topic_label(*position) == label)
+        {
+            let topic = self.game.topics.remove(position);
+            self.game.topics.insert(self.game.current_topic, topic);
+        }
+    }
+
     fn topic_title(&self) -> String {
-        encode(&self.current_topic().name)
+        self.current_topic().name.clone()
     }
 
     async fn ask_question(&mut self) {
         self.game.game_state = GameState::BeforeQuestion(false);
         self.send_message("Внимание, вопрос".to_string()).await;
+        self.play_bot
+            .send_chat_action(ChatId::new(self.game.chat_id), "typing");
         self.schedule_timeout(Duration::from_secs(1));
     }
 
     fn current_question(&self) -> Question {
-        self.current_topic().questions[self.game.current_question].fix()
+        self.current_topic().questions[self.game.current_question].clone()
     }
 
     fn current_topic(&self) -> &Topic {
@@ -637,26 +2327,38 @@ impl GameHandle {
             .display_question(&self.topic_title())
     }
 
+    /// Players seen joining are trusted, only the rest are looked up in the chat.
+    async fn all_players_present(&mut self) -> bool {
+        let absent = self
+            .game
+            .players
+            .iter()
+            .filter(|(_, (_, _, present))| !*present)
+            .map(|(id, _)| UserId::new(*id))
+            .collect::<Vec<_>>();
+        let members = self
+            .play_bot
+            .chat_members(ChatId::new(self.game.chat_id), &absent)
+            .await;
+        for (user_id, member) in absent.into_iter().zip(members) {
+            if member {
+                let id: i64 = user_id.into();
+                self.game.players.get_mut(&id).unwrap().2 = true;
+            }
+        }
+        self.game.players.values().all(|(_, _, present)| *present)
+    }
+
     async fn advance_state(&mut self) -> bool {
         if self.game.game_state.set_pause(false) {
+            self.game.end_pause();
+            self.game.pending_adjustment = None;
             self.send_message("Игра возобновлена".to_string()).await;
             self.schedule_timeout(Self::INTERMISSION);
         } else {
             match self.game.game_state.clone() {
                 GameState::BeforeGame(_, minutes) => {
-                    if minutes > 1
-                        && !self
-                            .play_bot
-                            .all_players_in_chat(
-                                ChatId::new(self.game.chat_id),
-                                self.game
-                                    .players
-                                    .keys()
-                                    .map(|id| UserId::new(*id))
-                                    .collect::<Vec<_>>(),
-                            )
-                            .await
-                    {
+                    if minutes > 1 && !self.all_players_present().await {
                         self.game.game_state = GameState::BeforeGame(false, minutes - 1);
                         self.send_message(format!(
                             "Некоторые игроки всё еще не зашли в чат. Через {} минут{} игра начнется автоматически",
@@ -667,12 +2369,16 @@ impl GameHandle {
                     } else {
                         self.game.game_state = GameState::BeforeTopic(false);
                         let mut list = "<b>Список тем:</b>\n".to_string();
-                        for i in self.game.topics.iter() {
-                            list +=
-                                format!("{}. {}\n", i + 1, self.topic_set.topics[*i].name).as_str();
+                        for (position, i) in self.game.topics.iter().enumerate() {
+                            list += if self.game.blind {
+                                format!("Тема {}\n", position + 1)
+                            } else {
+                                format!("{}. {}\n", i + 1, self.topic_set.topics[*i].name)
+                            }
+                            .as_str();
                         }
                         self.send_message(format!(
-                            "Игра началась. Игроки:\n{}\n\n{}\n{}\n{}\n\n",
+                            "Игра началась. Игроки:\n{}\n\n{}{}\n{}\n{}\n\n",
                             player_list(
                                 &self
                                     .game
@@ -682,63 +2388,121 @@ impl GameHandle {
                                     .collect::<Vec<_>>()[..]
                             ),
                             self.topic_set.title,
+                            author_line(&self.topic_set.author),
                             self.topic_set.description,
                             list
                         ))
                         .await;
+                        self.create_status_message().await;
                         self.schedule_timeout(Self::INTERMISSION);
                     }
                 }
                 GameState::BeforeTopic(_) => {
                     if self.game.current_topic == self.game.topics.len() {
                         self.end_game(false).await;
-                    } else {
-                        self.game.game_state = GameState::BeforeFirstQuestion(false);
-                        let remaining = self.game.topics.len() - self.game.current_topic;
-                        self.send_message(format!(
-                            "{}\n<b>Тема {}:</b> {}",
-                            if remaining == 1 {
-                                "Последняя тема".to_string()
-                            } else {
-                                format!("Осталось {}", Topic::topic_word(remaining))
-                            },
-                            self.game.topics[self.game.current_topic] + 1,
-                            self.topic_title()
-                        ))
-                        .await;
-                        self.schedule_timeout(Self::INTERMISSION);
+                    } else if self.game.final_round && self.is_last_topic() {
+                        self.start_final_betting().await;
+                    } else if !self.start_topic_selection().await {
+                        self.start_topic().await;
                     }
                 }
+                GameState::TopicSelection(..) => {
+                    self.start_topic().await;
+                }
                 GameState::BeforeFirstQuestion(_) => {
                     self.ask_question().await;
                 }
                 GameState::BeforeQuestion(_) => {
-                    let id = self
-                        .send_message_with_markup(self.question_text(), KeyboardOptions::None)
-                        .await
-                        .unwrap();
-                    self.game.game_state = GameState::Question(id, Vec::new());
-                    self.schedule_timeout(Self::FIRST_THINKING);
+                    // The countdown makes the caption longest
+                    let fits_caption = self.countdown_text(Self::COUNTDOWN[0]).chars().count()
+                        <= TelegramBot::MAX_CAPTION_LEN;
+                    let photo_id = match self.current_question().image {
+                        Some(image) if fits_caption => self
+                            .play_bot
+                            .send_photo(
+                                ChatId::new(self.game.chat_id),
+                                image,
+                                self.question_text(),
+                                KeyboardOptions::InlinePlus,
+                            )
+                            .await
+                            .log_error()
+                            .map(|id| id.into()),
+                        Some(image) => {
+                            // The text follows the photo as a message of its own
+                            self.play_bot
+                                .send_photo(
+                                    ChatId::new(self.game.chat_id),
+                                    image,
+                                    String::new(),
+                                    KeyboardOptions::None,
+                                )
+                                .await
+                                .log_error();
+                            None
+                        }
+                        None => None,
+                    };
+                    self.game.question_photo = photo_id.is_some();
+                    let id = match photo_id {
+                        Some(id) => id,
+                        None => match self
+                            .deliver(self.question_text(), KeyboardOptions::InlinePlus)
+                            .await
+                        {
+                            Ok(id) => id,
+                            Err(err) if err.is_outage() => {
+                                // The question is asked again once the game is resumed
+                                log::error!("Error sending question: {}", err);
+                                self.process_outage(None);
+                                return false;
+                            }
+                            Err(err) => {
+                                log::error!("Error sending question: {}", err);
+                                self.game.game_state =
+                                    GameState::AfterQuestion(false, Vec::new(), None);
+                                self.send_message(
+                                    "Не удалось показать вопрос, он пропущен".to_string(),
+                                )
+                                .await;
+                                self.schedule_timeout(Self::INTERMISSION);
+                                self.data.save_game_state(&self.game).log_error();
+                                return false;
+                            }
+                        },
+                    };
+                    let locked = std::mem::take(&mut self.game.last_wrong);
+                    let locked = if self.game.current_question == 0 {
+                        Vec::new()
+                    } else {
+                        locked
+                    };
+                    self.game.game_state =
+                        GameState::Question(id, Vec::new(), Game::now().as_millis() as u64, locked);
+                    self.game.skip_votes.clear();
+                    self.schedule_timeout(self.game.timings.first_thinking());
+                    self.schedule_countdown(self.game.timings.first_thinking());
                 }
-                GameState::Question(_, _) => {
-                    self.end_question(false).await;
+                GameState::Question(..) => {
+                    self.end_question(false, false).await;
                 }
                 GameState::Answer(_, _, _) => {
                     self.incorrect_answer(true, false).await;
                 }
                 GameState::AfterQuestion(_, answered, correct) => {
-                    for id in answered.iter() {
-                        if let Some(cid) = &correct {
-                            if cid == id {
-                                self.game.players.get_mut(id).unwrap().1 +=
-                                    self.current_question().cost as i32;
-                                break;
-                            }
-                        }
-                        self.update_status();
-                        self.game.players.get_mut(id).unwrap().1 -=
-                            self.current_question().cost as i32;
-                    }
+                    let cost = self.current_question().cost as i32;
+                    self.game.score_question(&answered, correct, cost);
+                    self.game.last_wrong = answered
+                        .iter()
+                        .filter(|id| Some(**id) != correct)
+                        .cloned()
+                        .collect();
+                    self.game.history.transcript.push((
+                        self.game.current_topic,
+                        self.game.current_question,
+                        correct,
+                    ));
+                    self.update_status();
                     self.game.current_question += 1;
                     if self.game.current_question == self.current_topic().questions.len() {
                         self.game.current_question = 0usize;
@@ -755,43 +2519,117 @@ impl GameHandle {
                         self.ask_question().await;
                     }
                 }
+                GameState::AppealVote(paused, answers, correct, appellant, votes) => {
+                    self.resolve_appeal(paused, answers, correct, appellant, votes)
+                        .await;
+                }
                 GameState::SpecialScore(_) => {
                     self.ask_question().await;
                 }
+                GameState::FinalBetting(bets) => {
+                    self.show_final_question(bets).await;
+                }
+                GameState::FinalAnswer(_, bets, answers) => {
+                    self.finish_final(bets, answers).await;
+                }
                 GameState::AfterGame => {
-                    self.data.remove_game(self.game.chat_id);
+                    self.data.remove_game(self.game.chat_id).log_error();
+                    self.data.prune_set_versions().log_error();
+                    self.edit_status_message().await;
+                    if let Some(message_id) = self.game.status_message.take() {
+                        self.play_bot
+                            .unpin_message(
+                                ChatId::new(self.game.chat_id),
+                                MessageId::new(message_id),
+                            )
+                            .await
+                            .log_error();
+                    }
                     self.play_bot
                         .invalidate_invite_link(
                             ChatId::new(self.game.chat_id),
                             self.game.invite_link.clone(),
                         )
-                        .await;
+                        .await
+                        .log_error();
                     for user in self.game.players.keys().chain(self.game.spectators.iter()) {
                         self.play_bot
-                            .kick(ChatId::from(self.game.chat_id), UserId::from(*user))
-                            .await;
+                            .kick(ChatId::from(self.game.chat_id), UserId::from(*user), false)
+                            .await
+                            .log_error();
                     }
                     return true;
                 }
             }
         }
-        self.data.save_game_state(&self.game);
+        self.data.save_game_state(&self.game).log_error();
         false
     }
 
-    pub async fn start_game(mut self, message_stream: UnboundedReceiverStream<Message>) {
+    pub async fn start_game(
+        mut self,
+        message_stream: UnboundedReceiverStream<BotUpdate>,
+        restored: bool,
+    ) {
         let mut event_stream = select_all(vec![
-            message_stream.map(Event::Message).boxed(),
+            message_stream
+                .map(|update| match update {
+                    BotUpdate::Message(message, _) => Event::Message(message),
+                    BotUpdate::CallbackQuery(query) => Event::CallbackQuery(query),
+                })
+                .boxed(),
             self.timeout_stream.take().unwrap().boxed(),
         ]);
+        if restored
+            && (self.game.game_state.before()
+                || self.game.players.values().any(|(_, _, present)| !*present))
+        {
+            self.reissue_invite_link().await;
+            for source_id in self.game.source_chats.iter() {
+                self.source_bot(*source_id).try_send_message(
+                    ChatId::new(*source_id),
+                    format!(
+                        "Бот перезапущен. Новая ссылка для входа в игру: {}",
+                        self.game.invite_link
+                    ),
+                );
+            }
+        }
         self.process_starting_state().await;
-        self.data.save_game_state(&self.game);
+        if !matches!(
+            self.game.game_state,
+            GameState::BeforeGame(..) | GameState::AfterGame
+        ) && self.game.departure_vote.is_none()
+        {
+            for id in self.game.player_ids() {
+                if !self.game.players[&id].2 {
+                    self.schedule_grace_period(id);
+                }
+            }
+        }
+        self.data.save_game_state(&self.game).log_error();
         while let Some(event) = event_stream.next().await {
             if match event {
                 Event::Message(message) => {
                     self.process_message(message).await;
                     false
                 }
+                Event::CallbackQuery(query) => {
+                    self.process_callback_query(query).await;
+                    false
+                }
+                Event::StatusEdit => {
+                    self.edit_status_message().await;
+                    false
+                }
+                Event::Outage(undelivered) => {
+                    self.process_outage(undelivered);
+                    false
+                }
+                Event::Reconnect => {
+                    self.reconnect().await;
+                    false
+                }
                 Event::Timeout(id) => {
                     if self.state_id == id {
                         self.advance_state().await
@@ -799,6 +2637,20 @@ impl GameHandle {
                         false
                     }
                 }
+                Event::Countdown(id, remaining) => {
+                    if self.state_id == id {
+                        self.show_countdown(remaining).await;
+                    }
+                    false
+                }
+                Event::GracePeriod(user, id) => {
+                    if self.grace_periods.get(&user) == Some(&id) {
+                        self.grace_periods.remove(&user);
+                        self.grace_period_expired(user).await;
+                        self.data.save_game_state(&self.game).log_error();
+                    }
+                    false
+                }
             } {
                 break;
             }
@@ -830,8 +2682,8 @@ impl GameHandle {
                 self.schedule_timeout(Self::PAUSE);
                 self.game.game_state = GameState::BeforeQuestion(true);
             }
-            GameState::Question(_, _) => {
-                self.end_question(true).await;
+            GameState::Question(..) => {
+                self.end_question(true, false).await;
             }
             GameState::Answer(_, _, _) => {
                 self.incorrect_answer(false, true).await;
@@ -841,10 +2693,42 @@ impl GameHandle {
                 self.game.game_state =
                     GameState::AfterQuestion(true, answers.clone(), correct_answer.clone());
             }
+            GameState::AppealVote(_, answers, correct, appellant, votes) => {
+                self.schedule_timeout(Self::APPEAL);
+                self.game.game_state =
+                    GameState::AppealVote(true, answers, correct, appellant, votes);
+                self.send_message(
+                    "Бот восстановлен после перезапуска. Голосование по апелляции продолжается."
+                        .to_string(),
+                )
+                .await;
+                return;
+            }
             GameState::SpecialScore(_) => {
                 self.schedule_timeout(Self::PAUSE);
                 self.game.game_state = GameState::SpecialScore(true);
             }
+            GameState::FinalBetting(_) => {
+                self.schedule_timeout(Self::FINAL_BETTING);
+                self.send_message(
+                    "Бот восстановлен после перезапуска. Приём ставок продолжается.".to_string(),
+                )
+                .await;
+                return;
+            }
+            GameState::TopicSelection(..) => {
+                self.schedule_timeout(Self::TOPIC_SELECTION);
+                self.send_topic_selection().await;
+                return;
+            }
+            GameState::FinalAnswer(_, _, _) => {
+                self.schedule_timeout(Self::FINAL_ANSWER);
+                self.send_message(
+                    "Бот восстановлен после перезапуска. Приём ответов продолжается.".to_string(),
+                )
+                .await;
+                return;
+            }
             GameState::AfterGame => {
                 self.schedule_timeout(Self::AFTER_GAME);
             }
@@ -861,5 +2745,132 @@ impl GameHandle {
         if let Some(handle) = self.timeout_handle.take() {
             handle.abort();
         }
+        for handle in self.countdown_handles.drain(..) {
+            handle.abort();
+        }
     }
 }
+
+#[cfg(test)]
+pub fn create_test_game(player_count: i64, teams: HashMap<i64, u8>) -> Game {
+    let players = (1..=player_count)
+        .map(|id| (id, UserData::new(id.to_string(), 15000)))
+        .collect::<HashMap<_, _>>();
+    Game::new(
+        0,
+        0,
+        Vec::new(),
+        HashMap::new(),
+        "set".to_string(),
+        0,
+        vec![0, 1],
+        players,
+        HashSet::new(),
+        String::new(),
+        Timings::default(),
+        false,
+        teams,
+        false,
+        TopicPicker::None,
+        false,
+    )
+}
+
+#[test]
+fn test_score_question() {
+    let mut game = create_test_game(3, HashMap::new());
+    game.score_question(&[1, 2, 3], Some(2), 30);
+    assert_eq!(game.players[&1].1, -30);
+    assert_eq!(game.players[&2].1, 30);
+    assert_eq!(game.players[&3].1, -30);
+    game.score_question(&[3, 1], None, 50);
+    assert_eq!(game.players[&1].1, -80);
+    assert_eq!(game.players[&2].1, 30);
+    assert_eq!(game.players[&3].1, -80);
+    assert_eq!(game.places(), HashMap::from([(1, 2), (2, 1), (3, 2)]));
+}
+
+#[test]
+fn test_team_answers() {
+    let teams = HashMap::from([(1, 0), (2, 1), (3, 1), (4, 0)]);
+    let mut game = create_test_game(4, teams);
+    assert!(game.can_answer(&1, &[]));
+    assert!(!game.can_answer(&4, &[1]));
+    assert!(game.can_answer(&2, &[1]));
+    assert!(!game.can_answer(&3, &[1, 2]));
+    game.score_question(&[1, 2], Some(2), 30);
+    game.score_question(&[4], Some(4), 50);
+    assert_eq!(game.team_scores(), vec![(1, 30), (0, 20)]);
+    assert_eq!(
+        game.places(),
+        HashMap::from([(1, 2), (2, 1), (3, 1), (4, 2)])
+    );
+}
+
+#[test]
+fn test_game_history() {
+    let mut game = create_test_game(2, HashMap::new());
+    game.score_question(&[1, 2], Some(2), 10);
+    game.current_topic = 1;
+    game.score_question(&[1], Some(1), 20);
+    game.score_question(&[1], Some(1), 30);
+    assert_eq!(
+        game.history.topic_scores[0],
+        HashMap::from([(1, -10), (2, 10)])
+    );
+    assert_eq!(game.history.topic_scores[1], HashMap::from([(1, 50)]));
+    let bytes = game.try_to_vec().unwrap();
+    let restored = Game::try_from_slice(&bytes).unwrap();
+    assert_eq!(restored.history.topic_scores, game.history.topic_scores);
+}
+
+#[test]
+fn test_pause_limits() {
+    let mut game = create_test_game(3, HashMap::new());
+    game.timings = Timings::default().with_pauses(1, 5).unwrap();
+    assert_eq!(
+        game.remaining_pause_time(&1),
+        Some(Duration::from_secs(300))
+    );
+    game.start_pause(1);
+    game.end_pause();
+    assert_eq!(game.remaining_pause_time(&1), None);
+    assert!(game.remaining_pause_time(&2).is_some());
+    assert!(Timings::default()
+        .with_pauses(Timings::MAX_PAUSES + 1, 5)
+        .is_none());
+}
+
+#[test]
+fn test_outage_pause() {
+    let mut outage = Outage::default();
+    let mut game_state = GameState::Question(1, Vec::new(), 0, Vec::new());
+    assert_eq!(outage.begin(&mut game_state), (true, false));
+    // The next question can't be sent either, the game stops there
+    let mut game_state = GameState::BeforeQuestion(false);
+    assert_eq!(outage.begin(&mut game_state), (false, true));
+    assert!(game_state.paused());
+    assert_eq!(outage.begin(&mut game_state), (false, false));
+    outage.reconnect_scheduled = false;
+    assert!(outage.end(&mut game_state));
+    assert!(!game_state.paused());
+    assert!(!outage.end(&mut game_state));
+
+    // A pause taken by the players is theirs to end
+    let mut game_state = GameState::AfterQuestion(true, Vec::new(), None);
+    assert_eq!(outage.begin(&mut game_state), (true, false));
+    assert!(!outage.end(&mut game_state));
+    assert!(game_state.paused());
+}
+
+#[test]
+fn test_absent_players() {
+    let mut game = create_test_game(4, HashMap::new());
+    game.absent.insert(2);
+    assert_eq!(game.active_count(), 3);
+    assert!(!game.is_active(&2));
+    assert!(game.is_active(&1));
+    let mut rated = game.rated_players().into_keys().collect::<Vec<_>>();
+    rated.sort();
+    assert_eq!(rated, vec![1, 3, 4]);
+}
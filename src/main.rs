@@ -5,31 +5,87 @@ mod parser;
 mod queue;
 mod topic;
 
-use crate::bot::{KeyboardOptions, TelegramBot};
-use crate::data::{display_name, display_rating, BitSet, Data, UserBanResult, UserData};
-use crate::game::{Game, GameHandle};
-use crate::parser::parse;
+use crate::bot::{BotUpdate, FileError, KeyboardOptions, TelegramBot};
+use crate::data::{
+    date, date_time, display_name, display_rating, is_year_month, year_month, BitSet, Data,
+    DataDump, DataError, Language, LogError, RankedEntry, UserBanResult, UserData,
+};
+use crate::game::{Game, GameHandle, Timings, TopicPicker};
+use crate::parser::{check_set, parse, ParseReport};
 use crate::queue::{PlayQueue, UpdateMessage};
+use crate::topic::{decode, TopicSet};
 use borsh::maybestd::collections::HashMap;
 use env_logger::WriteStyle;
 use futures::stream::select_all;
 use futures::StreamExt;
 use log::LevelFilter;
-use std::collections::HashSet;
-use std::env;
-use std::time::Duration;
-use telegram_bot::{ChatId, Message, MessageChat, MessageKind, UserId};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{HashSet, VecDeque};
+use std::env::{self, VarError};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use telegram_bot::{
+    CallbackQuery, ChatId, Document, Integer, Message, MessageChat, MessageId, MessageKind,
+    MessageOrChannelPost, UserId,
+};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-pub const PRIVATE_BOT_COMMANDS: [(&'static str, &'static str); 12] = [
+pub const PRIVATE_BOT_COMMANDS: [(&'static str, &'static str); 22] = [
     ("help", "выводит это сообщение"),
-    ("register", "добавляет в очередь на создание игры"),
+    (
+        "register",
+        "добавляет в очередь на создание игры, можно указать желаемый пакет (set 2019 для пакета с числовым id), согласие на игру до 5 или 6 игроков и время ожидания в минутах, например 5m",
+    ),
     ("unregister", "удаляет из очереди на создание игры"),
-    ("list", "выводит список пакетов"),
+    (
+        "queue",
+        "выводит ваше место в очереди, время ожидания и диапазон рейтинга соперников",
+    ),
+    (
+        "range",
+        "задает максимальную разницу в рейтинге с соперниками в очереди, 0 - без ограничения",
+    ),
+    (
+        "party",
+        "играть в паре с другом из очереди по @username или по номеру в списке игроков, с которыми вы играли в последнее время, без параметра - отменяет пару",
+    ),
+    (
+        "spectate",
+        "добавляет в список зрителей, которые получат приглашение в следующую найденную игру",
+    ),
+    ("unspectate", "удаляет из списка зрителей"),
+    (
+        "list",
+        "выводит список пакетов: с числом - страницу, с текстом - пакеты, в id или названии которых он есть",
+    ),
+    (
+        "remaining",
+        "выводит число несыгранных вами тем в каждом пакете",
+    ),
     ("status", "выводит список идущих игр"),
-    ("rating", "выводит таблицу рейтинга"),
+    (
+        "rating",
+        "выводит таблицу рейтинга: первые N мест, места с A по B или ваше место (me), с full - с числом игр, побед и максимальным рейтингом",
+    ),
+    (
+        "monthly",
+        "выводит изменения рейтинга за месяц, можно указать месяц в формате 2024-05",
+    ),
+    ("profile", "выводит ваш рейтинг и статистику игр"),
+    (
+        "history",
+        "выводит изменения вашего рейтинга за последние игры",
+    ),
+    (
+        "settings",
+        "выводит ваши настройки, с названием настройки переключает её",
+    ),
     ("block", "блокирует пакет"),
     (
         "unblock",
@@ -42,21 +98,46 @@ pub const PRIVATE_BOT_COMMANDS: [(&'static str, &'static str); 12] = [
     ("banlist", "список игроков, которых вы заблокировали"),
     (
         "ban",
-        "заблокировать игрока по номеру в списке игроков, с которыми вы играли в последнее время",
+        "заблокировать игрока по @username или по номеру в списке игроков, с которыми вы играли в последнее время",
     ),
     (
         "unban",
-        "разблокировать игрока по номеру в списке игроков, которых вы заблокировали",
+        "разблокировать игрока по @username или по номеру в списке игроков, которых вы заблокировали",
     ),
 ];
 
-pub const GROUP_BOT_COMMANDS: [(&'static str, &'static str); 16] = [
+pub const GROUP_BOT_COMMANDS: [(&'static str, &'static str); 28] = [
     ("help", "выводит это сообщение"),
     ("game", "создает новую игру"),
     ("set", "задает пакет, на которм будет идти игра"),
     ("topics", "устанавливает число тем"),
     ("minplayers", "устанавливает минимальное число игроков"),
     ("maxplayers", "устанавливает максимальное число игроков"),
+    (
+        "time",
+        "устанавливает время на размышление после вопроса, после неправильного ответа и на ответ",
+    ),
+    (
+        "pauses",
+        "устанавливает, сколько пауз может взять каждый игрок и на сколько минут в сумме",
+    ),
+    ("final", "включает или выключает финал со ставками"),
+    (
+        "teams",
+        "включает (on) или выключает (off) командную игру 2 на 2",
+    ),
+    (
+        "blind",
+        "включает или выключает режим, в котором названия тем не показываются до их начала",
+    ),
+    (
+        "strict",
+        "включает или выключает строгую проверку ответов, в которой опечатки не засчитываются",
+    ),
+    (
+        "pick",
+        "задает, кто выбирает следующую тему: off - никто, last - последний по очкам, winner - победитель предыдущей темы",
+    ),
     (
         "register",
         "регистрирует на текущую игру и создает игру, если она не начата",
@@ -65,9 +146,32 @@ pub const GROUP_BOT_COMMANDS: [(&'static str, &'static str); 16] = [
     ("unregister", "отменяет регистрацию"),
     ("start", "стартует текущую игру"),
     ("abort", "отменяет текущую игру"),
-    ("list", "выводит список пакетов"),
+    (
+        "link",
+        "объединяет игру с игрой в другом чате: без параметра выдает код, с кодом присоединяет к той игре игроков этого чата",
+    ),
+    (
+        "rematch",
+        "начинает новую игру с теми же игроками в течение 10 минут после окончания предыдущей",
+    ),
+    (
+        "list",
+        "выводит список пакетов: с числом - страницу, с текстом - пакеты, в id или названии которых он есть",
+    ),
     ("status", "выводит список идущих игр"),
-    ("rating", "выводит таблицу рейтинга"),
+    (
+        "rating",
+        "выводит таблицу рейтинга: первые N мест, места с A по B или ваше место (me), с full - с числом игр, побед и максимальным рейтингом",
+    ),
+    (
+        "monthly",
+        "выводит изменения рейтинга за месяц, можно указать месяц в формате 2024-05",
+    ),
+    ("profile", "выводит ваш рейтинг и статистику игр"),
+    (
+        "history",
+        "выводит изменения вашего рейтинга за последние игры",
+    ),
     ("block", "блокирует пакет"),
     (
         "unblock",
@@ -75,13 +179,76 @@ pub const GROUP_BOT_COMMANDS: [(&'static str, &'static str); 16] = [
     ),
 ];
 
-pub const MANAGER_COMMANDS: [(&'static str, &'static str); 4] = [
-    ("shutdown", "..."),
-    ("on", "..."),
-    ("off", "..."),
-    ("topics", "..."),
+pub const MANAGER_COMMANDS: [(&'static str, &'static str); 22] = [
+    (
+        "shutdown",
+        "отменяет ожидающие игры и готовит бота к перезагрузке",
+    ),
+    ("on", "включает пакет"),
+    ("off", "выключает пакет"),
+    ("topics", "выводит список тем пакета"),
+    (
+        "preview",
+        "выводит вопросы и ответы темы пакета по id пакета и номеру темы",
+    ),
+    (
+        "disabletopic",
+        "отключает тему пакета по id пакета и номеру темы, она не попадет в новые игры",
+    ),
+    ("enabletopic", "снова включает отключенную тему пакета"),
+    (
+        "announce",
+        "включает или выключает публикацию результатов игр в основном чате",
+    ),
+    (
+        "copies",
+        "включает или выключает учет тем, сыгранных в других пакетах, как сыгранных",
+    ),
+    (
+        "gban",
+        "исключает из рейтинговых игр пользователя по id или @username",
+    ),
+    ("gunban", "снова допускает пользователя к рейтинговым играм"),
+    ("gbanlist", "выводит список исключенных из рейтинговых игр"),
+    (
+        "limit",
+        "задает максимальное число игр в день, без параметра - снимает ограничение",
+    ),
+    (
+        "rematch",
+        "задает, сколько минут недавние соперники не попадают в одну игру в очереди",
+    ),
+    ("games", "выводит последние игры"),
+    (
+        "adjustments",
+        "выводит исправления счёта в игре с указанным номером",
+    ),
+    (
+        "export",
+        "выгружает базу данных, с sets - вместе с пакетами, с id пакета - только этот пакет",
+    ),
+    (
+        "import",
+        "загружает выгрузку, отправленную файлом с подписью /import",
+    ),
+    ("manager", "назначает менеджера по id или @username"),
+    ("unmanager", "снимает менеджера по id или @username"),
+    ("broadcast", "отправляет сообщение всем пользователям бота"),
+    ("health", "выводит состояние бота и очередей"),
+];
+
+/// Commands of the play bot, only the ones a player may need in the middle of a question.
+pub const PLAY_BOT_COMMANDS: [(&'static str, &'static str); 4] = [
+    ("pause", "приостанавливает игру"),
+    ("continue", "продолжает игру"),
+    ("score", "выводит текущий счёт"),
+    ("skip", "голосует за пропуск некорректного вопроса"),
 ];
 
+pub const DAILY_LIMIT_MESSAGE: &str =
+    "Вы сыграли максимальное число игр за сегодня, счетчик обнулится в 00:00 UTC";
+pub const DB_ERROR_MESSAGE: &str = "Ошибка при работе с базой данных, попробуйте позже";
+
 pub fn player_list(users: &[&UserData]) -> String {
     let mut res = String::new();
     for user in users {
@@ -96,9 +263,56 @@ pub fn player_list(users: &[&UserData]) -> String {
 #[derive(Debug)]
 pub enum UpdateType {
     StatusUpdate(String),
+    PlayerReplaced(i64, i64),
     GameEnded,
 }
 
+/// Ids the bot is tied to, set through MANAGER_ID, MAIN_CHAT_ID and DUMMY_ID, and the time a
+/// player who left a game has to return, set through LEAVE_GRACE_SECS.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub manager: UserId,
+    pub main_chat: ChatId,
+    /// Account that adds and removes play chats.
+    pub dummy: UserId,
+    pub leave_grace: Duration,
+}
+
+impl Config {
+    const DUMMY: i64 = 6160579794i64;
+    const MANAGER: i64 = 80788292i64;
+    const MAIN_CHAT: i64 = -1001053502877i64;
+    const LEAVE_GRACE: u64 = 120;
+
+    pub fn from_env() -> Self {
+        Self {
+            manager: UserId::new(Self::env_value("MANAGER_ID", Self::MANAGER)),
+            main_chat: ChatId::new(Self::env_value("MAIN_CHAT_ID", Self::MAIN_CHAT)),
+            dummy: UserId::new(Self::env_value("DUMMY_ID", Self::DUMMY)),
+            leave_grace: Duration::from_secs(Self::env_value(
+                "LEAVE_GRACE_SECS",
+                Self::LEAVE_GRACE,
+            )),
+        }
+    }
+
+    /// The default is only for an unset variable, a malformed one stops the bot from starting.
+    fn env_value<T: FromStr>(name: &str, default: T) -> T {
+        match env::var(name) {
+            Ok(value) => value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} has an invalid value: {}", name, value)),
+            Err(VarError::NotPresent) => default,
+            Err(err) => panic!("{} can't be read: {}", name, err),
+        }
+    }
+
+    /// The configured manager or one promoted by them.
+    pub fn is_manager(&self, user_id: UserId, data: &Data) -> bool {
+        user_id == self.manager || data.is_manager(user_id).log_error().unwrap_or(false)
+    }
+}
+
 #[derive(Debug)]
 pub struct StatusUpdate {
     pub chat_id: i64,
@@ -106,30 +320,59 @@ pub struct StatusUpdate {
 }
 
 enum Event {
-    FromScheduler(Message),
-    FromPlay(Message),
+    FromScheduler(BotUpdate),
+    FromPlay(BotUpdate),
     GameDataTimeout(ChatId, u32),
     GameStatus(StatusUpdate),
     QueueGame((GameStartData, String, Vec<usize>)),
+    RatingReset,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GameStartData {
     chat_ids: Vec<ChatId>,
     set_id: Option<String>,
     topic_count: u8,
+    timings: Timings,
+    final_round: bool,
     players: HashMap<UserId, UserData>,
     spectators: HashMap<UserId, UserData>,
+    teams: HashMap<UserId, u8>,
+    blind: bool,
+    topic_picker: TopicPicker,
+    strict: bool,
+    /// Forum topics of the chats the game was proposed in.
+    threads: HashMap<ChatId, Integer>,
+}
+
+impl GameStartData {
+    fn thread_id(&self, chat_id: ChatId) -> Option<Integer> {
+        self.threads.get(&chat_id).copied()
+    }
 }
 
 struct GameData {
     chat_id: ChatId,
+    /// Forum topics of the chats following the proposal, the others get it in the general topic.
+    threads: HashMap<ChatId, Integer>,
     set_id: Option<String>,
     topic_count: u8,
     min_players: u8,
     max_players: u8,
+    timings: Timings,
+    final_round: bool,
+    teams: bool,
+    /// Player limits from before the teams were turned on, restored when they are turned off.
+    limits_before_teams: Option<(u8, u8)>,
+    blind: bool,
+    topic_picker: TopicPicker,
+    strict: bool,
     players: HashMap<UserId, UserData>,
     spectators: HashMap<UserId, UserData>,
+    /// Chats whose proposals were merged into this one, the game is played for them as well.
+    linked_chats: Vec<ChatId>,
+    /// Code other chats use to join this game.
+    link_code: Option<String>,
     update_id: u32,
     sender: UnboundedSender<Event>,
     expire_timer: Option<JoinHandle<()>>,
@@ -138,16 +381,34 @@ struct GameData {
 
 impl GameData {
     const TIMEOUT: Duration = Duration::from_secs(300);
+    const TEAM_PLAYERS: u8 = 4;
 
-    pub fn new(sender: UnboundedSender<Event>, chat_id: ChatId, data: Data) -> Self {
+    pub fn new(
+        sender: UnboundedSender<Event>,
+        chat_id: ChatId,
+        thread_id: Option<Integer>,
+        data: Data,
+    ) -> Self {
         let mut res = Self {
             chat_id,
+            threads: thread_id
+                .map(|thread_id| HashMap::from([(chat_id, thread_id)]))
+                .unwrap_or_default(),
             set_id: None,
             topic_count: 6,
             min_players: 3,
             max_players: 4,
+            timings: Timings::default(),
+            final_round: false,
+            teams: false,
+            limits_before_teams: None,
+            blind: false,
+            topic_picker: TopicPicker::None,
+            strict: false,
             players: HashMap::new(),
             spectators: HashMap::new(),
+            linked_chats: Vec::new(),
+            link_code: None,
             update_id: 0u32,
             sender,
             expire_timer: None,
@@ -181,13 +442,25 @@ impl GameData {
     //noinspection RsSelfConvention
     pub fn to_string(&mut self) -> String {
         for (user_id, user_data) in self.players.iter_mut() {
-            *user_data = self.data.update_player(*user_id, user_data.clone());
+            if let Some(updated) = self
+                .data
+                .update_player(*user_id, user_data.clone())
+                .log_error()
+            {
+                *user_data = updated;
+            }
         }
         for (user_id, user_data) in self.spectators.iter_mut() {
-            *user_data = self.data.update_player(*user_id, user_data.clone());
+            if let Some(updated) = self
+                .data
+                .update_player(*user_id, user_data.clone())
+                .log_error()
+            {
+                *user_data = updated;
+            }
         }
         format!(
-            "{}\nТем - {}\nИгроков - {}-{}\nИгроки: {}\nЗрители: {}",
+            "{}\nТем - {}\nИгроков - {}-{}\nВремя на размышление - {}/{} сек, на ответ - {} сек\nПаузы - {} на игрока, до {} мин\nФинал со ставками - {}\nКомандная игра - {}\nСкрытые темы - {}\nВыбор темы - {}\nСтрогая проверка ответов - {}\nИгроки: {}\nЗрители: {}",
             if let Some(id) = &self.set_id {
                 format!("Игра по пакету {}", id)
             } else {
@@ -196,6 +469,16 @@ impl GameData {
             self.topic_count,
             self.min_players,
             self.max_players,
+            self.timings.first_thinking,
+            self.timings.successive_thinking,
+            self.timings.answer,
+            self.timings.pauses,
+            self.timings.pause_minutes,
+            if self.final_round { "да" } else { "нет" },
+            if self.teams { "да" } else { "нет" },
+            if self.blind { "да" } else { "нет" },
+            self.topic_picker.description(),
+            if self.strict { "да" } else { "нет" },
             player_list(&self.players.values().collect::<Vec<_>>()),
             player_list(&self.spectators.values().collect::<Vec<_>>()),
         )
@@ -221,10 +504,70 @@ impl GameData {
         self.schedule_expiration();
     }
 
-    pub fn add_player(&mut self, user_id: UserId, user_data: UserData) {
+    /// Keeps the pause limits, they are set separately.
+    pub fn set_timings(&mut self, timings: Timings) {
+        self.timings = Timings {
+            pauses: self.timings.pauses,
+            pause_minutes: self.timings.pause_minutes,
+            ..timings
+        };
+        self.schedule_expiration();
+    }
+
+    pub fn set_final_round(&mut self, final_round: bool) {
+        self.final_round = final_round;
+        self.schedule_expiration();
+    }
+
+    pub fn set_teams(&mut self, teams: bool) {
+        if teams && !self.teams {
+            self.limits_before_teams = Some((self.min_players, self.max_players));
+            self.min_players = Self::TEAM_PLAYERS;
+            self.max_players = Self::TEAM_PLAYERS;
+        } else if !teams {
+            if let Some((min_players, max_players)) = self.limits_before_teams.take() {
+                self.min_players = min_players;
+                self.max_players = max_players;
+            }
+        }
+        self.teams = teams;
+        self.schedule_expiration();
+    }
+
+    pub fn set_blind(&mut self, blind: bool) {
+        self.blind = blind;
+        self.schedule_expiration();
+    }
+
+    pub fn set_topic_picker(&mut self, topic_picker: TopicPicker) {
+        self.topic_picker = topic_picker;
+        self.schedule_expiration();
+    }
+
+    /// Timings with changed pause limits, see `Timings::with_pauses`.
+    pub fn set_pauses(&mut self, timings: Timings) {
+        self.timings = timings;
+        self.schedule_expiration();
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+        self.schedule_expiration();
+    }
+
+    pub fn add_player(&mut self, user_id: UserId, user_data: UserData) -> bool {
+        if self
+            .data
+            .is_globally_banned(user_id)
+            .log_error()
+            .unwrap_or(false)
+        {
+            return false;
+        }
         self.spectators.remove(&user_id);
         self.players.insert(user_id, user_data);
         self.schedule_expiration();
+        true
     }
 
     pub fn add_spectator(&mut self, user_id: UserId, user_data: UserData) {
@@ -239,13 +582,71 @@ impl GameData {
         self.schedule_expiration();
     }
 
+    /// Adds participants of a game proposed in other chats, a user registered in several chats is
+    /// counted once and playing takes precedence over watching.
+    pub fn merge(
+        &mut self,
+        players: HashMap<UserId, UserData>,
+        spectators: HashMap<UserId, UserData>,
+        chat_ids: Vec<ChatId>,
+        threads: HashMap<ChatId, Integer>,
+    ) {
+        for (user_id, user_data) in spectators {
+            if !self.players.contains_key(&user_id) {
+                self.spectators.insert(user_id, user_data);
+            }
+        }
+        for (user_id, user_data) in players {
+            self.spectators.remove(&user_id);
+            self.players.insert(user_id, user_data);
+        }
+        self.linked_chats.extend(chat_ids);
+        self.threads.extend(threads);
+        self.schedule_expiration();
+    }
+
+    pub fn chat_ids(&self) -> Vec<ChatId> {
+        let mut chat_ids = vec![self.chat_id];
+        chat_ids.extend(self.linked_chats.iter().cloned());
+        chat_ids
+    }
+
+    fn thread_id(&self, chat_id: ChatId) -> Option<Integer> {
+        self.threads.get(&chat_id).copied()
+    }
+
+    pub fn rematch(&mut self, start_data: &GameStartData) {
+        self.topic_count = start_data.topic_count;
+        self.min_players = start_data.players.len() as u8;
+        self.max_players = start_data.players.len() as u8;
+        self.timings = start_data.timings;
+        self.final_round = start_data.final_round;
+        self.teams = !start_data.teams.is_empty();
+        self.blind = start_data.blind;
+        self.topic_picker = start_data.topic_picker;
+        self.strict = start_data.strict;
+        self.players = start_data.players.clone();
+        self.spectators = start_data.spectators.clone();
+    }
+
     pub fn to_data(&self) -> GameStartData {
         GameStartData {
-            chat_ids: vec![self.chat_id],
+            chat_ids: self.chat_ids(),
             set_id: self.set_id.clone(),
             topic_count: self.topic_count,
+            timings: self.timings,
+            final_round: self.final_round,
             players: self.players.clone(),
             spectators: self.spectators.clone(),
+            teams: if self.teams {
+                assign_teams(&self.players)
+            } else {
+                HashMap::new()
+            },
+            blind: self.blind,
+            topic_picker: self.topic_picker,
+            strict: self.strict,
+            threads: self.threads.clone(),
         }
     }
 }
@@ -254,48 +655,90 @@ pub struct Main {
     data: Data,
     scheduler_bot: TelegramBot,
     play_bot: TelegramBot,
-    scheduler_stream: Option<UnboundedReceiverStream<Message>>,
-    play_stream: Option<UnboundedReceiverStream<Message>>,
+    scheduler_stream: Option<UnboundedReceiverStream<BotUpdate>>,
+    play_stream: Option<UnboundedReceiverStream<BotUpdate>>,
     status_sender: UnboundedSender<StatusUpdate>,
     status_receiver: Option<UnboundedReceiverStream<StatusUpdate>>,
     timeout_sender: UnboundedSender<Event>,
     timeout_receiver: Option<UnboundedReceiverStream<Event>>,
     queue: Option<PlayQueue>,
+    queue_handle: Option<JoinHandle<()>>,
     queue_sender: UnboundedSender<UpdateMessage>,
     queue_stream: Option<UnboundedReceiverStream<(GameStartData, String, Vec<usize>)>>,
     play_chats: HashSet<ChatId>,
-    games: HashMap<ChatId, (UnboundedSender<Message>, String)>,
+    games: HashMap<ChatId, (UnboundedSender<BotUpdate>, String)>,
+    game_players: HashMap<UserId, ChatId>,
     game_proposals: HashMap<ChatId, GameData>,
+    /// Chats that joined a game proposed in another chat, by the chat of the proposal.
+    linked_proposals: HashMap<ChatId, ChatId>,
+    link_codes: HashMap<String, ChatId>,
+    started_games: HashMap<ChatId, GameStartData>,
+    finished_games: HashMap<ChatId, (GameStartData, Instant)>,
+    /// Games found while all play chats were busy, started in order once chats are free.
+    pending_games: VecDeque<(GameStartData, String, Vec<usize>, bool)>,
+    rating_reset_interval: Duration,
     shutting_down: bool,
+    config: Config,
+    /// The configured manager and the ones promoted at runtime.
+    managers: HashSet<UserId>,
+    started: Instant,
 }
 
 impl Main {
-    const DUMMY: i64 = 6160579794i64;
-    const MANAGER: i64 = 80788292i64;
-    // pub const MAIN_CHAT: i64 = -741754684i64;
-    pub const MAIN_CHAT: i64 = -1001053502877i64;
+    const REMATCH_WINDOW: Duration = Duration::from_secs(600);
+    /// At most 25 broadcast messages a second.
+    const BROADCAST_INTERVAL: Duration = Duration::from_millis(40);
+    const BROADCAST_PROGRESS: usize = 100;
+    const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+    const RATING_RESET_DAYS: u64 = 7;
 
     pub async fn new() -> Self {
         let data = Data::new("svoyak.db");
+        let config = Config::from_env();
         let (scheduler_bot, scheduler_stream) =
             TelegramBot::new(env::var("SCHEDULER_BOT_TOKEN").unwrap());
-        let (play_bot, play_stream) = TelegramBot::new(env::var("PLAY_BOT_TOKEN").unwrap());
-        let (status_sender, status_receiver) = unbounded_channel();
-        let (timeout_sender, timeout_receiver) = unbounded_channel();
-        let (queue_sender, queue_receiver) = unbounded_channel();
+        let play = TelegramBot::new(env::var("PLAY_BOT_TOKEN").unwrap());
         let message_id = scheduler_bot
             .send_message(
-                ChatId::new(Self::MAIN_CHAT),
+                config.main_chat,
                 "Всего игроков в очереди <b>0</b>\nДля игры пройдите в @SvoyakSchedulerBot"
                     .to_string(),
                 KeyboardOptions::None,
             )
             .await
             .unwrap();
+        Self::with_bots(
+            data,
+            config,
+            (scheduler_bot, scheduler_stream),
+            play,
+            message_id,
+        )
+    }
+
+    /// `message_id` is the queue message already sent to the main chat.
+    fn with_bots(
+        data: Data,
+        config: Config,
+        (scheduler_bot, scheduler_stream): (TelegramBot, UnboundedReceiverStream<BotUpdate>),
+        (play_bot, play_stream): (TelegramBot, UnboundedReceiverStream<BotUpdate>),
+        message_id: MessageId,
+    ) -> Self {
+        let mut managers = data
+            .get_managers()
+            .log_error()
+            .unwrap_or_default()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        managers.insert(config.manager);
+        let (status_sender, status_receiver) = unbounded_channel();
+        let (timeout_sender, timeout_receiver) = unbounded_channel();
+        let (queue_sender, queue_receiver) = unbounded_channel();
         let (queue, queue_stream) = PlayQueue::new(
             data.clone(),
             scheduler_bot.clone(),
             UnboundedReceiverStream::new(queue_receiver),
+            config.main_chat,
             message_id,
         );
 
@@ -310,27 +753,63 @@ impl Main {
             timeout_sender,
             timeout_receiver: Some(UnboundedReceiverStream::new(timeout_receiver)),
             queue: Some(queue),
+            queue_handle: None,
             queue_sender,
             queue_stream: Some(queue_stream),
             play_chats: HashSet::new(),
             games: HashMap::new(),
+            game_players: HashMap::new(),
             game_proposals: HashMap::new(),
+            linked_proposals: HashMap::new(),
+            link_codes: HashMap::new(),
+            started_games: HashMap::new(),
+            finished_games: HashMap::new(),
+            pending_games: VecDeque::new(),
+            rating_reset_interval: Duration::from_secs(
+                env::var("RATING_RESET_DAYS")
+                    .ok()
+                    .and_then(|days| days.parse::<u64>().ok())
+                    .unwrap_or(Self::RATING_RESET_DAYS)
+                    * 24
+                    * 60
+                    * 60,
+            ),
             shutting_down: false,
+            config,
+            managers,
+            started: Instant::now(),
         }
     }
 
     pub async fn run(mut self) {
         self.scheduler_bot
-            .set_commands(ChatId::new(Self::MAIN_CHAT), ChatId::new(Self::MANAGER))
-            .await;
-        self.play_chats = self.data.get_game_chats().iter().map(|id| *id).collect();
-        for game in self.data.get_game_states() {
-            self.start_game(game);
+            .set_commands(
+                self.config.main_chat,
+                &self
+                    .managers
+                    .iter()
+                    .map(|manager| ChatId::from(*manager))
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .log_error();
+        self.play_bot.set_play_commands().await.log_error();
+        self.play_chats = self
+            .data
+            .get_game_chats()
+            .log_error()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        for game in self.data.get_game_states().log_error().unwrap_or_default() {
+            self.start_game(game, true);
         }
+        self.data.prune_set_versions().log_error();
+        self.process_rating_reset();
         let mut queue = self.queue.take().unwrap();
-        tokio::spawn(async move {
+        self.queue_handle = Some(tokio::spawn(async move {
             queue.start().await;
-        });
+        }));
         let mut stream = select_all(vec![
             self.scheduler_stream
                 .take()
@@ -356,12 +835,18 @@ impl Main {
         ]);
         while let Some(event) = stream.next().await {
             match event {
-                Event::FromScheduler(message) => {
-                    self.process_scheduler_message(message).await;
+                Event::FromScheduler(BotUpdate::Message(message, thread_id)) => {
+                    self.process_scheduler_message(message, thread_id).await;
+                }
+                Event::FromScheduler(BotUpdate::CallbackQuery(query)) => {
+                    self.scheduler_bot.try_answer_callback_query(query);
                 }
-                Event::FromPlay(message) => {
+                Event::FromPlay(BotUpdate::Message(message, _)) => {
                     self.process_play_message(message).await;
                 }
+                Event::FromPlay(BotUpdate::CallbackQuery(query)) => {
+                    self.process_play_callback_query(query);
+                }
                 Event::GameDataTimeout(chat_id, update_id) => {
                     self.process_game_data_timeout(&chat_id, update_id)
                 }
@@ -372,28 +857,144 @@ impl Main {
                     self.start_game_with_topics(&game_start_data, set_id, topics, true)
                         .await;
                 }
+                Event::RatingReset => {
+                    self.process_rating_reset();
+                }
             }
             if self.shutting_down && self.games.is_empty() {
                 break;
             }
         }
+        // The queue notifies its players on shutdown, which has to finish before the bot exits
+        if let Some(handle) = self.queue_handle.take() {
+            if let Err(err) = handle.await {
+                log::error!("Queue task failed: {}", err);
+            }
+        }
         self.scheduler_bot
             .send_message(
-                ChatId::new(Self::MANAGER),
+                self.config.manager.into(),
                 "Бот выключен".to_string(),
                 KeyboardOptions::Remove,
             )
-            .await;
+            .await
+            .log_error();
+    }
+
+    fn process_rating_reset(&mut self) {
+        if self
+            .data
+            .rating_reset(SystemTime::now(), self.rating_reset_interval)
+            .log_error()
+            .unwrap_or(false)
+        {
+            self.scheduler_bot.try_send_message(
+                self.config.main_chat,
+                "Рейтинги всех игроков приближены к начальному на 1%".to_string(),
+            );
+        }
+        let delay = self
+            .data
+            .get_next_reset()
+            .log_error()
+            .flatten()
+            .and_then(|next_reset| next_reset.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default();
+        let sender = self.timeout_sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            match sender.send(Event::RatingReset) {
+                Ok(_) => {}
+                Err(err) => log::error!("Error with sending update: {}", err),
+            }
+        });
     }
 
     async fn process_status_update(&mut self, update: StatusUpdate) {
         match update.update_type {
             UpdateType::StatusUpdate(status) => {
-                self.games.get_mut(&ChatId::new(update.chat_id)).unwrap().1 = status;
+                // The game may not know yet that its chat has migrated
+                if let Some(game) = self.games.get_mut(&ChatId::new(update.chat_id)) {
+                    game.1 = status;
+                }
+            }
+            UpdateType::PlayerReplaced(original, substitute) => {
+                let chat_id = ChatId::new(update.chat_id);
+                self.game_players.remove(&UserId::new(original));
+                self.game_players.insert(UserId::new(substitute), chat_id);
+                if let Some(game_data) = self.started_games.get_mut(&chat_id) {
+                    game_data.players.remove(&UserId::new(original));
+                    if let Some(user_data) = self
+                        .data
+                        .get_user_data(&UserId::new(substitute))
+                        .log_error()
+                        .flatten()
+                    {
+                        game_data.players.insert(UserId::new(substitute), user_data);
+                    }
+                    if let Some(team) = game_data.teams.remove(&UserId::new(original)) {
+                        game_data.teams.insert(UserId::new(substitute), team);
+                    }
+                }
             }
             UpdateType::GameEnded => {
-                self.games.remove(&ChatId::new(update.chat_id));
+                let chat_id = ChatId::new(update.chat_id);
+                self.games.remove(&chat_id);
+                if let Some(game_data) = self.started_games.remove(&chat_id) {
+                    for source_id in game_data.chat_ids.iter() {
+                        if !game_data
+                            .players
+                            .contains_key(&UserId::new((*source_id).into()))
+                        {
+                            self.finished_games
+                                .insert(*source_id, (game_data.clone(), Instant::now()));
+                        }
+                    }
+                }
+                self.game_players
+                    .retain(|_, game_chat_id| *game_chat_id != chat_id);
+                self.start_pending_game().await;
+            }
+        }
+    }
+
+    async fn start_pending_game(&mut self) {
+        if self.shutting_down {
+            return;
+        }
+        while let Some((game_data, set_id, topics, from_private)) = self.pending_games.pop_front() {
+            if game_data
+                .players
+                .keys()
+                .any(|user_id| self.game_players.contains_key(user_id))
+            {
+                self.send_to_chats(
+                    &game_data,
+                    "Игра отменена, один из игроков уже играет в другой игре".to_string(),
+                );
+                continue;
             }
+            self.start_game_with_topics(&game_data, set_id, topics, from_private)
+                .await;
+            break;
+        }
+    }
+
+    /// Sends the message to the chats the game was proposed in, each to its forum topic.
+    fn send_to_chats(&self, game_data: &GameStartData, message: String) {
+        for chat_id in game_data.chat_ids.iter() {
+            self.scheduler_bot
+                .in_thread(game_data.thread_id(*chat_id))
+                .try_send_message(*chat_id, message.clone());
+        }
+    }
+
+    fn cancel_pending_games(&mut self) {
+        for (game_data, ..) in self.pending_games.drain(..) {
+            self.send_to_chats(
+                &game_data,
+                "Игра отменена, бот будет перезагружен".to_string(),
+            );
         }
     }
 
@@ -418,7 +1019,8 @@ impl Main {
                 match command {
                     "shutdown" | "выключение" => {
                         self.shutting_down = true;
-                        self.send_shutting_down(ChatId::new(Self::MAIN_CHAT));
+                        self.send_shutting_down(&self.scheduler_bot, self.config.main_chat);
+                        self.cancel_pending_games();
                         self.queue_sender.send(UpdateMessage::Shutdown).unwrap();
                         true
                     }
@@ -435,18 +1037,22 @@ impl Main {
                                     );
                                 }
                                 Some(_) => {
-                                    if self.data.is_active(&tokens[0].to_string()) {
-                                        self.scheduler_bot.try_send_message(
-                                            chat_id,
-                                            "Пакет уже включен".to_string(),
-                                        );
-                                    } else {
-                                        self.data.add_active(&tokens[0].to_string());
-                                        self.scheduler_bot.try_send_message(
-                                            chat_id,
-                                            format!("Пакет включен - {}", tokens[0]),
-                                        );
-                                    }
+                                    let set_id = tokens[0].to_string();
+                                    let message = self.data.is_active(&set_id).and_then(|active| {
+                                        if active {
+                                            Ok("Пакет уже включен".to_string())
+                                        } else {
+                                            self.data
+                                                .add_active(&set_id)
+                                                .map(|_| format!("Пакет включен - {}", set_id))
+                                        }
+                                    });
+                                    self.scheduler_bot.try_send_message(
+                                        chat_id,
+                                        message
+                                            .log_error()
+                                            .unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+                                    );
                                 }
                             }
                         }
@@ -465,18 +1071,22 @@ impl Main {
                                     );
                                 }
                                 Some(_) => {
-                                    if !self.data.is_active(&tokens[0].to_string()) {
-                                        self.scheduler_bot.try_send_message(
-                                            chat_id,
-                                            "Пакет уже выключен".to_string(),
-                                        );
-                                    } else {
-                                        self.data.remove_active(&tokens[0].to_string());
-                                        self.scheduler_bot.try_send_message(
-                                            chat_id,
-                                            format!("Пакет выключен - {}", tokens[0]),
-                                        );
-                                    }
+                                    let set_id = tokens[0].to_string();
+                                    let message = self.data.is_active(&set_id).and_then(|active| {
+                                        if !active {
+                                            Ok("Пакет уже выключен".to_string())
+                                        } else {
+                                            self.data
+                                                .remove_active(&set_id)
+                                                .map(|_| format!("Пакет выключен - {}", set_id))
+                                        }
+                                    });
+                                    self.scheduler_bot.try_send_message(
+                                        chat_id,
+                                        message
+                                            .log_error()
+                                            .unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+                                    );
                                 }
                             }
                         }
@@ -495,10 +1105,18 @@ impl Main {
                                     );
                                 }
                                 Some(set) => {
+                                    let disabled = self
+                                        .data
+                                        .get_disabled_topics(&set.id)
+                                        .log_error()
+                                        .flatten();
                                     let mut list = "<b>Список тем:</b>".to_string();
                                     for (i, topic) in set.topics.iter().enumerate() {
                                         list +=
                                             format!("\n<b>{}.</b> {}", i + 1, topic.name).as_str();
+                                        if disabled.as_ref().is_some_and(|bits| bits.is_set(i)) {
+                                            list += " (отключена)";
+                                        }
                                     }
                                     self.scheduler_bot.try_send_message(chat_id, list);
                                 }
@@ -506,46 +1124,558 @@ impl Main {
                         }
                         true
                     }
-                    _ => false,
-                }
-            }
-            MessageKind::Document { data, .. } => {
-                match self.scheduler_bot.get_file(data.clone()).await {
-                    None => {
-                        self.scheduler_bot
-                            .try_send_message(chat_id, "Не удалось скачать".to_string());
+                    "preview" | "просмотр" => {
+                        self.preview(chat_id, tokens);
+                        true
                     }
-                    Some((id, content)) => match parse(id, content) {
-                        None => {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Не удалось распарсить".to_string());
-                        }
-                        Some(set) => {
-                            let id = set.id.clone();
-                            if self.data.add_new_set(&id, set) {
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, "Пакет загружен".to_string());
-                            } else {
+                    "disabletopic" | "enabletopic" => {
+                        self.disable_topic(chat_id, tokens, command == "disabletopic");
+                        true
+                    }
+                    "gban" | "gunban" => {
+                        let banned = command == "gban";
+                        let user = match tokens.first() {
+                            None => None,
+                            Some(token) => match token.strip_prefix('@') {
+                                Some(username) => {
+                                    self.data.find_username(username).log_error().flatten()
+                                }
+                                None => token.parse::<i64>().ok().map(UserId::new),
+                            },
+                        };
+                        match user {
+                            None => {
                                 self.scheduler_bot.try_send_message(
                                     chat_id,
-                                    "Пакет уже был активным с другим числом тем".to_string(),
+                                    "Укажите id или @username пользователя".to_string(),
                                 );
                             }
+                            Some(user) => {
+                                let message = match self.data.set_globally_banned(user, banned) {
+                                    Err(err) => {
+                                        log::error!("{}", err);
+                                        DB_ERROR_MESSAGE
+                                    }
+                                    Ok(false) => "Ничего не изменилось",
+                                    Ok(true) if banned => {
+                                        "Пользователь исключен из рейтинговых игр"
+                                    }
+                                    Ok(true) => "Пользователь снова допущен к рейтинговым играм",
+                                };
+                                self.scheduler_bot
+                                    .try_send_message(chat_id, format!("{} - {}", message, user));
+                            }
                         }
-                    },
-                }
-                true
-            }
-            _ => false,
-        }
-    }
-
-    async fn process_private_message(&mut self, message: Message) {
-        if message.from.is_none() {
-            return;
+                        true
+                    }
+                    "gbanlist" => {
+                        let banned = self
+                            .data
+                            .get_global_ban_list()
+                            .log_error()
+                            .unwrap_or_default();
+                        let mut message = "<b>Исключены из рейтинговых игр:</b>".to_string();
+                        for id in banned {
+                            message += format!(
+                                "\n{} {}",
+                                id,
+                                self.data
+                                    .get_user_data(&id)
+                                    .log_error()
+                                    .flatten()
+                                    .map(|user_data| user_data.display_name())
+                                    .unwrap_or_default()
+                            )
+                            .as_str();
+                        }
+                        self.scheduler_bot.try_send_message(chat_id, message);
+                        true
+                    }
+                    "manager" | "unmanager" => {
+                        if message.from.as_ref().map(|from| from.id) != Some(self.config.manager) {
+                            self.scheduler_bot.try_send_message(
+                                chat_id,
+                                "Назначать менеджеров может только основной менеджер".to_string(),
+                            );
+                            return true;
+                        }
+                        let manager = command == "manager";
+                        let user = match tokens.first() {
+                            None => None,
+                            Some(token) => match token.strip_prefix('@') {
+                                Some(username) => {
+                                    self.data.find_username(username).log_error().flatten()
+                                }
+                                None => token.parse::<i64>().ok().map(UserId::new),
+                            },
+                        };
+                        match user {
+                            None => {
+                                self.scheduler_bot.try_send_message(
+                                    chat_id,
+                                    "Укажите id или @username пользователя".to_string(),
+                                );
+                            }
+                            Some(user) if user == self.config.manager => {
+                                self.scheduler_bot.try_send_message(
+                                    chat_id,
+                                    "Основной менеджер задается при запуске".to_string(),
+                                );
+                            }
+                            Some(user) => {
+                                let message = match self.data.set_manager(user, manager) {
+                                    Err(err) => {
+                                        log::error!("{}", err);
+                                        DB_ERROR_MESSAGE
+                                    }
+                                    Ok(false) => "Ничего не изменилось",
+                                    Ok(true) if manager => {
+                                        self.managers.insert(user);
+                                        self.scheduler_bot
+                                            .set_manager_commands(user.into())
+                                            .await
+                                            .log_error();
+                                        "Пользователь назначен менеджером"
+                                    }
+                                    Ok(true) => {
+                                        self.managers.remove(&user);
+                                        self.scheduler_bot
+                                            .remove_manager_commands(user.into())
+                                            .await
+                                            .log_error();
+                                        "Пользователь больше не менеджер"
+                                    }
+                                };
+                                self.scheduler_bot
+                                    .try_send_message(chat_id, format!("{} - {}", message, user));
+                            }
+                        }
+                        true
+                    }
+                    "limit" | "лимит" => {
+                        match tokens.first().map(|token| token.parse::<u32>()) {
+                            None => {
+                                let message = self
+                                    .data
+                                    .set_daily_limit(None)
+                                    .map(|_| "Ограничение числа игр в день снято".to_string());
+                                self.scheduler_bot.try_send_message(
+                                    chat_id,
+                                    message
+                                        .log_error()
+                                        .unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+                                );
+                            }
+                            Some(Ok(limit)) => {
+                                let message = self
+                                    .data
+                                    .set_daily_limit(Some(limit))
+                                    .map(|_| format!("Не более {} игр в день", limit));
+                                self.scheduler_bot.try_send_message(
+                                    chat_id,
+                                    message
+                                        .log_error()
+                                        .unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+                                );
+                            }
+                            Some(Err(_)) => {
+                                self.scheduler_bot.try_send_message(
+                                    chat_id,
+                                    format!("Некорректное число - {}", tokens[0]),
+                                );
+                            }
+                        }
+                        true
+                    }
+                    "rematch" | "повтор" => {
+                        let minutes = match tokens.first().map(|token| token.parse::<u32>()) {
+                            None => self.data.get_rematch_delay(),
+                            Some(Ok(minutes)) => {
+                                self.data.set_rematch_delay(minutes).map(|_| minutes)
+                            }
+                            Some(Err(_)) => {
+                                self.scheduler_bot.try_send_message(
+                                    chat_id,
+                                    format!("Некорректное число - {}", tokens[0]),
+                                );
+                                return true;
+                            }
+                        };
+                        let message = minutes.map(|minutes| {
+                            format!(
+                                "Недавние соперники не попадают в одну игру первые {} минут ожидания в очереди",
+                                minutes
+                            )
+                        });
+                        self.scheduler_bot.try_send_message(
+                            chat_id,
+                            message
+                                .log_error()
+                                .unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+                        );
+                        true
+                    }
+                    "announce" | "объявления" => {
+                        let message = self.data.announce_results().and_then(|announce| {
+                            self.data.set_announce_results(!announce).map(|_| {
+                                format!(
+                                    "Результаты игр {}публикуются в основном чате",
+                                    if announce { "не " } else { "" }
+                                )
+                            })
+                        });
+                        self.scheduler_bot.try_send_message(
+                            chat_id,
+                            message
+                                .log_error()
+                                .unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+                        );
+                        true
+                    }
+                    "copies" | "повторы" => {
+                        let message = self.data.copies_played().and_then(|copies_played| {
+                            self.data.set_copies_played(!copies_played).map(|_| {
+                                format!(
+                                    "Темы, сыгранные в других пакетах, {}считаются сыгранными",
+                                    if copies_played { "не " } else { "" }
+                                )
+                            })
+                        });
+                        self.scheduler_bot.try_send_message(
+                            chat_id,
+                            message
+                                .log_error()
+                                .unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+                        );
+                        true
+                    }
+                    "games" | "игры" => {
+                        let limit = match tokens.first().map(|token| token.parse::<usize>()) {
+                            Some(Ok(value)) => value.min(Self::RATING_LIST_LIMIT),
+                            _ => 20,
+                        };
+                        self.recent_games(chat_id, limit);
+                        true
+                    }
+                    "adjustments" | "исправления" => {
+                        match tokens.first().map(|token| token.parse::<u64>()) {
+                            Some(Ok(game_id)) => self.adjustments(chat_id, game_id),
+                            _ => {
+                                self.scheduler_bot
+                                    .try_send_message(chat_id, "Укажите номер игры".to_string());
+                            }
+                        }
+                        true
+                    }
+                    "export" | "экспорт" => {
+                        let with_sets = match tokens.first() {
+                            None => false,
+                            Some(&"sets") | Some(&"пакеты") => true,
+                            Some(set_id) => {
+                                self.export_set(chat_id, set_id);
+                                return true;
+                            }
+                        };
+                        let bot = self.scheduler_bot.clone();
+                        let data = self.data.clone();
+                        tokio::spawn(async move {
+                            match data.export(with_sets).log_error() {
+                                None => {
+                                    bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                                }
+                                Some(dump) => {
+                                    bot.send_document(
+                                        chat_id,
+                                        format!("svoyak-{}.json", date(SystemTime::now())),
+                                        serde_json::to_vec(&dump).unwrap(),
+                                    )
+                                    .await
+                                    .log_error();
+                                }
+                            }
+                        });
+                        true
+                    }
+                    "broadcast" | "рассылка" => {
+                        // Line breaks and spacing of the announcement are kept as written
+                        let body = text.split_once(' ').map_or("", |(_, body)| body).trim();
+                        if body.is_empty() {
+                            self.scheduler_bot
+                                .try_send_message(chat_id, "Текст рассылки не указан".to_string());
+                        } else {
+                            tokio::spawn(Self::broadcast(
+                                self.scheduler_bot.clone(),
+                                self.data.clone(),
+                                chat_id,
+                                html_escape::encode_text(body).to_string(),
+                            ));
+                        }
+                        true
+                    }
+                    "health" | "состояние" => {
+                        let message = self.health().await;
+                        self.scheduler_bot.try_send_message(chat_id, message);
+                        true
+                    }
+                    "import" | "импорт" => {
+                        self.scheduler_bot.try_send_message(
+                            chat_id,
+                            "Отправьте файл выгрузки с подписью /import".to_string(),
+                        );
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            MessageKind::Document { data, caption } => {
+                if matches!(
+                    caption.as_deref().map(|caption| caption.trim()),
+                    Some("/import") | Some("/импорт")
+                ) {
+                    self.import_data(chat_id, data.clone()).await;
+                    return true;
+                }
+                // Intentionally unusual packs are uploaded without the checks
+                let forced = matches!(
+                    caption.as_deref().map(|caption| caption.trim()),
+                    Some("/force") | Some("/принудительно")
+                );
+                match self.scheduler_bot.get_file_bytes(data.clone()).await {
+                    Err(err) => {
+                        self.scheduler_bot
+                            .try_send_message(chat_id, Self::file_error_message(err));
+                    }
+                    Ok((id, content)) => match parse(id, content) {
+                        Err(report) => {
+                            self.scheduler_bot.try_send_message(
+                                chat_id,
+                                format!("Не удалось распарсить\n{}", report),
+                            );
+                        }
+                        Ok((set, report)) => {
+                            let problems = if forced { Vec::new() } else { check_set(&set) };
+                            if !problems.is_empty() {
+                                let mut message = "Пакет не загружен:".to_string();
+                                message += ParseReport::limited(&problems, "И еще ошибок").as_str();
+                                message += "\nЧтобы загрузить пакет как есть, отправьте файл с подписью /force";
+                                self.scheduler_bot.try_send_message(chat_id, message);
+                                return true;
+                            }
+                            let id = set.id.clone();
+                            let duplicates = self
+                                .data
+                                .find_duplicate_topics(&id, &set)
+                                .log_error()
+                                .unwrap_or_default();
+                            let added = match self.data.add_new_set(&id, set) {
+                                Ok(added) => added,
+                                Err(err) => {
+                                    log::error!("{}", err);
+                                    self.scheduler_bot
+                                        .try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                                    return true;
+                                }
+                            };
+                            let mut message = if added {
+                                "Пакет загружен"
+                            } else {
+                                "Пакет уже был активным с большим числом тем"
+                            }
+                            .to_string();
+                            message += format!("\n{}", report).as_str();
+                            for duplicate in duplicates.iter() {
+                                message += format!(
+                                    "\nТема {} совпадает с {}/{}",
+                                    duplicate.index + 1,
+                                    html_escape::encode_text(&duplicate.original_set),
+                                    duplicate.original_index + 1
+                                )
+                                .as_str();
+                            }
+                            self.scheduler_bot.try_send_message(chat_id, message);
+                        }
+                    },
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Questions and answers of a topic, for managers to check a set before turning it on.
+    /// Finds the set and the zero-based topic index given as `<set id> <номер>`, reports
+    /// problems to the chat.
+    fn find_set_topic(&self, chat_id: ChatId, tokens: &[&str]) -> Option<(Arc<TopicSet>, usize)> {
+        let set = match tokens.first() {
+            None => {
+                self.scheduler_bot
+                    .try_send_message(chat_id, "Пакет не указан".to_string());
+                return None;
+            }
+            Some(set_id) => match self.data.get_set(&set_id.to_string()) {
+                None => {
+                    self.scheduler_bot.try_send_message(
+                        chat_id,
+                        format!("Неизвестный пакет - {}", html_escape::encode_text(set_id)),
+                    );
+                    return None;
+                }
+                Some(set) => set,
+            },
+        };
+        match tokens.get(1).map(|token| token.parse::<usize>()) {
+            Some(Ok(number)) if (1..=set.topics.len()).contains(&number) => Some((set, number - 1)),
+            _ => {
+                self.scheduler_bot.try_send_message(
+                    chat_id,
+                    format!("Укажите номер темы от 1 до {}", set.topics.len()),
+                );
+                None
+            }
+        }
+    }
+
+    fn preview(&self, chat_id: ChatId, tokens: &[&str]) {
+        let (set, index) = match self.find_set_topic(chat_id, tokens) {
+            None => return,
+            Some(found) => found,
+        };
+        let topic = &set.topics[index];
+        let mut message = format!("<b>{}</b>", topic.name);
+        for question in topic.questions.iter() {
+            message += format!(
+                "\n\n{}\n{}",
+                question.display_question(&topic.name),
+                question.display_answers(false)
+            )
+            .as_str();
+        }
+        self.scheduler_bot.try_send_message(chat_id, message);
+    }
+
+    fn disable_topic(&self, chat_id: ChatId, tokens: &[&str], to_disable: bool) {
+        let (set, index) = match self.find_set_topic(chat_id, tokens) {
+            None => return,
+            Some(found) => found,
+        };
+        let state = if to_disable {
+            "отключена"
+        } else {
+            "включена"
+        };
+        let message = self
+            .data
+            .set_topic_disabled(&set.id, index, to_disable)
+            .log_error()
+            .map(|changed| {
+                format!(
+                    "Тема {} пакета {} {}{}",
+                    index + 1,
+                    html_escape::encode_text(&set.id),
+                    if changed { "" } else { "уже " },
+                    state
+                )
+            });
+        self.scheduler_bot.try_send_message(
+            chat_id,
+            message.unwrap_or_else(|| DB_ERROR_MESSAGE.to_string()),
+        );
+    }
+
+    /// Sends the set as a file that can be uploaded again after fixing it.
+    fn export_set(&self, chat_id: ChatId, set_id: &str) {
+        let set = match self.data.get_set(&set_id.to_string()) {
+            None => {
+                self.scheduler_bot.try_send_message(
+                    chat_id,
+                    format!("Неизвестный пакет - {}", html_escape::encode_text(set_id)),
+                );
+                return;
+            }
+            Some(set) => set,
+        };
+        let bot = self.scheduler_bot.clone();
+        tokio::spawn(async move {
+            bot.send_document(
+                chat_id,
+                format!("{}.json", set.id),
+                serde_json::to_vec_pretty(&set.decoded()).unwrap(),
+            )
+            .await
+            .log_error();
+        });
+    }
+
+    fn file_error_message(err: FileError) -> String {
+        match err {
+            FileError::Unsupported => {
+                "Неподдерживаемый файл, нужен текстовый файл в кодировке UTF-8".to_string()
+            }
+            FileError::DownloadFailed => "Не удалось скачать".to_string(),
+        }
+    }
+
+    async fn import_data(&mut self, chat_id: ChatId, document: Document) {
+        if !self.games.is_empty() {
+            self.scheduler_bot.try_send_message(
+                chat_id,
+                "Нельзя восстановить базу во время игры".to_string(),
+            );
+            return;
+        }
+        let dump = match self.scheduler_bot.get_file(document).await {
+            Err(err) => {
+                self.scheduler_bot
+                    .try_send_message(chat_id, Self::file_error_message(err));
+                return;
+            }
+            Ok((_, content)) => match serde_json::from_str::<DataDump>(content.as_str()) {
+                Ok(dump) => dump,
+                Err(err) => {
+                    log::error!("Error parsing dump: {}", err);
+                    self.scheduler_bot
+                        .try_send_message(chat_id, "Не удалось распарсить".to_string());
+                    return;
+                }
+            },
+        };
+        match self.data.import(&dump) {
+            Ok(()) => {
+                self.play_chats = self
+                    .data
+                    .get_game_chats()
+                    .log_error()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect();
+                self.scheduler_bot
+                    .try_send_message(chat_id, "База восстановлена".to_string());
+            }
+            Err(DataError::SchemaMismatch(version)) => {
+                self.scheduler_bot.try_send_message(
+                    chat_id,
+                    format!(
+                        "Дамп сделан другой версией бота (схема {}), восстановить его нельзя",
+                        version
+                    ),
+                );
+            }
+            Err(err) => {
+                log::error!("{}", err);
+                self.scheduler_bot
+                    .try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+            }
+        }
+    }
+
+    async fn process_private_message(&mut self, message: Message) {
+        if message.from.is_none() {
+            return;
         }
         let from = message.from.clone().unwrap();
-        if from.id == UserId::new(Self::MANAGER) {
+        self.data.add_private_chat(from.id).log_error();
+        self.data.remember_username(&from).log_error();
+        if self.managers.contains(&from.id) {
             if self.process_manager_message(&message).await {
                 return;
             }
@@ -569,46 +1699,218 @@ impl Main {
                 let tokens = &tokens[1..];
                 match command {
                     "help" | "помощь" | "start" => {
-                        let help = Self::build_help(&PRIVATE_BOT_COMMANDS);
+                        let help = Self::build_help(
+                            &PRIVATE_BOT_COMMANDS,
+                            self.managers.contains(&user_id),
+                        );
                         self.scheduler_bot.try_send_message(user_id.into(), help);
                     }
                     "register" | "+" => {
+                        let mut set_id = None;
+                        let mut max_players = PlayQueue::DEFAULT_PLAYERS;
+                        let mut expiry = PlayQueue::DEFAULT_EXPIRY;
+                        let mut tokens = tokens.iter();
+                        while let Some(token) = tokens.next() {
+                            // A set id that looks like a number or a time is given after "set"
+                            if matches!(token.to_lowercase().as_str(), "set" | "пакет") {
+                                set_id = tokens.next().map(|id| id.to_string());
+                                continue;
+                            }
+                            let minutes = token
+                                .strip_suffix('m')
+                                .or_else(|| token.strip_suffix('м'))
+                                .and_then(|minutes| minutes.parse::<u64>().ok());
+                            match (token.parse::<usize>(), minutes) {
+                                (Ok(number), _) => max_players = number,
+                                (_, Some(minutes)) => {
+                                    expiry = Duration::from_secs(minutes * 60);
+                                }
+                                _ => set_id = Some(token.to_string()),
+                            }
+                        }
                         if self.shutting_down {
-                            self.send_shutting_down(user_id.into());
+                            self.send_shutting_down(&self.scheduler_bot, user_id.into());
+                        } else if !(PlayQueue::MIN_EXPIRY..=PlayQueue::MAX_EXPIRY).contains(&expiry)
+                        {
+                            self.scheduler_bot.try_send_message(
+                                user_id.into(),
+                                format!(
+                                    "Время ожидания должно быть от {} до {} минут",
+                                    PlayQueue::MIN_EXPIRY.as_secs() / 60,
+                                    PlayQueue::MAX_EXPIRY.as_secs() / 60
+                                ),
+                            );
+                        } else if !(PlayQueue::DEFAULT_PLAYERS..=PlayQueue::MAX_PLAYERS)
+                            .contains(&max_players)
+                        {
+                            self.scheduler_bot.try_send_message(
+                                user_id.into(),
+                                format!(
+                                    "Размер игры должен быть от {} до {}",
+                                    PlayQueue::DEFAULT_PLAYERS,
+                                    PlayQueue::MAX_PLAYERS
+                                ),
+                            );
+                        } else if let Some(set_id) = set_id.as_ref().filter(|set_id| {
+                            !self.data.is_active(set_id).log_error().unwrap_or(false)
+                        }) {
+                            self.scheduler_bot.try_send_message(
+                                user_id.into(),
+                                format!(
+                                    "Пакет не обнаружен - {}",
+                                    html_escape::encode_text(&set_id)
+                                ),
+                            );
                         } else {
-                            self.data
-                                .update_player(user_id, self.data.get_or_create_user(from));
-                            self.queue_sender
-                                .send(UpdateMessage::UserEntered(user_id))
-                                .unwrap();
+                            let updated = self
+                                .data
+                                .get_or_create_user(from)
+                                .and_then(|user_data| self.data.update_player(user_id, user_data));
+                            if updated.log_error().is_some() {
+                                self.queue_sender
+                                    .send(UpdateMessage::UserEntered(
+                                        user_id,
+                                        set_id,
+                                        max_players,
+                                        expiry,
+                                    ))
+                                    .unwrap();
+                            } else {
+                                self.scheduler_bot
+                                    .try_send_message(user_id.into(), DB_ERROR_MESSAGE.to_string());
+                            }
                         }
                     }
                     "unregister" | "-" => {
                         if self.shutting_down {
-                            self.send_shutting_down(user_id.into());
+                            self.send_shutting_down(&self.scheduler_bot, user_id.into());
                         } else {
                             self.queue_sender
                                 .send(UpdateMessage::UserLeft(user_id))
                                 .unwrap();
                         }
                     }
+                    "party" | "пара" => {
+                        if self.shutting_down {
+                            self.send_shutting_down(&self.scheduler_bot, user_id.into());
+                            return;
+                        }
+                        let partner = match tokens.first() {
+                            None => None,
+                            Some(token) => {
+                                let mut played_with = self
+                                    .data
+                                    .get_last_played(user_id)
+                                    .log_error()
+                                    .unwrap_or_default();
+                                played_with.reverse();
+                                match self.resolve_user(token, &played_with) {
+                                    Ok(partner) if partner == user_id => {
+                                        self.scheduler_bot.try_send_message(
+                                            user_id.into(),
+                                            "Нельзя играть в паре с самим собой".to_string(),
+                                        );
+                                        return;
+                                    }
+                                    Ok(partner) => Some(partner),
+                                    Err(error) => {
+                                        self.scheduler_bot.try_send_message(user_id.into(), error);
+                                        return;
+                                    }
+                                }
+                            }
+                        };
+                        self.queue_sender
+                            .send(UpdateMessage::Party(user_id, partner))
+                            .unwrap();
+                    }
+                    "spectate" | "смотреть" => {
+                        if self.shutting_down {
+                            self.send_shutting_down(&self.scheduler_bot, user_id.into());
+                        } else if self
+                            .data
+                            .get_or_create_user(from)
+                            .and_then(|user_data| self.data.update_player(user_id, user_data))
+                            .log_error()
+                            .is_some()
+                        {
+                            self.queue_sender
+                                .send(UpdateMessage::SpectatorEntered(user_id))
+                                .unwrap();
+                        } else {
+                            self.scheduler_bot
+                                .try_send_message(user_id.into(), DB_ERROR_MESSAGE.to_string());
+                        }
+                    }
+                    "unspectate" => {
+                        if self.shutting_down {
+                            self.send_shutting_down(&self.scheduler_bot, user_id.into());
+                        } else {
+                            self.queue_sender
+                                .send(UpdateMessage::SpectatorLeft(user_id))
+                                .unwrap();
+                        }
+                    }
                     "list" | "список" => {
-                        self.set_list(user_id.into());
+                        self.set_list(&self.scheduler_bot, user_id.into(), Some(user_id), tokens);
                     }
                     "status" | "статус" => {
-                        self.status(user_id.into(), None);
+                        self.status(&self.scheduler_bot, user_id.into(), None);
+                    }
+                    "queue" | "очередь" => {
+                        if self.shutting_down {
+                            self.send_shutting_down(&self.scheduler_bot, user_id.into());
+                        } else {
+                            self.queue_sender
+                                .send(UpdateMessage::QueryStatus(user_id))
+                                .unwrap();
+                        }
                     }
                     "rating" | "рейтинг" => {
-                        self.rating(user_id.into(), tokens);
+                        self.rating(&self.scheduler_bot, user_id.into(), user_id, tokens);
+                    }
+                    "monthly" | "месяц" => {
+                        self.monthly(&self.scheduler_bot, user_id.into(), tokens);
+                    }
+                    "profile" | "профиль" => {
+                        self.profile(&self.scheduler_bot, user_id.into(), user_id);
+                    }
+                    "history" | "история" => {
+                        self.history(&self.scheduler_bot, user_id.into(), user_id, tokens);
+                    }
+                    "settings" | "настройки" => {
+                        self.settings(user_id, tokens);
+                    }
+                    "range" | "разница" => {
+                        self.rating_range(user_id, tokens);
+                    }
+                    "remaining" | "осталось" => {
+                        self.remaining(user_id);
                     }
                     "block" => {
-                        self.block_set(&message, user_id.into(), user_id, tokens);
+                        self.block_set(
+                            &self.scheduler_bot,
+                            &message,
+                            user_id.into(),
+                            user_id,
+                            tokens,
+                        );
                     }
                     "unblock" => {
-                        self.unblock_set(&message, user_id.into(), user_id, tokens);
+                        self.unblock_set(
+                            &self.scheduler_bot,
+                            &message,
+                            user_id.into(),
+                            user_id,
+                            tokens,
+                        );
                     }
                     "played" => {
-                        let played_with = self.data.get_last_played(user_id);
+                        let played_with = self
+                            .data
+                            .get_last_played(user_id)
+                            .log_error()
+                            .unwrap_or_default();
                         if played_with.is_empty() {
                             self.scheduler_bot.try_send_message(
                                 user_id.into(),
@@ -620,7 +1922,12 @@ impl Main {
                                 message += format!(
                                     "\n<b>{}</b>. {}",
                                     i + 1,
-                                    self.data.get_user_data(id).unwrap().display_name()
+                                    self.data
+                                        .get_user_data(id)
+                                        .log_error()
+                                        .flatten()
+                                        .map(|user_data| user_data.display_name())
+                                        .unwrap_or_default()
                                 )
                                 .as_str();
                             }
@@ -628,7 +1935,11 @@ impl Main {
                         }
                     }
                     "banlist" => {
-                        let banned = self.data.get_ban_list(user_id);
+                        let banned = self
+                            .data
+                            .get_ban_list(user_id)
+                            .log_error()
+                            .unwrap_or_default();
                         if banned.is_empty() {
                             self.scheduler_bot.try_send_message(
                                 user_id.into(),
@@ -640,7 +1951,12 @@ impl Main {
                                 message += format!(
                                     "\n<b>{}</b>. {}",
                                     i + 1,
-                                    self.data.get_user_data(id).unwrap().display_name()
+                                    self.data
+                                        .get_user_data(id)
+                                        .log_error()
+                                        .flatten()
+                                        .map(|user_data| user_data.display_name())
+                                        .unwrap_or_default()
                                 )
                                 .as_str();
                             }
@@ -651,61 +1967,58 @@ impl Main {
                         if tokens.is_empty() {
                             self.scheduler_bot.try_send_message(
                                 user_id.into(),
-                                "Укажите номер в списке игроков, с которыми вы недавно играли"
+                                "Укажите @username или номер в списке игроков, с которыми вы недавно играли"
                                     .to_string(),
                             );
-                        } else {
-                            match tokens[0].parse::<usize>() {
-                                Err(_) => {
-                                    self.scheduler_bot.try_send_message(
-                                        user_id.into(),
-                                        format!("Некорректное число - {}", tokens[0]),
-                                    );
-                                }
-                                Ok(number) => {
-                                    let played_with = self.data.get_last_played(user_id);
-                                    if number < 1 || number > played_with.len() {
-                                        self.scheduler_bot.try_send_message(
-                                            user_id.into(),
-                                            format!("Некорректное число - {}", tokens[0]),
-                                        );
-                                    } else {
-                                        let to_ban = played_with[played_with.len() - number];
-                                        match self.data.add_to_ban_list(user_id, to_ban) {
-                                            UserBanResult::Banned => {
-                                                self.scheduler_bot.try_send_message(
-                                                    user_id.into(),
-                                                    format!(
-                                                        "Пользователь {} заблокирован",
-                                                        self.data
-                                                            .get_user_data(&to_ban)
-                                                            .unwrap()
-                                                            .display_name()
-                                                    ),
-                                                );
-                                            }
-                                            UserBanResult::AlreadyInList => {
-                                                self.scheduler_bot.try_send_message(
-                                                    user_id.into(),
-                                                    format!(
-                                                        "Пользователь {} уже находится в вашем бан-листе",
-                                                        self.data
-                                                            .get_user_data(&to_ban)
-                                                            .unwrap()
-                                                            .display_name()
-                                                    ),
-                                                );
-                                            }
-                                            UserBanResult::SizeLimitReached => {
-                                                self.scheduler_bot.try_send_message(
-                                                    user_id.into(),
-                                                    "Вы достигли лимита на размер бан-листа"
-                                                        .to_string(),
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
+                            return;
+                        }
+                        let mut played_with = self
+                            .data
+                            .get_last_played(user_id)
+                            .log_error()
+                            .unwrap_or_default();
+                        played_with.reverse();
+                        let to_ban = match self.resolve_user(tokens[0], &played_with) {
+                            Ok(to_ban) => to_ban,
+                            Err(error) => {
+                                self.scheduler_bot.try_send_message(user_id.into(), error);
+                                return;
+                            }
+                        };
+                        if to_ban == user_id {
+                            self.scheduler_bot.try_send_message(
+                                user_id.into(),
+                                "Нельзя заблокировать самого себя".to_string(),
+                            );
+                            return;
+                        }
+                        let name = self.user_name(to_ban, tokens[0]);
+                        match self.data.add_to_ban_list(user_id, to_ban) {
+                            Err(err) => {
+                                log::error!("{}", err);
+                                self.scheduler_bot
+                                    .try_send_message(user_id.into(), DB_ERROR_MESSAGE.to_string());
+                            }
+                            Ok(UserBanResult::Banned) => {
+                                self.scheduler_bot.try_send_message(
+                                    user_id.into(),
+                                    format!("Пользователь {} заблокирован", name),
+                                );
+                            }
+                            Ok(UserBanResult::AlreadyInList) => {
+                                self.scheduler_bot.try_send_message(
+                                    user_id.into(),
+                                    format!(
+                                        "Пользователь {} уже находится в вашем бан-листе",
+                                        name
+                                    ),
+                                );
+                            }
+                            Ok(UserBanResult::SizeLimitReached) => {
+                                self.scheduler_bot.try_send_message(
+                                    user_id.into(),
+                                    "Вы достигли лимита на размер бан-листа".to_string(),
+                                );
                             }
                         }
                     }
@@ -713,41 +2026,35 @@ impl Main {
                         if tokens.is_empty() {
                             self.scheduler_bot.try_send_message(
                                 user_id.into(),
-                                "Укажите номер в списке игроков, которых вы заблокировали"
+                                "Укажите @username или номер в списке игроков, которых вы заблокировали"
                                     .to_string(),
                             );
-                        } else {
-                            match tokens[0].parse::<usize>() {
-                                Err(_) => {
-                                    self.scheduler_bot.try_send_message(
-                                        user_id.into(),
-                                        format!("Некорректное число - {}", tokens[0]),
-                                    );
-                                }
-                                Ok(number) => {
-                                    let banned = self.data.get_ban_list(user_id);
-                                    if number < 1 || number > banned.len() {
-                                        self.scheduler_bot.try_send_message(
-                                            user_id.into(),
-                                            format!("Некорректное число - {}", tokens[0]),
-                                        );
-                                    } else {
-                                        let to_ban = banned[number - 1];
-                                        assert!(self.data.remove_from_ban_list(user_id, to_ban));
-                                        self.scheduler_bot.try_send_message(
-                                            user_id.into(),
-                                            format!(
-                                                "Пользователь {} разблокирован",
-                                                self.data
-                                                    .get_user_data(&to_ban)
-                                                    .unwrap()
-                                                    .display_name()
-                                            ),
-                                        );
-                                    }
-                                }
-                            }
+                            return;
                         }
+                        let banned = self
+                            .data
+                            .get_ban_list(user_id)
+                            .log_error()
+                            .unwrap_or_default();
+                        let to_unban = match self.resolve_user(tokens[0], &banned) {
+                            Ok(to_unban) => to_unban,
+                            Err(error) => {
+                                self.scheduler_bot.try_send_message(user_id.into(), error);
+                                return;
+                            }
+                        };
+                        let name = self.user_name(to_unban, tokens[0]);
+                        let message = match self.data.remove_from_ban_list(user_id, to_unban) {
+                            Err(err) => {
+                                log::error!("{}", err);
+                                DB_ERROR_MESSAGE.to_string()
+                            }
+                            Ok(true) => format!("Пользователь {} разблокирован", name),
+                            Ok(false) => {
+                                format!("Пользователь {} не находится в вашем бан-листе", name)
+                            }
+                        };
+                        self.scheduler_bot.try_send_message(user_id.into(), message);
                     }
                     _ => {}
                 }
@@ -756,34 +2063,162 @@ impl Main {
         }
     }
 
-    fn build_help(commands: &[(&str, &str)]) -> String {
+    /// Resolves `@username` among the users known to the bot, or a 1-based index in `list`.
+    fn resolve_user(&self, token: &str, list: &[UserId]) -> Result<UserId, String> {
+        match token.strip_prefix('@') {
+            Some(username) => match self.data.find_username(username) {
+                Ok(Some(user_id)) => Ok(user_id),
+                Ok(None) => Err(format!(
+                    "Пользователь {} не найден, он должен хотя бы раз написать боту",
+                    html_escape::encode_text(token)
+                )),
+                Err(err) => {
+                    log::error!("{}", err);
+                    Err(DB_ERROR_MESSAGE.to_string())
+                }
+            },
+            None => match token.parse::<usize>() {
+                Ok(number) if number >= 1 && number <= list.len() => Ok(list[number - 1]),
+                _ => Err(format!(
+                    "Некорректное число - {}",
+                    html_escape::encode_text(token)
+                )),
+            },
+        }
+    }
+
+    /// Display name of the user, with the `@username` it was referred by, since display names are
+    /// not unique.
+    fn user_name(&self, user_id: UserId, token: &str) -> String {
+        let name = self
+            .data
+            .get_user_data(&user_id)
+            .log_error()
+            .flatten()
+            .map(|user_data| user_data.display_name())
+            .unwrap_or_default();
+        if token.starts_with('@') {
+            format!("{} ({})", name, html_escape::encode_text(token))
+        } else {
+            name
+        }
+    }
+
+    /// The command lists come from the same tables the command menus are set from.
+    fn build_help(commands: &[(&str, &str)], manager: bool) -> String {
         let mut help = "Бот для спортивной своей игры. Команды:\n".to_string();
         for (command, description) in commands {
             help += format!("/{} - {}\n", command, description).as_str();
         }
+        if manager {
+            help += "\nКоманды менеджера:\n";
+            for (command, description) in MANAGER_COMMANDS.iter() {
+                help += format!("/{} - {}\n", command, description).as_str();
+            }
+        }
         help += "\n\
                 Во время игры:\n\
                 \"+\" - Если вы хотите ответить на вопрос\n\
-                \"Да\" - если вы хотите подтвердить правильность СОБСТВЕННОГО ответа, не зачтенного автоматически. Не жмите \"да\" на чужие ответы.\n\
+                \"Пропустить\" - проголосовать за пропуск некорректного вопроса. Вопрос пропускается, если за это проголосовало большинство игроков, очки за него не меняются\n\
+                \"Да\" или \"Апелляция\" - если вы считаете, что ваш СОБСТВЕННЫЙ ответ, не зачтенный автоматически, правильный. Остальные игроки в течение 30 секунд голосуют \"Да\" или \"Нет\", при равенстве голосов решение не меняется.\n\
                 \"Нет\" - если вы по ошибке нажали \"Да\" и вам засчитали неправильный ответ.\n\
-                \"Пауза\" - приостановить игру\n\
+                \"Пауза\" - приостановить игру (число пауз каждого игрока и их общая длительность ограничены, по умолчанию 3 паузы до 10 минут)\n\
+                \"Счёт\" - показать текущий счёт (не чаще раза в 15 секунд, кроме времени вопроса)\n\
                 \"Продолжить\" - продолжить игру.\n\
+                \"Замена\" - если вам нужно покинуть игру, отправьте эту команду ответом на сообщение пользователя, который займет ваше место с вашим счётом\n\
                 В режиме паузы можно исправить неверно посчитанные очки. Для этого следует ввести команду \n\
                 \"Исправить\" с параметром \"количество очков\"\n\
                 Например, если вы не успели на вопрос за 50 нажать \"Да\", то следует исправить 100 очков командой: Исправить 100\n\
-                В случае необходимости вычесть очки, просто поставьте минус перед параметром: Исправить -100";
+                В случае необходимости вычесть очки, просто поставьте минус перед параметром: Исправить -100\n\
+                Чтобы исправить счёт другого игрока, укажите его @username или номер в таблице счёта: Исправить @username 100 или Исправить 2 100. \
+                Такое исправление применяется после того, как кто-то из остальных игроков напишет \"Да\"";
         help
     }
 
-    fn send_shutting_down(&self, chat_id: ChatId) {
-        self.scheduler_bot.try_send_message(
+    /// Sends the text to every registered user who hasn't turned notifications off, reporting
+    /// the progress to the manager.
+    async fn broadcast(bot: TelegramBot, data: Data, chat_id: ChatId, text: String) {
+        let user_ids = match data.get_user_ids().log_error() {
+            Some(user_ids) => user_ids,
+            None => {
+                bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                return;
+            }
+        };
+        let total = user_ids.len();
+        let (mut delivered, mut blocked, mut failed, mut skipped) = (0, 0, 0, 0);
+        let mut interval = tokio::time::interval(Self::BROADCAST_INTERVAL);
+        for (i, user_id) in user_ids.into_iter().enumerate() {
+            if i > 0 && i % Self::BROADCAST_PROGRESS == 0 {
+                bot.try_send_message(chat_id, format!("Рассылка: {} из {}", i, total));
+            }
+            let notifications = data
+                .get_user_settings(user_id)
+                .log_error()
+                .unwrap_or_default()
+                .notifications;
+            if !notifications {
+                skipped += 1;
+                continue;
+            }
+            interval.tick().await;
+            match bot
+                .send_message(user_id.into(), text.clone(), KeyboardOptions::None)
+                .await
+            {
+                Ok(_) => delivered += 1,
+                Err(err) if err.is_blocked() => blocked += 1,
+                Err(err) => {
+                    log::error!("Broadcast to {} failed: {}", user_id, err);
+                    failed += 1;
+                }
+            }
+        }
+        bot.try_send_message(
+            chat_id,
+            format!(
+                "Рассылка завершена\nДоставлено: {}\nЗаблокировали бота: {}\nОшибки: {}\nОтключили уведомления: {}",
+                delivered, blocked, failed, skipped
+            ),
+        );
+    }
+
+    async fn health(&self) -> String {
+        let (sender, receiver) = oneshot::channel();
+        let queue_length = match self.queue_sender.send(UpdateMessage::QueueLength(sender)) {
+            Ok(_) => tokio::time::timeout(Self::HEALTH_TIMEOUT, receiver)
+                .await
+                .ok()
+                .and_then(|result| result.ok()),
+            Err(_) => None,
+        };
+        let uptime = self.started.elapsed().as_secs();
+        format!(
+            "<b>Состояние</b>\nРаботает: {} ч {} мин\nИгр: {}\nПредложенных игр: {}\nОжидают комнату: {}\nВ очереди: {}\nБот-организатор: {}\nИгровой бот: {}",
+            uptime / 3600,
+            uptime / 60 % 60,
+            self.games.len(),
+            self.game_proposals.len(),
+            self.pending_games.len(),
+            queue_length.map_or("нет ответа".to_string(), |length| length.to_string()),
+            self.scheduler_bot.stats(),
+            self.play_bot.stats()
+        )
+    }
+
+    fn send_shutting_down(&self, bot: &TelegramBot, chat_id: ChatId) {
+        bot.try_send_message(
             chat_id,
             "Бот в ближайшее время будет перезагружен. Создание новых игр временно отключено."
                 .to_string(),
         );
     }
 
-    async fn process_group_message(&mut self, message: Message) {
+    async fn process_group_message(&mut self, message: Message, thread_id: Option<Integer>) {
+        if let MessageKind::MigrateToChatId { data } = message.kind {
+            self.migrate_scheduler_chat(message.chat.id(), ChatId::new(data), message);
+            return;
+        }
         if message.from.is_none() {
             return;
         }
@@ -791,9 +2226,11 @@ impl Main {
         match &message.kind {
             MessageKind::Text { data, .. } => {
                 let chat_id = message.chat.id();
-                if chat_id == ChatId::new(Self::MAIN_CHAT) {
+                if chat_id == self.config.main_chat {
                     return;
                 }
+                // Replies go to the forum topic the command was sent from
+                let bot = self.scheduler_bot.in_thread(thread_id);
                 let text = data.trim();
                 if text.is_empty() {
                     return;
@@ -809,30 +2246,30 @@ impl Main {
                     command = &command[1..];
                 }
                 let tokens = &tokens[1..];
-                let game_data = self.game_proposals.get(&chat_id);
+                // Commands in a chat that joined another chat's game apply to that game
+                let proposal_id = self
+                    .linked_proposals
+                    .get(&chat_id)
+                    .copied()
+                    .unwrap_or(chat_id);
+                let game_data = self.game_proposals.get(&proposal_id);
                 match command {
                     "help" | "помощь" => {
-                        self.scheduler_bot
-                            .try_send_message(chat_id, Self::build_help(&GROUP_BOT_COMMANDS));
+                        bot.try_send_message(chat_id, Self::build_help(&GROUP_BOT_COMMANDS, false));
                     }
                     "game" | "игра" => {
                         if self.shutting_down {
-                            self.send_shutting_down(chat_id);
+                            self.send_shutting_down(&bot, chat_id);
                             return;
                         }
                         match game_data {
                             None => {
-                                let mut game_data = GameData::new(
-                                    self.timeout_sender.clone(),
-                                    chat_id,
-                                    self.data.clone(),
-                                );
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, game_data.to_string());
+                                let mut game_data = self.new_proposal(chat_id, thread_id);
+                                bot.try_send_message(chat_id, game_data.to_string());
                                 self.game_proposals.insert(chat_id, game_data);
                             }
                             Some(_) => {
-                                self.scheduler_bot.try_send_message(
+                                bot.try_send_message(
                                     chat_id,
                                     "Существует активная игра".to_string(),
                                 );
@@ -841,23 +2278,29 @@ impl Main {
                     }
                     "set" | "пакет" => match game_data {
                         None => {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Игра не начата".to_string());
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
                         }
                         Some(_) => {
                             if tokens.is_empty() {
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, "Укажите пакет".to_string());
+                                bot.try_send_message(chat_id, "Укажите пакет".to_string());
                             } else {
-                                if self.data.is_active(&tokens[0].to_string()) {
-                                    let game_data = self.game_proposals.get_mut(&chat_id).unwrap();
+                                if self
+                                    .data
+                                    .is_active(&tokens[0].to_string())
+                                    .log_error()
+                                    .unwrap_or(false)
+                                {
+                                    let game_data =
+                                        self.game_proposals.get_mut(&proposal_id).unwrap();
                                     game_data.set_set_id(tokens[0].to_string());
-                                    self.scheduler_bot
-                                        .try_send_message(chat_id, game_data.to_string());
+                                    bot.try_send_message(chat_id, game_data.to_string());
                                 } else {
-                                    self.scheduler_bot.try_send_message(
+                                    bot.try_send_message(
                                         chat_id,
-                                        format!("Пакет не обнаружен - {}", tokens[0]),
+                                        format!(
+                                            "Пакет не обнаружен - {}",
+                                            html_escape::encode_text(tokens[0])
+                                        ),
                                     );
                                 }
                             }
@@ -865,33 +2308,30 @@ impl Main {
                     },
                     "topics" | "темы" => match game_data {
                         None => {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Игра не начата".to_string());
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
                         }
                         Some(_) => {
                             if tokens.is_empty() {
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, "Укажите число".to_string());
+                                bot.try_send_message(chat_id, "Укажите число".to_string());
                             } else {
                                 match tokens[0].parse::<u8>() {
                                     Err(_) => {
-                                        self.scheduler_bot.try_send_message(
+                                        bot.try_send_message(
                                             chat_id,
                                             format!("Некорректное число - {}", tokens[0]),
                                         );
                                     }
                                     Ok(number) => {
                                         if number < 1 || number > 20 {
-                                            self.scheduler_bot.try_send_message(
+                                            bot.try_send_message(
                                                 chat_id,
                                                 format!("Некорректное число - {}", tokens[0]),
                                             );
                                         } else {
                                             let game_data =
-                                                self.game_proposals.get_mut(&chat_id).unwrap();
+                                                self.game_proposals.get_mut(&proposal_id).unwrap();
                                             game_data.set_topic_count(number);
-                                            self.scheduler_bot
-                                                .try_send_message(chat_id, game_data.to_string());
+                                            bot.try_send_message(chat_id, game_data.to_string());
                                         }
                                     }
                                 }
@@ -900,33 +2340,30 @@ impl Main {
                     },
                     "minplayers" | "минигроков" => match game_data {
                         None => {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Игра не начата".to_string());
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
                         }
                         Some(game_data) => {
                             if tokens.is_empty() {
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, "Укажите число".to_string());
+                                bot.try_send_message(chat_id, "Укажите число".to_string());
                             } else {
                                 match tokens[0].parse::<u8>() {
                                     Err(_) => {
-                                        self.scheduler_bot.try_send_message(
+                                        bot.try_send_message(
                                             chat_id,
                                             format!("Некорректное число - {}", tokens[0]),
                                         );
                                     }
                                     Ok(number) => {
                                         if number < 1 || number > game_data.max_players {
-                                            self.scheduler_bot.try_send_message(
+                                            bot.try_send_message(
                                                 chat_id,
                                                 format!("Некорректное число - {}", tokens[0]),
                                             );
                                         } else {
                                             let game_data =
-                                                self.game_proposals.get_mut(&chat_id).unwrap();
+                                                self.game_proposals.get_mut(&proposal_id).unwrap();
                                             game_data.set_min_players(number);
-                                            self.scheduler_bot
-                                                .try_send_message(chat_id, game_data.to_string());
+                                            bot.try_send_message(chat_id, game_data.to_string());
                                         }
                                     }
                                 }
@@ -935,17 +2372,15 @@ impl Main {
                     },
                     "maxplayers" | "максигроков" => match game_data {
                         None => {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Игра не начата".to_string());
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
                         }
                         Some(game_data) => {
                             if tokens.is_empty() {
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, "Укажите число".to_string());
+                                bot.try_send_message(chat_id, "Укажите число".to_string());
                             } else {
                                 match tokens[0].parse::<u8>() {
                                     Err(_) => {
-                                        self.scheduler_bot.try_send_message(
+                                        bot.try_send_message(
                                             chat_id,
                                             format!("Некорректное число - {}", tokens[0]),
                                         );
@@ -957,166 +2392,771 @@ impl Main {
                                                 .max(game_data.players.len() as u8)
                                             || number > 20
                                         {
-                                            self.scheduler_bot.try_send_message(
+                                            bot.try_send_message(
                                                 chat_id,
                                                 format!("Некорректное число - {}", tokens[0]),
                                             );
                                         } else {
                                             let game_data =
-                                                self.game_proposals.get_mut(&chat_id).unwrap();
+                                                self.game_proposals.get_mut(&proposal_id).unwrap();
                                             game_data.set_max_players(number);
-                                            self.scheduler_bot
-                                                .try_send_message(chat_id, game_data.to_string());
+                                            bot.try_send_message(chat_id, game_data.to_string());
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    "time" | "время" => match game_data {
+                        None => {
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
+                        }
+                        Some(_) => {
+                            if tokens.len() != 3 {
+                                bot.try_send_message(chat_id, "Укажите три числа".to_string());
+                            } else {
+                                let mut values = Vec::new();
+                                for token in tokens {
+                                    match token.parse::<u8>() {
+                                        Err(_) => {
+                                            bot.try_send_message(
+                                                chat_id,
+                                                format!("Некорректное число - {}", token),
+                                            );
+                                            return;
                                         }
+                                        Ok(value) => values.push(value),
+                                    }
+                                }
+                                match Timings::new(values[0], values[1], values[2]) {
+                                    None => {
+                                        bot.try_send_message(
+                                            chat_id,
+                                            format!(
+                                                "Время должно быть от {} до {} секунд",
+                                                Timings::MIN,
+                                                Timings::MAX
+                                            ),
+                                        );
                                     }
+                                    Some(timings) => {
+                                        let game_data =
+                                            self.game_proposals.get_mut(&proposal_id).unwrap();
+                                        game_data.set_timings(timings);
+                                        bot.try_send_message(chat_id, game_data.to_string());
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    "pauses" | "паузы" => match game_data {
+                        None => {
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
+                        }
+                        Some(game_data) => {
+                            let values = tokens
+                                .iter()
+                                .map(|token| token.parse::<u8>())
+                                .collect::<Result<Vec<_>, _>>();
+                            let timings = match values.as_deref() {
+                                Ok([pauses, pause_minutes]) => {
+                                    game_data.timings.with_pauses(*pauses, *pause_minutes)
+                                }
+                                _ => {
+                                    bot.try_send_message(
+                                        chat_id,
+                                        "Укажите число пауз и их общую длительность в минутах"
+                                            .to_string(),
+                                    );
+                                    return;
+                                }
+                            };
+                            match timings {
+                                None => {
+                                    bot.try_send_message(
+                                        chat_id,
+                                        format!(
+                                            "Пауз может быть не больше {}, общая длительность - не больше {} минут",
+                                            Timings::MAX_PAUSES,
+                                            Timings::MAX_PAUSE_MINUTES
+                                        ),
+                                    );
+                                }
+                                Some(timings) => {
+                                    let game_data =
+                                        self.game_proposals.get_mut(&proposal_id).unwrap();
+                                    game_data.set_pauses(timings);
+                                    bot.try_send_message(chat_id, game_data.to_string());
+                                }
+                            }
+                        }
+                    },
+                    "final" | "финал" => match game_data {
+                        None => {
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
+                        }
+                        Some(game_data) => {
+                            let final_round = !game_data.final_round;
+                            let game_data = self.game_proposals.get_mut(&proposal_id).unwrap();
+                            game_data.set_final_round(final_round);
+                            bot.try_send_message(chat_id, game_data.to_string());
+                        }
+                    },
+                    "blind" | "вслепую" => match game_data {
+                        None => {
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
+                        }
+                        Some(game_data) => {
+                            let blind = !game_data.blind;
+                            let game_data = self.game_proposals.get_mut(&proposal_id).unwrap();
+                            game_data.set_blind(blind);
+                            bot.try_send_message(chat_id, game_data.to_string());
+                        }
+                    },
+                    "strict" | "строго" => match game_data {
+                        None => {
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
+                        }
+                        Some(game_data) => {
+                            let strict = !game_data.strict;
+                            let game_data = self.game_proposals.get_mut(&proposal_id).unwrap();
+                            game_data.set_strict(strict);
+                            bot.try_send_message(chat_id, game_data.to_string());
+                        }
+                    },
+                    "pick" | "выбор" => match game_data {
+                        None => {
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
+                        }
+                        Some(_) => {
+                            let topic_picker = match tokens.first() {
+                                Some(&"off") | Some(&"выкл") => Some(TopicPicker::None),
+                                Some(&"last") | Some(&"последний") => {
+                                    Some(TopicPicker::Last)
                                 }
+                                Some(&"winner") | Some(&"победитель") => {
+                                    Some(TopicPicker::Winner)
+                                }
+                                _ => None,
+                            };
+                            match topic_picker {
+                                None => {
+                                    bot.try_send_message(
+                                        chat_id,
+                                        "Укажите off, last или winner".to_string(),
+                                    );
+                                }
+                                Some(topic_picker) => {
+                                    let game_data =
+                                        self.game_proposals.get_mut(&proposal_id).unwrap();
+                                    game_data.set_topic_picker(topic_picker);
+                                    bot.try_send_message(chat_id, game_data.to_string());
+                                }
+                            }
+                        }
+                    },
+                    "teams" | "команды" => match game_data {
+                        None => {
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
+                        }
+                        Some(game_data) => {
+                            let teams = match tokens.first() {
+                                Some(&"on") | Some(&"вкл") => true,
+                                Some(&"off") | Some(&"выкл") => false,
+                                _ => !game_data.teams,
+                            };
+                            if teams && game_data.players.len() as u8 > GameData::TEAM_PLAYERS {
+                                bot.try_send_message(
+                                    chat_id,
+                                    "Для командной игры нужно ровно 4 игрока".to_string(),
+                                );
+                            } else {
+                                let game_data = self.game_proposals.get_mut(&proposal_id).unwrap();
+                                game_data.set_teams(teams);
+                                bot.try_send_message(chat_id, game_data.to_string());
                             }
                         }
                     },
                     "register" | "+" => {
                         if self.shutting_down {
-                            self.send_shutting_down(chat_id);
+                            self.send_shutting_down(&bot, chat_id);
                             return;
                         }
                         let game_data = match game_data {
                             None => {
-                                let game_data = GameData::new(
-                                    self.timeout_sender.clone(),
-                                    chat_id,
-                                    self.data.clone(),
-                                );
-                                self.game_proposals.insert(chat_id, game_data);
-                                self.game_proposals.get_mut(&chat_id).unwrap()
+                                let game_data = self.new_proposal(chat_id, thread_id);
+                                self.game_proposals.insert(proposal_id, game_data);
+                                self.game_proposals.get_mut(&proposal_id).unwrap()
                             }
-                            Some(_) => self.game_proposals.get_mut(&chat_id).unwrap(),
+                            Some(_) => self.game_proposals.get_mut(&proposal_id).unwrap(),
                         };
                         if game_data.players.len() as u8 == game_data.max_players
                             && !game_data.players.contains_key(&user_id)
                         {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Все места заняты".to_string());
+                            bot.try_send_message(chat_id, "Все места заняты".to_string());
+                        } else if self
+                            .data
+                            .daily_limit_reached(user_id, SystemTime::now())
+                            .log_error()
+                            .unwrap_or(false)
+                        {
+                            bot.try_send_message(chat_id, DAILY_LIMIT_MESSAGE.to_string());
+                        } else if let Some(user_data) =
+                            self.data.get_or_create_user(from).log_error()
+                        {
+                            if !game_data.add_player(user_id, user_data) {
+                                bot.try_send_message(
+                                    chat_id,
+                                    "Вы исключены из рейтинговых игр".to_string(),
+                                );
+                            } else {
+                                bot.try_send_message(chat_id, game_data.to_string());
+                            }
                         } else {
-                            game_data
-                                .add_player(user_id, self.data.get_or_create_user(from));
-                            self.scheduler_bot
-                                .try_send_message(chat_id, game_data.to_string());
+                            bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
                         }
                     }
                     "spectator" | "зритель" => {
                         if self.shutting_down {
-                            self.send_shutting_down(chat_id);
+                            self.send_shutting_down(&bot, chat_id);
                             return;
                         }
                         match game_data {
                             None => {
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, "Игра не начата".to_string());
+                                bot.try_send_message(chat_id, "Игра не начата".to_string());
                             }
                             Some(_) => {
-                                let game_data = self.game_proposals.get_mut(&chat_id).unwrap();
-                                game_data.add_spectator(
-                                    user_id,
-                                    self.data.get_or_create_user(from),
-                                );
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, game_data.to_string());
+                                let game_data = self.game_proposals.get_mut(&proposal_id).unwrap();
+                                match self.data.get_or_create_user(from).log_error() {
+                                    Some(user_data) => {
+                                        game_data.add_spectator(user_id, user_data);
+                                        bot.try_send_message(chat_id, game_data.to_string());
+                                    }
+                                    None => {
+                                        bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                                    }
+                                }
                             }
                         }
                     }
                     "unregister" | "-" => match game_data {
                         None => {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Игра не начата".to_string());
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
                         }
                         Some(_) => {
-                            let game_data = self.game_proposals.get_mut(&chat_id).unwrap();
+                            let game_data = self.game_proposals.get_mut(&proposal_id).unwrap();
                             game_data.remove(user_id);
-                            self.scheduler_bot
-                                .try_send_message(chat_id, game_data.to_string());
+                            bot.try_send_message(chat_id, game_data.to_string());
                         }
                     },
                     "abort" => match game_data {
                         None => {
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Игра не начата".to_string());
+                            bot.try_send_message(chat_id, "Игра не начата".to_string());
                         }
                         Some(_) => {
-                            self.game_proposals.remove(&chat_id);
-                            self.scheduler_bot
-                                .try_send_message(chat_id, "Игра отменена".to_string());
+                            let game_data = self.remove_proposal(proposal_id).unwrap();
+                            for chat_id in game_data.chat_ids() {
+                                self.scheduler_bot
+                                    .in_thread(game_data.thread_id(chat_id))
+                                    .try_send_message(chat_id, "Игра отменена".to_string());
+                            }
                         }
                     },
+                    "link" | "связать" => {
+                        if self.shutting_down {
+                            self.send_shutting_down(&bot, chat_id);
+                            return;
+                        }
+                        match tokens.first() {
+                            Some(code) => {
+                                self.link_chat(chat_id, thread_id, code);
+                            }
+                            None => match game_data {
+                                None => {
+                                    bot.try_send_message(chat_id, "Игра не начата".to_string());
+                                }
+                                Some(_) => {
+                                    let code = self.link_code(proposal_id);
+                                    bot.try_send_message(
+                                        chat_id,
+                                        format!(
+                                            "Чтобы присоединиться к этой игре, отправьте в другом чате /link {}",
+                                            code
+                                        ),
+                                    );
+                                }
+                            },
+                        }
+                    }
                     "start" | "старт" => {
                         if self.shutting_down {
-                            self.send_shutting_down(chat_id);
+                            self.send_shutting_down(&bot, chat_id);
                             return;
                         }
                         match game_data {
                             None => {
-                                self.scheduler_bot
-                                    .try_send_message(chat_id, "Игра не начата".to_string());
+                                bot.try_send_message(chat_id, "Игра не начата".to_string());
                             }
                             Some(game_data) => {
                                 assert!((game_data.players.len() as u8) <= game_data.max_players);
                                 if (game_data.players.len() as u8) < game_data.min_players {
-                                    self.scheduler_bot.try_send_message(
+                                    bot.try_send_message(
                                         chat_id,
                                         "Недостаточно игроков".to_string(),
                                     );
+                                } else if game_data.teams
+                                    && game_data.players.len() as u8 != GameData::TEAM_PLAYERS
+                                {
+                                    bot.try_send_message(
+                                        chat_id,
+                                        "Для командной игры нужно ровно 4 игрока".to_string(),
+                                    );
                                 } else {
                                     let game_start_data = game_data.to_data();
                                     self.game_proposals
-                                        .get_mut(&chat_id)
+                                        .get_mut(&proposal_id)
                                         .unwrap()
                                         .cancel_timer();
                                     self.try_start_game(game_start_data).await;
-                                    self.game_proposals.remove(&chat_id);
+                                    self.remove_proposal(proposal_id);
                                 }
                             }
                         }
                     }
+                    "rematch" | "реванш" => {
+                        if self.shutting_down {
+                            self.send_shutting_down(&bot, chat_id);
+                            return;
+                        }
+                        if game_data.is_some() {
+                            bot.try_send_message(chat_id, "Существует активная игра".to_string());
+                            return;
+                        }
+                        let finished = self
+                            .finished_games
+                            .remove(&chat_id)
+                            .filter(|(_, ended_at)| ended_at.elapsed() < Self::REMATCH_WINDOW);
+                        match finished {
+                            None => {
+                                bot.try_send_message(
+                                    chat_id,
+                                    "Нет недавно завершенной игры".to_string(),
+                                );
+                            }
+                            Some((start_data, _)) => {
+                                let mut game_data = self.new_proposal(chat_id, thread_id);
+                                game_data.rematch(&start_data);
+                                game_data.cancel_timer();
+                                let game_start_data = game_data.to_data();
+                                self.game_proposals.insert(chat_id, game_data);
+                                self.try_start_game(game_start_data).await;
+                                self.game_proposals.remove(&chat_id);
+                            }
+                        }
+                    }
                     "list" | "список" => {
-                        self.set_list(chat_id);
+                        self.set_list(&bot, chat_id, None, tokens);
                     }
                     "status" | "статус" => {
-                        self.status(chat_id, game_data);
+                        self.status(&bot, chat_id, game_data);
                     }
                     "rating" | "рейтинг" => {
-                        self.rating(chat_id, tokens);
+                        self.rating(&bot, chat_id, user_id, tokens);
+                    }
+                    "monthly" | "месяц" => {
+                        self.monthly(&bot, chat_id, tokens);
+                    }
+                    "profile" | "профиль" => {
+                        self.profile(&bot, chat_id, user_id);
+                    }
+                    "history" | "история" => {
+                        self.history(&bot, chat_id, user_id, tokens);
                     }
                     "block" => {
-                        self.block_set(&message, chat_id, user_id, tokens);
+                        self.block_set(&bot, &message, chat_id, user_id, tokens);
                     }
                     "unblock" => {
-                        self.unblock_set(&message, chat_id, user_id, tokens);
+                        self.unblock_set(&bot, &message, chat_id, user_id, tokens);
                     }
                     _ => {}
                 }
             }
-            _ => {}
-        }
+            _ => {}
+        }
+    }
+
+    const RATING_LIST_LIMIT: usize = 200;
+    const RATING_AROUND: usize = 5;
+
+    fn ranked_list(entries: &[RankedEntry], format_value: impl Fn(i64) -> String) -> String {
+        let mut res = String::new();
+        for entry in entries {
+            res += format!(
+                "<b>{}.</b> {} {}\n",
+                entry.place,
+                entry.name,
+                format_value(entry.value)
+            )
+            .as_str();
+        }
+        res
+    }
+
+    /// Rating table with the number of games, wins and the peak rating of every player.
+    fn full_rating_list(data: &Data, entries: &[RankedEntry]) -> String {
+        let mut res = String::new();
+        for entry in entries {
+            let stats = data
+                .get_user_stats(&UserId::new(entry.user_id))
+                .log_error()
+                .unwrap_or_default();
+            res += format!(
+                "<b>{}.</b> {} {} (игр: {}, побед: {}, максимум: {})\n",
+                entry.place,
+                entry.name,
+                display_rating(entry.value as u32),
+                stats.games,
+                stats.wins,
+                display_rating(stats.peak_rating.max(entry.value as u32))
+            )
+            .as_str();
+        }
+        res
+    }
+
+    fn rating(&self, bot: &TelegramBot, chat_id: ChatId, user_id: UserId, tokens: &[&str]) {
+        let parse = |token: &str| token.parse::<usize>().ok();
+        let bot = bot.clone();
+        let data = self.data.clone();
+        let is_full = |token: &&str| *token == "full" || *token == "полный";
+        let full = tokens.iter().any(is_full);
+        let tokens = tokens
+            .iter()
+            .filter(|token| !is_full(token))
+            .map(|token| token.to_string())
+            .collect::<Vec<_>>();
+        tokio::spawn(async move {
+            let entries = match data.get_rating_list().log_error() {
+                Some(entries) => entries,
+                None => {
+                    bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                    return;
+                }
+            };
+            let entries = match tokens.first().map(|token| token.as_str()) {
+                Some("me") | Some("я") => {
+                    match entries
+                        .iter()
+                        .position(|entry| entry.user_id == i64::from(user_id))
+                    {
+                        None => {
+                            bot.try_send_message(chat_id, "Вас нет в рейтинге".to_string());
+                            return;
+                        }
+                        Some(position) => {
+                            let from = position.saturating_sub(Self::RATING_AROUND);
+                            let to = (position + Self::RATING_AROUND + 1).min(entries.len());
+                            entries[from..to].to_vec()
+                        }
+                    }
+                }
+                _ => {
+                    let (from, to) = match (
+                        tokens.first().and_then(|token| parse(token)),
+                        tokens.get(1).and_then(|token| parse(token)),
+                    ) {
+                        (Some(from), Some(to)) => (from, to.min(from + Self::RATING_LIST_LIMIT)),
+                        (Some(top), None) => (1, top.min(Self::RATING_LIST_LIMIT)),
+                        _ => (1, 20),
+                    };
+                    entries
+                        .into_iter()
+                        .filter(|entry| entry.place >= from && entry.place <= to)
+                        .collect()
+                }
+            };
+            let list = if full {
+                Self::full_rating_list(&data, &entries)
+            } else {
+                Self::ranked_list(&entries, |rating| display_rating(rating as u32).to_string())
+            };
+            bot.try_send_message(chat_id, format!("<b>Рейтинг игроков:</b>\n{}", list))
+        });
+    }
+
+    fn monthly(&self, bot: &TelegramBot, chat_id: ChatId, tokens: &[&str]) {
+        let mut month = year_month(SystemTime::now());
+        let mut top = 20;
+        for token in tokens {
+            if token.contains('-') {
+                if !is_year_month(token) {
+                    bot.try_send_message(
+                        chat_id,
+                        "Месяц указывается в формате ГГГГ-ММ, например 2024-05".to_string(),
+                    );
+                    return;
+                }
+                month = token.to_string();
+            } else if let Ok(value) = token.parse::<usize>() {
+                top = value.min(Self::RATING_LIST_LIMIT);
+            }
+        }
+        let bot = bot.clone();
+        let data = self.data.clone();
+        tokio::spawn(async move {
+            let entries = match data.get_monthly_list(&month).log_error() {
+                Some(entries) => entries
+                    .into_iter()
+                    .filter(|entry| entry.place <= top)
+                    .collect::<Vec<_>>(),
+                None => {
+                    bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                    return;
+                }
+            };
+            bot.try_send_message(
+                chat_id,
+                format!(
+                    "<b>Рейтинг за {}:</b>\n{}",
+                    month,
+                    Self::ranked_list(&entries, |points| format!(
+                        "{:+}",
+                        (points as f64 / 10f64).round() as i64
+                    ))
+                ),
+            )
+        });
+    }
+
+    fn recent_games(&self, chat_id: ChatId, limit: usize) {
+        let games = match self.data.get_recent_games(limit).log_error() {
+            Some(games) => games,
+            None => {
+                self.scheduler_bot
+                    .try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                return;
+            }
+        };
+        if games.is_empty() {
+            self.scheduler_bot
+                .try_send_message(chat_id, "Сыгранных игр нет".to_string());
+            return;
+        }
+        let mut message = "<b>Последние игры:</b>\n".to_string();
+        for game in games {
+            let players = game
+                .scores
+                .iter()
+                .map(|(user_id, score)| {
+                    let name = self
+                        .data
+                        .get_user_data(&UserId::new(*user_id))
+                        .log_error()
+                        .flatten()
+                        .map(|user_data| user_data.display_name())
+                        .unwrap_or_default();
+                    format!("{} {}", name, score)
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            message += format!(
+                "<b>{}</b> {} {}: {}{}\n",
+                game.id,
+                date(UNIX_EPOCH + Duration::from_millis(game.timestamp)),
+                game.set_id,
+                players,
+                if game.aborted {
+                    " (отменена)"
+                } else {
+                    ""
+                }
+            )
+            .as_str();
+        }
+        self.scheduler_bot.try_send_message(chat_id, message);
+    }
+
+    fn adjustments(&self, chat_id: ChatId, game_id: u64) {
+        let adjustments = match self.data.get_adjustments(game_id).log_error() {
+            Some(adjustments) => adjustments,
+            None => {
+                self.scheduler_bot
+                    .try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                return;
+            }
+        };
+        if adjustments.is_empty() {
+            self.scheduler_bot
+                .try_send_message(chat_id, format!("В игре {} счёт не исправлялся", game_id));
+            return;
+        }
+        let name = |user_id: i64| {
+            self.data
+                .get_user_data(&UserId::new(user_id))
+                .log_error()
+                .flatten()
+                .map(|user_data| user_data.display_name())
+                .unwrap_or_else(|| user_id.to_string())
+        };
+        let mut message = format!(
+            "<b>Исправления счёта в игре {}</b> (чат {}):\n",
+            game_id, adjustments[0].chat_id
+        );
+        for adjustment in adjustments {
+            message += format!(
+                "{} {} → {}: {:+}\n",
+                date_time(UNIX_EPOCH + Duration::from_millis(adjustment.timestamp)),
+                name(adjustment.adjusted_by),
+                name(adjustment.target),
+                adjustment.delta
+            )
+            .as_str();
+        }
+        self.scheduler_bot.try_send_message(chat_id, message);
+    }
+
+    fn profile(&self, bot: &TelegramBot, chat_id: ChatId, user_id: UserId) {
+        match self.data.get_profile(user_id) {
+            Err(err) => {
+                log::error!("{}", err);
+                bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+            }
+            Ok(None) => {
+                bot.try_send_message(chat_id, "Вы еще не играли".to_string());
+            }
+            Ok(Some(profile)) => {
+                bot.try_send_message(chat_id, profile);
+            }
+        }
+    }
+
+    /// Without arguments shows the settings, with a setting name toggles it.
+    fn settings(&self, user_id: UserId, tokens: &[&str]) {
+        let mut settings = match self.data.get_user_settings(user_id) {
+            Ok(settings) => settings,
+            Err(err) => {
+                log::error!("{}", err);
+                self.scheduler_bot
+                    .try_send_message(user_id.into(), DB_ERROR_MESSAGE.to_string());
+                return;
+            }
+        };
+        match tokens.first().map(|token| token.to_lowercase()).as_deref() {
+            None => {}
+            Some("notifications" | "уведомления") => {
+                settings.notifications = !settings.notifications;
+            }
+            Some("language" | "язык") => {
+                settings.language = match settings.language {
+                    Language::Russian => Language::English,
+                    Language::English => Language::Russian,
+                };
+            }
+            Some("unrated" | "безрейтинга") => {
+                if self.game_players.contains_key(&user_id) {
+                    self.scheduler_bot.try_send_message(
+                        user_id.into(),
+                        "Нельзя изменить рейтинговость во время игры".to_string(),
+                    );
+                    return;
+                }
+                settings.unrated = !settings.unrated;
+            }
+            Some(_) => {
+                self.scheduler_bot.try_send_message(
+                    user_id.into(),
+                    "Неизвестная настройка, доступны notifications, language и unrated".to_string(),
+                );
+                return;
+            }
+        }
+        if !tokens.is_empty() {
+            if let Err(err) = self.data.set_user_settings(user_id, &settings) {
+                log::error!("{}", err);
+                self.scheduler_bot
+                    .try_send_message(user_id.into(), DB_ERROR_MESSAGE.to_string());
+                return;
+            }
+        }
+        let on_off = |value: bool| if value { "вкл" } else { "выкл" };
+        self.scheduler_bot.try_send_message(
+            user_id.into(),
+            format!(
+                "<b>Настройки</b>\nУведомления о найденной игре (notifications): {}\nЯзык интерфейса (language): {}\nИгры без рейтинга (unrated): {}\nЧтобы изменить настройку, отправьте /settings и её название",
+                on_off(settings.notifications),
+                match settings.language {
+                    Language::Russian => "русский",
+                    Language::English => "English",
+                },
+                on_off(settings.unrated)
+            ),
+        );
+    }
+
+    /// Shows or sets the maximum rating difference with opponents in the queue, 0 removes it.
+    fn rating_range(&self, user_id: UserId, tokens: &[&str]) {
+        let range = match tokens.first() {
+            None => self.data.get_rating_range(user_id),
+            Some(token) => match token.parse::<u32>() {
+                Ok(value) => {
+                    let range = (value != 0).then(|| value.saturating_mul(10));
+                    self.data.set_rating_range(user_id, range).map(|_| range)
+                }
+                Err(_) => {
+                    self.scheduler_bot.try_send_message(
+                        user_id.into(),
+                        format!("Некорректное число - {}", html_escape::encode_text(token)),
+                    );
+                    return;
+                }
+            },
+        };
+        let message = match range {
+            Err(err) => {
+                log::error!("{}", err);
+                DB_ERROR_MESSAGE.to_string()
+            }
+            Ok(None) => "Разница в рейтинге с соперниками не ограничена".to_string(),
+            Ok(Some(range)) => format!(
+                "Максимальная разница в рейтинге с соперниками: {}",
+                display_rating(range)
+            ),
+        };
+        self.scheduler_bot.try_send_message(user_id.into(), message);
     }
 
-    fn rating(&self, chat_id: ChatId, tokens: &[&str]) {
-        let top = if tokens.is_empty() {
-            20
-        } else {
-            match tokens[0].parse::<usize>() {
-                Ok(value) => value.min(200usize),
-                Err(_) => 20,
+    fn history(&self, bot: &TelegramBot, chat_id: ChatId, user_id: UserId, tokens: &[&str]) {
+        let count = match tokens.first().map(|token| token.parse::<usize>()) {
+            Some(Ok(value)) => value,
+            _ => 10,
+        };
+        let history = match self.data.get_rating_history(&user_id).log_error() {
+            Some(history) => history,
+            None => {
+                bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                return;
             }
         };
-        let bot = self.scheduler_bot.clone();
-        let data = self.data.clone();
-        tokio::spawn(async move {
-            bot.try_send_message(
-                chat_id,
-                format!("<b>Рейтинг игроков:</b>\n{}", data.get_rating_list(top)),
+        if history.is_empty() {
+            bot.try_send_message(chat_id, "Вы еще не играли".to_string());
+            return;
+        }
+        let mut message = "<b>История рейтинга:</b>\n".to_string();
+        for change in history.iter().rev().take(count) {
+            message += format!(
+                "<b>{}</b>: {} очков, {} → {} ({:+})\n",
+                change.set_id,
+                change.score,
+                display_rating(change.old_rating),
+                display_rating(change.new_rating),
+                display_rating(change.new_rating) as i64 - display_rating(change.old_rating) as i64
             )
-        });
+            .as_str();
+        }
+        bot.try_send_message(chat_id, message);
     }
 
-    fn status(&self, chat_id: ChatId, game_data: Option<&GameData>) {
+    fn status(&self, bot: &TelegramBot, chat_id: ChatId, game_data: Option<&GameData>) {
         let mut status = match game_data {
             None => "",
             Some(_) => "Открыта регистрация\n",
@@ -1129,114 +3169,247 @@ impl Main {
                 status += game_status.as_str();
             }
         }
-        self.scheduler_bot.try_send_message(chat_id, status);
+        bot.try_send_message(chat_id, status);
     }
 
-    fn set_list(&self, chat_id: ChatId) {
-        let list = self.data.get_active_set_ids();
+    const SETS_PER_PAGE: usize = 20;
+
+    /// Active sets, newest first. Takes a page number and a substring of the id or title to look
+    /// for, sets blocked by the user are marked when `user_id` is given.
+    fn set_list(
+        &self,
+        bot: &TelegramBot,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        tokens: &[&str],
+    ) {
+        let matching = |filter: String| {
+            let filter = filter.to_lowercase();
+            self.data
+                .get_active_set_ids_newest_first()
+                .log_error()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|id| self.data.get_set(&id))
+                .filter(|set| {
+                    set.id.to_lowercase().contains(&filter)
+                        || decode(&set.title).to_lowercase().contains(&filter)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut page = 1;
+        let mut sets = matching(tokens.join(" "));
+        if let Some((last, rest)) = tokens.split_last() {
+            if let Ok(number) = last.parse::<usize>() {
+                // Past the last page the number is a part of the filter, e.g. a year
+                let paged = matching(rest.join(" "));
+                let pages = paged.len().div_ceil(Self::SETS_PER_PAGE);
+                if (1..=pages).contains(&number) || sets.is_empty() {
+                    page = number;
+                    sets = paged;
+                }
+            }
+        }
+        if sets.is_empty() {
+            bot.try_send_message(chat_id, "Пакеты не найдены".to_string());
+            return;
+        }
+        let pages = sets.len().div_ceil(Self::SETS_PER_PAGE);
+        if !(1..=pages).contains(&page) {
+            bot.try_send_message(
+                chat_id,
+                format!("Нет страницы {}, всего страниц: {}", page, pages),
+            );
+            return;
+        }
         let mut message = "<b>Список пакетов:</b>\n".to_string();
-        for id in list {
+        for set in sets
+            .iter()
+            .skip((page - 1) * Self::SETS_PER_PAGE)
+            .take(Self::SETS_PER_PAGE)
+        {
+            message += format!("<b>{}</b> - {}", set.id, set.title).as_str();
+            if let Some(author) = &set.author {
+                message += format!(" ({})", author).as_str();
+            }
+            let blocked = user_id.is_some_and(|user_id| {
+                self.data
+                    .is_set_blocked(user_id, &set.id)
+                    .log_error()
+                    .unwrap_or(false)
+            });
+            if blocked {
+                message += " — заблокирован";
+            }
+            message += "\n";
+        }
+        if pages > 1 {
+            message += format!("\nстраница {}/{}", page, pages).as_str();
+        }
+        bot.try_send_message(chat_id, message);
+    }
+
+    fn remaining(&self, user_id: UserId) {
+        let mut sets = self
+            .data
+            .get_active_set_ids()
+            .log_error()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|id| {
+                !self
+                    .data
+                    .is_set_blocked(user_id, id)
+                    .log_error()
+                    .unwrap_or(false)
+            })
+            .filter_map(|id| {
+                let remain = self.data.topics_in_set_remain(user_id, &id).log_error()?;
+                Some((remain, id))
+            })
+            .collect::<Vec<_>>();
+        sets.sort_by_key(|(remain, id)| (Reverse(*remain), id.clone()));
+        let mut message = "<b>Осталось тем:</b>\n".to_string();
+        for (remain, id) in sets {
             message += format!(
-                "<b>{}</b> - {}\n",
+                "<b>{}</b> - {}: {}\n",
                 id,
-                self.data.get_set(&id).unwrap().title
+                self.data.get_set(&id).unwrap().title,
+                remain
             )
             .as_str();
         }
-        self.scheduler_bot.try_send_message(chat_id, message);
+        self.scheduler_bot.try_send_message(user_id.into(), message);
     }
 
-    fn unblock_set(&self, message: &Message, chat_id: ChatId, user_id: UserId, tokens: &[&str]) {
+    fn unblock_set(
+        &self,
+        bot: &TelegramBot,
+        message: &Message,
+        chat_id: ChatId,
+        user_id: UserId,
+        tokens: &[&str],
+    ) {
         assert!(message.from.is_some());
         let from = message.from.clone().unwrap();
         if tokens.is_empty() {
-            self.scheduler_bot
-                .try_send_message(chat_id, "Укажите пакет".to_string());
+            bot.try_send_message(chat_id, "Укажите пакет".to_string());
         } else {
             if self
                 .data
-                .get_active_set_ids()
-                .contains(&tokens[0].to_string())
+                .is_active(&tokens[0].to_string())
+                .log_error()
+                .unwrap_or(false)
             {
-                if self
+                match self
                     .data
                     .set_set_blocked(user_id, &tokens[0].to_string(), false)
                 {
-                    self.scheduler_bot.try_send_message(
-                        chat_id,
-                        format!(
-                            "Пакет {} разблокирован для пользователя {}",
-                            tokens[0],
-                            display_name(&from)
-                        ),
-                    );
-                } else {
-                    self.scheduler_bot.try_send_message(
-                        chat_id,
-                        format!(
-                            "Пакет {} не был заблокирован для пользователя {}",
-                            tokens[0],
-                            display_name(&from)
-                        ),
-                    );
+                    Err(err) => {
+                        log::error!("{}", err);
+                        bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                    }
+                    Ok(true) => {
+                        bot.try_send_message(
+                            chat_id,
+                            format!(
+                                "Пакет {} разблокирован для пользователя {}",
+                                tokens[0],
+                                display_name(&from)
+                            ),
+                        );
+                    }
+                    Ok(false) => {
+                        bot.try_send_message(
+                            chat_id,
+                            format!(
+                                "Пакет {} не был заблокирован для пользователя {}",
+                                tokens[0],
+                                display_name(&from)
+                            ),
+                        );
+                    }
                 }
             } else {
-                self.scheduler_bot
-                    .try_send_message(chat_id, format!("Пакет не обнаружен - {}", tokens[0]));
+                bot.try_send_message(
+                    chat_id,
+                    format!(
+                        "Пакет не обнаружен - {}",
+                        html_escape::encode_text(tokens[0])
+                    ),
+                );
             }
         }
     }
 
-    fn block_set(&self, message: &Message, chat_id: ChatId, user_id: UserId, tokens: &[&str]) {
+    fn block_set(
+        &self,
+        bot: &TelegramBot,
+        message: &Message,
+        chat_id: ChatId,
+        user_id: UserId,
+        tokens: &[&str],
+    ) {
         assert!(message.from.is_some());
         let from = message.from.clone().unwrap();
         if tokens.is_empty() {
-            self.scheduler_bot
-                .try_send_message(chat_id, "Укажите пакет".to_string());
+            bot.try_send_message(chat_id, "Укажите пакет".to_string());
         } else {
             if self
                 .data
-                .get_active_set_ids()
-                .contains(&tokens[0].to_string())
+                .is_active(&tokens[0].to_string())
+                .log_error()
+                .unwrap_or(false)
             {
-                if self
+                match self
                     .data
                     .set_set_blocked(user_id, &tokens[0].to_string(), true)
                 {
-                    self.scheduler_bot.try_send_message(
-                        chat_id,
-                        format!(
-                            "Пакет {} заблокирован для пользователя {}",
-                            tokens[0],
-                            display_name(&from)
-                        ),
-                    );
-                } else {
-                    self.scheduler_bot.try_send_message(
-                        chat_id,
-                        format!(
-                            "Пакет {} уже был заблокирован для пользователя {}",
-                            tokens[0],
-                            display_name(&from)
-                        ),
-                    );
+                    Err(err) => {
+                        log::error!("{}", err);
+                        bot.try_send_message(chat_id, DB_ERROR_MESSAGE.to_string());
+                    }
+                    Ok(true) => {
+                        bot.try_send_message(
+                            chat_id,
+                            format!(
+                                "Пакет {} заблокирован для пользователя {}",
+                                tokens[0],
+                                display_name(&from)
+                            ),
+                        );
+                    }
+                    Ok(false) => {
+                        bot.try_send_message(
+                            chat_id,
+                            format!(
+                                "Пакет {} уже был заблокирован для пользователя {}",
+                                tokens[0],
+                                display_name(&from)
+                            ),
+                        );
+                    }
                 }
             } else {
-                self.scheduler_bot
-                    .try_send_message(chat_id, format!("Пакет не обнаружен - {}", tokens[0]));
+                bot.try_send_message(
+                    chat_id,
+                    format!(
+                        "Пакет не обнаружен - {}",
+                        html_escape::encode_text(tokens[0])
+                    ),
+                );
             }
         }
     }
 
     async fn try_start_game(&mut self, mut game_data: GameStartData) {
-        match find_topics(&self.data, &mut game_data) {
+        let found = find_topics(&self.data, &mut game_data, &mut rand::thread_rng());
+        match found {
             None => {
-                for chat_id in game_data.chat_ids.iter() {
-                    self.scheduler_bot.try_send_message(
-                        *chat_id,
-                        "Недостаточно тем, которые бы не играли все игроки".to_string(),
-                    );
-                }
+                self.send_to_chats(
+                    &game_data,
+                    "Недостаточно тем, которые бы не играли все игроки".to_string(),
+                );
             }
             Some((set_id, topics)) => {
                 assert_eq!(topics.len() as u8, game_data.topic_count);
@@ -1254,32 +3427,73 @@ impl Main {
         from_private: bool,
     ) {
         log::info!("{:#?}", game_data);
+        if let Some(banned) = game_data.players.iter().find(|(user_id, _)| {
+            self.data
+                .is_globally_banned(**user_id)
+                .log_error()
+                .unwrap_or(false)
+        }) {
+            self.send_to_chats(
+                game_data,
+                format!(
+                    "Игрок {} исключен из рейтинговых игр",
+                    banned.1.display_name()
+                ),
+            );
+            return;
+        }
         let chat_id = self
             .play_chats
             .iter()
             .filter(|chat_id| !self.games.contains_key(chat_id))
             .next();
         if chat_id.is_none() {
-            for chat_id in game_data.chat_ids.iter() {
-                self.scheduler_bot.try_send_message(
-                    *chat_id,
-                    "На текущий момент свободных комнат нет".to_string(),
-                );
-            }
+            self.send_to_chats(game_data, "На текущий момент свободных комнат нет, игра начнется, как только освободится комната"
+                        .to_string());
+            self.pending_games
+                .push_back((game_data.clone(), set_id, topics, from_private));
             return;
         }
         let chat_id = chat_id.unwrap().clone();
-        self.data.set_played(
-            &game_data
-                .players
-                .keys()
-                .chain(game_data.spectators.keys())
-                .collect::<Vec<_>>()[..],
-            &set_id,
-            &topics[..],
-        );
+        let invite_link = match self
+            .play_bot
+            .create_invite_link(
+                chat_id,
+                game_data.players.len() + game_data.spectators.len(),
+            )
+            .await
+            .log_error()
+        {
+            Some(invite_link) => invite_link,
+            None => {
+                self.send_to_chats(
+                    game_data,
+                    "Не удалось создать ссылку для входа в игру, попробуйте позже".to_string(),
+                );
+                return;
+            }
+        };
+        let game_id = match self.data.next_game_id().log_error() {
+            Some(game_id) => game_id,
+            None => {
+                self.send_to_chats(game_data, DB_ERROR_MESSAGE.to_string());
+                return;
+            }
+        };
         self.data
-            .add_game(&game_data.players.keys().cloned().collect::<Vec<_>>());
+            .set_played(
+                &game_data
+                    .players
+                    .keys()
+                    .chain(game_data.spectators.keys())
+                    .collect::<Vec<_>>()[..],
+                &set_id,
+                &topics[..],
+            )
+            .log_error();
+        self.data
+            .add_game(&game_data.players.keys().cloned().collect::<Vec<_>>())
+            .log_error();
         let mut user_list = String::new();
         for (user_id, user_data) in game_data.players.iter() {
             if !user_list.is_empty() {
@@ -1291,38 +3505,57 @@ impl Main {
             )
             .as_str();
         }
-        let invite_link = self.play_bot.create_invite_link(chat_id).await;
         for chat_id in game_data.chat_ids.iter() {
             if from_private {
                 log::info!(
                     "Invite sent to {}",
                     self.data
                         .get_user_data(&UserId::new((*chat_id).into()))
-                        .unwrap()
-                        .display_name()
-                );
-                self.scheduler_bot.try_send_message(
-                    *chat_id,
-                    format!("Игра найдена! Для игры пройдите по ссылке: {}", invite_link),
+                        .log_error()
+                        .flatten()
+                        .map(|user_data| user_data.display_name())
+                        .unwrap_or_default()
                 );
+                let text = format!("Игра найдена! Для игры пройдите по ссылке: {}", invite_link);
+                let notifications = self
+                    .data
+                    .get_user_settings(UserId::new((*chat_id).into()))
+                    .log_error()
+                    .unwrap_or_default()
+                    .notifications;
+                if notifications {
+                    self.scheduler_bot.try_send_message(*chat_id, text);
+                } else {
+                    self.scheduler_bot.try_send_silent_message(*chat_id, text);
+                }
             } else {
-                self.scheduler_bot.try_send_message(
-                    *chat_id,
-                    format!(
-                        "{} - для игры пройдите по ссылке: {}",
-                        user_list, invite_link
-                    ),
-                );
+                self.scheduler_bot
+                    .in_thread(game_data.thread_id(*chat_id))
+                    .try_send_message(
+                        *chat_id,
+                        format!(
+                            "{} - для игры пройдите по ссылке: {}",
+                            user_list, invite_link
+                        ),
+                    );
             }
         }
-        self.start_game(Game::new(
+        let set_version = self.data.get_set_version(&set_id).log_error().unwrap_or(0);
+        let game = Game::new(
+            game_id,
             chat_id.into(),
             game_data
                 .chat_ids
                 .iter()
                 .map(|id| id.clone().into())
                 .collect(),
+            game_data
+                .threads
+                .iter()
+                .map(|(chat_id, thread_id)| ((*chat_id).into(), *thread_id))
+                .collect(),
             set_id,
+            set_version,
             topics,
             game_data
                 .players
@@ -1335,39 +3568,69 @@ impl Main {
                 .map(|user_id| (*user_id).into())
                 .collect::<HashSet<i64>>(),
             invite_link,
-        ));
+            game_data.timings,
+            game_data.final_round,
+            game_data
+                .teams
+                .iter()
+                .map(|(user_id, team)| ((*user_id).into(), *team))
+                .collect::<HashMap<i64, u8>>(),
+            game_data.blind,
+            game_data.topic_picker,
+            game_data.strict,
+        );
+        self.started_games.insert(chat_id, game_data.clone());
+        self.start_game(game, false);
     }
 
-    async fn process_scheduler_message(&mut self, message: Message) {
+    async fn process_scheduler_message(&mut self, message: Message, thread_id: Option<Integer>) {
         match message.chat {
             MessageChat::Private(_) => {
                 self.process_private_message(message).await;
             }
             MessageChat::Group(_) | MessageChat::Supergroup(_) => {
-                self.process_group_message(message).await;
+                self.process_group_message(message, thread_id).await;
             }
             MessageChat::Unknown(_) => {}
         }
     }
 
+    fn process_play_callback_query(&mut self, query: CallbackQuery) {
+        let chat_id = match &query.message {
+            Some(MessageOrChannelPost::Message(message)) => Some(message.chat.id()),
+            _ => None,
+        };
+        match chat_id.and_then(|chat_id| self.games.get(&chat_id)) {
+            Some((sender, _)) => sender.send(BotUpdate::CallbackQuery(query)).unwrap(),
+            None => self.play_bot.try_answer_callback_query(query),
+        }
+    }
+
     async fn process_play_message(&mut self, message: Message) {
+        if let MessageKind::MigrateToChatId { data } = message.kind {
+            self.migrate_play_chat(message.chat.id(), ChatId::new(data), message);
+            return;
+        }
         if message.from.is_none() {
             return;
         }
         let from = message.from.clone().unwrap();
-        if from.id == UserId::new(Self::DUMMY) {
+        if from.id == self.config.dummy {
             match message.kind {
                 MessageKind::Text { data, .. } => {
                     if data == "добавить" {
                         if self.play_chats.insert(message.chat.id()) {
-                            self.data.add_game_chat(&message.chat.id().into());
+                            self.data
+                                .add_game_chat(&message.chat.id().into())
+                                .log_error();
                             self.play_bot
                                 .send_message(
                                     message.chat.id(),
                                     "Чат добавлен".to_string(),
                                     KeyboardOptions::None,
                                 )
-                                .await;
+                                .await
+                                .log_error();
                         } else {
                             self.play_bot
                                 .send_message(
@@ -1375,18 +3638,22 @@ impl Main {
                                     "Чат уже добавлен".to_string(),
                                     KeyboardOptions::None,
                                 )
-                                .await;
+                                .await
+                                .log_error();
                         }
                     } else if data == "удалить" {
                         if self.play_chats.remove(&message.chat.id()) {
-                            self.data.remove_game_chat(&message.chat.id().into());
+                            self.data
+                                .remove_game_chat(&message.chat.id().into())
+                                .log_error();
                             self.play_bot
                                 .send_message(
                                     message.chat.id(),
                                     "Чат удален".to_string(),
                                     KeyboardOptions::None,
                                 )
-                                .await;
+                                .await
+                                .log_error();
                         } else {
                             self.play_bot
                                 .send_message(
@@ -1394,7 +3661,8 @@ impl Main {
                                     "Чат не в списке".to_string(),
                                     KeyboardOptions::None,
                                 )
-                                .await;
+                                .await
+                                .log_error();
                         }
                     }
                 }
@@ -1403,25 +3671,45 @@ impl Main {
         } else {
             match &message.chat {
                 MessageChat::Private(_) => {
-                    self.play_bot.try_send_message(
-                        message.chat.id(),
-                        "Если вы хотите играть в свою игру, добавьте @SvoyakSchedulerBot"
-                            .to_string(),
-                    );
+                    if let Some((sender, _)) = self
+                        .game_players
+                        .get(&from.id)
+                        .and_then(|chat_id| self.games.get(chat_id))
+                    {
+                        sender.send(BotUpdate::Message(message, None)).unwrap();
+                    } else {
+                        self.play_bot.try_send_message(
+                            message.chat.id(),
+                            "Если вы хотите играть в свою игру, добавьте @SvoyakSchedulerBot"
+                                .to_string(),
+                        );
+                    }
                 }
-                MessageChat::Group(_) => {
+                MessageChat::Group(_) | MessageChat::Supergroup(_) => {
                     if self.play_chats.contains(&message.chat.id()) {
                         if let Some((sender, _)) = self.games.get(&message.chat.id()) {
-                            sender.send(message).unwrap();
+                            sender.send(BotUpdate::Message(message, None)).unwrap();
                         } else {
                             match message.kind {
                                 MessageKind::Text { .. } => {
-                                    self.play_bot.kick(message.chat.id(), from.id).await;
+                                    self.play_bot
+                                        .kick(message.chat.id(), from.id, false)
+                                        .await
+                                        .log_error();
                                 }
                                 MessageKind::NewChatMembers { data } => {
                                     for user in data {
-                                        self.play_bot.kick(message.chat.id(), user.id).await;
+                                        self.play_bot
+                                            .kick(message.chat.id(), user.id, false)
+                                            .await
+                                            .log_error();
                                     }
+                                    self.play_bot
+                                        .try_delete_message(message.chat.id(), message.id);
+                                }
+                                MessageKind::LeftChatMember { .. } => {
+                                    self.play_bot
+                                        .try_delete_message(message.chat.id(), message.id);
                                 }
                                 _ => {}
                             }
@@ -1434,55 +3722,291 @@ impl Main {
                         );
                     }
                 }
-                MessageChat::Supergroup(_) => {}
                 MessageChat::Unknown(_) => {}
             }
         }
     }
 
+    /// Telegram gives a group a new id when it is upgraded to a supergroup, the room and the game
+    /// in it move to that id.
+    fn migrate_play_chat(&mut self, chat_id: ChatId, new_chat_id: ChatId, message: Message) {
+        log::info!("Play chat {} migrated to {}", chat_id, new_chat_id);
+        if self.play_chats.remove(&chat_id) {
+            self.play_chats.insert(new_chat_id);
+            self.data
+                .migrate_game_chat(&chat_id.into(), &new_chat_id.into())
+                .log_error();
+        }
+        if let Some(game_data) = self.started_games.remove(&chat_id) {
+            self.started_games.insert(new_chat_id, game_data);
+        }
+        for game_chat_id in self.game_players.values_mut() {
+            if *game_chat_id == chat_id {
+                *game_chat_id = new_chat_id;
+            }
+        }
+        if let Some(game) = self.games.remove(&chat_id) {
+            game.0.send(BotUpdate::Message(message, None)).unwrap();
+            self.games.insert(new_chat_id, game);
+        }
+    }
+
+    /// Same as `migrate_play_chat` for the chats games are proposed in.
+    fn migrate_scheduler_chat(&mut self, chat_id: ChatId, new_chat_id: ChatId, message: Message) {
+        log::info!("Scheduler chat {} migrated to {}", chat_id, new_chat_id);
+        if let Some(mut game_data) = self.game_proposals.remove(&chat_id) {
+            game_data.chat_id = new_chat_id;
+            game_data.schedule_expiration();
+            if let Some(code) = game_data.link_code.as_ref() {
+                self.link_codes.insert(code.clone(), new_chat_id);
+            }
+            for linked in game_data.linked_chats.iter() {
+                self.linked_proposals.insert(*linked, new_chat_id);
+            }
+            self.game_proposals.insert(new_chat_id, game_data);
+        }
+        if let Some(proposal_id) = self.linked_proposals.remove(&chat_id) {
+            self.linked_proposals.insert(new_chat_id, proposal_id);
+            if let Some(game_data) = self.game_proposals.get_mut(&proposal_id) {
+                for linked in game_data.linked_chats.iter_mut() {
+                    if *linked == chat_id {
+                        *linked = new_chat_id;
+                    }
+                }
+            }
+        }
+        for (game_chat_id, game_data) in self.started_games.iter_mut() {
+            if !game_data.chat_ids.contains(&chat_id) {
+                continue;
+            }
+            for source_id in game_data.chat_ids.iter_mut() {
+                if *source_id == chat_id {
+                    *source_id = new_chat_id;
+                }
+            }
+            // The running game sends its results to the chats it was proposed in
+            if let Some((sender, _)) = self.games.get(game_chat_id) {
+                sender
+                    .send(BotUpdate::Message(message.clone(), None))
+                    .unwrap();
+            }
+        }
+        if let Some(finished) = self.finished_games.remove(&chat_id) {
+            self.finished_games.insert(new_chat_id, finished);
+        }
+    }
+
     fn process_game_data_timeout(&mut self, chat_id: &ChatId, update_id: u32) {
         if let Some(data) = self.game_proposals.get(&chat_id) {
             if data.update_id == update_id {
-                self.game_proposals.remove(&chat_id);
-                self.scheduler_bot.try_send_message(
-                    chat_id.clone(),
-                    "Игра отменена из-за отсутствия активности".to_string(),
+                let game_data = self.remove_proposal(*chat_id).unwrap();
+                for chat_id in game_data.chat_ids() {
+                    self.scheduler_bot
+                        .in_thread(game_data.thread_id(chat_id))
+                        .try_send_message(
+                            chat_id,
+                            "Игра отменена из-за отсутствия активности".to_string(),
+                        );
+                }
+            }
+        }
+    }
+
+    /// Proposal updates and the game results go to the forum topic it was proposed in.
+    fn new_proposal(&self, chat_id: ChatId, thread_id: Option<Integer>) -> GameData {
+        GameData::new(
+            self.timeout_sender.clone(),
+            chat_id,
+            thread_id,
+            self.data.clone(),
+        )
+    }
+
+    /// Removes the game proposal along with the links other chats have to it.
+    fn remove_proposal(&mut self, chat_id: ChatId) -> Option<GameData> {
+        let mut game_data = self.game_proposals.remove(&chat_id)?;
+        game_data.cancel_timer();
+        if let Some(code) = game_data.link_code.as_ref() {
+            self.link_codes.remove(code);
+        }
+        for linked in game_data.linked_chats.iter() {
+            self.linked_proposals.remove(linked);
+        }
+        Some(game_data)
+    }
+
+    fn link_code(&mut self, proposal_id: ChatId) -> String {
+        let game_data = self.game_proposals.get_mut(&proposal_id).unwrap();
+        if let Some(code) = game_data.link_code.as_ref() {
+            return code.clone();
+        }
+        let code = loop {
+            let code = rand::thread_rng().gen_range(1000..10000).to_string();
+            if !self.link_codes.contains_key(&code) {
+                break code;
+            }
+        };
+        self.link_codes.insert(code.clone(), proposal_id);
+        game_data.link_code = Some(code.clone());
+        code
+    }
+
+    /// Moves the participants of the game proposed in the chat, if any, to the game with the code.
+    fn link_chat(&mut self, chat_id: ChatId, thread_id: Option<Integer>, code: &str) {
+        let bot = self.scheduler_bot.in_thread(thread_id);
+        let source_id = match self.link_codes.get(code) {
+            Some(source_id) => *source_id,
+            None => {
+                bot.try_send_message(
+                    chat_id,
+                    format!("Неизвестный код - {}", html_escape::encode_text(code)),
                 );
+                return;
             }
+        };
+        let proposal_id = self
+            .linked_proposals
+            .get(&chat_id)
+            .copied()
+            .unwrap_or(chat_id);
+        if source_id == proposal_id {
+            bot.try_send_message(chat_id, "Этот чат уже участвует в этой игре".to_string());
+            return;
+        }
+        let (players, spectators) = match self.game_proposals.get(&proposal_id) {
+            Some(game_data) => (game_data.players.clone(), game_data.spectators.clone()),
+            None => (HashMap::new(), HashMap::new()),
+        };
+        // Codes are removed together with their proposals
+        let source = self.game_proposals.get(&source_id).unwrap();
+        let player_count = source
+            .players
+            .keys()
+            .chain(players.keys())
+            .collect::<HashSet<_>>()
+            .len();
+        if player_count > source.max_players as usize {
+            bot.try_send_message(
+                chat_id,
+                format!(
+                    "Слишком много игроков - {}, в игре может быть не больше {}",
+                    player_count, source.max_players
+                ),
+            );
+            return;
+        }
+        for one in source.players.keys() {
+            for other in players.keys() {
+                if one != other
+                    && (self
+                        .data
+                        .in_ban_list(*one, *other)
+                        .log_error()
+                        .unwrap_or(true)
+                        || self
+                            .data
+                            .in_ban_list(*other, *one)
+                            .log_error()
+                            .unwrap_or(true))
+                {
+                    bot.try_send_message(
+                        chat_id,
+                        "Среди игроков двух игр есть заблокировавшие друг друга".to_string(),
+                    );
+                    return;
+                }
+            }
+        }
+        // A chat joining with its own proposal keeps that proposal's topics
+        let (chat_ids, threads) = match self.remove_proposal(proposal_id) {
+            Some(game_data) => (game_data.chat_ids(), game_data.threads),
+            None => (
+                vec![chat_id],
+                thread_id
+                    .map(|thread_id| HashMap::from([(chat_id, thread_id)]))
+                    .unwrap_or_default(),
+            ),
+        };
+        for linked in chat_ids.iter() {
+            self.linked_proposals.insert(*linked, source_id);
+        }
+        let source = self.game_proposals.get_mut(&source_id).unwrap();
+        source.merge(players, spectators, chat_ids, threads);
+        let text = source.to_string();
+        for chat_id in source.chat_ids() {
+            self.scheduler_bot
+                .in_thread(source.thread_id(chat_id))
+                .try_send_message(chat_id, text.clone());
         }
     }
 
-    fn start_game(&mut self, game: Game) {
+    fn start_game(&mut self, game: Game, restored: bool) {
         let chat_id = game.chat_id;
-        let set_id = game.set_id.clone();
+        let set = match self
+            .data
+            .get_set_at_version(&game.set_id, game.set_version)
+            .log_error()
+            .flatten()
+        {
+            Some(set) => set,
+            None => {
+                // Another version has different topics, the game can't go on with it
+                log::error!(
+                    "Set {} version {} of game {} is missing, the game is ended",
+                    game.set_id,
+                    game.set_version,
+                    game.id
+                );
+                self.data.remove_game(chat_id).log_error();
+                self.play_bot.try_send_message(
+                    ChatId::new(chat_id),
+                    "Не удалось загрузить пакет, игра отменена".to_string(),
+                );
+                return;
+            }
+        };
+        for user_id in game.player_ids() {
+            self.game_players
+                .insert(UserId::new(user_id), ChatId::new(chat_id));
+        }
         let handle = GameHandle::create_game(
             self.play_bot.clone(),
             self.scheduler_bot.clone(),
             self.status_sender.clone(),
             game,
-            self.data.get_set(&set_id).unwrap(),
+            set,
             self.data.clone(),
+            self.config,
         );
         let (game_sender, game_receiver) = unbounded_channel();
         self.games
             .insert(ChatId::new(chat_id), (game_sender, handle.status()));
         tokio::spawn(async move {
             handle
-                .start_game(UnboundedReceiverStream::new(game_receiver))
+                .start_game(UnboundedReceiverStream::new(game_receiver), restored)
                 .await;
         });
     }
 }
 
-pub fn find_topics(data: &Data, game_data: &mut GameStartData) -> Option<(String, Vec<usize>)> {
+pub fn find_topics<R: Rng>(
+    data: &Data,
+    game_data: &mut GameStartData,
+    rng: &mut R,
+) -> Option<(String, Vec<usize>)> {
     let set_ids = match game_data.set_id.take() {
-        None => data.get_active_set_ids(),
+        None => data.get_active_set_ids().log_error().unwrap_or_default(),
         Some(set_id) => vec![set_id],
     };
+    let copies_played = data.copies_played().log_error().unwrap_or(false);
     for set_id in set_ids {
         let mut good = true;
         for user_id in game_data.players.keys() {
-            if data.topics_in_set_remain(*user_id, &set_id) < game_data.topic_count as usize {
+            let remain = data
+                .topics_in_set_remain(*user_id, &set_id)
+                .log_error()
+                .unwrap_or(0);
+            if remain < game_data.topic_count as usize {
                 good = false;
                 break;
             }
@@ -1492,11 +4016,23 @@ pub fn find_topics(data: &Data, game_data: &mut GameStartData) -> Option<(String
         }
         let set = data.get_set(&set_id).unwrap();
         let total = set.topics.len();
-        let mut unused = BitSet::new(total);
+        let mut unused = match data.get_disabled_topics(&set_id).log_error() {
+            None => continue,
+            Some(disabled) => disabled.unwrap_or_else(|| BitSet::new(total)),
+        };
         for user_id in game_data.players.keys() {
-            match data.get_played(*user_id, &set_id) {
-                None => {}
-                Some(bit_set) => {
+            let played = if copies_played {
+                data.get_played_with_copies(*user_id, &set_id)
+            } else {
+                data.get_played(*user_id, &set_id)
+            };
+            match played.log_error() {
+                None => {
+                    good = false;
+                    break;
+                }
+                Some(None) => {}
+                Some(Some(bit_set)) => {
                     unused.unite(&bit_set);
                 }
             }
@@ -1506,21 +4042,35 @@ pub fn find_topics(data: &Data, game_data: &mut GameStartData) -> Option<(String
             }
         }
         if good {
-            let mut topics = Vec::new();
-            for i in 0..total {
-                if !unused.is_set(i) {
-                    topics.push(i);
-                    if topics.len() as u8 == game_data.topic_count {
-                        break;
-                    }
-                }
-            }
-            return Some((set_id, topics));
+            return Some((
+                set_id,
+                choose_topics(&unused, total, game_data.topic_count as usize, rng),
+            ));
         }
     }
     None
 }
 
+fn assign_teams(players: &HashMap<UserId, UserData>) -> HashMap<UserId, u8> {
+    let mut by_rating = players.iter().collect::<Vec<_>>();
+    by_rating.sort_by_key(|(user_id, user_data)| (Reverse(user_data.rating), i64::from(**user_id)));
+    by_rating
+        .iter()
+        .enumerate()
+        .map(|(i, (user_id, _))| (**user_id, if i % 4 == 0 || i % 4 == 3 { 0 } else { 1 }))
+        .collect()
+}
+
+fn choose_topics<R: Rng>(played: &BitSet, total: usize, count: usize, rng: &mut R) -> Vec<usize> {
+    let mut topics = (0..total)
+        .filter(|i| !played.is_set(*i))
+        .collect::<Vec<_>>();
+    topics.shuffle(rng);
+    topics.truncate(count);
+    topics.sort();
+    topics
+}
+
 async fn async_main() {
     let main = Main::new().await;
     main.run().await;
@@ -1538,3 +4088,143 @@ fn main() {
         .unwrap();
     rt.block_on(async_main());
 }
+
+#[test]
+fn test_choose_topics() {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    let total = 30usize;
+    let mut played = BitSet::new(total);
+    for i in (0..total).step_by(3) {
+        played.set_bit(i);
+    }
+    let mut rng = StdRng::seed_from_u64(239);
+    let mut seen = vec![0usize; total];
+    for _ in 0..200 {
+        let topics = choose_topics(&played, total, 6, &mut rng);
+        assert_eq!(topics.len(), 6);
+        for i in 1..topics.len() {
+            assert!(topics[i - 1] < topics[i]);
+        }
+        for topic in topics {
+            assert!(!played.is_set(topic));
+            seen[topic] += 1;
+        }
+    }
+    for (i, count) in seen.iter().enumerate() {
+        assert_eq!(*count > 0, !played.is_set(i));
+    }
+}
+
+#[test]
+fn test_find_topics() {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    let data = Data::new_temporary();
+    for (set_id, count) in [("small", 6), ("large", 10)] {
+        let set_id = set_id.to_string();
+        let set = TopicSet::with_empty_topics(&set_id, count);
+        assert!(data.add_new_set(&set_id, set).unwrap());
+        data.add_active(&set_id).unwrap();
+    }
+    let first = UserId::new(1);
+    let second = UserId::new(2);
+    data.set_played(&[&first], &"small".to_string(), &[0])
+        .unwrap();
+    data.set_played(&[&first], &"large".to_string(), &[0, 1])
+        .unwrap();
+    data.set_played(&[&second], &"large".to_string(), &[2, 3])
+        .unwrap();
+    let mut game_data = GameStartData {
+        chat_ids: vec![ChatId::new(0)],
+        set_id: None,
+        topic_count: 6,
+        timings: Timings::default(),
+        final_round: false,
+        players: [first, second]
+            .iter()
+            .map(|id| (*id, UserData::new(id.to_string(), 1500)))
+            .collect(),
+        spectators: HashMap::new(),
+        teams: HashMap::new(),
+        blind: false,
+        topic_picker: TopicPicker::None,
+        strict: false,
+        threads: HashMap::new(),
+    };
+    let mut rng = StdRng::seed_from_u64(239);
+    assert_eq!(
+        find_topics(&data, &mut game_data.clone(), &mut rng),
+        Some(("large".to_string(), (4..10).collect()))
+    );
+    game_data.set_id = Some("small".to_string());
+    assert_eq!(find_topics(&data, &mut game_data.clone(), &mut rng), None);
+    data.set_played(&[&second], &"large".to_string(), &[4])
+        .unwrap();
+    game_data.set_id = None;
+    assert_eq!(find_topics(&data, &mut game_data, &mut rng), None);
+}
+
+#[test]
+fn test_env_value() {
+    env::set_var("SVOYAK_TEST_ID", "42");
+    assert_eq!(Config::env_value("SVOYAK_TEST_ID", 1i64), 42);
+    assert_eq!(Config::env_value("SVOYAK_TEST_UNSET", 1i64), 1);
+    // A typo doesn't fall back to the default
+    env::set_var("SVOYAK_TEST_ID", "4 2");
+    assert!(std::panic::catch_unwind(|| Config::env_value("SVOYAK_TEST_ID", 1i64)).is_err());
+}
+
+#[cfg(test)]
+fn group_message(chat_id: i64, user_id: i64, text: &str) -> Message {
+    serde_json::from_value(serde_json::json!({
+        "message_id": 1,
+        "from": {"id": user_id, "is_bot": false, "first_name": user_id.to_string()},
+        "date": 0,
+        "chat": {"id": chat_id, "type": "supergroup", "title": "chat"},
+        "text": text,
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_start_from_linked_chat() {
+    let config = Config {
+        manager: UserId::new(1),
+        main_chat: ChatId::new(-100),
+        dummy: UserId::new(2),
+        leave_grace: Duration::from_secs(Config::LEAVE_GRACE),
+    };
+    let mut main = Main::with_bots(
+        Data::new_temporary(),
+        config,
+        TelegramBot::new("scheduler".to_string()),
+        TelegramBot::new("play".to_string()),
+        MessageId::new(1),
+    );
+    let (proposed, linked) = (ChatId::new(-1), ChatId::new(-2));
+    for (chat_id, user_id, text) in [(-1, 1, "/game"), (-1, 1, "/link")] {
+        main.process_group_message(group_message(chat_id, user_id, text), None)
+            .await;
+    }
+    let code = main.game_proposals[&proposed].link_code.clone().unwrap();
+    let link = format!("/link {}", code);
+    for (chat_id, user_id, text) in [
+        (-2, 2, link.as_str()),
+        (-1, 1, "+"),
+        (-2, 2, "+"),
+        (-2, 3, "+"),
+    ] {
+        main.process_group_message(group_message(chat_id, user_id, text), None)
+            .await;
+    }
+    assert_eq!(main.linked_proposals.get(&linked), Some(&proposed));
+    assert_eq!(main.game_proposals[&proposed].players.len(), 3);
+    // There are no sets, so the game doesn't start, but the proposal is used up
+    main.process_group_message(group_message(-2, 3, "/start"), None)
+        .await;
+    assert!(main.game_proposals.is_empty());
+    assert!(main.linked_proposals.is_empty());
+}
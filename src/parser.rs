@@ -1,5 +1,59 @@
 use crate::topic::{Question, Topic, TopicSet};
 use core::option::Option::{None, Some};
+use html_escape::encode_text;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::fmt::{Display, Formatter};
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+/// What became of the topics of an uploaded pack, shown to the uploader.
+#[derive(Debug, Default)]
+pub struct ParseReport {
+    /// Topics found in the file, parsed or not.
+    pub topics: usize,
+    pub parsed: usize,
+    /// Html-escaped descriptions of what was skipped and why.
+    pub problems: Vec<String>,
+    /// Topics that were only recognized by the lenient rules, so the uploader can check them.
+    pub notes: Vec<String>,
+}
+
+impl ParseReport {
+    pub const MAX_PROBLEMS: usize = 50;
+
+    fn failed(problem: String) -> Self {
+        Self {
+            problems: vec![problem],
+            ..Self::default()
+        }
+    }
+
+    /// The lines, each starting with a line break, past `MAX_PROBLEMS` they are only counted.
+    pub fn limited(lines: &[String], more: &str) -> String {
+        let mut res = String::new();
+        for line in lines.iter().take(Self::MAX_PROBLEMS) {
+            res += format!("\n{}", line).as_str();
+        }
+        if lines.len() > Self::MAX_PROBLEMS {
+            res += format!("\n{}: {}", more, lines.len() - Self::MAX_PROBLEMS).as_str();
+        }
+        res
+    }
+}
+
+impl Display for ParseReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Распознано тем: {} из {}", self.parsed, self.topics)?;
+        write!(f, "{}", Self::limited(&self.problems, "И еще ошибок"))?;
+        if !self.notes.is_empty() {
+            write!(f, "\nРаспознано с допущениями:")?;
+        }
+        write!(f, "{}", Self::limited(&self.notes, "И еще замечаний"))
+    }
+}
+
+pub type ParseResult = Result<(TopicSet, ParseReport), ParseReport>;
 
 struct LineCollection<'s> {
     lines: Vec<&'s str>,
@@ -11,14 +65,33 @@ impl<'s> LineCollection<'s> {
         LineCollection { lines, pos: 0usize }
     }
 
+    /// Number of the line the next block starts at, counting from one.
+    pub fn line_number(&self) -> usize {
+        self.pos + 1
+    }
+
     pub fn next(&mut self) -> Option<Vec<&'s str>> {
         if self.pos == self.lines.len() {
             None
         } else {
             let mut res = Vec::new();
-            while self.pos < self.lines.len() && !self.lines[self.pos].is_empty() {
-                res.push(self.lines[self.pos]);
-                self.pos += 1;
+            loop {
+                while self.pos < self.lines.len() && !self.lines[self.pos].trim().is_empty() {
+                    res.push(self.lines[self.pos]);
+                    self.pos += 1;
+                }
+                // The answer after an empty answer marker may be separated by blank lines, they
+                // are kept so the line numbers stay right. The next topic is never taken for it.
+                if !res.last().is_some_and(|line| is_empty_answer_marker(line)) {
+                    break;
+                }
+                while self.pos < self.lines.len() && self.lines[self.pos].trim().is_empty() {
+                    res.push(self.lines[self.pos]);
+                    self.pos += 1;
+                }
+                if self.pos == self.lines.len() || self.lines[self.pos].starts_with("Тема ") {
+                    return Some(res);
+                }
             }
             if self.pos != self.lines.len() {
                 self.pos += 1;
@@ -28,102 +101,728 @@ impl<'s> LineCollection<'s> {
     }
 }
 
-fn get_part(lines: &mut Vec<&str>, from: &str, to: &str) -> Option<String> {
-    let mut res = String::new();
-    let mut on = false;
-    for (i, s) in lines.iter().enumerate() {
-        if on {
-            if s.starts_with(to) {
-                *lines = lines[i..].to_vec();
-                break;
+/// Part of a topic the following lines of the block continue.
+enum TopicPart {
+    Title,
+    Question,
+    Answer,
+    Comment,
+}
+
+struct PrettyQuestion {
+    cost: u16,
+    line: usize,
+    question: String,
+    answers: Vec<String>,
+    comment: Option<String>,
+    all_required: bool,
+}
+
+const MIN_QUESTIONS: usize = 3;
+const MAX_QUESTIONS: usize = 10;
+
+/// Cost and text of a line like "10. Question".
+fn numbered_line(line: &str) -> Option<(u16, &str)> {
+    let (number, text) = line.split_once(". ")?;
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((number.parse().ok()?, text))
+}
+
+/// Text after a marker like "Ответ:" starting the line. The marker may be in any case and the
+/// space after the colon may be missing, such lines are noted.
+fn strip_marker<'l>(
+    line: &'l str,
+    marker: &str,
+    line_number: usize,
+    notes: &mut Vec<String>,
+) -> Option<&'l str> {
+    let prefix = line.get(..marker.len())?;
+    if prefix.to_lowercase() != marker.to_lowercase() {
+        return None;
+    }
+    let rest = line[marker.len()..].strip_prefix(':')?;
+    // The prefix only has the letters of the marker, nothing to escape
+    if prefix != marker {
+        notes.push(format!(
+            "«{}:» в другом регистре (строка {})",
+            prefix, line_number
+        ));
+    }
+    Some(match rest.strip_prefix(' ') {
+        Some(text) => text,
+        None => {
+            if !rest.is_empty() {
+                notes.push(format!(
+                    "нет пробела после «{}:» (строка {})",
+                    prefix, line_number
+                ));
             }
-            res += "\n";
-            res += s;
-        } else {
-            if s.starts_with(from) {
-                on = true;
-                res += &s[from.len()..];
+            rest
+        }
+    })
+}
+
+/// "Ответ:" with the answer on the following line.
+fn is_empty_answer_marker(line: &str) -> bool {
+    ["Ответ (все)", "Ответ"].iter().any(|marker| {
+        strip_marker(line, marker, 0, &mut Vec::new())
+            .is_some_and(|answer| answer.trim().is_empty())
+    })
+}
+
+fn missing_answer(question: &PrettyQuestion) -> String {
+    format!(
+        "не найден ответ на вопрос {} (строка {})",
+        question.cost, question.line
+    )
+}
+
+/// Sets the answer of the question, the parts of an all-required answer are separated by
+/// semicolons.
+fn set_answer(
+    question: &mut PrettyQuestion,
+    answer: &str,
+    all_required: bool,
+    line_number: usize,
+) -> Result<(), String> {
+    if all_required {
+        question.answers = answer
+            .split(';')
+            .map(|part| part.trim().to_string())
+            .filter(|part| !part.is_empty())
+            .collect();
+        if question.answers.is_empty() {
+            return Err(format!("пустой ответ в строке {}", line_number));
+        }
+        question.all_required = true;
+    } else {
+        question.answers.push(answer.to_string());
+    }
+    Ok(())
+}
+
+/// Costs are taken from the numbered lines. A numbered line inside a question continues its
+/// text, after an answer it starts the next question and must have a higher cost. The answer
+/// may be followed by "Зачет: " lines with other accepted answers and a "Комментарий: ".
+/// "Ответ (все): " lists parts separated by semicolons that all have to be named, such answers
+/// can't have "Зачет: " lines. An "Автор: "
+/// line right after the title names the author of the topic. Markers are matched leniently, see
+/// `strip_marker`, and an empty answer marker takes the answer from the next non-empty line
+/// unless it starts the next topic or a question with a higher cost.
+/// The error is the reason the topic is skipped, notes say which lenient rules were applied.
+fn parse_topic(lines: &[&str], first_line: usize) -> Result<(Topic, Vec<String>), String> {
+    let start = lines
+        .iter()
+        .position(|line| line.starts_with("Тема "))
+        .ok_or_else(|| "пропущена — нет заголовка".to_string())?;
+    let mut title = lines[start]["Тема ".len()..].to_string();
+    let mut author = None;
+    let mut questions: Vec<PrettyQuestion> = Vec::new();
+    let mut part = TopicPart::Title;
+    let mut notes = Vec::new();
+    // Whether the answer on the next line is all-required
+    let mut pending_answer = None;
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        let line_number = first_line + i;
+        if pending_answer.is_some() && line.trim().is_empty() {
+            continue;
+        }
+        if let Some(all_required) = pending_answer.take() {
+            // The next topic or question rather than the answer
+            let last = questions.last().unwrap();
+            if line.starts_with("Тема ")
+                || numbered_line(line).is_some_and(|(cost, _)| cost > last.cost)
+            {
+                return Err(missing_answer(last));
+            }
+            set_answer(
+                questions.last_mut().unwrap(),
+                line,
+                all_required,
+                line_number,
+            )?;
+            continue;
+        }
+        if let Some((cost, text)) = numbered_line(line) {
+            let next = match (&part, questions.last()) {
+                (TopicPart::Title, _) => true,
+                (TopicPart::Answer | TopicPart::Comment, Some(last)) => {
+                    if cost <= last.cost {
+                        return Err(format!(
+                            "цена {} в строке {} не больше предыдущей {}",
+                            cost, line_number, last.cost
+                        ));
+                    }
+                    true
+                }
+                _ => false,
+            };
+            if next {
+                questions.push(PrettyQuestion {
+                    cost,
+                    line: line_number,
+                    question: text.to_string(),
+                    answers: Vec::new(),
+                    comment: None,
+                    all_required: false,
+                });
+                part = TopicPart::Question;
+                continue;
+            }
+        }
+        match part {
+            TopicPart::Title => {
+                if let Some(name) = strip_marker(line, "Автор", line_number, &mut notes) {
+                    author = Some(name.to_string());
+                    continue;
+                }
+            }
+            TopicPart::Question => {
+                let answer = strip_marker(line, "Ответ (все)", line_number, &mut notes)
+                    .map(|answer| (answer, true))
+                    .or_else(|| {
+                        strip_marker(line, "Ответ", line_number, &mut notes)
+                            .map(|answer| (answer, false))
+                    });
+                if let Some((answer, all_required)) = answer {
+                    if answer.trim().is_empty() {
+                        notes.push(format!(
+                            "ответ на следующей строке (строка {})",
+                            line_number
+                        ));
+                        pending_answer = Some(all_required);
+                    } else {
+                        set_answer(
+                            questions.last_mut().unwrap(),
+                            answer,
+                            all_required,
+                            line_number,
+                        )?;
+                    }
+                    part = TopicPart::Answer;
+                    continue;
+                }
+            }
+            TopicPart::Answer => {
+                let accepted = strip_marker(line, "Зачет", line_number, &mut notes)
+                    .or_else(|| strip_marker(line, "Зачёт", line_number, &mut notes));
+                if let Some(answer) = accepted {
+                    let question = questions.last_mut().unwrap();
+                    // Every listed part is required, there is nowhere to put an alternative
+                    if question.all_required {
+                        return Err(format!(
+                            "зачет в строке {} у вопроса с ответом из нескольких частей",
+                            line_number
+                        ));
+                    }
+                    question.answers.push(answer.to_string());
+                    continue;
+                }
+                if let Some(comment) = strip_marker(line, "Комментарий", line_number, &mut notes)
+                {
+                    questions.last_mut().unwrap().comment = Some(comment.to_string());
+                    part = TopicPart::Comment;
+                    continue;
+                }
             }
+            _ => {}
         }
+        let text = match part {
+            TopicPart::Title => &mut title,
+            TopicPart::Question => &mut questions.last_mut().unwrap().question,
+            TopicPart::Answer => questions.last_mut().unwrap().answers.last_mut().unwrap(),
+            TopicPart::Comment => questions.last_mut().unwrap().comment.as_mut().unwrap(),
+        };
+        *text += "\n";
+        *text += line;
     }
-    if on {
-        Some(res)
-    } else {
-        None
+    match (part, questions.last()) {
+        (TopicPart::Title, _) | (_, None) => {
+            return Err("нет вопросов".to_string());
+        }
+        // No answer marker, or an answer marker with nothing after it
+        (part, Some(last)) if matches!(part, TopicPart::Question) || pending_answer.is_some() => {
+            return Err(missing_answer(last));
+        }
+        _ => {}
+    }
+    if questions.len() < MIN_QUESTIONS || questions.len() > MAX_QUESTIONS {
+        return Err(format!(
+            "вопросов {}, нужно от {} до {}",
+            questions.len(),
+            MIN_QUESTIONS,
+            MAX_QUESTIONS
+        ));
     }
+    let topic = Topic::new(
+        title,
+        questions
+            .into_iter()
+            .map(|question| Question {
+                all_required: question.all_required,
+                ..Question::new(
+                    question.cost,
+                    question.question,
+                    &question.answers,
+                    question.comment,
+                )
+            })
+            .collect(),
+    )
+    .with_author(author);
+    Ok((topic, notes))
 }
 
-pub fn parse_pretty(id: String, content: String) -> Option<TopicSet> {
-    let mut lc = LineCollection::new(content.split("\r\n").collect());
+pub fn parse_pretty(id: String, content: String) -> ParseResult {
+    let lines = content
+        .trim_start_matches('\u{feff}')
+        .split('\n')
+        .map(|line| line.trim_end_matches('\r'))
+        .collect();
+    let mut lc = LineCollection::new(lines);
+    let header = "нет названия или описания пакета в начале файла".to_string();
     if lc.next().is_none() {
-        return None;
+        return Err(ParseReport::failed(header));
     }
     let title = match lc.next() {
         Some(title) => title.join("\n"),
         None => {
-            return None;
+            return Err(ParseReport::failed(header));
         }
     };
+    // An "Автор: " line of the description names the author of the set
+    let mut author = None;
     let description = match lc.next() {
-        Some(description) => description.join("\n"),
+        Some(description) => description
+            .into_iter()
+            .filter(|line| match line.strip_prefix("Автор: ") {
+                Some(name) => {
+                    author = Some(name.to_string());
+                    false
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
         None => {
-            return None;
+            return Err(ParseReport::failed(header));
         }
     };
+    let mut report = ParseReport::default();
     let mut topics = Vec::new();
-    while let Some(mut topic) = lc.next() {
-        let title = match get_part(&mut topic, "Тема ", "10. ") {
-            None => {
-                continue;
-            }
-            Some(title) => title,
+    loop {
+        let first_line = lc.line_number();
+        let topic = match lc.next() {
+            Some(topic) => topic,
+            None => break,
         };
-        let mut questions = Vec::new();
-        let mut good = true;
-        for i in 1..=5 {
-            let question = match get_part(&mut topic, format!("{}. ", i * 10).as_str(), "Ответ: ")
-            {
-                None => {
-                    good = false;
-                    break;
-                }
-                Some(question) => question,
-            };
-            let answer = match get_part(
-                &mut topic,
-                "Ответ: ",
-                format!("{}. ", (i + 1) * 10).as_str(),
-            ) {
-                None => {
-                    good = false;
-                    break;
-                }
-                Some(question) => question,
-            };
-            questions.push(Question::new(i * 10 as u16, question, &vec![answer], None));
+        // Extra blank lines between topics
+        if topic.is_empty() {
+            continue;
         }
-        if good {
-            topics.push(Topic::new(title, questions));
+        report.topics += 1;
+        match parse_topic(&topic, first_line) {
+            Ok((topic, notes)) => {
+                if !notes.is_empty() {
+                    report.notes.push(format!(
+                        "Тема {} (строка {}): {}",
+                        report.topics,
+                        first_line,
+                        notes.join(", ")
+                    ));
+                }
+                topics.push(topic);
+            }
+            Err(problem) => {
+                report.problems.push(format!(
+                    "Тема {} (строка {}): {}",
+                    report.topics, first_line, problem
+                ));
+            }
         }
     }
+    report.parsed = topics.len();
     if topics.is_empty() {
-        None
+        Err(report)
     } else {
-        Some(TopicSet::new(id, title, description, topics))
+        Ok((
+            TopicSet::new(id, title, description, topics).with_author(author),
+            report,
+        ))
+    }
+}
+
+/// What makes the topics of a parsed set unplayable: the number of questions, costs that are not
+/// positive and increasing, questions without text or answers. One line per broken topic.
+pub fn check_set(set: &TopicSet) -> Vec<String> {
+    let mut res = Vec::new();
+    if set.topics.is_empty() {
+        res.push("В пакете нет тем".to_string());
+    }
+    for (i, topic) in set.topics.iter().enumerate() {
+        let mut problems = Vec::new();
+        if !(MIN_QUESTIONS..=MAX_QUESTIONS).contains(&topic.questions.len()) {
+            problems.push(format!(
+                "вопросов {}, нужно от {} до {}",
+                topic.questions.len(),
+                MIN_QUESTIONS,
+                MAX_QUESTIONS
+            ));
+        }
+        let mut last_cost = 0;
+        for (j, question) in topic.questions.iter().enumerate() {
+            if question.cost == 0 {
+                problems.push(format!("вопрос {} без цены", j + 1));
+            } else if question.cost <= last_cost {
+                problems.push(format!(
+                    "цена {} вопроса {} не больше предыдущей {}",
+                    question.cost,
+                    j + 1,
+                    last_cost
+                ));
+            }
+            last_cost = question.cost;
+            // A picture is enough for a question
+            if question.question.trim().is_empty() && question.image.is_none() {
+                problems.push(format!("вопрос {} без текста", j + 1));
+            }
+            if question
+                .answers
+                .iter()
+                .all(|answer| answer.trim().is_empty())
+            {
+                problems.push(format!("вопрос {} без ответа", j + 1));
+            } else if question
+                .answers
+                .iter()
+                .any(|answer| answer.trim().is_empty())
+            {
+                problems.push(format!("пустой ответ на вопрос {}", j + 1));
+            }
+        }
+        if !problems.is_empty() {
+            res.push(format!("Тема {}: {}", i + 1, problems.join(", ")));
+        }
     }
+    res
 }
 
-pub fn parse_json(id: String, content: String) -> Option<TopicSet> {
+/// Reads a set in the shape `TopicSet::decoded` exports it.
+pub fn parse_json(id: String, content: String) -> ParseResult {
     match serde_json::from_str::<TopicSet>(content.as_str()) {
-        Ok(set) => Some(set),
+        Ok(set) if set.topics.is_empty() => {
+            Err(ParseReport::failed("в пакете нет тем".to_string()))
+        }
+        Ok(set) => {
+            let set = set.encoded();
+            let report = ParseReport {
+                topics: set.topics.len(),
+                parsed: set.topics.len(),
+                ..ParseReport::default()
+            };
+            Ok((set, report))
+        }
         Err(err) => {
             log::error!("Error parsing {} {}", id, err);
-            None
+            Err(ParseReport::failed(format!(
+                "ошибка в JSON: {}",
+                encode_text(&err.to_string())
+            )))
         }
     }
 }
 
-pub fn parse(mut id: String, content: String) -> Option<TopicSet> {
+/// Element of content.xml whose text is being collected.
+enum SiqText {
+    None,
+    Description,
+    Question,
+    Answer,
+    Comment,
+}
+
+#[derive(Default)]
+struct SiqQuestion {
+    cost: Option<u16>,
+    text: Vec<String>,
+    answers: Vec<String>,
+    comment: String,
+    /// Inside the content shown with the answer rather than the question.
+    after_question: bool,
+    in_right: bool,
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element
+        .try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|value| value.unescape_value().ok())
+        .map(|value| value.into_owned())
+}
+
+/// Parses a SIGame pack, a zip archive with content.xml. Final rounds are left out, questions
+/// without text (pictures, sounds and videos only) are skipped and reported.
+pub fn parse_siq(id: String, content: &[u8]) -> ParseResult {
+    let mut archive = match ZipArchive::new(Cursor::new(content)) {
+        Ok(archive) => archive,
+        Err(err) => {
+            log::error!("Error parsing {} {}", id, err);
+            return Err(ParseReport::failed(
+                "файл не является архивом SIGame".to_string(),
+            ));
+        }
+    };
+    let mut xml = String::new();
+    match archive.by_name("content.xml") {
+        Ok(mut file) => {
+            if let Err(err) = file.read_to_string(&mut xml) {
+                log::error!("Error parsing {} {}", id, err);
+                return Err(ParseReport::failed(
+                    "не удалось прочитать content.xml".to_string(),
+                ));
+            }
+        }
+        Err(err) => {
+            log::error!("Error parsing {} {}", id, err);
+            return Err(ParseReport::failed("в архиве нет content.xml".to_string()));
+        }
+    }
+    let mut reader = Reader::from_str(xml.as_str());
+    reader.trim_text(true);
+    let mut title = String::new();
+    let mut description = String::new();
+    let mut topics = Vec::new();
+    let mut report = ParseReport::default();
+    let mut in_rounds = false;
+    let mut final_round = false;
+    let mut theme: Option<(String, Vec<Question>)> = None;
+    let mut question: Option<SiqQuestion> = None;
+    let mut text = SiqText::None;
+    loop {
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(err) => {
+                log::error!("Error parsing {} {}", id, err);
+                return Err(ParseReport::failed(format!(
+                    "ошибка в content.xml: {}",
+                    encode_text(&err.to_string())
+                )));
+            }
+        };
+        match event {
+            Event::Start(element) => match element.name().as_ref() {
+                b"package" => {
+                    title = attribute(&element, "name").unwrap_or_default();
+                }
+                b"rounds" => {
+                    in_rounds = true;
+                }
+                b"round" => {
+                    final_round = attribute(&element, "type").as_deref() == Some("final");
+                }
+                b"theme" => {
+                    if !final_round {
+                        report.topics += 1;
+                    }
+                    theme = Some((attribute(&element, "name").unwrap_or_default(), Vec::new()));
+                }
+                b"question" => {
+                    question = Some(SiqQuestion {
+                        cost: attribute(&element, "price").and_then(|price| price.parse().ok()),
+                        ..SiqQuestion::default()
+                    });
+                }
+                // Older packs keep the content in scenario atoms, newer ones in param items
+                b"param" => {
+                    if let Some(question) = question.as_mut() {
+                        question.after_question =
+                            attribute(&element, "name").as_deref() != Some("question");
+                    }
+                }
+                b"atom" | b"item" => {
+                    if let Some(question) = question.as_mut() {
+                        let kind = attribute(&element, "type");
+                        if !question.after_question
+                            && matches!(kind.as_deref(), None | Some("text") | Some("say"))
+                        {
+                            question.text.push(String::new());
+                            text = SiqText::Question;
+                        }
+                    }
+                }
+                b"right" => {
+                    if let Some(question) = question.as_mut() {
+                        question.in_right = true;
+                    }
+                }
+                b"answer" => {
+                    if let Some(question) = question.as_mut() {
+                        if question.in_right {
+                            question.answers.push(String::new());
+                            text = SiqText::Answer;
+                        }
+                    }
+                }
+                b"comments" => {
+                    if question.is_some() {
+                        text = SiqText::Comment;
+                    } else if !in_rounds {
+                        text = SiqText::Description;
+                    }
+                }
+                _ => {}
+            },
+            // Atoms after the marker are shown with the answer
+            Event::Empty(element) => {
+                if element.name().as_ref() == b"atom"
+                    && attribute(&element, "type").as_deref() == Some("marker")
+                {
+                    if let Some(question) = question.as_mut() {
+                        question.after_question = true;
+                    }
+                }
+            }
+            Event::Text(value) => {
+                let value = match value.unescape() {
+                    Ok(value) => value.into_owned(),
+                    Err(err) => {
+                        log::error!("Error parsing {} {}", id, err);
+                        return Err(ParseReport::failed(format!(
+                            "ошибка в content.xml: {}",
+                            encode_text(&err.to_string())
+                        )));
+                    }
+                };
+                match (&text, question.as_mut()) {
+                    (SiqText::Description, _) => {
+                        description += value.as_str();
+                    }
+                    (SiqText::Question, Some(question)) => {
+                        if let Some(last) = question.text.last_mut() {
+                            *last += value.as_str();
+                        }
+                    }
+                    (SiqText::Answer, Some(question)) => {
+                        if let Some(last) = question.answers.last_mut() {
+                            *last += value.as_str();
+                        }
+                    }
+                    (SiqText::Comment, Some(question)) => {
+                        question.comment += value.as_str();
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(element) => match element.name().as_ref() {
+                b"atom" | b"item" | b"answer" | b"comments" => {
+                    text = SiqText::None;
+                }
+                b"param" => {
+                    if let Some(question) = question.as_mut() {
+                        question.after_question = false;
+                    }
+                }
+                b"right" => {
+                    if let Some(question) = question.as_mut() {
+                        question.in_right = false;
+                    }
+                }
+                b"question" => {
+                    let question = match question.take() {
+                        Some(question) => question,
+                        None => continue,
+                    };
+                    let body = question
+                        .text
+                        .iter()
+                        .map(|text| text.trim())
+                        .filter(|text| !text.is_empty())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    let answers = question
+                        .answers
+                        .iter()
+                        .map(|answer| answer.trim().to_string())
+                        .filter(|answer| !answer.is_empty())
+                        .collect::<Vec<_>>();
+                    let comment = question.comment.trim();
+                    match (question.cost, theme.as_mut()) {
+                        (Some(cost), Some((_, questions)))
+                            if !body.is_empty() && !answers.is_empty() =>
+                        {
+                            questions.push(Question::new(
+                                cost,
+                                body,
+                                &answers,
+                                if comment.is_empty() {
+                                    None
+                                } else {
+                                    Some(comment.to_string())
+                                },
+                            ));
+                        }
+                        _ if final_round => {}
+                        _ => {
+                            report.problems.push(format!(
+                                "Тема {}: пропущен вопрос{} без текста или ответа",
+                                report.topics,
+                                question
+                                    .cost
+                                    .map(|cost| format!(" за {}", cost))
+                                    .unwrap_or_default()
+                            ));
+                        }
+                    }
+                }
+                b"theme" => match theme.take() {
+                    Some(_) if final_round => {}
+                    Some((_, questions)) if questions.is_empty() => {
+                        report.problems.push(format!(
+                            "Тема {}: пропущена — нет вопросов с текстом",
+                            report.topics
+                        ));
+                    }
+                    Some((name, questions)) => {
+                        topics.push(Topic::new(name, questions));
+                    }
+                    None => {}
+                },
+                b"round" => {
+                    final_round = false;
+                }
+                _ => {}
+            },
+            Event::Eof => {
+                break;
+            }
+            _ => {}
+        }
+    }
+    report.parsed = topics.len();
+    if topics.is_empty() {
+        Err(report)
+    } else {
+        Ok((
+            TopicSet::new(id, title, description.trim().to_string(), topics),
+            report,
+        ))
+    }
+}
+
+pub fn parse(mut id: String, content: Vec<u8>) -> ParseResult {
+    if let Some(id) = id.strip_suffix(".siq") {
+        return parse_siq(id.to_string(), &content);
+    }
+    let content = match String::from_utf8(content) {
+        Ok(content) => content.trim_start_matches('\u{feff}').to_string(),
+        Err(err) => {
+            log::error!("Error parsing {} {}", id, err);
+            return Err(ParseReport::failed("файл не в кодировке UTF-8".to_string()));
+        }
+    };
     if id.ends_with(".json") {
         parse_json(id[..id.len() - 5].to_string(), content)
     } else {
@@ -136,3 +835,533 @@ pub fn parse(mut id: String, content: String) -> Option<TopicSet> {
         parse_pretty(id, content)
     }
 }
+
+#[test]
+fn test_parse_siq() {
+    let content = include_bytes!("../tests/fixtures/sample.siq").to_vec();
+    let (set, report) = parse_siq("sample".to_string(), &content).unwrap();
+    assert_eq!((report.topics, report.parsed), (2, 2));
+    assert_eq!(
+        report.problems,
+        vec!["Тема 1: пропущен вопрос за 300 без текста или ответа"]
+    );
+    assert_eq!(set.id, "sample");
+    assert_eq!(set.title, "Тестовый пакет");
+    assert_eq!(set.description, "Пакет для проверки загрузки");
+    assert_eq!(set.topics.len(), 2);
+
+    let capitals = &set.topics[0];
+    assert_eq!(capitals.name, "Столицы");
+    assert_eq!(
+        capitals
+            .questions
+            .iter()
+            .map(|question| question.cost)
+            .collect::<Vec<_>>(),
+        vec![100, 200, 400]
+    );
+    assert_eq!(capitals.questions[1].answers, vec!["Рим", "Roma"]);
+    assert_eq!(capitals.questions[2].question, "Столица Австрии");
+    assert_eq!(
+        capitals.questions[2].comment.as_deref(),
+        Some("Город на Дунае")
+    );
+    assert!(capitals.questions[0].comment.is_none());
+
+    let rivers = &set.topics[1];
+    assert_eq!(rivers.questions.len(), 2);
+    assert_eq!(rivers.questions[0].question, "Река в Париже");
+    assert_eq!(
+        rivers.questions[1].question,
+        "Река в Лондоне &amp; Оксфорде"
+    );
+
+    assert_eq!(
+        parse("sample.siq".to_string(), content)
+            .unwrap()
+            .0
+            .topics
+            .len(),
+        2
+    );
+    assert!(parse_siq("broken".to_string(), b"not a zip").is_err());
+}
+
+#[cfg(test)]
+fn pretty_pack(line_end: &str) -> String {
+    [
+        "Пакет",
+        "",
+        "Тестовый пакет",
+        "",
+        "Описание пакета",
+        "",
+        "Тема Столицы",
+        "10. Столица Франции",
+        "Ответ: Париж",
+        "20. Столица Италии",
+        "Ответ: Рим",
+        "30. Столица Австрии",
+        "Ответ: Вена",
+        "40. Столица Испании",
+        "Ответ: Мадрид",
+        "50. Столица Японии",
+        "Ответ: Токио",
+        "  ",
+        "Тема Реки",
+        "10. Река в Париже",
+        "Ответ: Сена",
+        "20. Река в Лондоне",
+        "Ответ: Темза",
+        "30. Река в Риме",
+        "Ответ: Тибр",
+        "40. Река в Вене",
+        "Ответ: Дунай",
+        "50. Река в Каире",
+        "Ответ: Нил",
+    ]
+    .join(line_end)
+}
+
+#[test]
+fn test_parse_pretty_line_endings() {
+    use borsh::BorshSerialize;
+
+    let parse_bytes = |content: String| {
+        parse_pretty("test".to_string(), content)
+            .unwrap()
+            .0
+            .try_to_vec()
+            .unwrap()
+    };
+    let (expected, _) = parse_pretty("test".to_string(), pretty_pack("\r\n")).unwrap();
+    assert_eq!(expected.title, "Тестовый пакет");
+    assert_eq!(expected.topics.len(), 2);
+    assert_eq!(expected.topics[1].questions[4].answers, vec!["Нил"]);
+    let expected = expected.try_to_vec().unwrap();
+    assert_eq!(parse_bytes(pretty_pack("\n")), expected);
+    assert_eq!(
+        parse_bytes(format!("\u{feff}{}", pretty_pack("\r\n"))),
+        expected
+    );
+    assert_eq!(
+        parse_bytes(format!("\u{feff}{}", pretty_pack("\n"))),
+        expected
+    );
+}
+
+#[cfg(test)]
+fn pretty_topic(name: &str, costs: &[u16]) -> String {
+    let mut topic = format!("Тема {}", name);
+    for cost in costs {
+        topic += format!("\n{}. Вопрос за {}\nОтвет: Ответ {}", cost, cost, cost).as_str();
+    }
+    topic
+}
+
+#[test]
+fn test_parse_pretty_costs() {
+    let content = [
+        "Пакет".to_string(),
+        "Тестовый пакет".to_string(),
+        "Описание пакета".to_string(),
+        pretty_topic("Двадцатки", &[20, 40, 60, 80, 100]),
+        pretty_topic("Шесть вопросов", &[10, 20, 30, 40, 50, 60]),
+        pretty_topic("Два вопроса", &[10, 20]),
+        pretty_topic("Одиннадцать вопросов", &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+        pretty_topic("Не по возрастанию", &[10, 30, 20, 40]),
+        "Тема Годы\n100. Вопрос\n1990. год\nОтвет: Ответ\n200. Вопрос\nОтвет: 300. Ответ\n\
+        300. Вопрос\nОтвет: Ответ"
+            .to_string(),
+        "Тема Без ответа\n10. Вопрос\nОтвет: Ответ\n20. Вопрос\nОтвет: Ответ\n30. Вопрос"
+            .to_string(),
+        "Просто текст".to_string(),
+    ]
+    .join("\n\n");
+    let (set, report) = parse_pretty("test".to_string(), content).unwrap();
+    assert_eq!((report.topics, report.parsed), (8, 3));
+    assert_eq!(
+        report.problems,
+        vec![
+            "Тема 3 (строка 33): вопросов 2, нужно от 3 до 10",
+            "Тема 4 (строка 39): вопросов 11, нужно от 3 до 10",
+            "Тема 5 (строка 63): цена 20 в строке 68 не больше предыдущей 30",
+            "Тема 7 (строка 82): не найден ответ на вопрос 30 (строка 87)",
+            "Тема 8 (строка 89): пропущена — нет заголовка",
+        ]
+    );
+    assert!(report
+        .to_string()
+        .starts_with("Распознано тем: 3 из 8\nТема 3"));
+    let costs = set
+        .topics
+        .iter()
+        .map(|topic| {
+            (
+                topic.name.as_str(),
+                topic
+                    .questions
+                    .iter()
+                    .map(|question| question.cost)
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        costs,
+        vec![
+            ("Двадцатки", vec![20, 40, 60, 80, 100]),
+            ("Шесть вопросов", vec![10, 20, 30, 40, 50, 60]),
+            ("Годы", vec![100, 200, 300]),
+        ]
+    );
+    assert_eq!(set.topics[0].questions[4].question, "Вопрос за 100");
+    assert_eq!(set.topics[0].questions[4].answers, vec!["Ответ 100"]);
+    assert_eq!(set.topics[2].questions[0].question, "Вопрос\n1990. год");
+    assert_eq!(set.topics[2].questions[1].answers, vec!["300. Ответ"]);
+}
+
+#[test]
+fn test_parse_pretty_accepted_answers() {
+    let content = [
+        "Пакет",
+        "Тестовый пакет",
+        "Описание пакета",
+        "Тема Города\n\
+        10. Столица Франции\n\
+        Ответ: Париж\n\
+        20. Северная столица\n\
+        Ответ: Санкт-Петербург\n\
+        Зачет: Петербург\n\
+        Комментарий: Основан в 1703 году\n\
+        30. Столица Японии\n\
+        Ответ: Токио\n\
+        Зачет: Tokyo\n\
+        Зачёт: Эдо\n\
+        Комментарий: Эдо - прежнее название\n\
+        города до 1868 года\n\
+        40. Столица Италии\n\
+        Ответ: Рим\n\
+        Комментарий: Вечный город",
+    ]
+    .join("\n\n");
+    let (set, _) = parse_pretty("test".to_string(), content).unwrap();
+    let questions = &set.topics[0].questions;
+    assert_eq!(questions.len(), 4);
+    assert_eq!(questions[0].answers, vec!["Париж"]);
+    assert!(questions[0].comment.is_none());
+    assert_eq!(questions[1].answers, vec!["Санкт-Петербург", "Петербург"]);
+    assert_eq!(questions[1].comment.as_deref(), Some("Основан в 1703 году"));
+    assert_eq!(questions[2].answers, vec!["Токио", "Tokyo", "Эдо"]);
+    assert_eq!(
+        questions[2].comment.as_deref(),
+        Some("Эдо - прежнее название\nгорода до 1868 года")
+    );
+    assert_eq!(questions[3].answers, vec!["Рим"]);
+    assert!(questions[2].check_answer("эдо", true));
+    assert!(!questions[3].check_answer("эдо", true));
+}
+
+#[test]
+fn test_export_round_trip() {
+    use borsh::BorshSerialize;
+
+    let content = [
+        "Пакет",
+        "Пакет <для> проверки",
+        "Tom & Jerry",
+        "Тема Символы & <теги>\n\
+        10. Что значит \"&amp;\"?\n\
+        Ответ: Амперсанд\n\
+        Зачет: &\n\
+        20. Вопрос\n\
+        Ответ: <b>\n\
+        Комментарий: Тег > текст\n\
+        30. Вопрос\n\
+        Ответ: Ответ",
+    ]
+    .join("\n\n");
+    let (set, _) = parse_pretty("test".to_string(), content).unwrap();
+    let exported = serde_json::to_string(&set.decoded()).unwrap();
+    assert!(exported.contains("Символы & <теги>"));
+    assert!(exported.contains("\\\"&amp;\\\""));
+    let (imported, report) = parse_json("test".to_string(), exported).unwrap();
+    assert_eq!((report.topics, report.parsed), (1, 1));
+    assert_eq!(imported.try_to_vec().unwrap(), set.try_to_vec().unwrap());
+}
+
+#[test]
+fn test_parse_escaping() {
+    let pretty = [
+        "Пакет",
+        "Классика",
+        "Описание",
+        "Тема Книги\n\
+        10. Автор романа <Война и мир>\n\
+        Ответ: Толстой\n\
+        Комментарий: Роман <Война и мир> написан в 1860-х\n\
+        20. Вопрос\n\
+        Ответ: Ответ\n\
+        30. Вопрос\n\
+        Ответ: Ответ",
+    ]
+    .join("\n\n");
+    let json = r#"{"id": "test", "title": "Классика", "description": "Описание", "topics": [{
+        "name": "Книги",
+        "questions": [{
+            "cost": 10,
+            "question": "Автор романа <Война и мир>",
+            "answers": ["Толстой"],
+            "comment": "Роман <Война и мир> написан в 1860-х"
+        }]
+    }]}"#;
+    for (set, _) in [
+        parse_pretty("test".to_string(), pretty).unwrap(),
+        parse_json("test".to_string(), json.to_string()).unwrap(),
+    ] {
+        let question = &set.topics[0].questions[0];
+        assert_eq!(
+            question.display_question(&set.topics[0].name),
+            "<b>Тема</b> Книги\n<b>10.</b> Автор романа &lt;Война и мир&gt;"
+        );
+        assert_eq!(
+            question.display_answers(false),
+            "<b>Ответ:</b> Толстой\n<b>Комментарий</b>: Роман &lt;Война и мир&gt; написан в 1860-х"
+        );
+        assert!(question.check_answer("толстой", true));
+    }
+}
+
+#[test]
+fn test_parse_all_required() {
+    let pretty = [
+        "Пакет",
+        "География",
+        "Описание",
+        "Тема Полюса\n\
+        10. Назовите оба полюса\n\
+        Ответ (все): Северный; Южный\n\
+        20. Вопрос\n\
+        Ответ: Ответ\n\
+        30. Вопрос\n\
+        Ответ: Ответ",
+    ]
+    .join("\n\n");
+    let report = parse_pretty(
+        "test".to_string(),
+        pretty.replace("Северный; Южный", "Северный; Южный\nЗачет: Полярный"),
+    )
+    .unwrap_err();
+    assert_eq!(
+        report.problems,
+        vec!["Тема 1 (строка 7): зачет в строке 10 у вопроса с ответом из нескольких частей"]
+    );
+    let json = r#"{"id": "test", "title": "География", "description": "Описание", "topics": [{
+        "name": "Полюса",
+        "questions": [{
+            "cost": 10,
+            "question": "Назовите оба полюса",
+            "answers": ["Северный", "Южный"],
+            "comment": null,
+            "all_required": true
+        }]
+    }]}"#;
+    for (set, _) in [
+        parse_pretty("test".to_string(), pretty).unwrap(),
+        parse_json("test".to_string(), json.to_string()).unwrap(),
+    ] {
+        let question = &set.topics[0].questions[0];
+        assert!(question.all_required);
+        assert_eq!(question.answers, vec!["Северный", "Южный"]);
+        assert!(question.check_answer("южный и северный", true));
+        assert!(!question.check_answer("северный", true));
+        assert!(set.topics[0].questions[1..]
+            .iter()
+            .all(|question| !question.all_required));
+    }
+}
+
+#[test]
+fn test_parse_authors() {
+    let pretty = [
+        "Пакет",
+        "Классика",
+        "Описание\nАвтор: Иванов & Петров",
+        "Тема Книги\n\
+        Автор: Сидоров\n\
+        10. Вопрос\n\
+        Ответ: Ответ\n\
+        20. Вопрос\n\
+        Ответ: Ответ\n\
+        30. Вопрос\n\
+        Ответ: Ответ",
+        pretty_topic("Без автора", &[10, 20, 30]).as_str(),
+    ]
+    .join("\n\n");
+    let (set, _) = parse_pretty("test".to_string(), pretty).unwrap();
+    assert_eq!(set.description, "Описание");
+    assert_eq!(set.author.as_deref(), Some("Иванов &amp; Петров"));
+    assert_eq!(set.topics[0].name, "Книги");
+    assert_eq!(set.topics[0].author.as_deref(), Some("Сидоров"));
+    assert!(set.topics[1].author.is_none());
+
+    let exported = serde_json::to_string(&set.decoded()).unwrap();
+    let (imported, _) = parse_json("test".to_string(), exported).unwrap();
+    assert_eq!(imported.author, set.author);
+    assert_eq!(imported.topics[0].author, set.topics[0].author);
+    let (imported, _) = parse_json(
+        "test".to_string(),
+        r#"{"id": "test", "title": "Классика", "description": "", "topics": []}"#.to_string(),
+    )
+    .unwrap();
+    assert!(imported.author.is_none());
+}
+
+#[test]
+fn test_check_set() {
+    let topic = |costs: &[u16]| {
+        let questions = costs
+            .iter()
+            .map(|cost| {
+                Question::new(
+                    *cost,
+                    format!("Вопрос за {}", cost),
+                    &[format!("Ответ {}", cost)],
+                    None,
+                )
+            })
+            .collect();
+        Topic::new("Тема".to_string(), questions)
+    };
+    let mut broken = topic(&[10, 20, 30]);
+    broken.questions[0].question = String::new();
+    broken.questions[1].answers = vec![String::new()];
+    broken.questions[2].answers.push(" ".to_string());
+    let mut picture = topic(&[10, 20, 30]);
+    picture.questions[0].question = String::new();
+    picture.questions[0].image = Some("file".to_string());
+    let set = TopicSet::new(
+        "test".to_string(),
+        "Пакет".to_string(),
+        String::new(),
+        vec![
+            topic(&[10, 20, 30, 40, 50]),
+            topic(&[10, 10, 30, 20, 50]),
+            topic(&[0, 10, 20]),
+            topic(&[10, 20]),
+            broken,
+            picture,
+        ],
+    );
+    assert_eq!(
+        check_set(&set),
+        vec![
+            "Тема 2: цена 10 вопроса 2 не больше предыдущей 10, \
+            цена 20 вопроса 4 не больше предыдущей 30",
+            "Тема 3: вопрос 1 без цены",
+            "Тема 4: вопросов 2, нужно от 3 до 10",
+            "Тема 5: вопрос 1 без текста, вопрос 2 без ответа, пустой ответ на вопрос 3",
+        ]
+    );
+    let empty = TopicSet::new(
+        "test".to_string(),
+        "Пакет".to_string(),
+        String::new(),
+        Vec::new(),
+    );
+    assert_eq!(check_set(&empty), vec!["В пакете нет тем"]);
+    let json = r#"{"id": "test", "title": "Пакет", "description": "", "topics": []}"#;
+    assert_eq!(
+        parse_json("test".to_string(), json.to_string())
+            .unwrap_err()
+            .problems,
+        vec!["в пакете нет тем"]
+    );
+}
+
+#[test]
+fn test_parse_pretty_lenient_markers() {
+    let content = [
+        "Пакет",
+        "Тестовый пакет",
+        "Описание пакета",
+        "Тема Регистр\n\
+        10. Вопрос\n\
+        ОТВЕТ: Первый\n\
+        20. Вопрос\n\
+        ответ:Второй\n\
+        30. Вопрос\n\
+        Ответ: Третий\n\
+        ЗАЧЕТ: 3",
+        "Тема Ответ ниже\n\
+        10. Вопрос\n\
+        Ответ:\n\
+        7. класс\n\
+        20. Вопрос\n\
+        Ответ (все):\n\
+        \n\
+        Север; Юг\n\
+        30. Вопрос\n\
+        Ответ: Третий",
+        "Тема Без ответа\n\
+        10. Вопрос\n\
+        Ответ: А\n\
+        20. Вопрос\n\
+        Ответ: Б\n\
+        30. Вопрос\n\
+        Ответ:",
+    ]
+    .join("\n\n");
+    let (set, report) = parse_pretty("test".to_string(), content).unwrap();
+    assert_eq!((report.topics, report.parsed), (3, 2));
+    assert_eq!(
+        report.problems,
+        vec!["Тема 3 (строка 27): не найден ответ на вопрос 30 (строка 32)"]
+    );
+    assert_eq!(
+        report.notes,
+        vec![
+            "Тема 1 (строка 7): «ОТВЕТ:» в другом регистре (строка 9), \
+            «ответ:» в другом регистре (строка 11), нет пробела после «ответ:» (строка 11), \
+            «ЗАЧЕТ:» в другом регистре (строка 14)",
+            "Тема 2 (строка 16): ответ на следующей строке (строка 18), \
+            ответ на следующей строке (строка 21)",
+        ]
+    );
+    assert!(report.to_string().contains("Распознано с допущениями:"));
+    let questions = &set.topics[0].questions;
+    assert_eq!(questions[0].answers, vec!["Первый"]);
+    assert_eq!(questions[1].answers, vec!["Второй"]);
+    assert_eq!(questions[2].answers, vec!["Третий", "3"]);
+    let questions = &set.topics[1].questions;
+    assert_eq!(questions.len(), 3);
+    assert_eq!(questions[0].answers, vec!["7. класс"]);
+    assert_eq!(questions[1].answers, vec!["Север", "Юг"]);
+    assert!(questions[1].all_required);
+    assert_eq!(questions[2].answers, vec!["Третий"]);
+}
+
+#[test]
+fn test_parse_pretty_missing_answer() {
+    let content = [
+        "Пакет".to_string(),
+        "Тестовый пакет".to_string(),
+        "Описание пакета".to_string(),
+        "Тема Обрыв\n10. Вопрос\nОтвет: А\n20. Вопрос\nОтвет: Б\n30. Вопрос\nОтвет:".to_string(),
+        pretty_topic("Следующая", &[10, 20, 30]),
+        "Тема Вопрос вместо ответа\n10. Вопрос\nОтвет:\n20. Вопрос\nОтвет: Б\n30. Вопрос\nОтвет: В"
+            .to_string(),
+    ]
+    .join("\n\n");
+    let (set, report) = parse_pretty("test".to_string(), content).unwrap();
+    assert_eq!((report.topics, report.parsed), (3, 1));
+    assert_eq!(
+        report.problems,
+        vec![
+            "Тема 1 (строка 7): не найден ответ на вопрос 30 (строка 12)",
+            "Тема 3 (строка 23): не найден ответ на вопрос 10 (строка 24)",
+        ]
+    );
+    assert_eq!(set.topics[0].name, "Следующая");
+    assert_eq!(set.topics[0].questions.len(), 3);
+}
@@ -1,21 +1,33 @@
 use crate::bot::TelegramBot;
-use crate::data::{Data, UserData};
-use crate::{find_topics, GameStartData, Main};
+use crate::data::{display_rating, Data, LogError, QueueRecord, SetTopics, UserData};
+use crate::game::{Timings, TopicPicker};
+use crate::{find_topics, GameStartData, DAILY_LIMIT_MESSAGE};
 use futures::stream::select_all;
 use futures::StreamExt;
-use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use telegram_bot::{ChatId, MessageId, UserId};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 #[derive(Debug)]
 pub enum UpdateMessage {
-    UserEntered(UserId),
+    /// Optionally with the id of the preferred set, the largest game the user agrees to and how
+    /// long the user is ready to wait.
+    UserEntered(UserId, Option<String>, usize, Duration),
     UserLeft(UserId),
     Shutdown,
     FindGames,
+    QueryStatus(UserId),
+    /// The user wants to be matched together with another one, or no longer does.
+    Party(UserId, Option<UserId>),
+    SpectatorEntered(UserId),
+    SpectatorLeft(UserId),
+    /// Number of users waiting in the queue.
+    QueueLength(oneshot::Sender<usize>),
 }
 
 #[derive(Debug)]
@@ -25,37 +37,86 @@ struct QueueEntry {
     min_rating: i64,
     max_rating: i64,
     force_add: bool,
+    /// The player has set a maximum rating difference, which waiting does not override.
+    limited: bool,
+    set_id: Option<String>,
+    max_players: usize,
+    /// The player has not waited long enough to be matched with recent opponents.
+    avoid_rematch: bool,
+    /// Index of the party partner, the two are only placed into a game together.
+    partner: Option<usize>,
+    /// The player has waited long enough to agree to a game of three.
+    will_play_with_three: bool,
+    /// Active sets in which the player has enough unplayed topics for a game.
+    sets: HashSet<String>,
 }
 
 struct GameFinder<'s> {
     players: &'s Vec<QueueEntry>,
     result: Vec<&'s QueueEntry>,
+    num_players: usize,
     data: Data,
+    /// Ban lists of the players, loaded beforehand since the search checks them for every pair.
+    ban_lists: &'s HashMap<UserId, HashSet<UserId>>,
+    /// Sets playable by everyone in the result so far, one entry per step of the search.
+    common_sets: Vec<HashSet<String>>,
 }
 
 impl<'s> GameFinder<'s> {
+    const TOPIC_COUNT: usize = 6;
+
     pub fn find_game(
         players: &'s Vec<QueueEntry>,
         num_players: usize,
         data: Data,
+        ban_lists: &'s HashMap<UserId, HashSet<UserId>>,
     ) -> Option<(GameStartData, String, Vec<usize>)> {
         let mut game_finder = Self {
             players,
             result: Vec::new(),
+            num_players,
             data,
+            ban_lists,
+            common_sets: Vec::new(),
         };
         game_finder.do_find_game(num_players, players.len())
     }
 
     fn compatible(&self, one: &'s QueueEntry, another: &'s QueueEntry) -> bool {
-        (one.force_add
-            || another.force_add
+        (one.force_add && !another.limited
+            || another.force_add && !one.limited
             || one.max_rating >= another.user_data.rating as i64
                 && one.min_rating <= another.user_data.rating as i64
                 && another.max_rating >= one.user_data.rating as i64
                 && another.min_rating <= another.user_data.rating as i64)
-            && !self.data.in_ban_list(one.user_id, another.user_id)
-            && !self.data.in_ban_list(another.user_id, one.user_id)
+            && (one.set_id.is_none() || another.set_id.is_none() || one.set_id == another.set_id)
+            && !((one.avoid_rematch || another.avoid_rematch) && self.played_recently(one, another))
+            && !self.banned(one.user_id, another.user_id)
+            && !self.banned(another.user_id, one.user_id)
+    }
+
+    fn banned(&self, user_id: UserId, other: UserId) -> bool {
+        self.ban_lists
+            .get(&user_id)
+            .map_or(false, |ban_list| ban_list.contains(&other))
+    }
+
+    fn fits(&self, candidate: &'s QueueEntry) -> bool {
+        candidate.max_players >= self.num_players
+            && (self.num_players > 3 || candidate.will_play_with_three)
+            && self
+                .result
+                .iter()
+                .all(|player| self.compatible(player, candidate))
+    }
+
+    fn played_recently(&self, one: &QueueEntry, another: &QueueEntry) -> bool {
+        let played_with =
+            |user_id: UserId, other: UserId| match self.data.get_last_played(user_id).log_error() {
+                Some(played) => played.contains(&other),
+                None => true,
+            };
+        played_with(one.user_id, another.user_id) || played_with(another.user_id, one.user_id)
     }
 
     fn do_find_game(
@@ -70,36 +131,59 @@ impl<'s> GameFinder<'s> {
                     .iter()
                     .map(|entry| entry.user_id.into())
                     .collect(),
-                set_id: None,
-                topic_count: 6,
+                set_id: self.result.iter().find_map(|entry| entry.set_id.clone()),
+                topic_count: Self::TOPIC_COUNT as u8,
+                timings: Timings::default(),
+                final_round: false,
                 players: self
                     .result
                     .iter()
                     .map(|entry| (entry.user_id, entry.user_data.clone()))
                     .collect(),
                 spectators: HashMap::new(),
+                teams: HashMap::new(),
+                blind: false,
+                topic_picker: TopicPicker::None,
+                strict: false,
+                threads: HashMap::new(),
             };
-            find_topics(&self.data, &mut game_start_data)
+            find_topics(&self.data, &mut game_start_data, &mut rand::thread_rng())
                 .map(|(set_id, topics)| (game_start_data, set_id, topics))
         } else {
             if left_players > limit {
                 None
             } else {
+                let players = self.players;
                 for next in (left_players - 1)..limit {
-                    let mut good = true;
-                    for player in self.result.iter() {
-                        if !self.compatible(player, &self.players[next]) {
-                            good = false;
-                            break;
-                        }
+                    // A party is picked by the member with the larger index, which brings the other
+                    let partner = match players[next].partner {
+                        Some(partner) if partner > next => continue,
+                        Some(_) if left_players < 2 => continue,
+                        partner => partner.map(|partner| &players[partner]),
+                    };
+                    let mut candidates = vec![&players[next]];
+                    candidates.extend(partner);
+                    if !candidates.iter().all(|candidate| self.fits(*candidate)) {
+                        continue;
+                    }
+                    let mut common = match self.common_sets.last() {
+                        Some(common) => common.clone(),
+                        None => candidates[0].sets.clone(),
+                    };
+                    for candidate in candidates.iter() {
+                        common.retain(|set_id| candidate.sets.contains(set_id));
+                    }
+                    if common.is_empty() {
+                        continue;
                     }
-                    if good {
-                        self.result.push(&self.players[next]);
-                        if let Some(res) = self.do_find_game(left_players - 1, next) {
-                            return Some(res);
-                        }
-                        self.result.pop();
+                    self.common_sets.push(common);
+                    let added = candidates.len();
+                    self.result.extend(candidates);
+                    if let Some(res) = self.do_find_game(left_players - added, next) {
+                        return Some(res);
                     }
+                    self.result.truncate(self.result.len() - added);
+                    self.common_sets.pop();
                 }
                 None
             }
@@ -107,23 +191,63 @@ impl<'s> GameFinder<'s> {
     }
 }
 
+struct Queued {
+    user_id: UserId,
+    message_id: MessageId,
+    entered: Instant,
+    last_update: Instant,
+    set_id: Option<String>,
+    max_players: usize,
+    /// The search is given up after this long without registering again.
+    expiry: Duration,
+    /// The player was told that the search is about to expire.
+    warned: bool,
+    /// The player wants to be matched together with this one, which works once both want that.
+    partner: Option<UserId>,
+    /// Text of the message as it was last sent or edited.
+    shown: String,
+}
+
 pub struct PlayQueue {
     data: Data,
     bot: TelegramBot,
-    queue: Vec<(UserId, MessageId, Instant, Instant)>,
+    queue: Vec<Queued>,
     handle: Option<JoinHandle<()>>,
     sender: UnboundedSender<(GameStartData, String, Vec<usize>)>,
     update_stream: Option<UnboundedReceiverStream<UpdateMessage>>,
     find_sender: UnboundedSender<UpdateMessage>,
     find_stream: Option<UnboundedReceiverStream<UpdateMessage>>,
+    main_chat: ChatId,
     queue_message_id: MessageId,
+    /// Users waiting to watch the next found game, they don't take part in matchmaking.
+    spectators: Vec<UserId>,
+    /// Queue size shown in the main chat.
+    shown_count: usize,
+    /// Messages are behind the queue and get edited on the next tick.
+    messages_outdated: bool,
+    /// Queue saved before the restart, players get new messages once the queue starts.
+    restored: Vec<QueueRecord>,
+    /// Partners requested by the restored players.
+    restored_parties: HashMap<i64, i64>,
 }
 
 impl PlayQueue {
+    pub const DEFAULT_PLAYERS: usize = 4;
+    pub const MAX_PLAYERS: usize = 6;
+    const MAX_SPECTATORS: usize = 2;
+    pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(600);
+    pub const MIN_EXPIRY: Duration = Duration::from_secs(60);
+    pub const MAX_EXPIRY: Duration = Duration::from_secs(1800);
+    /// Players are warned this long before the search expires.
+    const EXPIRY_WARNING: Duration = Duration::from_secs(120);
+    const EXPIRY_WARNING_MESSAGE: &'static str =
+        "Осталось 2 минуты, отправьте /register чтобы продлить поиск";
+
     pub fn new(
         data: Data,
         scheduler_bot: TelegramBot,
         update_stream: UnboundedReceiverStream<UpdateMessage>,
+        main_chat: ChatId,
         queue_message_id: MessageId,
     ) -> (
         Self,
@@ -131,6 +255,13 @@ impl PlayQueue {
     ) {
         let (game_sender, game_receiver) = unbounded_channel();
         let (find_sender, find_receiver) = unbounded_channel();
+        let restored = data.get_queue().log_error().unwrap_or_default();
+        let restored_parties = data
+            .get_parties()
+            .log_error()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
         (
             Self {
                 data,
@@ -141,78 +272,468 @@ impl PlayQueue {
                 update_stream: Some(update_stream),
                 find_sender,
                 find_stream: Some(UnboundedReceiverStream::new(find_receiver)),
+                main_chat,
                 queue_message_id,
+                spectators: Vec::new(),
+                shown_count: 0,
+                messages_outdated: false,
+                restored,
+                restored_parties,
             },
             UnboundedReceiverStream::new(game_receiver),
         )
     }
 
+    fn timestamp(instant: Instant) -> u64 {
+        (SystemTime::now() - instant.elapsed())
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    fn instant(timestamp: u64) -> Instant {
+        let elapsed = SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_millis(timestamp))
+            .unwrap_or_default();
+        Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or_else(Instant::now)
+    }
+
+    fn save_queue(&self) {
+        self.data
+            .set_queue(
+                self.queue
+                    .iter()
+                    .map(|queued| QueueRecord {
+                        user_id: queued.user_id.into(),
+                        entered: Self::timestamp(queued.entered),
+                        last_update: Self::timestamp(queued.last_update),
+                        set_id: queued.set_id.clone(),
+                        max_players: queued.max_players as u8,
+                        expiry: queued.expiry.as_millis() as u64,
+                    })
+                    .collect(),
+            )
+            .log_error();
+        self.data
+            .set_parties(
+                self.queue
+                    .iter()
+                    .filter_map(|queued| Some((queued.user_id.into(), queued.partner?.into())))
+                    .collect(),
+            )
+            .log_error();
+    }
+
+    async fn restore_queue(&mut self) {
+        let restored = std::mem::take(&mut self.restored);
+        let parties = std::mem::take(&mut self.restored_parties);
+        if restored.is_empty() {
+            return;
+        }
+        for record in restored.iter() {
+            let user_id = UserId::new(record.user_id);
+            let last_update = Self::instant(record.last_update);
+            let expiry = Duration::from_millis(record.expiry);
+            if last_update.elapsed() > expiry {
+                self.bot
+                    .try_send_message(user_id.into(), Self::expired_message(expiry));
+                continue;
+            }
+            let text = Self::queue_message_text(restored.len());
+            if let Some(message_id) = self.bot.try_send_once(user_id.into(), text.clone()).await {
+                self.queue.push(Queued {
+                    user_id,
+                    message_id,
+                    entered: Self::instant(record.entered),
+                    last_update,
+                    set_id: record.set_id.clone(),
+                    max_players: record.max_players as usize,
+                    expiry,
+                    warned: false,
+                    partner: parties.get(&record.user_id).copied().map(UserId::new),
+                    shown: text,
+                });
+            }
+        }
+        self.save_queue();
+        self.update_messages();
+    }
+
+    fn expired_message(expiry: Duration) -> String {
+        format!("Игра не найдена за {} минут", expiry.as_secs() / 60)
+    }
+
+    /// Marks players whose search expires in `warning` as warned and removes the ones whose search
+    /// expired, returns the players to warn and the removed ones with their expiry.
+    fn check_expiry(
+        queue: &mut Vec<Queued>,
+        warning: Duration,
+    ) -> (Vec<UserId>, Vec<(UserId, Duration)>) {
+        let mut warned = Vec::new();
+        let mut expired = Vec::new();
+        queue.retain_mut(|queued| {
+            let since_update = queued.last_update.elapsed();
+            if since_update > queued.expiry {
+                expired.push((queued.user_id, queued.expiry));
+                return false;
+            }
+            if queued.expiry > warning && since_update >= queued.expiry - warning && !queued.warned
+            {
+                queued.warned = true;
+                warned.push(queued.user_id);
+            }
+            true
+        });
+        (warned, expired)
+    }
+
+    /// Players who agreed to play together, both ways.
+    fn parties(&self) -> HashMap<UserId, UserId> {
+        let requested = self
+            .queue
+            .iter()
+            .filter_map(|queued| Some((queued.user_id, queued.partner?)))
+            .collect::<HashMap<_, _>>();
+        requested
+            .iter()
+            .filter(|(user_id, partner)| requested.get(partner) == Some(user_id))
+            .map(|(user_id, partner)| (*user_id, *partner))
+            .collect()
+    }
+
+    fn display_name(&self, user_id: UserId) -> String {
+        self.data
+            .get_user_data(&user_id)
+            .log_error()
+            .flatten()
+            .map(|user_data| user_data.display_name())
+            .unwrap_or_default()
+    }
+
+    /// Players who wanted to play with someone who is no longer in the queue remain there alone.
+    fn dissolve_parties(&mut self, left: &[UserId]) {
+        for at in 0..self.queue.len() {
+            if let Some(partner) = self.queue[at]
+                .partner
+                .filter(|partner| left.contains(partner))
+            {
+                self.queue[at].partner = None;
+                self.bot.try_send_message(
+                    self.queue[at].user_id.into(),
+                    format!(
+                        "Пара отменена, {} больше не в очереди",
+                        self.display_name(partner)
+                    ),
+                );
+            }
+        }
+    }
+
+    fn set_partner(&mut self, user_id: UserId, partner: Option<UserId>) {
+        let at = match self.find_in_queue(user_id) {
+            Some(at) => at,
+            None => {
+                self.bot.try_send_message(
+                    user_id.into(),
+                    "Сначала зарегистрируйтесь в очереди".to_string(),
+                );
+                return;
+            }
+        };
+        let parties = self.parties();
+        if let Some(previous) = parties
+            .get(&user_id)
+            .filter(|previous| Some(**previous) != partner)
+        {
+            self.bot.try_send_message(
+                (*previous).into(),
+                format!(
+                    "{} больше не играет с вами в паре",
+                    self.display_name(user_id)
+                ),
+            );
+        }
+        self.queue[at].partner = partner;
+        self.save_queue();
+        let partner = match partner {
+            Some(partner) => partner,
+            None => {
+                self.bot
+                    .try_send_message(user_id.into(), "Вы больше не в паре".to_string());
+                return;
+            }
+        };
+        if self.parties().contains_key(&user_id) {
+            self.bot.try_send_message(
+                user_id.into(),
+                format!("Вы играете в паре с {}", self.display_name(partner)),
+            );
+            self.bot.try_send_message(
+                partner.into(),
+                format!("Вы играете в паре с {}", self.display_name(user_id)),
+            );
+        } else {
+            self.bot.try_send_message(
+                user_id.into(),
+                format!(
+                    "Пара будет создана, когда {} зарегистрируется в очереди и тоже отправит /party",
+                    self.display_name(partner)
+                ),
+            );
+            self.bot.try_send_message(
+                partner.into(),
+                format!(
+                    "{} хочет играть с вами в паре. Для этого зарегистрируйтесь в очереди и отправьте /party с @username пригласившего",
+                    self.display_name(user_id)
+                ),
+            );
+        }
+    }
+
     fn find_in_queue(&self, user_id: UserId) -> Option<usize> {
-        for (i, (id, ..)) in self.queue.iter().enumerate() {
-            if *id == user_id {
+        for (i, queued) in self.queue.iter().enumerate() {
+            if queued.user_id == user_id {
                 return Some(i);
             }
         }
         None
     }
 
+    /// Ratings of opponents a player accepts, widens the longer the player waits up to the range
+    /// set by the player.
+    fn rating_window(rating: u32, since_entered: Duration, range: Option<u32>) -> (i64, i64) {
+        let mut delta = (since_entered.as_millis() / 100 + 50) as i64;
+        if let Some(range) = range {
+            delta = delta.min(range as i64);
+        }
+        (rating as i64 - delta, rating as i64 + delta)
+    }
+
+    /// Players waiting this long are matched regardless of rating, unless they set a range.
+    fn force_add(since_entered: Duration, range: Option<u32>) -> bool {
+        range.is_none() && since_entered >= Duration::from_secs(300)
+    }
+
+    /// Players only agree to a game of three after waiting that long, so that bigger games get a
+    /// chance to gather.
+    fn will_play_with_three(since_entered: Duration) -> bool {
+        since_entered >= Duration::from_secs(60)
+    }
+
     fn find_games(&mut self) {
         if self.queue.len() < 3 {
             return;
         }
+        let rematch_delay = Duration::from_secs(
+            self.data
+                .get_rematch_delay()
+                .log_error()
+                .unwrap_or_default() as u64
+                * 60,
+        );
+        let mut set_ids = self
+            .data
+            .get_active_set_ids()
+            .log_error()
+            .unwrap_or_default();
+        for queued in self.queue.iter() {
+            if let Some(set_id) = queued.set_id.as_ref().filter(|id| !set_ids.contains(id)) {
+                set_ids.push(set_id.clone());
+            }
+        }
+        // Read once per search, since every player is checked against every set
+        let set_topics = set_ids
+            .into_iter()
+            .filter_map(|set_id| {
+                let set_topics = self.data.get_set_topics(&set_id).log_error()?;
+                Some((set_id, set_topics))
+            })
+            .collect::<HashMap<_, _>>();
+        let mut ban_lists = HashMap::new();
         let players = self
             .queue
             .iter()
-            .map(|(user_id, _, entered, _)| {
-                let since_entered = entered.elapsed();
-                let user_data = self.data.get_user_data(user_id).unwrap();
-                let delta = (since_entered.as_millis() / 100 + 50) as i64;
-                let min_rating = (user_data.rating as i64) - delta;
-                let max_rating = (user_data.rating as i64) + delta;
-                QueueEntry {
-                    user_id: *user_id,
+            .filter_map(|queued| {
+                let since_entered = queued.entered.elapsed();
+                let user_data = self
+                    .data
+                    .get_user_data(&queued.user_id)
+                    .log_error()
+                    .flatten()?;
+                let range = self.data.get_rating_range(queued.user_id).log_error()?;
+                let (min_rating, max_rating) =
+                    Self::rating_window(user_data.rating, since_entered, range);
+                let sets = Self::playable_sets(
+                    &self.data,
+                    queued.user_id,
+                    queued.set_id.as_ref(),
+                    &set_topics,
+                )?;
+                let ban_list = self.data.get_ban_list(queued.user_id).log_error()?;
+                ban_lists.insert(queued.user_id, ban_list.into_iter().collect::<HashSet<_>>());
+                Some(QueueEntry {
+                    user_id: queued.user_id,
                     user_data,
                     min_rating,
                     max_rating,
-                    force_add: since_entered >= Duration::from_secs(300),
-                }
+                    force_add: Self::force_add(since_entered, range),
+                    limited: range.is_some(),
+                    set_id: queued.set_id.clone(),
+                    max_players: queued.max_players,
+                    avoid_rematch: since_entered < rematch_delay,
+                    partner: None,
+                    will_play_with_three: Self::will_play_with_three(since_entered),
+                    sets,
+                })
             })
-            .collect();
-        let mut min_num_players = 3usize;
-        for (_, _, added, _) in self.queue.iter() {
-            if added.elapsed() < Duration::from_secs(60) {
-                min_num_players = 4usize;
-                break;
+            .collect::<Vec<_>>();
+        let mut games = Self::form_games(players, &self.parties(), self.data.clone(), &ban_lists);
+        if games.is_empty() {
+            return;
+        }
+        let left = games
+            .iter()
+            .flat_map(|(game_data, ..)| game_data.players.keys().copied())
+            .collect::<Vec<_>>();
+        self.spectators.retain(|user_id| !left.contains(user_id));
+        for (game_data, ..) in games.iter_mut() {
+            self.add_spectators(game_data);
+        }
+        for user_id in left.iter() {
+            self.queue.remove(self.find_in_queue(*user_id).unwrap());
+        }
+        self.dissolve_parties(&left);
+        self.save_queue();
+        self.update_messages();
+        for res in games {
+            self.sender.send(res).unwrap();
+        }
+    }
+
+    /// Sets the player can play, only the requested one if the player asked for a set.
+    fn playable_sets(
+        data: &Data,
+        user_id: UserId,
+        requested: Option<&String>,
+        set_topics: &HashMap<String, SetTopics>,
+    ) -> Option<HashSet<String>> {
+        let mut sets = HashSet::new();
+        for (set_id, set_topics) in set_topics.iter() {
+            if requested.map_or(false, |requested| requested != set_id) {
+                continue;
+            }
+            if data
+                .topics_remain(user_id, set_id, set_topics)
+                .log_error()?
+                >= GameFinder::TOPIC_COUNT
+            {
+                sets.insert(set_id.clone());
             }
         }
-        for num_players in (min_num_players..=4usize).rev() {
-            if let Some(res) = GameFinder::find_game(&players, num_players, self.data.clone()) {
-                for user_id in res.0.players.keys() {
-                    self.queue.remove(self.find_in_queue(*user_id).unwrap());
-                }
-                self.update_messages();
-                self.sender.send(res).unwrap();
-                return;
+        Some(sets)
+    }
+
+    /// Invites the first waiting spectators to the game.
+    fn add_spectators(&mut self, game_data: &mut GameStartData) {
+        while game_data.spectators.len() < Self::MAX_SPECTATORS && !self.spectators.is_empty() {
+            let user_id = self.spectators.remove(0);
+            if let Some(user_data) = self.data.get_user_data(&user_id).log_error().flatten() {
+                game_data.chat_ids.push(user_id.into());
+                game_data.spectators.insert(user_id, user_data);
+            }
+        }
+    }
+
+    fn spectator_entered(&mut self, user_id: UserId) {
+        let message = if self.find_in_queue(user_id).is_some() {
+            "Вы находитесь в очереди как игрок"
+        } else if self.spectators.contains(&user_id) {
+            "Вы уже в списке зрителей"
+        } else {
+            self.spectators.push(user_id);
+            "Вы добавлены в список зрителей, приглашение придет, когда будет найдена игра"
+        };
+        self.bot
+            .try_send_message(user_id.into(), message.to_string());
+    }
+
+    fn spectator_left(&mut self, user_id: UserId) {
+        let message = match self.spectators.iter().position(|id| *id == user_id) {
+            Some(at) => {
+                self.spectators.remove(at);
+                "Вы удалены из списка зрителей"
+            }
+            None => "Вы не находились в списке зрителей",
+        };
+        self.bot
+            .try_send_message(user_id.into(), message.to_string());
+    }
+
+    /// Forms games until none can be formed from the remaining players, bigger games first.
+    fn form_games(
+        mut players: Vec<QueueEntry>,
+        parties: &HashMap<UserId, UserId>,
+        data: Data,
+        ban_lists: &HashMap<UserId, HashSet<UserId>>,
+    ) -> Vec<(GameStartData, String, Vec<usize>)> {
+        let mut games = Vec::new();
+        while players.len() >= 3 {
+            let indices = players
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| (entry.user_id, i))
+                .collect::<HashMap<_, _>>();
+            for entry in players.iter_mut() {
+                entry.partner = parties
+                    .get(&entry.user_id)
+                    .and_then(|partner| indices.get(partner).copied());
+            }
+            let res = match (3..=Self::MAX_PLAYERS).rev().find_map(|num_players| {
+                GameFinder::find_game(&players, num_players, data.clone(), ban_lists)
+            }) {
+                Some(res) => res,
+                None => break,
+            };
+            let before = players.len();
+            players.retain(|entry| !res.0.players.contains_key(&entry.user_id));
+            // A game without players from the list would be found again and again
+            if players.len() == before {
+                break;
             }
+            games.push(res);
         }
+        games
     }
 
-    fn update_messages(&self) {
-        for (user_id, message_id, ..) in self.queue.iter() {
+    /// Changes within a tick are shown together by the end of it.
+    fn update_messages(&mut self) {
+        self.messages_outdated = true;
+    }
+
+    /// Edits the messages that show a different queue size than the current one.
+    fn flush_messages(&mut self) {
+        self.messages_outdated = false;
+        let text = Self::queue_message_text(self.queue.len());
+        for queued in self.queue.iter_mut() {
+            if queued.shown != text {
+                self.bot
+                    .try_edit_message(queued.user_id.into(), queued.message_id, text.clone());
+                queued.shown = text.clone();
+            }
+        }
+        if self.shown_count != self.queue.len() {
+            self.shown_count = self.queue.len();
             self.bot.try_edit_message(
-                (*user_id).into(),
-                *message_id,
-                Self::queue_message_text(self.queue.len()),
+                self.main_chat,
+                self.queue_message_id,
+                format!(
+                    "Всего игроков в очереди <b>{}</b>\nДля игры пройдите в @SvoyakSchedulerBot",
+                    self.queue.len()
+                ),
             );
         }
-        self.bot.try_edit_message(
-            ChatId::new(Main::MAIN_CHAT),
-            self.queue_message_id,
-            format!(
-                "Всего игроков в очереди <b>{}</b>\nДля игры пройдите в @SvoyakSchedulerBot",
-                self.queue.len()
-            ),
-        );
     }
 
     pub async fn start(&mut self) {
@@ -220,10 +741,14 @@ impl PlayQueue {
             self.update_stream.take().unwrap().boxed(),
             self.find_stream.take().unwrap().boxed(),
         ]);
+        self.restore_queue().await;
         self.schedule_timeout();
         while let Some(message) = stream.next().await {
             match message {
-                UpdateMessage::UserEntered(user_id) => self.user_entered(user_id).await,
+                UpdateMessage::UserEntered(user_id, set_id, max_players, expiry) => {
+                    self.user_entered(user_id, set_id, max_players, expiry)
+                        .await
+                }
                 UpdateMessage::UserLeft(user_id) => match self.find_in_queue(user_id) {
                     None => {
                         self.bot.try_send_message(
@@ -233,53 +758,129 @@ impl PlayQueue {
                     }
                     Some(at) => {
                         self.queue.remove(at);
+                        self.dissolve_parties(&[user_id]);
+                        self.save_queue();
                         self.bot
                             .try_send_message(user_id.into(), "Вы вышли из очереди".to_string());
-                        self.update_messages();
+                        self.flush_messages();
                     }
                 },
                 UpdateMessage::Shutdown => {
                     self.cancel_timer();
+                    self.shutdown().await;
                     break;
                 }
+                UpdateMessage::QueryStatus(user_id) => self.query_status(user_id),
+                UpdateMessage::Party(user_id, partner) => self.set_partner(user_id, partner),
+                UpdateMessage::SpectatorEntered(user_id) => self.spectator_entered(user_id),
+                UpdateMessage::SpectatorLeft(user_id) => self.spectator_left(user_id),
+                UpdateMessage::QueueLength(sender) => {
+                    if sender.send(self.queue.len()).is_err() {
+                        log::error!("Queue length request was dropped");
+                    }
+                }
                 UpdateMessage::FindGames => {
                     self.find_games();
-                    let mut removed = false;
-                    self.queue = self
-                        .queue
-                        .drain(..)
-                        .filter(|(id, _, _, last_update)| {
-                            let retain = last_update.elapsed() <= Duration::from_secs(600);
-                            if !retain {
-                                self.bot.try_send_message(
-                                    (*id).into(),
-                                    "Игра не найдена за 10 минут".to_string(),
-                                );
-                                removed = true;
-                            }
-                            retain
-                        })
-                        .collect();
-                    if removed {
+                    let (warned, expired) =
+                        Self::check_expiry(&mut self.queue, Self::EXPIRY_WARNING);
+                    for user_id in warned {
+                        self.bot.try_send_message(
+                            user_id.into(),
+                            Self::EXPIRY_WARNING_MESSAGE.to_string(),
+                        );
+                    }
+                    for (user_id, expiry) in expired.iter() {
+                        self.bot
+                            .try_send_message((*user_id).into(), Self::expired_message(*expiry));
+                    }
+                    let expired = expired
+                        .into_iter()
+                        .map(|(user_id, _)| user_id)
+                        .collect::<Vec<_>>();
+                    self.dissolve_parties(&expired);
+                    if !expired.is_empty() {
+                        self.save_queue();
                         self.update_messages();
                     }
+                    if self.messages_outdated {
+                        self.flush_messages();
+                    }
                     self.schedule_timeout();
                 }
             }
         }
     }
 
-    async fn user_entered(&mut self, user_id: UserId) {
+    /// Tells everyone in the queue that it is gone, messages are sent before returning so that they
+    /// are not lost when the bot exits.
+    async fn shutdown(&mut self) {
+        let message = "Бот перезагружается, очередь очищена, зарегистрируйтесь позже";
+        for queued in self.queue.drain(..) {
+            self.bot
+                .edit_message(
+                    queued.user_id.into(),
+                    queued.message_id,
+                    message.to_string(),
+                )
+                .await
+                .log_error();
+            self.bot
+                .try_send_once(queued.user_id.into(), message.to_string())
+                .await;
+        }
+        for user_id in std::mem::take(&mut self.spectators) {
+            self.bot
+                .try_send_once(user_id.into(), message.to_string())
+                .await;
+        }
+        self.save_queue();
+        self.flush_messages();
+    }
+
+    async fn user_entered(
+        &mut self,
+        user_id: UserId,
+        set_id: Option<String>,
+        max_players: usize,
+        expiry: Duration,
+    ) {
+        if self
+            .data
+            .is_globally_banned(user_id)
+            .log_error()
+            .unwrap_or(false)
+        {
+            self.bot.try_send_message(
+                user_id.into(),
+                "Вы исключены из рейтинговых игр".to_string(),
+            );
+            return;
+        }
+        if self
+            .data
+            .daily_limit_reached(user_id, SystemTime::now())
+            .log_error()
+            .unwrap_or(false)
+        {
+            self.bot
+                .try_send_message(user_id.into(), DAILY_LIMIT_MESSAGE.to_string());
+            return;
+        }
+        self.spectators.retain(|id| *id != user_id);
         match self.find_in_queue(user_id) {
             Some(at) => {
-                self.queue[at].3 = Instant::now();
-                if let Some(message_id) = self
-                    .bot
-                    .try_send_once(user_id.into(), Self::queue_message_text(self.queue.len()))
-                    .await
+                self.queue[at].last_update = Instant::now();
+                self.queue[at].warned = false;
+                self.queue[at].set_id = set_id;
+                self.queue[at].max_players = max_players;
+                self.queue[at].expiry = expiry;
+                let text = Self::queue_message_text(self.queue.len());
+                if let Some(message_id) = self.bot.try_send_once(user_id.into(), text.clone()).await
                 {
-                    self.queue[at].1 = message_id;
+                    self.queue[at].message_id = message_id;
+                    self.queue[at].shown = text;
                 }
+                self.save_queue();
             }
             None => {
                 if let Some(message_id) = self
@@ -287,14 +888,90 @@ impl PlayQueue {
                     .try_send_once(user_id.into(), "Вы добавлены в очередь".to_string())
                     .await
                 {
-                    self.queue
-                        .push((user_id, message_id, Instant::now(), Instant::now()));
-                    self.update_messages();
+                    self.queue.push(Queued {
+                        user_id,
+                        message_id,
+                        entered: Instant::now(),
+                        last_update: Instant::now(),
+                        set_id,
+                        max_players,
+                        expiry,
+                        warned: false,
+                        partner: None,
+                        shown: "Вы добавлены в очередь".to_string(),
+                    });
+                    self.save_queue();
+                    self.flush_messages();
                 }
             }
         }
     }
 
+    fn query_status(&self, user_id: UserId) {
+        let position = match self.find_in_queue(user_id) {
+            Some(position) => position,
+            None => {
+                self.bot
+                    .try_send_message(user_id.into(), "Вы не в очереди".to_string());
+                return;
+            }
+        };
+        let queued = &self.queue[position];
+        let since_entered = queued.entered.elapsed();
+        let range = self.data.get_rating_range(user_id).log_error().flatten();
+        let rating_window = if Self::force_add(since_entered, range) {
+            "любой".to_string()
+        } else {
+            match self.data.get_user_data(&user_id).log_error().flatten() {
+                Some(user_data) => {
+                    let (min_rating, max_rating) =
+                        Self::rating_window(user_data.rating, since_entered, range);
+                    format!(
+                        "{}-{}",
+                        display_rating(min_rating.max(0) as u32),
+                        display_rating(max_rating as u32)
+                    )
+                }
+                None => "неизвестен".to_string(),
+            }
+        };
+        let range_text = match range {
+            None => "не ограничена".to_string(),
+            Some(range) => display_rating(range).to_string(),
+        };
+        let party = match self.parties().get(&user_id) {
+            Some(partner) => self.display_name(*partner),
+            None => "нет".to_string(),
+        };
+        let seconds = since_entered.as_secs();
+        let remaining = queued
+            .expiry
+            .saturating_sub(queued.last_update.elapsed())
+            .as_secs();
+        self.bot.try_send_message(
+            user_id.into(),
+            format!(
+                "Место в очереди: <b>{}</b> из <b>{}</b>\nОжидание: {}:{:02}\nДо окончания поиска: {}:{:02}\nРейтинг соперников: {}\nМаксимальная разница в рейтинге: {}\nПакет: {}\nИгроков в игре: до {}\nИгры на троих: {}\nПара: {}",
+                position + 1,
+                self.queue.len(),
+                seconds / 60,
+                seconds % 60,
+                remaining / 60,
+                remaining % 60,
+                rating_window,
+                range_text,
+                queued.set_id.as_deref().unwrap_or("любой"),
+                queued.max_players,
+                if Self::will_play_with_three(since_entered) {
+                    "возможны"
+                } else {
+                    "после минуты ожидания"
+                },
+                party
+            ),
+        );
+    }
+
     fn queue_message_text(in_queue: usize) -> String {
         format!("Ищем игру. Всего игроков в очереди <b>{}</b>", in_queue)
     }
@@ -317,3 +994,299 @@ impl PlayQueue {
         }))
     }
 }
+
+#[cfg(test)]
+fn test_data(set_ids: &[&str], topic_count: usize) -> Data {
+    let data = Data::new_temporary();
+    for set_id in set_ids {
+        let set_id = set_id.to_string();
+        let set = crate::topic::TopicSet::with_empty_topics(&set_id, topic_count);
+        assert!(data.add_new_set(&set_id, set).unwrap());
+        data.add_active(&set_id).unwrap();
+    }
+    data
+}
+
+#[cfg(test)]
+fn test_entry(id: i64, sets: HashSet<String>) -> QueueEntry {
+    QueueEntry {
+        user_id: UserId::new(id),
+        user_data: UserData::new(id.to_string(), 15000),
+        min_rating: 14000,
+        max_rating: 16000,
+        force_add: false,
+        limited: false,
+        set_id: None,
+        max_players: PlayQueue::DEFAULT_PLAYERS,
+        avoid_rematch: false,
+        partner: None,
+        will_play_with_three: true,
+        sets,
+    }
+}
+
+#[cfg(test)]
+fn player_ids(game_data: &GameStartData) -> Vec<i64> {
+    let mut ids = game_data
+        .players
+        .keys()
+        .map(|id| i64::from(*id))
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_find_game_size() {
+    let data = test_data(&["set"], 12);
+    let players = (1..=6)
+        .map(|id| QueueEntry {
+            max_players: if id == 6 { 4 } else { 5 },
+            ..test_entry(id, HashSet::from(["set".to_string()]))
+        })
+        .collect::<Vec<_>>();
+    let ban_lists = HashMap::new();
+    assert!(GameFinder::find_game(&players, 6, data.clone(), &ban_lists).is_none());
+    let (game_data, found_set, topics) =
+        GameFinder::find_game(&players, 5, data.clone(), &ban_lists).unwrap();
+    assert_eq!(found_set, "set");
+    assert_eq!(topics.len(), 6);
+    assert_eq!(player_ids(&game_data), vec![1, 2, 3, 4, 5]);
+    assert_eq!(
+        GameFinder::find_game(&players, 4, data, &ban_lists)
+            .unwrap()
+            .0
+            .players
+            .len(),
+        4
+    );
+}
+
+#[test]
+fn test_avoid_rematch() {
+    let data = test_data(&["set"], 12);
+    for game in [[1, 2], [3, 4], [2, 5], [4, 5]] {
+        data.add_game(&game.map(UserId::new)).unwrap();
+    }
+    let queue = |avoid_rematch: bool| {
+        (1..=5)
+            .map(|id| QueueEntry {
+                max_players: 4,
+                avoid_rematch: avoid_rematch && id != 5,
+                ..test_entry(id, HashSet::from(["set".to_string()]))
+            })
+            .collect::<Vec<_>>()
+    };
+    let ban_lists = HashMap::new();
+    let players = queue(true);
+    let (game_data, ..) = GameFinder::find_game(&players, 3, data.clone(), &ban_lists).unwrap();
+    assert_eq!(player_ids(&game_data), vec![1, 3, 5]);
+    assert!(GameFinder::find_game(&players, 4, data.clone(), &ban_lists).is_none());
+    let players = queue(false);
+    assert!(GameFinder::find_game(&players, 4, data, &ban_lists).is_some());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_expiry_warning() {
+    async fn tick(
+        queue: &mut Vec<Queued>,
+        warning: Duration,
+    ) -> (Vec<UserId>, Vec<(UserId, Duration)>) {
+        tokio::time::advance(Duration::from_millis(250)).await;
+        PlayQueue::check_expiry(queue, warning)
+    }
+
+    let warning = Duration::from_millis(400);
+    let expiry = Duration::from_millis(800);
+    let short_expiry = Duration::from_millis(300);
+    let mut queue = (1..=3)
+        .map(|id| Queued {
+            user_id: UserId::new(id),
+            message_id: MessageId::new(id),
+            entered: Instant::now(),
+            last_update: Instant::now(),
+            set_id: None,
+            max_players: PlayQueue::DEFAULT_PLAYERS,
+            expiry: if id == 3 { short_expiry } else { expiry },
+            warned: false,
+            partner: None,
+            shown: String::new(),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(tick(&mut queue, warning).await, (vec![], vec![]));
+    // The short search expires without a warning
+    assert_eq!(
+        tick(&mut queue, warning).await,
+        (
+            vec![UserId::new(1), UserId::new(2)],
+            vec![(UserId::new(3), short_expiry)]
+        )
+    );
+    queue[1].last_update = Instant::now();
+    queue[1].warned = false;
+    assert_eq!(tick(&mut queue, warning).await, (vec![], vec![]));
+    assert_eq!(
+        tick(&mut queue, warning).await,
+        (vec![UserId::new(2)], vec![(UserId::new(1), expiry)])
+    );
+    assert_eq!(queue.len(), 1);
+    assert_eq!(tick(&mut queue, warning).await, (vec![], vec![]));
+    assert_eq!(
+        tick(&mut queue, warning).await,
+        (vec![], vec![(UserId::new(2), expiry)])
+    );
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_party() {
+    let data = test_data(&["set"], 12);
+    let players = (1..=5)
+        .map(|id| QueueEntry {
+            max_players: 4,
+            partner: match id {
+                4 => Some(4),
+                5 => Some(3),
+                _ => None,
+            },
+            ..test_entry(id, HashSet::from(["set".to_string()]))
+        })
+        .collect::<Vec<_>>();
+    let found = |num_players: usize, ban_lists: &HashMap<UserId, HashSet<UserId>>| {
+        GameFinder::find_game(&players, num_players, data.clone(), ban_lists)
+            .map(|(game_data, ..)| player_ids(&game_data))
+    };
+    let mut ban_lists = HashMap::new();
+    assert_eq!(found(4, &ban_lists), Some(vec![1, 2, 4, 5]));
+    ban_lists.insert(UserId::new(1), HashSet::from([UserId::new(5)]));
+    assert_eq!(found(4, &ban_lists), Some(vec![2, 3, 4, 5]));
+    ban_lists.insert(UserId::new(2), HashSet::from([UserId::new(5)]));
+    assert_eq!(found(4, &ban_lists), None);
+    assert_eq!(found(3, &ban_lists), Some(vec![1, 2, 3]));
+}
+
+#[test]
+fn test_find_game_uses_loaded_ban_lists() {
+    let data = test_data(&["set"], 12);
+    let players = (1..=30)
+        .map(|id| QueueEntry {
+            max_players: PlayQueue::MAX_PLAYERS,
+            ..test_entry(id, HashSet::from(["set".to_string()]))
+        })
+        .collect::<Vec<_>>();
+    // Only the last six players don't ban each other, so the search rejects most candidates,
+    // while the bans stored in the database would forbid any game
+    let ban_lists = players
+        .iter()
+        .map(|entry| {
+            let banned = players
+                .iter()
+                .map(|other| other.user_id)
+                .filter(|other| {
+                    *other != entry.user_id
+                        && (i64::from(entry.user_id) <= 24 || i64::from(*other) <= 24)
+                })
+                .collect::<HashSet<_>>();
+            (entry.user_id, banned)
+        })
+        .collect::<HashMap<_, _>>();
+    for entry in players.iter() {
+        for other in players.iter() {
+            if entry.user_id != other.user_id {
+                data.add_to_ban_list(entry.user_id, other.user_id).unwrap();
+            }
+        }
+    }
+    let start = Instant::now();
+    let (game_data, ..) =
+        GameFinder::find_game(&players, PlayQueue::MAX_PLAYERS, data, &ban_lists).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(player_ids(&game_data), (25..=30).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_form_several_games() {
+    let data = test_data(&["set"], 12);
+    let players = (1..=8)
+        .map(|id| test_entry(id, HashSet::from(["set".to_string()])))
+        .collect::<Vec<_>>();
+    let games = PlayQueue::form_games(players, &HashMap::new(), data, &HashMap::new());
+    assert_eq!(games.len(), 2);
+    let mut found = games
+        .iter()
+        .flat_map(|(game_data, ..)| player_ids(game_data))
+        .collect::<Vec<_>>();
+    found.sort();
+    assert_eq!(found, (1..=8).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_three_players_wait() {
+    let data = test_data(&["set"], 12);
+    // The newcomer is out of everyone's rating window, so only a game of three is possible
+    let queue = |waited: &[i64]| {
+        (1..=4)
+            .map(|id| QueueEntry {
+                user_data: UserData::new(id.to_string(), if id == 4 { 20000 } else { 15000 }),
+                will_play_with_three: waited.contains(&id),
+                ..test_entry(id, HashSet::from(["set".to_string()]))
+            })
+            .collect::<Vec<_>>()
+    };
+    let games = PlayQueue::form_games(
+        queue(&[1, 2, 3]),
+        &HashMap::new(),
+        data.clone(),
+        &HashMap::new(),
+    );
+    assert_eq!(games.len(), 1);
+    assert_eq!(player_ids(&games[0].0), vec![1, 2, 3]);
+    assert!(
+        PlayQueue::form_games(queue(&[1, 2, 4]), &HashMap::new(), data, &HashMap::new()).is_empty()
+    );
+}
+
+#[test]
+fn test_common_sets() {
+    let data = test_data(&["a", "b"], 8);
+    let set_ids = ["a".to_string(), "b".to_string()];
+    data.set_played(&[&UserId::new(1)], &set_ids[1], &[0, 1, 2, 3])
+        .unwrap();
+    data.set_played(&[&UserId::new(2)], &set_ids[0], &[0, 1, 2, 3])
+        .unwrap();
+    let set_topics = set_ids
+        .iter()
+        .map(|set_id| (set_id.clone(), data.get_set_topics(set_id).unwrap()))
+        .collect::<HashMap<_, _>>();
+    let queue = |requested: Option<&String>| {
+        (1..=4)
+            .map(|id| {
+                let set_id = requested.filter(|_| id == 4);
+                QueueEntry {
+                    set_id: set_id.cloned(),
+                    ..test_entry(
+                        id,
+                        PlayQueue::playable_sets(&data, UserId::new(id), set_id, &set_topics)
+                            .unwrap(),
+                    )
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let players = queue(None);
+    assert_eq!(players[0].sets, HashSet::from([set_ids[0].clone()]));
+    assert_eq!(players[1].sets, HashSet::from([set_ids[1].clone()]));
+    assert_eq!(players[2].sets.len(), 2);
+    let ban_lists = HashMap::new();
+    assert!(GameFinder::find_game(&players, 4, data.clone(), &ban_lists).is_none());
+    let (game_data, found_set, _) =
+        GameFinder::find_game(&players, 3, data.clone(), &ban_lists).unwrap();
+    assert_eq!(player_ids(&game_data), vec![1, 3, 4]);
+    assert_eq!(found_set, set_ids[0]);
+    // The player who asked for a set is not grouped with someone who can only play another one
+    let players = queue(Some(&set_ids[1]));
+    assert_eq!(players[3].sets, HashSet::from([set_ids[1].clone()]));
+    let (game_data, found_set, _) = GameFinder::find_game(&players, 3, data, &ban_lists).unwrap();
+    assert_eq!(player_ids(&game_data), vec![2, 3, 4]);
+    assert_eq!(found_set, set_ids[1]);
+}
@@ -1,13 +1,20 @@
 use borsh::{BorshDeserialize, BorshSerialize};
-use html_escape::encode_text;
-use serde::Deserialize;
+use html_escape::{decode_html_entities, encode_text};
+use serde::{Deserialize, Serialize};
+use std::iter::once;
 
-#[derive(BorshSerialize, BorshDeserialize, Clone, Deserialize)]
+#[derive(BorshSerialize, BorshDeserialize, Clone, Deserialize, Serialize)]
 pub struct Question {
     pub cost: u16,
     pub question: String,
     pub answers: Vec<String>,
     pub comment: Option<String>,
+    /// Telegram file id or URL of the picture shown with the question.
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Answers are parts that all have to be named, not alternatives.
+    #[serde(default)]
+    pub all_required: bool,
 }
 
 impl Question {
@@ -17,23 +24,97 @@ impl Question {
             question: encode(&question),
             answers: answers.iter().map(|ans| encode(ans)).collect(),
             comment: comment.map(|comment| encode(&comment)),
+            image: None,
+            all_required: false,
         }
     }
 
-    pub fn check_answer(&self, answer: &str) -> bool {
-        let answer = answer.trim();
-        let answer_no_par = Self::no_space(answer, false);
-        let answer_par = Self::no_space(answer, true);
-        self.answers.iter().any(|expected| {
-            let expected_no_par = Self::no_space(expected.as_str(), false);
-            let expected_par = Self::no_space(expected.as_str(), true);
-            answer_no_par == expected_no_par
-                || answer_no_par == expected_par
-                || answer_par == expected_no_par
-                || answer_par == expected_par
+    /// Answers shorter than this have to match exactly.
+    const MIN_FUZZY_LENGTH: usize = 4;
+    /// Answers up to this length may differ by one typo, longer ones by two.
+    const SHORT_ANSWER_LENGTH: usize = 8;
+
+    /// Without `strict` a typo or two is forgiven, see `is_close`. When all answers are required
+    /// each of them has to match a part of the typed answer, and no part may be wrong.
+    pub fn check_answer(&self, answer: &str, strict: bool) -> bool {
+        if self.all_required {
+            // A part also counts by its leading words, the noun shared by all parts follows the
+            // last one in "Северный и Южный полюс"
+            let parts = answer_parts(answer)
+                .iter()
+                .map(|part| {
+                    let words = part.split_whitespace().collect::<Vec<_>>();
+                    (1..=words.len())
+                        .rev()
+                        .map(|len| AnswerForm::variants(&words[..len].join(" "), false))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let matches = |part: &Vec<[AnswerForm; 2]>, expected: &String| {
+                part.iter()
+                    .any(|prefix| Self::matches(prefix, expected, strict))
+            };
+            self.answers
+                .iter()
+                .all(|expected| parts.iter().any(|part| matches(part, expected)))
+                && parts
+                    .iter()
+                    .all(|part| self.answers.iter().any(|expected| matches(part, expected)))
+        } else {
+            let answer_variants = AnswerForm::variants(answer.trim(), false);
+            self.answers
+                .iter()
+                .any(|expected| Self::matches(&answer_variants, expected, strict))
+        }
+    }
+
+    fn matches(answer_variants: &[AnswerForm; 2], expected: &str, strict: bool) -> bool {
+        // Stored answers are html-escaped, typed ones are not
+        let expected_variants = AnswerForm::variants(decode(expected).as_str(), true);
+        answer_variants.iter().any(|answer| {
+            expected_variants.iter().any(|expected| {
+                let matches = |answer: &String, expected: &String| {
+                    answer == expected || !strict && Self::is_close(answer, expected)
+                };
+                matches(&answer.text, &expected.text)
+                    || answer.numeric.is_some() && answer.numeric == expected.numeric
+                    || match (&answer.latin, &expected.latin) {
+                        (Some(answer), Some(expected)) => matches(answer, expected),
+                        _ => false,
+                    }
+            })
         })
     }
 
+    /// Damerau-Levenshtein distance between normalized answers is within the tolerance. Numbers
+    /// are never fuzzy, 1812 and 1813 are different answers.
+    fn is_close(answer: &str, expected: &str) -> bool {
+        let answer = answer.chars().collect::<Vec<_>>();
+        let expected = expected.chars().collect::<Vec<_>>();
+        if expected.len() < Self::MIN_FUZZY_LENGTH
+            || expected.iter().all(|c| c.is_numeric())
+            || answer.iter().all(|c| c.is_numeric())
+        {
+            return false;
+        }
+        let leading_digits = |s: &[char]| {
+            s.iter()
+                .take_while(|c| c.is_numeric())
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        if leading_digits(&answer) != leading_digits(&expected) {
+            return false;
+        }
+        let tolerance = if expected.len() <= Self::SHORT_ANSWER_LENGTH {
+            1
+        } else {
+            2
+        };
+        answer.len().abs_diff(expected.len()) <= tolerance
+            && edit_distance(&answer, &expected) <= tolerance
+    }
+
     pub fn display_question(&self, topic_name: &str) -> String {
         format!(
             "<b>Тема</b> {}\n<b>{}.</b> {}",
@@ -41,15 +122,6 @@ impl Question {
         )
     }
 
-    pub fn fix(&self) -> Question {
-        Self::new(
-            self.cost,
-            self.question.clone(),
-            &self.answers.iter().cloned().collect::<Vec<_>>(),
-            self.comment.clone(),
-        )
-    }
-
     pub fn display_answers(&self, after_right_answer: bool) -> String {
         let mut res = if after_right_answer {
             "<b>Авторский ответ</b>: "
@@ -61,6 +133,8 @@ impl Question {
         for answer in self.answers.iter() {
             if first {
                 first = false;
+            } else if self.all_required {
+                res += " + ";
             } else {
                 res += "\n<b>Зачет</b>: ";
             }
@@ -74,31 +148,318 @@ impl Question {
     }
 
     fn no_space(answer: &str, skip_parenthesis: bool) -> String {
-        let mut res = String::new();
+        Self::words(answer, skip_parenthesis).concat()
+    }
+
+    /// Lowercased words of the answer, digits and letters are separate words.
+    fn words(answer: &str, skip_parenthesis: bool) -> Vec<String> {
+        let mut res = Vec::new();
+        let mut word = String::new();
         let mut level = 0i16;
         for c in answer.chars() {
             if c == '(' || c == '[' || c == '{' {
                 level += 1;
             } else if c == ')' || c == ']' || c == '}' {
                 level -= 1;
-            } else if (level == 0 || !skip_parenthesis) && c.is_alphanumeric() {
-                if c == 'ё' || c == 'Ё' {
-                    res.push('е');
-                } else {
-                    for d in c.to_lowercase() {
-                        res.push(d);
-                    }
+            }
+            let letter = (level == 0 || !skip_parenthesis) && c.is_alphanumeric();
+            let same_word = word
+                .chars()
+                .last()
+                .is_some_and(|last| last.is_numeric() == c.is_numeric());
+            if !word.is_empty() && !(letter && same_word) {
+                res.push(std::mem::take(&mut word));
+            }
+            if letter {
+                word.push(c);
+            }
+        }
+        if !word.is_empty() {
+            res.push(word);
+        }
+        res.iter().map(|word| normalize_word(word)).collect()
+    }
+}
+
+/// An answer prepared for comparison.
+struct AnswerForm {
+    text: String,
+    numeric: Option<String>,
+    /// Spelling in Latin letters, typed answers have it only when typed in Latin and expected
+    /// ones only when written in Cyrillic.
+    latin: Option<String>,
+}
+
+impl AnswerForm {
+    /// Forms of the answer with and without the parts in parentheses.
+    fn variants(answer: &str, expected: bool) -> [Self; 2] {
+        [false, true].map(|skip_parenthesis| {
+            let words = Question::words(answer, skip_parenthesis);
+            let text = words.concat();
+            let cyrillic = text.chars().any(is_cyrillic);
+            let latin = if expected && cyrillic {
+                Some(latin_key(
+                    &words
+                        .iter()
+                        .map(|word| transliterate(word))
+                        .collect::<String>(),
+                ))
+            } else if !expected && !cyrillic {
+                Some(latin_key(&text))
+            } else {
+                None
+            };
+            Self {
+                numeric: numeric_form(&words),
+                text,
+                latin,
+            }
+        })
+    }
+}
+
+/// Latin letters and the Cyrillic ones that look the same.
+const HOMOGLYPHS: [(char, char); 20] = [
+    ('A', 'А'),
+    ('B', 'В'),
+    ('C', 'С'),
+    ('E', 'Е'),
+    ('H', 'Н'),
+    ('K', 'К'),
+    ('M', 'М'),
+    ('O', 'О'),
+    ('P', 'Р'),
+    ('T', 'Т'),
+    ('X', 'Х'),
+    ('Y', 'У'),
+    ('a', 'а'),
+    ('c', 'с'),
+    ('e', 'е'),
+    ('k', 'к'),
+    ('o', 'о'),
+    ('p', 'р'),
+    ('x', 'х'),
+    ('y', 'у'),
+];
+
+fn is_cyrillic(c: char) -> bool {
+    matches!(c, 'а'..='я' | 'А'..='Я' | 'ё' | 'Ё')
+}
+
+/// Lowercases the word. Look-alike letters of the other script in a word typed with a wrong
+/// keyboard layout are replaced, the script is the one of the letters without a look-alike.
+fn normalize_word(word: &str) -> String {
+    let has_cyrillic = word.chars().any(is_cyrillic);
+    let only_cyrillic = word
+        .chars()
+        .any(|c| is_cyrillic(c) && !HOMOGLYPHS.iter().any(|(_, cyrillic)| *cyrillic == c));
+    let only_latin = word
+        .chars()
+        .any(|c| c.is_ascii_alphabetic() && !HOMOGLYPHS.iter().any(|(latin, _)| *latin == c));
+    let mut res = String::new();
+    for c in word.chars() {
+        let c = if only_latin && !only_cyrillic {
+            HOMOGLYPHS
+                .iter()
+                .find(|(_, cyrillic)| *cyrillic == c)
+                .map_or(c, |(latin, _)| *latin)
+        } else if has_cyrillic && !only_latin {
+            HOMOGLYPHS
+                .iter()
+                .find(|(latin, _)| *latin == c)
+                .map_or(c, |(_, cyrillic)| *cyrillic)
+        } else {
+            c
+        };
+        if c == 'ё' || c == 'Ё' {
+            res.push('е');
+        } else {
+            res.extend(c.to_lowercase());
+        }
+    }
+    res
+}
+
+const TRANSLITERATION: [(char, &str); 32] = [
+    ('а', "a"),
+    ('б', "b"),
+    ('в', "v"),
+    ('г', "g"),
+    ('д', "d"),
+    ('е', "e"),
+    ('ж', "zh"),
+    ('з', "z"),
+    ('и', "i"),
+    ('й', "y"),
+    ('к', "k"),
+    ('л', "l"),
+    ('м', "m"),
+    ('н', "n"),
+    ('о', "o"),
+    ('п', "p"),
+    ('р', "r"),
+    ('с', "s"),
+    ('т', "t"),
+    ('у', "u"),
+    ('ф', "f"),
+    ('х', "kh"),
+    ('ц', "ts"),
+    ('ч', "ch"),
+    ('ш', "sh"),
+    ('щ', "shch"),
+    ('ъ', ""),
+    ('ы', "y"),
+    ('ь', ""),
+    ('э', "e"),
+    ('ю', "yu"),
+    ('я', "ya"),
+];
+
+/// Latin spelling of a normalized word, "Достоевский" becomes "dostoevsky".
+fn transliterate(word: &str) -> String {
+    let (stem, ending) = match word.strip_suffix("ий").or_else(|| word.strip_suffix("ый")) {
+        Some(stem) => (stem, "y"),
+        None => (word, ""),
+    };
+    let mut res = String::new();
+    for c in stem.chars() {
+        match TRANSLITERATION.iter().find(|(cyrillic, _)| *cyrillic == c) {
+            Some((_, latin)) => res.push_str(latin),
+            None => res.push(c),
+        }
+    }
+    res + ending
+}
+
+/// Spellings of the same sound are made equal, "Моцарт" is spelled "Mozart".
+fn latin_key(latin: &str) -> String {
+    latin.replace("ts", "z")
+}
+
+const NUMBER_WORDS: [(&str, u32); 40] = [
+    ("ноль", 0),
+    ("один", 1),
+    ("одна", 1),
+    ("одно", 1),
+    ("два", 2),
+    ("две", 2),
+    ("три", 3),
+    ("четыре", 4),
+    ("пять", 5),
+    ("шесть", 6),
+    ("семь", 7),
+    ("восемь", 8),
+    ("девять", 9),
+    ("десять", 10),
+    ("одиннадцать", 11),
+    ("двенадцать", 12),
+    ("тринадцать", 13),
+    ("четырнадцать", 14),
+    ("пятнадцать", 15),
+    ("шестнадцать", 16),
+    ("семнадцать", 17),
+    ("восемнадцать", 18),
+    ("девятнадцать", 19),
+    ("двадцать", 20),
+    ("тридцать", 30),
+    ("сорок", 40),
+    ("пятьдесят", 50),
+    ("шестьдесят", 60),
+    ("семьдесят", 70),
+    ("восемьдесят", 80),
+    ("девяносто", 90),
+    ("сто", 100),
+    ("двести", 200),
+    ("триста", 300),
+    ("четыреста", 400),
+    ("пятьсот", 500),
+    ("шестьсот", 600),
+    ("семьсот", 700),
+    ("восемьсот", 800),
+    ("девятьсот", 900),
+];
+const THOUSAND_WORDS: [&str; 3] = ["тысяча", "тысячи", "тысяч"];
+/// Dropped next to numbers, "1984 год" is the same answer as "1984".
+const NUMBER_UNITS: [&str; 9] = [
+    "г",
+    "гг",
+    "год",
+    "года",
+    "году",
+    "годом",
+    "годы",
+    "годах",
+    "лет",
+];
+
+/// Words of an answer with spelled-out numbers in digits and without units, `None` if there is no
+/// number in the answer.
+fn numeric_form(words: &[String]) -> Option<String> {
+    let mut res = Vec::new();
+    let mut has_number = false;
+    // Spelled number being read: thousands, the rest and the place of its last word
+    let mut spelled: Option<(u32, u32, u8)> = None;
+    for word in words {
+        if word.chars().all(|c| c.is_ascii_digit()) {
+            flush_number(&mut spelled, &mut res);
+            let trimmed = word.trim_start_matches('0');
+            res.push(if trimmed.is_empty() { "0" } else { trimmed }.to_string());
+            has_number = true;
+        } else if THOUSAND_WORDS.contains(&word.as_str()) {
+            let (thousands, rest, _) = spelled.unwrap_or((0, 0, 0));
+            spelled = Some((thousands + rest.max(1) * 1000, 0, 4));
+            has_number = true;
+        } else if let Some(value) = number_word(word) {
+            let place = match value {
+                100.. => 3,
+                20.. => 2,
+                _ => 1,
+            };
+            spelled = match spelled {
+                // "сто двадцать три" is one number, "двадцать сто" are two
+                Some((thousands, rest, last)) if place < last => {
+                    Some((thousands, rest + value, place))
                 }
+                _ => {
+                    flush_number(&mut spelled, &mut res);
+                    Some((0, value, place))
+                }
+            };
+            has_number = true;
+        } else {
+            flush_number(&mut spelled, &mut res);
+            if !NUMBER_UNITS.contains(&word.as_str()) {
+                res.push(word.clone());
             }
         }
-        res
     }
+    flush_number(&mut spelled, &mut res);
+    if has_number {
+        Some(res.join(" "))
+    } else {
+        None
+    }
+}
+
+fn flush_number(spelled: &mut Option<(u32, u32, u8)>, res: &mut Vec<String>) {
+    if let Some((thousands, rest, _)) = spelled.take() {
+        res.push((thousands + rest).to_string());
+    }
+}
+
+fn number_word(word: &str) -> Option<u32> {
+    NUMBER_WORDS
+        .iter()
+        .find(|(number_word, _)| *number_word == word)
+        .map(|(_, value)| *value)
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Clone, Deserialize)]
+#[derive(BorshSerialize, BorshDeserialize, Clone, Deserialize, Serialize)]
 pub struct Topic {
     pub name: String,
     pub questions: Vec<Question>,
+    #[serde(default)]
+    pub author: Option<String>,
 }
 
 impl Topic {
@@ -106,7 +467,34 @@ impl Topic {
         Topic {
             name: encode(&name),
             questions,
+            author: None,
+        }
+    }
+
+    pub fn with_author(mut self, author: Option<String>) -> Self {
+        self.author = author.map(|author| encode(&author));
+        self
+    }
+
+    /// Hash of the lowercased name and question texts that stays the same between runs, so it
+    /// can be stored. Equal for a topic copied to another set.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        let parts =
+            once(&self.name).chain(self.questions.iter().map(|question| &question.question));
+        for part in parts {
+            let normalized = part
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase();
+            // Zero separates the parts, so moving text between them changes the hash
+            for byte in normalized.bytes().chain(once(0)) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
         }
+        hash
     }
 
     pub fn topic_word(topics: usize) -> String {
@@ -120,12 +508,14 @@ impl Topic {
     }
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Deserialize)]
+#[derive(BorshSerialize, BorshDeserialize, Deserialize, Serialize)]
 pub struct TopicSet {
     pub id: String,
     pub title: String,
     pub description: String,
     pub topics: Vec<Topic>,
+    #[serde(default)]
+    pub author: Option<String>,
 }
 
 impl TopicSet {
@@ -135,10 +525,293 @@ impl TopicSet {
             title: encode(&title),
             description: encode(&description),
             topics,
+            author: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_empty_topics(id: &str, topic_count: usize) -> Self {
+        Self::new(
+            id.to_string(),
+            "title".to_string(),
+            String::new(),
+            (0..topic_count)
+                .map(|i| Topic::new(i.to_string(), Vec::new()))
+                .collect(),
+        )
+    }
+
+    pub fn with_author(mut self, author: Option<String>) -> Self {
+        self.author = author.map(|author| encode(&author));
+        self
+    }
+
+    /// Html-escapes the text of a set read from a file, the way the constructors do.
+    pub fn encoded(self) -> Self {
+        Self::new(
+            self.id,
+            self.title,
+            self.description,
+            self.topics
+                .into_iter()
+                .map(|topic| {
+                    Topic::new(
+                        topic.name,
+                        topic
+                            .questions
+                            .into_iter()
+                            .map(|question| Question {
+                                image: question.image,
+                                all_required: question.all_required,
+                                ..Question::new(
+                                    question.cost,
+                                    question.question,
+                                    &question.answers,
+                                    question.comment,
+                                )
+                            })
+                            .collect(),
+                    )
+                    .with_author(topic.author)
+                })
+                .collect(),
+        )
+        .with_author(self.author)
+    }
+
+    /// The set with the text as it was uploaded, the reverse of `encoded`.
+    pub fn decoded(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            title: decode(&self.title),
+            description: decode(&self.description),
+            topics: self
+                .topics
+                .iter()
+                .map(|topic| Topic {
+                    name: decode(&topic.name),
+                    questions: topic
+                        .questions
+                        .iter()
+                        .map(|question| Question {
+                            cost: question.cost,
+                            question: decode(&question.question),
+                            answers: question.answers.iter().map(decode).collect(),
+                            comment: question.comment.as_ref().map(decode),
+                            image: question.image.clone(),
+                            all_required: question.all_required,
+                        })
+                        .collect(),
+                    author: topic.author.as_ref().map(decode),
+                })
+                .collect(),
+            author: self.author.as_ref().map(decode),
+        }
+    }
+}
+
+/// Line shown under a set or topic name, empty when the author is unknown.
+pub fn author_line(author: &Option<String>) -> String {
+    match author {
+        Some(author) => format!("\n<i>Автор: {}</i>", author),
+        None => String::new(),
+    }
+}
+
+/// Parts of an answer naming several things, "Северный и Южный" or "Север, Юг".
+fn answer_parts(answer: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    for part in answer.split(|c: char| matches!(c, ',' | ';' | '/' | '+' | '&')) {
+        let mut words = Vec::new();
+        for word in part.split_whitespace() {
+            if ["и", "and"].contains(&word.to_lowercase().as_str()) {
+                res.push(words.join(" "));
+                words.clear();
+            } else {
+                words.push(word);
+            }
+        }
+        res.push(words.join(" "));
+    }
+    res.retain(|part| !part.is_empty());
+    res
+}
+
+/// Edit distance where swapping two adjacent characters counts as one edit.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut distance = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in distance.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in distance[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            distance[i][j] = (distance[i - 1][j] + 1)
+                .min(distance[i][j - 1] + 1)
+                .min(distance[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance[i][j] = distance[i][j].min(distance[i - 2][j - 2] + 1);
+            }
         }
     }
+    distance[a.len()][b.len()]
 }
 
 pub fn encode(s: &String) -> String {
     encode_text(s.as_str()).to_string()
 }
+
+pub fn decode(s: &String) -> String {
+    decode_html_entities(s.as_str()).to_string()
+}
+
+#[test]
+fn test_fuzzy_answers() {
+    let question =
+        |answer: &str| Question::new(10, "Вопрос".to_string(), &[answer.to_string()], None);
+    let cases = [
+        ("Эйнштейн", "ейнштейн", true),
+        ("Эйнштейн", "эйншетйн", true),
+        ("Эйнштейн", "эйнштеин", true),
+        ("Эйнштейн", "энштеин", false),
+        ("Достоевский", "достаевский", true),
+        ("Достоевский", "дастаевский", true),
+        ("Достоевский", "дастаевскей", false),
+        ("Париж", "парижж", true),
+        ("Париж", "пориш", false),
+        ("Рим", "рис", false),
+        ("Ум", "ус", false),
+        ("1812", "1813", false),
+        ("1812 год", "1812 гот", true),
+        ("1812 год", "1813 год", false),
+        ("5 углов", "6 углов", false),
+        ("Три мушкетера", "тримушкетер", true),
+        ("Волга (река)", "волго", true),
+    ];
+    for (expected, answer, accepted) in cases {
+        let question = question(expected);
+        assert_eq!(
+            question.check_answer(answer, false),
+            accepted,
+            "{} / {}",
+            expected,
+            answer
+        );
+        assert_eq!(
+            question.check_answer(answer, true),
+            Question::no_space(answer, false) == Question::no_space(expected, false),
+            "{} / {} strict",
+            expected,
+            answer
+        );
+    }
+}
+
+#[test]
+fn test_number_answers() {
+    let cases = [
+        ("1984", "1984", true),
+        ("1984", "тысяча девятьсот восемьдесят четыре", true),
+        ("1984", "1984 год", true),
+        ("1984", "1984г.", true),
+        ("1984 год", "1984", true),
+        ("тысяча девятьсот восемьдесят четыре", "1984", true),
+        ("Тысяча девятьсот восемьдесят четвертый", "1984", false),
+        ("2100", "две тысячи сто", true),
+        ("Две тысячи двадцать", "2020 год", true),
+        ("100", "сто", true),
+        ("0", "ноль", true),
+        ("12 лет", "двенадцать", true),
+        ("Три мушкетера", "3 мушкетера", true),
+        ("1984", "1985", false),
+        ("1984", "девятнадцать восемьдесят четыре", false),
+        ("1812", "в 1812 году", false),
+        ("Пять", "шесть", false),
+    ];
+    for (expected, answer, accepted) in cases {
+        let question = Question::new(10, "Вопрос".to_string(), &[expected.to_string()], None);
+        for strict in [false, true] {
+            assert_eq!(
+                question.check_answer(answer, strict),
+                accepted,
+                "{} / {}",
+                expected,
+                answer
+            );
+        }
+    }
+}
+
+#[test]
+fn test_mixed_script_answers() {
+    let cases = [
+        ("Масло", "Мacлo", true, true),
+        ("Mozart", "Mоzart", true, true),
+        ("Моцарт", "Mozart", true, true),
+        ("Лондон", "London", true, true),
+        ("Сад", "sad", true, true),
+        ("Достоевский", "Dostoevsky", true, true),
+        ("Чайковский", "Tchaikovsky", true, false),
+        ("Пушкин", "Pushkinn", true, false),
+        ("Рим", "Pum", false, false),
+        ("Вор", "Bop", false, false),
+        ("Нос", "Hoc", false, false),
+        ("Сок", "cok", false, false),
+        ("Рапира", "Panupa", false, false),
+        ("Толстой", "Tolstaya", false, false),
+        ("London", "Лондон", false, false),
+    ];
+    for (expected, answer, accepted, accepted_strict) in cases {
+        let question = Question::new(10, "Вопрос".to_string(), &[expected.to_string()], None);
+        assert_eq!(
+            question.check_answer(answer, false),
+            accepted,
+            "{} / {}",
+            expected,
+            answer
+        );
+        assert_eq!(
+            question.check_answer(answer, true),
+            accepted_strict,
+            "{} / {} strict",
+            expected,
+            answer
+        );
+    }
+}
+
+#[test]
+fn test_all_required_answers() {
+    let question = Question {
+        all_required: true,
+        ..Question::new(
+            10,
+            "Назовите оба полюса".to_string(),
+            &["Северный".to_string(), "Южный".to_string()],
+            None,
+        )
+    };
+    let cases = [
+        ("Северный и Южный", true),
+        ("южный, северный", true),
+        ("Северный / Южный", true),
+        ("Северный + южный полюс", true),
+        ("Северный и южный полюс", true),
+        ("Северный полюс и южный полюс", true),
+        ("Северный и западный полюс", false),
+        ("Северный", false),
+        ("Южный и Южный", false),
+        ("Северный и Восточный", false),
+        ("Северный, Южный и Восточный", false),
+    ];
+    for (answer, accepted) in cases {
+        assert_eq!(question.check_answer(answer, true), accepted, "{}", answer);
+    }
+    assert_eq!(
+        question.display_answers(false),
+        "<b>Ответ:</b> Северный + Южный"
+    );
+}